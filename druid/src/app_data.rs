@@ -1,8 +1,8 @@
 use druid::{Point, Vec2};
 use swarm_rs::{
-    behavior_tree_lite::parse_file,
     // agent::AvoidanceRenderParams,
     game::{BoardParams, BoardType, Game, GameParams, TeamConfig},
+    parse_tree_source,
 };
 
 use ::druid::{Data, Lens};
@@ -43,6 +43,8 @@ pub struct AppData {
     pub message: String,
     pub(crate) big_message: String,
     pub big_message_time: f64,
+    /// Show the built-in overlay announcing the game start and the winner
+    pub show_big_message: bool,
     pub(super) mouse_pos: Option<Point>,
     pub(crate) get_board_time: f64,
     // pub(crate) render_board_time: Cell<f64>,
@@ -80,6 +82,7 @@ impl AppData {
                 spawner_source: Rc::new(
                     include_str!("../../behavior_tree_config/green/spawner.btc").to_string(),
                 ),
+                ..TeamConfig::default()
             },
             TeamConfig {
                 agent_source: Rc::new(
@@ -88,6 +91,7 @@ impl AppData {
                 spawner_source: Rc::new(
                     include_str!("../../behavior_tree_config/red/spawner.btc").to_string(),
                 ),
+                ..TeamConfig::default()
             },
         ];
 
@@ -118,6 +122,7 @@ impl AppData {
             message: "".to_string(),
             big_message: "Game Start".to_string(),
             big_message_time: 5000.,
+            show_big_message: true,
             mouse_pos: None,
             // render_board_time: Cell::new(0.),
             get_board_time: 0.,
@@ -144,10 +149,17 @@ impl AppData {
         let interval = game.interval;
         if !self.game_params.paused {
             let update_res = game.update();
-            if let swarm_rs::game::UpdateResult::TeamWon(team) = update_res {
+            let message = match update_res {
+                swarm_rs::game::UpdateResult::TeamWon(result) => {
+                    Some(["Green team won!!", "Red team won!!"][result.won_team])
+                }
+                swarm_rs::game::UpdateResult::Draw { .. } => Some("Draw!!"),
+                swarm_rs::game::UpdateResult::Running => None,
+            };
+            if let Some(message) = message {
                 drop(game);
                 self.new_game();
-                self.big_message = ["Green team won!!", "Red team won!!"][team].to_string();
+                self.big_message = message.to_string();
                 self.big_message_time = 5000.;
             }
         }
@@ -165,11 +177,16 @@ impl AppData {
             seed,
             simplify,
             maze_expansions: self.maze_expansions.parse().unwrap_or(1),
+            slow_shores: false,
         };
         let mut game = self.game.borrow_mut();
         game.new_board(self.board_type, &params);
         game.init();
 
+        if let Some(e) = game.qtree_error() {
+            self.message = format!("QTree failed to initialize, using grid path finding: {e}");
+        }
+
         self.big_message = "Game Start".to_string();
         self.big_message_time = 5000.;
     }
@@ -179,13 +196,9 @@ impl AppData {
         src: Rc<String>,
         setter: fn(&mut GameParams) -> &mut Rc<String>,
     ) -> bool {
-        fn count_newlines(src: &str) -> usize {
-            src.lines().count()
-        }
-
         // Check the syntax before applying
-        match parse_file(&src) {
-            Ok(("", _)) => {
+        match parse_tree_source(&src) {
+            Ok(_) => {
                 *setter(&mut self.game_params) = src.clone();
                 self.message = format!(
                     "Behavior tree applied! {}",
@@ -193,15 +206,6 @@ impl AppData {
                 );
                 true
             }
-            Ok((rest, _)) => {
-                let parsed_src = &src[..rest.as_ptr() as usize - src.as_ptr() as usize];
-                self.message = format!(
-                    "Behavior tree source ended unexpectedly at ({}) {:?}",
-                    count_newlines(parsed_src),
-                    rest
-                );
-                false
-            }
             Err(e) => {
                 self.message = format!("Behavior tree failed to parse: {}", e);
                 false
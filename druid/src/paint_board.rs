@@ -1,7 +1,7 @@
 use ::swarm_rs::{
-    agent::{AgentClass, Bullet, AGENT_HALFLENGTH, AGENT_HALFWIDTH, BULLET_RADIUS},
+    agent::{AgentClass, Bullet, BULLET_RADIUS},
     entity::Entity,
-    game::Resource,
+    game::{Resource, DEFAULT_TEAM_COLORS},
     marching_squares::{cell_lines, cell_polygon_index, pick_bits, BoolField, CELL_POLYGON_BUFFER},
     perlin_noise::Xor128,
     triangle_utils::center_of_triangle_obj,
@@ -66,7 +66,8 @@ pub(crate) fn paint_board(
 
     let shape = (xs as isize, ys as isize);
 
-    let field = BoolField::new(game.board.as_ref(), shape);
+    let passable: Vec<bool> = game.board.iter().map(|t| t.is_passable()).collect();
+    let field = BoolField::new(&passable, shape);
 
     ctx.with_save(|ctx| {
         ctx.transform(*view_transform);
@@ -283,17 +284,15 @@ fn to_vec2(pos: [f64; 2]) -> Vec2 {
 }
 
 fn paint_agents(ctx: &mut PaintCtx, data: &AppData, env: &Env, view_transform: &Affine) {
-    const AGENT_COLORS: [Color; 2] = [Color::rgb8(0, 255, 127), Color::rgb8(255, 0, 63)];
-
     let game = data.game.borrow();
-    let draw_rectangle = 1. / AGENT_HALFLENGTH < data.scale;
     let entities = &game.entities;
 
     for agent in entities.iter() {
         let agent = agent.borrow();
         let pos = to_point(agent.get_pos());
         let circle = Circle::new(*view_transform * pos, 5.);
-        let brush = &AGENT_COLORS[agent.get_team() % AGENT_COLORS.len()];
+        let [r, g, b] = DEFAULT_TEAM_COLORS[agent.get_team() % DEFAULT_TEAM_COLORS.len()];
+        let brush = &Color::rgb8(r, g, b);
         ctx.fill(circle, brush);
 
         if !agent.is_agent() {
@@ -336,7 +335,8 @@ fn paint_agents(ctx: &mut PaintCtx, data: &AppData, env: &Env, view_transform: &
             let orient_line = Line::new(view_pos, dest);
             ctx.stroke(orient_line, brush, 3.);
 
-            if draw_rectangle {
+            // Draw the outline only if the agent is big enough on the screen
+            if 1. / class.shape().0 < data.scale {
                 let rot_transform =
                     *view_transform * Affine::translate(pos.to_vec2()) * Affine::rotate(orient);
                 let mut path = BezPath::new();
@@ -400,7 +400,7 @@ fn paint_agents(ctx: &mut PaintCtx, data: &AppData, env: &Env, view_transform: &
                 if let Some(avoidance_plan) = &agent.avoidance_plan {
                     for &(drive, steer) in avoidance_plan {
                         let target = agent.get_avoidance_state((drive, steer));
-                        let circle = Circle::new(to_point(target.into()), AGENT_HALFWIDTH);
+                        let circle = Circle::new(to_point(target.into()), agent.class.shape().1);
                         ctx.fill(*view_transform * circle, &Color::rgba8(255, 255, 255, 127));
                     }
                 }
@@ -609,7 +609,7 @@ fn paint_temp_ents(ctx: &mut PaintCtx, data: &AppData, view_transform: &Affine)
 }
 
 fn paint_big_message(ctx: &mut PaintCtx, env: &Env, data: &AppData) {
-    if 0. < data.big_message_time {
+    if data.show_big_message && 0. < data.big_message_time {
         let mut layout = TextLayout::<String>::from_text(&data.big_message);
         layout.set_font(FontDescriptor::new(FontFamily::SANS_SERIF).with_size(48.0));
         layout.set_text_color(Color::rgba(
@@ -38,34 +38,24 @@ pub(crate) fn paint_qtree(ctx: &mut PaintCtx, data: &AppData, view_transform: &A
 
     let qtree = qtree_searcher.get_qtree();
 
-    for (level, cells) in qtree.levels.iter().enumerate() {
-        let width = qtree.width(level);
-        for (cell, state) in cells
-            .iter()
-            .filter(|(_, state)| !matches!(state, CellState::Mixed))
-        {
-            let (x, y) = (
-                cell[0] << (qtree.toplevel - level),
-                cell[1] << (qtree.toplevel - level),
-            );
-            let rect = Rect::new(
-                x as f64 + CELL_MARGIN,
-                y as f64 + CELL_MARGIN,
-                x as f64 + width as f64 - CELL_MARGIN,
-                y as f64 + width as f64 - CELL_MARGIN,
-            );
-            let rect = rect.to_path(1.);
-            ctx.stroke(
-                *view_transform * rect,
-                &match state {
-                    CellState::Obstacle => Color::rgb8(255, 127, 127),
-                    CellState::Occupied(_) => Color::rgb8(255, 127, 255),
-                    CellState::Free => Color::rgb8(0, 255, 127),
-                    _ => Color::rgb8(255, 0, 255),
-                },
-                1.,
-            );
-        }
+    for ([x0, y0, x1, y1], state) in qtree.leaves() {
+        let rect = Rect::new(
+            x0 as f64 + CELL_MARGIN,
+            y0 as f64 + CELL_MARGIN,
+            x1 as f64 - CELL_MARGIN,
+            y1 as f64 - CELL_MARGIN,
+        );
+        let rect = rect.to_path(1.);
+        ctx.stroke(
+            *view_transform * rect,
+            &match state {
+                CellState::Obstacle => Color::rgb8(255, 127, 127),
+                CellState::Occupied(_) => Color::rgb8(255, 127, 255),
+                CellState::Free => Color::rgb8(0, 255, 127),
+                _ => Color::rgb8(255, 0, 255),
+            },
+            1.,
+        );
     }
 }
 
@@ -47,6 +47,7 @@ pub(crate) fn make_widget() -> impl Widget<AppData> {
                         ("Perlin", BoardType::Perlin),
                         ("Rooms", BoardType::Rooms),
                         ("Maze", BoardType::Maze),
+                        ("Rooms+Maze", BoardType::RoomsMaze),
                     ])
                     .lens(AppData::board_type),
                 )
@@ -7,18 +7,47 @@ use std::path::Path;
 pub(crate) use self::paint_bt::BTWidget;
 use self::syntax_highlighting::{highlight, CodeTheme};
 use crate::{
-    app_data::{AppData, BtType},
+    app_data::{AppData, BtType, BulletStyle, ViewSettings},
     bg_image::BgImage,
 };
 use cgmath::{Matrix3, Point2, Transform, Vector2};
 use egui::{Color32, Pos2, RichText, Ui};
 use swarm_rs::{
-    game::{BoardParams, BoardType, UpdateResult},
+    behavior_tree_lite::PortType,
+    describe_agent_nodes, describe_spawner_nodes,
+    game::{BoardParams, BoardType, GameEventRecord},
     vfs::Vfs,
 };
 
 const WINDOW_HEIGHT: f64 = 800.;
 
+/// Options to launch the application preconfigured, e.g. when it is embedded in another program.
+pub struct AppOptions {
+    pub title: String,
+    /// Initial window size in points. `None` leaves it to the platform.
+    pub window_size: Option<[f32; 2]>,
+    pub board_type: BoardType,
+    pub board_size: (usize, usize),
+    pub seed: u32,
+    pub agent_count: usize,
+    /// Show the menu bar, the side panel and the behavior tree graph around the game view.
+    pub show_panels: bool,
+}
+
+impl Default for AppOptions {
+    fn default() -> Self {
+        Self {
+            title: "swarm-rs application in eframe".to_owned(),
+            window_size: None,
+            board_type: BoardType::Rooms,
+            board_size: (128, 128),
+            seed: 123513,
+            agent_count: 3,
+            show_panels: true,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 enum Panel {
     Main,
@@ -46,9 +75,15 @@ pub struct SwarmRsApp {
 
     show_labels: bool,
 
+    show_kill_heatmap: bool,
+
     #[serde(skip)]
     app_data: AppData,
 
+    /// The camera and view options of `app_data`, synced on save. `None` in the state saved
+    /// before they were persisted.
+    view_settings: Option<ViewSettings>,
+
     draw_circle: bool,
 
     board_type: BoardType,
@@ -56,6 +91,7 @@ pub struct SwarmRsApp {
     xs: usize,
     ys: usize,
     maze_expansions: usize,
+    slow_shores: bool,
     agent_count: usize,
     bt_source_file: [BTSourceFiles; 2],
 
@@ -67,24 +103,38 @@ pub struct SwarmRsApp {
 
     #[serde(skip)]
     last_log: Option<String>,
+
+    #[serde(skip)]
+    show_panels: bool,
 }
 
 impl Default for SwarmRsApp {
     fn default() -> Self {
-        let seed = 123513;
+        Self::from_options(&AppOptions::default())
+    }
+}
+
+impl SwarmRsApp {
+    fn from_options(options: &AppOptions) -> Self {
+        let window_height = options
+            .window_size
+            .map_or(WINDOW_HEIGHT, |size| size[1] as f64);
         Self {
-            seed_text: seed.to_string(),
+            seed_text: options.seed.to_string(),
             img_gray: BgImage::new(),
             img_labels: BgImage::new(),
             open_panel: Panel::Main,
             show_labels: false,
-            app_data: AppData::new(WINDOW_HEIGHT),
+            show_kill_heatmap: false,
+            app_data: AppData::new(window_height),
+            view_settings: None,
             draw_circle: false,
-            board_type: BoardType::Rooms,
-            xs: 128,
-            ys: 128,
+            board_type: options.board_type,
+            xs: options.board_size.0,
+            ys: options.board_size.1,
             maze_expansions: 512,
-            agent_count: 3,
+            slow_shores: false,
+            agent_count: options.agent_count,
             bt_source_file: [
                 BTSourceFiles {
                     agent: "green/agent.txt".to_owned(),
@@ -98,11 +148,30 @@ impl Default for SwarmRsApp {
             canvas_offset: Pos2::ZERO,
             mouse_pos: None,
             last_log: None,
+            show_panels: options.show_panels,
+        }
+    }
+
+    /// Create the application configured by `options` without restoring the persisted state.
+    pub fn with_options(options: AppOptions) -> Self {
+        let mut res = Self::from_options(&options);
+        res.app_data.game_params.agent_count = res.agent_count;
+        res.app_data.game.set_params(&res.app_data.game_params);
+        res.app_data
+            .new_game(res.board_type, res.board_params(), true);
+        res
+    }
+
+    fn board_params(&self) -> BoardParams {
+        BoardParams {
+            shape: (self.xs, self.ys),
+            seed: self.seed_text.parse().unwrap_or(1),
+            simplify: 0.,
+            maze_expansions: self.maze_expansions,
+            slow_shores: self.slow_shores,
         }
     }
-}
 
-impl SwarmRsApp {
     pub(crate) fn view_transform(&self) -> Matrix3<f64> {
         Matrix3::from_scale(self.app_data.scale)
             * Matrix3::from_translation(self.app_data.origin.into())
@@ -113,26 +182,51 @@ impl SwarmRsApp {
             * Matrix3::from_scale(1. / self.app_data.scale)
     }
 
-    /// Called once before the first frame.
-    pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
+    /// Move `scale` toward `target_scale` by an animation step of `dt` seconds, shifting the
+    /// origin so that the world point under `zoom_anchor` stays at the same screen position.
+    pub(crate) fn step_zoom(&mut self, dt: f64) {
+        /// Rate of the exponential ease, i.e. the inverse of its time constant in seconds
+        const ZOOM_SPEED: f64 = 15.;
+        /// Relative difference of scales below which the animation snaps to the target
+        const ZOOM_SNAP: f64 = 1e-3;
+
+        let (scale, target) = (self.app_data.scale, self.app_data.target_scale);
+        if scale == target {
+            return;
+        }
+        let anchor = Point2::from(self.app_data.zoom_anchor);
+        let old_offset = transform_point(&self.inverse_view_transform(), anchor);
+
+        // Interpolate in log space so that zooming in and out take the same time
+        let rate = 1. - (-ZOOM_SPEED * dt).exp();
+        let new_scale = (scale.ln() + (target.ln() - scale.ln()) * rate).exp();
+        self.app_data.scale = if (new_scale / target - 1.).abs() < ZOOM_SNAP {
+            target
+        } else {
+            new_scale
+        };
+
+        let new_offset = transform_point(&self.inverse_view_transform(), anchor);
+        let diff = new_offset - old_offset;
+        self.app_data.origin = (Vector2::<f64>::from(self.app_data.origin) + diff).into();
+    }
+
+    /// Called once before the first frame. The persisted state takes precedence over `options`,
+    /// which configure the application on the first launch.
+    pub fn new(cc: &eframe::CreationContext<'_>, options: AppOptions) -> Self {
         // This is also where you can customize the look and feel of egui using
         // `cc.egui_ctx.set_visuals` and `cc.egui_ctx.set_fonts`.
 
         // Load previous app state (if any).
         // Note that you must enable the `persistence` feature for this to work.
-        let mut res = if let Some(storage) = cc.storage {
-            eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default()
-        } else {
-            Self::default()
-        };
+        let mut res = cc
+            .storage
+            .and_then(|storage| eframe::get_value::<Self>(storage, eframe::APP_KEY))
+            .unwrap_or_else(|| Self::from_options(&options));
+        res.show_panels = options.show_panels;
 
         println!("Recreating Game with {:?}", (res.xs, res.ys));
-        let params = BoardParams {
-            shape: (res.xs, res.ys),
-            seed: res.seed_text.parse().unwrap_or(1),
-            simplify: 0.,
-            maze_expansions: res.maze_expansions,
-        };
+        let params = res.board_params();
 
         // "Consume" the error, since we don't have a good way to communicate the error on the startup of
         // the program. At least it will show on console if you run native build.
@@ -162,6 +256,10 @@ impl SwarmRsApp {
 
         res.app_data.new_game(res.board_type, params, true);
 
+        if let Some(view_settings) = &res.view_settings {
+            res.app_data.apply_view_settings(view_settings);
+        }
+
         res
     }
 
@@ -175,14 +273,17 @@ impl SwarmRsApp {
             ui.checkbox(&mut self.app_data.bt_visible, "BT Graphical editor");
         });
 
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.app_data.bullet_time, "Bullet time on selection");
+            ui.add_enabled(
+                self.app_data.bullet_time,
+                egui::Slider::new(&mut self.app_data.bullet_time_rate, 0.05..=1.).text("Rate"),
+            );
+        });
+
         ui.collapsing("New game options", |ui| {
             if ui.button("New game").clicked() {
-                let params = BoardParams {
-                    shape: (self.xs, self.ys),
-                    seed: self.seed_text.parse().unwrap_or(1),
-                    simplify: 0.,
-                    maze_expansions: self.maze_expansions,
-                };
+                let params = self.board_params();
                 self.app_data.new_game(self.board_type, params, true);
                 self.img_gray.clear();
                 self.img_labels.clear();
@@ -194,6 +295,7 @@ impl SwarmRsApp {
                 ui.radio_value(&mut self.board_type, BoardType::Perlin, "Perlin");
                 ui.radio_value(&mut self.board_type, BoardType::Rooms, "Rooms");
                 ui.radio_value(&mut self.board_type, BoardType::Maze, "Maze");
+                ui.radio_value(&mut self.board_type, BoardType::RoomsMaze, "Rooms+Maze");
             });
 
             ui.horizontal(|ui| {
@@ -213,12 +315,21 @@ impl SwarmRsApp {
                 ui.label("Maze expansion");
                 ui.add(egui::Slider::new(&mut self.maze_expansions, 32..=1024));
             });
+            ui.checkbox(&mut self.slow_shores, "Slow terrain along shores (Perlin)");
 
             ui.horizontal(|ui| {
                 ui.label("Agents");
                 ui.add(egui::Slider::new(&mut self.agent_count, 1..=100));
             });
 
+            ui.horizontal(|ui| {
+                ui.label("QTree build budget");
+                ui.add(egui::Slider::new(
+                    &mut self.app_data.game_params.qtree_build_budget,
+                    0..=10000,
+                ));
+            });
+
             ui.horizontal(|ui| {
                 ui.checkbox(&mut self.app_data.game_params.fow, "Fog of War");
 
@@ -227,6 +338,50 @@ impl SwarmRsApp {
                     "Fog raycasting",
                 );
             });
+
+            ui.horizontal(|ui| {
+                ui.label("Fog raycast step");
+                ui.add(egui::Slider::new(
+                    &mut self.app_data.game_params.fog_raycast_step,
+                    1..=8,
+                ));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Min spawner distance");
+                ui.add(egui::Slider::new(
+                    &mut self.app_data.game_params.min_spawner_distance,
+                    0.0..=100.0,
+                ));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Time limit ticks (0 = unlimited)");
+                ui.add(egui::Slider::new(
+                    &mut self.app_data.game_params.time_limit_ticks,
+                    0..=100000,
+                ));
+            });
+
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.app_data.game_params.ricochet, "Ricochet");
+
+                ui.checkbox(&mut self.app_data.game_params.sensor_cone, "Sensor cone");
+
+                ui.checkbox(&mut self.app_data.game_params.limited_ammo, "Limited ammo");
+
+                ui.checkbox(
+                    &mut self.app_data.game_params.damage_falloff,
+                    "Damage falloff",
+                );
+
+                ui.checkbox(&mut self.app_data.game_params.safe_spawn, "Safe spawn");
+
+                ui.checkbox(
+                    &mut self.app_data.game_params.slow_blocks_bullets,
+                    "Slow terrain blocks bullets",
+                );
+            });
         });
 
         ui.collapsing("View options", |ui| {
@@ -234,6 +389,11 @@ impl SwarmRsApp {
                 ui.add(egui::Checkbox::new(&mut self.app_data.path_visible, "Path"));
 
                 ui.add(egui::Checkbox::new(&mut self.draw_circle, "Circle"));
+
+                ui.add(egui::Checkbox::new(
+                    &mut self.app_data.clamp_camera,
+                    "Clamp camera",
+                ));
             });
 
             ui.horizontal(|ui| {
@@ -246,6 +406,8 @@ impl SwarmRsApp {
                     &mut self.app_data.qtree_search_visible,
                     "QTree search",
                 ));
+
+                ui.add(egui::Checkbox::new(&mut self.app_data.mesh_visible, "Mesh"));
             });
 
             ui.horizontal(|ui| {
@@ -258,8 +420,44 @@ impl SwarmRsApp {
                     &mut self.app_data.entity_trace_visible,
                     "Trace line",
                 ));
+
+                ui.add(egui::Checkbox::new(
+                    &mut self.app_data.show_sight_lines,
+                    "Sight lines",
+                ));
+
+                ui.add(egui::Checkbox::new(
+                    &mut self.app_data.show_dijkstra,
+                    "Dijkstra",
+                ));
+
+                ui.add(egui::Checkbox::new(
+                    &mut self.app_data.show_velocity,
+                    "Velocity",
+                ));
+
+                ui.add(egui::Checkbox::new(
+                    &mut self.app_data.show_contest,
+                    "Resource contest",
+                ));
+
+                ui.add(egui::Checkbox::new(&mut self.app_data.show_roles, "Roles"));
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Bullets:");
+                let style = &mut self.app_data.bullet_style;
+                ui.radio_value(style, BulletStyle::ByClass, "By class");
+                ui.radio_value(style, BulletStyle::Tracer, "Tracer");
+                ui.radio_value(style, BulletStyle::Dot, "Dot");
+                ui.radio_value(style, BulletStyle::Beam, "Beam");
             });
 
+            ui.add(egui::Checkbox::new(
+                &mut self.app_data.show_big_message,
+                "Big message",
+            ));
+
             ui.add(egui::Checkbox::new(
                 &mut self.app_data.entity_label_visible,
                 "Entity labels",
@@ -268,6 +466,11 @@ impl SwarmRsApp {
             ui.horizontal_wrapped(|ui| {
                 ui.add(egui::Checkbox::new(&mut self.show_labels, "Label image"));
 
+                ui.add(egui::Checkbox::new(
+                    &mut self.show_kill_heatmap,
+                    "Kill heatmap",
+                ));
+
                 ui.add(egui::Checkbox::new(
                     &mut self.app_data.game.enable_raycast_board,
                     "Raycast image",
@@ -284,6 +487,61 @@ impl SwarmRsApp {
                     "Fog raycast",
                 );
             });
+
+            ui.horizontal(|ui| {
+                for (i, color) in self.app_data.team_colors.iter_mut().enumerate() {
+                    ui.label(format!("Team {i} color"));
+                    ui.color_edit_button_srgb(color);
+                }
+            });
+        });
+
+        ui.collapsing("Replay", |ui| {
+            if let Some((player, _)) = &self.app_data.replay_player {
+                let ticks = player.replay().ticks();
+                let in_sync = player.is_in_sync();
+                let res = ui
+                    .add(egui::Slider::new(&mut self.app_data.replay_tick, 0..=ticks).text("Tick"));
+                // Seeking backward replays the match from the start, so wait for the slider
+                // to be released
+                if res.drag_released() || (res.changed() && !res.dragged()) {
+                    self.app_data.seek_replay(self.app_data.replay_tick);
+                }
+                if self.app_data.replay_seek.is_some() {
+                    ui.label(format!("Seeking: {}", self.app_data.game.global_time));
+                }
+                if !in_sync {
+                    ui.colored_label(egui::Color32::RED, "Out of sync with the recording");
+                }
+                if ui.button("Back to the game").clicked() {
+                    self.app_data.close_replay();
+                    self.img_gray.clear();
+                    self.img_labels.clear();
+                }
+            } else {
+                ui.horizontal(|ui| {
+                    if ui.button("Replay this match").clicked() {
+                        if let Some(replay) = self.app_data.game.replay().cloned() {
+                            self.app_data.open_replay(replay);
+                            self.img_gray.clear();
+                            self.img_labels.clear();
+                        }
+                    }
+                    if ui
+                        .add_enabled(
+                            self.app_data.last_replay.is_some(),
+                            egui::Button::new("Replay last match"),
+                        )
+                        .clicked()
+                    {
+                        if let Some(replay) = self.app_data.last_replay.clone() {
+                            self.app_data.open_replay(replay);
+                            self.img_gray.clear();
+                            self.img_labels.clear();
+                        }
+                    }
+                });
+            }
         });
 
         ui.collapsing("Statistics", |ui| {
@@ -301,13 +559,55 @@ impl SwarmRsApp {
                             ui.label(format!("Spawned: {}", game.stats[team].spawned));
                             ui.label(format!("Kills: {}", game.stats[team].kills));
                             ui.label(format!("Wins: {}", game.stats[team].wins));
+                            if ui.button("Clear team").clicked() {
+                                game.clear_team(team);
+                            }
                         });
                     });
                 }
             });
         });
 
+        ui.collapsing("Behavior trees", |ui| {
+            let game = &self.app_data.game;
+            // Sources are distinguished by their first line and length, since they are too long
+            // to show in full.
+            let summary = |source: &str| {
+                let first_line = source
+                    .lines()
+                    .map(str::trim)
+                    .find(|line| !line.is_empty())
+                    .unwrap_or("");
+                format!("\"{first_line}\" ({} bytes)", source.len())
+            };
+            let team_sources = game.team_bt_sources();
+            for (team, (agent_source, spawner_source)) in team_sources.iter().enumerate() {
+                ui.group(|ui| {
+                    ui.label(["Green team", "Red team"][team]);
+                    ui.label(format!("Agent: {}", summary(agent_source)));
+                    ui.label(format!("Spawner: {}", summary(spawner_source)));
+                    for (source, ids) in game.agent_bt_sources(team) {
+                        let status = if source == *agent_source {
+                            "current"
+                        } else {
+                            "outdated"
+                        };
+                        ui.label(format!(
+                            "{} agents, {status}: {}\n  ids: {ids:?}",
+                            ids.len(),
+                            summary(&source)
+                        ));
+                    }
+                });
+            }
+        });
+
         ui.collapsing("Debug output", |ui| {
+            ui.checkbox(
+                &mut self.app_data.game_params.profiling_enabled,
+                "Profiling",
+            );
+
             let game = &self.app_data.game;
 
             ui.label(format!("Scale: {:.06}", self.app_data.scale));
@@ -347,6 +647,17 @@ impl SwarmRsApp {
 
             ui.label(format!("Id: {:?}", self.app_data.selected_entity));
 
+            if let Some(id) = self.app_data.selected_entity {
+                let mut frozen = self.app_data.game.frozen_agents.contains(&id);
+                if ui.checkbox(&mut frozen, "Freeze").changed() {
+                    if frozen {
+                        self.app_data.game.frozen_agents.insert(id);
+                    } else {
+                        self.app_data.game.frozen_agents.remove(&id);
+                    }
+                }
+            }
+
             let entity = self.app_data.selected_entity.and_then(|id| {
                 self.app_data
                     .game
@@ -384,6 +695,17 @@ impl SwarmRsApp {
                 None => ui.label("Resource: ? / ?"),
             };
 
+            match entity.as_ref().and_then(|entity| entity.avoidance_stats()) {
+                Some(stats) => ui.label(format!(
+                    "Avoidance: tree {}, iterations {}, skipped {}, {:.03}ms",
+                    stats.tree_size,
+                    stats.iterations,
+                    stats.skipped_nodes,
+                    stats.last_time * 1e3
+                )),
+                None => ui.label("Avoidance: ?"),
+            };
+
             ui.label("Print log:");
 
             egui::ScrollArea::vertical()
@@ -582,6 +904,34 @@ impl SwarmRsApp {
             }
         });
 
+        ui.collapsing("Node reference", |ui| {
+            let docs = match self.app_data.selected_bt.1 {
+                BtType::Agent => describe_agent_nodes(),
+                BtType::Spawner => describe_spawner_nodes(),
+            };
+            egui::ScrollArea::vertical()
+                .id_source("node_reference")
+                .max_height(200.)
+                .show(ui, |ui| {
+                    for doc in docs {
+                        let ports = doc
+                            .ports
+                            .iter()
+                            .map(|port| match port.ty {
+                                PortType::Input => format!("{} <-", port.key),
+                                PortType::Output => format!("{} ->", port.key),
+                                PortType::InOut => format!("{} <->", port.key),
+                            })
+                            .collect::<Vec<_>>();
+                        if ports.is_empty() {
+                            ui.monospace(doc.name);
+                        } else {
+                            ui.monospace(format!("{} ({})", doc.name, ports.join(", ")));
+                        }
+                    }
+                });
+        });
+
         let theme = CodeTheme::from_style(&ui.ctx().style());
 
         let mut layouter = |ui: &egui::Ui, string: &str, wrap_width: f32| {
@@ -613,6 +963,7 @@ impl SwarmRsApp {
 impl eframe::App for SwarmRsApp {
     /// Called by the frame work to save state before shutdown.
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        self.view_settings = Some(self.app_data.view_settings());
         eframe::set_value(storage, eframe::APP_KEY, self);
     }
 
@@ -623,15 +974,16 @@ impl eframe::App for SwarmRsApp {
 
         let dt = ctx.input().stable_dt.min(0.1);
 
-        let update_res = self.app_data.update(dt as f64 * 1000., self.agent_count);
+        self.app_data.update(dt as f64 * 1000., self.agent_count);
 
-        if let Some(UpdateResult::TeamWon(_)) = update_res {
-            let params = BoardParams {
-                shape: (self.xs, self.ys),
-                seed: self.seed_text.parse().unwrap_or(1),
-                simplify: 0.,
-                maze_expansions: self.maze_expansions,
-            };
+        let events = self.app_data.take_events();
+        if events.iter().any(|event| {
+            matches!(
+                event,
+                GameEventRecord::TeamWon(_) | GameEventRecord::Draw { .. }
+            )
+        }) {
+            let params = self.board_params();
             self.app_data.new_game(self.board_type, params, false);
             self.img_gray.clear();
             self.img_labels.clear();
@@ -644,6 +996,13 @@ impl eframe::App for SwarmRsApp {
         // Tip: a good default choice is to just keep the `CentralPanel`.
         // For inspiration and more examples, go to https://emilk.github.io/egui
 
+        if !self.show_panels {
+            egui::CentralPanel::default().show(ctx, |ui| {
+                self.paint_game(ui);
+            });
+            return;
+        }
+
         #[cfg(not(target_arch = "wasm32"))] // no File->Quit on web pages!
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             // The top panel is often a good place for a menu bar:
@@ -692,8 +1051,235 @@ fn _transform_vector(m: &Matrix3<f64>, v: impl Into<Vector2<f64>>) -> Vector2<f6
     <Matrix3<f64> as Transform<Point2<f64>>>::transform_vector(m, v.into())
 }
 
+/// Margin in screen points that the camera may pan beyond the edges of the board when
+/// `AppData::clamp_camera` is enabled
+const CAMERA_MARGIN: f64 = 50.;
+
+/// Clamp the view `origin` so that the board of `board` size keeps filling the `canvas` of the
+/// given size in screen points, except for `CAMERA_MARGIN`. If the whole board fits in the
+/// canvas, it is kept inside the canvas instead.
+fn clamp_origin(origin: [f64; 2], scale: f64, canvas: [f64; 2], board: [f64; 2]) -> [f64; 2] {
+    let margin = CAMERA_MARGIN / scale;
+    [0, 1].map(|i| {
+        // The world coordinate at the left or top edge of the canvas is `-origin`
+        let view = canvas[i] / scale;
+        let far = board[i] + margin - view;
+        let left = (-origin[i]).clamp(far.min(-margin), far.max(-margin));
+        -left
+    })
+}
+
 /// Transform a point. Equivalent to `(m * v.extend(1.)).truncate()`.
 fn transform_point(m: &Matrix3<f64>, v: impl Into<Point2<f64>>) -> Point2<f64> {
     // I don't really get the point of having the vector and the point as different types.
     <Matrix3<f64> as Transform<Point2<f64>>>::transform_point(m, v.into())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cgmath::InnerSpace;
+    use swarm_rs::game::Game;
+
+    #[test]
+    fn test_with_options() {
+        let options = AppOptions {
+            board_type: BoardType::Maze,
+            board_size: (64, 48),
+            seed: 42,
+            agent_count: 7,
+            show_panels: false,
+            ..AppOptions::default()
+        };
+        let app = SwarmRsApp::with_options(options);
+        assert!(!app.show_panels);
+        assert_eq!(app.agent_count, 7);
+        assert_eq!(app.app_data.game.params.agent_count, 7);
+
+        let game = &app.app_data.game;
+        assert_eq!(game.shape(), (64, 48));
+        let mut expected = Game::new();
+        expected.new_board(BoardType::Maze, &app.board_params());
+        assert_eq!(app.board_params().seed, 42);
+        assert!(game.board == expected.board);
+    }
+
+    #[test]
+    fn test_step_zoom() {
+        let mut app = SwarmRsApp::with_options(AppOptions::default());
+        app.app_data.origin = [-10., 5.];
+        app.app_data.scale = 2.;
+        app.app_data.target_scale = 8.;
+        app.app_data.zoom_anchor = [300., 200.];
+        let anchor = Point2::from(app.app_data.zoom_anchor);
+        let world = transform_point(&app.inverse_view_transform(), anchor);
+
+        app.step_zoom(1. / 60.);
+        assert!(2. < app.app_data.scale && app.app_data.scale < 8.);
+        let moved = transform_point(&app.inverse_view_transform(), anchor);
+        assert!((moved - world).magnitude() < 1e-9);
+        let screen = transform_point(&app.view_transform(), world);
+        assert!((screen - anchor).magnitude() < 1e-9);
+
+        for _ in 0..120 {
+            app.step_zoom(1. / 60.);
+        }
+        assert_eq!(app.app_data.scale, 8.);
+        let moved = transform_point(&app.inverse_view_transform(), anchor);
+        assert!((moved - world).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn test_render_pixel_scale_reversed_range() {
+        let mut app = SwarmRsApp::with_options(AppOptions::default());
+        app.app_data.render_scale_range = [3., 0.5];
+        app.app_data.scale = app.app_data.render_reference_scale * 10.;
+        assert_eq!(app.app_data.render_pixel_scale(), 3.);
+        app.app_data.scale = app.app_data.render_reference_scale * 0.1;
+        assert_eq!(app.app_data.render_pixel_scale(), 0.5);
+    }
+
+    #[test]
+    fn test_clamp_origin() {
+        const CANVAS: [f64; 2] = [800., 600.];
+        const BOARD: [f64; 2] = [128., 64.];
+        let visible = |origin: [f64; 2], scale: f64| {
+            [0, 1].map(|i| [-origin[i], CANVAS[i] / scale - origin[i]])
+        };
+
+        // Zoomed in, the view stays within the margin around the board
+        for scale in [20., 40.] {
+            let margin = CAMERA_MARGIN / scale;
+            assert_eq!(
+                clamp_origin([-50., -20.], scale, CANVAS, BOARD),
+                [-50., -20.]
+            );
+            for origin in [[1e3, 1e3], [-1e3, -1e3]] {
+                let clamped = clamp_origin(origin, scale, CANVAS, BOARD);
+                for (i, [min, max]) in visible(clamped, scale).into_iter().enumerate() {
+                    assert!(-margin - 1e-9 <= min, "{scale} {clamped:?}");
+                    assert!(max <= BOARD[i] + margin + 1e-9, "{scale} {clamped:?}");
+                }
+            }
+        }
+
+        // Zoomed out, the whole board stays in the view
+        for scale in [1., 2.] {
+            for origin in [[1e3, 1e3], [-1e3, -1e3], [0., 0.]] {
+                let clamped = clamp_origin(origin, scale, CANVAS, BOARD);
+                let [x, y] = visible(clamped, scale);
+                for (i, [min, max]) in [x, y].into_iter().enumerate() {
+                    assert!(min <= 0. && BOARD[i] <= max, "{scale} {clamped:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_bullet_time() {
+        let mut app = SwarmRsApp::with_options(AppOptions::default());
+        app.app_data.bullet_time = true;
+        app.app_data.bullet_time_rate = 0.25;
+        let ticks = |app: &mut SwarmRsApp, frames: usize| {
+            let start = app.app_data.game.global_time;
+            for _ in 0..frames {
+                app.app_data.update(16., app.agent_count);
+            }
+            app.app_data.game.global_time - start
+        };
+
+        assert_eq!(ticks(&mut app, 20), 20, "Full speed without a selection");
+
+        app.app_data.selected_entity = Some(0);
+        assert_eq!(ticks(&mut app, 20), 5);
+
+        app.app_data.selected_entity = None;
+        assert_eq!(ticks(&mut app, 20), 20, "Full speed after deselection");
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut app = SwarmRsApp::with_options(AppOptions::default());
+        for _ in 0..150 {
+            app.app_data.update(16., app.agent_count);
+        }
+        let replay = app.app_data.game.replay().cloned().unwrap();
+        assert_eq!(replay.ticks(), 150);
+
+        app.app_data.open_replay(replay);
+        assert_eq!(app.app_data.game.global_time, 0);
+        app.app_data.seek_replay(30);
+        assert_eq!(app.app_data.replay_tick, 30);
+
+        // The replayed game is not advanced by the updates
+        app.app_data.update(16., app.agent_count);
+        assert_eq!(app.app_data.game.global_time, 30);
+
+        // A long seek is spread over the updates
+        app.app_data.seek_replay(150);
+        assert_eq!(app.app_data.game.global_time, 130);
+        app.app_data.update(16., app.agent_count);
+        assert_eq!(app.app_data.game.global_time, 150);
+        assert_eq!(app.app_data.replay_tick, 150);
+
+        app.app_data.close_replay();
+        assert_eq!(app.app_data.game.global_time, 150);
+
+        // The replay of the finished match is kept for a new game
+        app.app_data
+            .new_game(BoardType::Rect, app.board_params(), false);
+        assert_eq!(app.app_data.last_replay.as_ref().unwrap().ticks(), 150);
+    }
+
+    #[test]
+    fn test_win_event() {
+        let mut app = SwarmRsApp::with_options(AppOptions::default());
+        let win = |app: &mut SwarmRsApp| {
+            // Remove the red spawner so that the green team wins on the next update
+            app.app_data.game.entities.retain(|entity| {
+                let entity = entity.borrow();
+                entity.is_agent() || entity.get_team() == 0
+            });
+            app.app_data.big_message_time = 0.;
+            app.app_data.update(16., app.agent_count);
+            app.app_data.take_events()
+        };
+        let is_green_won = |event: &GameEventRecord| matches!(event, GameEventRecord::TeamWon(result) if result.won_team == 0);
+
+        let events = win(&mut app);
+        assert!(events.iter().any(is_green_won));
+        assert_eq!(app.app_data.big_message, "Green team won!!");
+        assert!(0. < app.app_data.big_message_time);
+        assert!(app.app_data.take_events().is_empty());
+
+        app.app_data.show_big_message = false;
+        app.app_data
+            .new_game(BoardType::Rect, app.board_params(), true);
+        let events = win(&mut app);
+        assert!(events.iter().any(is_green_won));
+        assert_eq!(app.app_data.big_message_time, 0.);
+    }
+
+    #[test]
+    fn test_view_settings_round_trip() {
+        let mut app = SwarmRsApp::with_options(AppOptions::default());
+        app.app_data.origin = [-12.5, 30.];
+        app.app_data.scale = 4.;
+        app.app_data.qtree_visible = true;
+        app.app_data.fog_active = [false, true];
+        app.app_data.team_colors[1] = [10, 20, 30];
+        app.app_data.show_velocity = true;
+        app.view_settings = Some(app.app_data.view_settings());
+
+        let serialized = ron::to_string(&app).unwrap();
+        let restored: SwarmRsApp = ron::from_str(&serialized).unwrap();
+        assert_eq!(restored.view_settings, app.view_settings);
+
+        let mut fresh = SwarmRsApp::with_options(AppOptions::default());
+        fresh
+            .app_data
+            .apply_view_settings(restored.view_settings.as_ref().unwrap());
+        assert_eq!(fresh.app_data.view_settings(), app.app_data.view_settings());
+        assert_eq!(fresh.app_data.target_scale, 4.);
+    }
+}
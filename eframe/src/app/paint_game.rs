@@ -1,24 +1,27 @@
 use std::cell::RefCell;
 
-use crate::app_data::AppData;
-use cgmath::{InnerSpace, Matrix2, Matrix3, MetricSpace, Point2, Rad, Vector2};
+use crate::app_data::{AppData, BulletStyle};
+use cgmath::{InnerSpace, Matrix2, Matrix3, MetricSpace, Point2, Rad, Vector2, Zero};
 use eframe::{
     emath::RectTransform,
     epaint::{self, PathShape},
 };
 use egui::{pos2, Align2, Color32, FontId, Frame, Painter, Pos2, Rect, Response, Stroke, Ui, Vec2};
 use swarm_rs::{
-    agent::{AgentClass, AGENT_HALFLENGTH, BULLET_RADIUS},
+    agent::{AgentClass, BULLET_RADIUS},
     entity::Entity,
     game::Resource,
+    perlin_noise::Xor128,
     qtree::FRESH_TICKS,
-    Bullet, CellState,
+    Bullet, CellState, DijkstraResult,
 };
 
-use super::{transform_point, SwarmRsApp};
+use super::{clamp_origin, transform_point, SwarmRsApp};
 
 /// In points
 const SCREEN_SELECT_RADIUS: f64 = 20.;
+/// Ticks of motion that a velocity arrow represents
+const VELOCITY_ARROW_SCALE: f64 = 20.;
 
 impl SwarmRsApp {
     pub(crate) fn paint_game(&mut self, ui: &mut Ui) {
@@ -30,6 +33,7 @@ impl SwarmRsApp {
             interact_pos: Point2<f64>,
             hover_pos: Option<Pos2>,
             clicked: bool,
+            dt: f32,
         }
 
         let ui_result = {
@@ -49,24 +53,22 @@ impl SwarmRsApp {
                 interact_pos: Point2::new(interact_pos.x as f64, interact_pos.y as f64),
                 hover_pos: input.pointer.hover_pos(),
                 clicked: input.pointer.primary_released(),
+                dt: input.stable_dt.min(0.1),
             }
         };
 
+        self.step_zoom(ui_result.dt as f64);
+
         if ui.ui_contains_pointer() {
             if ui_result.scroll_delta != 0. || ui_result.zoom_delta != 1. {
-                let old_offset =
-                    transform_point(&self.inverse_view_transform(), ui_result.interact_pos);
                 if ui_result.scroll_delta < 0. {
-                    self.app_data.scale /= 1.2;
+                    self.app_data.target_scale /= 1.2;
                 } else if 0. < ui_result.scroll_delta {
-                    self.app_data.scale *= 1.2;
+                    self.app_data.target_scale *= 1.2;
                 } else if ui_result.zoom_delta != 1. {
-                    self.app_data.scale *= ui_result.zoom_delta as f64;
+                    self.app_data.target_scale *= ui_result.zoom_delta as f64;
                 }
-                let new_offset =
-                    transform_point(&self.inverse_view_transform(), ui_result.interact_pos);
-                let diff = new_offset - old_offset;
-                self.app_data.origin = (Vector2::<f64>::from(self.app_data.origin) + diff).into();
+                self.app_data.zoom_anchor = ui_result.interact_pos.into();
             }
 
             if ui_result.pointer {
@@ -101,6 +103,17 @@ impl SwarmRsApp {
 
             self.canvas_offset = response.rect.min;
 
+            if self.app_data.clamp_camera {
+                let (xs, ys) = self.app_data.game.shape();
+                let size = response.rect.size();
+                self.app_data.origin = clamp_origin(
+                    self.app_data.origin,
+                    self.app_data.scale,
+                    [size.x as f64, size.y as f64],
+                    [xs as f64, ys as f64],
+                );
+            }
+
             self.mouse_pos = ui_result.hover_pos.map(|pos| {
                 let from_screen = egui::emath::RectTransform::from_to(
                     response.rect,
@@ -115,11 +128,21 @@ impl SwarmRsApp {
             let image_getter = |app_data: &AppData| {
                 let (size, image) = app_data
                     .game
-                    .occupancy_image(&app_data.fog_active, app_data.colored_fog)
+                    .occupancy_image(
+                        &app_data.fog_active,
+                        app_data.colored_fog,
+                        &app_data.team_colors,
+                    )
                     .unwrap_or_else(|| ([0, 0], vec![]));
                 egui::ColorImage::from_rgb(size, &image)
             };
 
+            let dijkstra = if self.app_data.show_dijkstra {
+                selected_dijkstra(&self.app_data)
+            } else {
+                None
+            };
+
             if self.show_labels {
                 self.img_labels
                     .paint(&response, &painter, &self.app_data, |app_data| {
@@ -129,6 +152,23 @@ impl SwarmRsApp {
                             .unwrap_or_else(|| ([0, 0], vec![]));
                         egui::ColorImage::from_rgb(size, &image)
                     });
+            } else if self.show_kill_heatmap {
+                self.img_gray.clear();
+                self.img_gray
+                    .paint(&response, &painter, &self.app_data, |app_data| {
+                        let (size, image) = app_data
+                            .game
+                            .kill_heatmap_image()
+                            .unwrap_or_else(|| ([0, 0], vec![]));
+                        egui::ColorImage::from_rgb(size, &image)
+                    });
+            } else if let Some(dijkstra) = &dijkstra {
+                self.img_gray.clear();
+                self.img_gray
+                    .paint(&response, &painter, &self.app_data, |app_data| {
+                        let (size, image) = app_data.game.dijkstra_image(dijkstra);
+                        egui::ColorImage::from_rgb(size, &image)
+                    });
             } else if self.app_data.game.enable_raycast_board {
                 let raycast_board = self.app_data.game.raycast_board.borrow();
                 let ray_valid = raycast_board.len() == self.app_data.game.board.len();
@@ -141,7 +181,11 @@ impl SwarmRsApp {
                         |app_data: &AppData| {
                             let (size, image) = app_data
                                 .game
-                                .occupancy_image(&app_data.fog_active, app_data.colored_fog)
+                                .occupancy_image(
+                                    &app_data.fog_active,
+                                    app_data.colored_fog,
+                                    &app_data.team_colors,
+                                )
                                 .unwrap_or_else(|| ([0, 0], vec![]));
                             let image = image
                                 .chunks(3)
@@ -169,13 +213,23 @@ impl SwarmRsApp {
 
             paint_qtree(&response, &painter, &self.app_data);
 
+            paint_mesh(&response, &painter, &self.app_data);
+
             paint_resources(&response, &painter, &self.app_data);
 
             paint_agents((&response, &painter), self, &self.view_transform());
 
+            paint_sight_lines(&response, &painter, &self.app_data);
+
+            if let Some(dijkstra) = &dijkstra {
+                paint_dijkstra_path(&response, &painter, &self.app_data, dijkstra);
+            }
+
             paint_bullets(&response, &painter, &self.app_data);
 
             paint_big_message(&response, &painter, &self.app_data, ui.available_size());
+
+            paint_navigation_status(&painter, &self.app_data);
         });
     }
 }
@@ -223,43 +277,87 @@ pub(crate) fn paint_qtree(response: &Response, painter: &Painter, data: &AppData
 
         let qtree = qtree_searcher.get_qtree();
 
-        for (level, cells) in qtree.levels.iter().enumerate() {
-            let width = qtree.width(level);
-            for (cell, state) in cells
-                .iter()
-                .filter(|(_, state)| !matches!(state, CellState::Mixed))
-            {
-                let (x, y) = (
-                    cell[0] << (qtree.toplevel - level),
-                    cell[1] << (qtree.toplevel - level),
-                );
-                let cell_pos = Vec2::new(x as f32, y as f32);
-                let min_margin = Vec2::splat(CELL_MARGIN);
-                let max_margin = Vec2::splat(width as f32 - CELL_MARGIN);
-                let rect = Rect {
-                    min: ((cell_pos + min_margin) * scale + offset).to_pos2(),
-                    max: ((cell_pos + max_margin) * scale + offset).to_pos2(),
-                };
-                let rect = to_screen.transform_rect(rect);
-                // let rect = rect.to_path(1.);
-                painter.rect_stroke(
-                    rect,
-                    0.,
-                    Stroke {
-                        width: 1.,
-                        color: match state {
-                            CellState::Obstacle => Color32::from_rgb(255, 127, 127),
-                            CellState::Occupied(_) => Color32::from_rgb(255, 127, 255),
-                            CellState::Free => Color32::from_rgb(0, 255, 127),
-                            _ => Color32::from_rgb(255, 0, 255),
-                        },
+        for ([x0, y0, x1, _], state) in qtree.leaves() {
+            let width = x1 - x0;
+            let cell_pos = Vec2::new(x0 as f32, y0 as f32);
+            let min_margin = Vec2::splat(CELL_MARGIN);
+            let max_margin = Vec2::splat(width as f32 - CELL_MARGIN);
+            let rect = Rect {
+                min: ((cell_pos + min_margin) * scale + offset).to_pos2(),
+                max: ((cell_pos + max_margin) * scale + offset).to_pos2(),
+            };
+            let rect = to_screen.transform_rect(rect);
+            // let rect = rect.to_path(1.);
+            painter.rect_stroke(
+                rect,
+                0.,
+                Stroke {
+                    width: 1.,
+                    color: match state {
+                        CellState::Obstacle => Color32::from_rgb(255, 127, 127),
+                        CellState::Occupied(_) => Color32::from_rgb(255, 127, 255),
+                        CellState::Free => Color32::from_rgb(0, 255, 127),
+                        _ => Color32::from_rgb(255, 0, 255),
                     },
-                );
-            }
+                },
+            );
         }
     });
 }
 
+/// Draw the edges of the triangulated navigation mesh, colored by the connected component of
+/// passable triangles, or red for impassable ones.
+fn paint_mesh(response: &Response, painter: &Painter, data: &AppData) {
+    if !data.mesh_visible {
+        return;
+    }
+
+    let to_screen = egui::emath::RectTransform::from_to(
+        Rect::from_min_size(Pos2::ZERO, response.rect.size()),
+        response.rect,
+    );
+    let offset = Vec2::new(data.origin[0] as f32, data.origin[1] as f32);
+    let to_point = |pos: &[f64; 2]| {
+        let pos = Vec2::new(pos[0] as f32, pos[1] as f32);
+        to_screen.transform_pos(((pos + offset) * data.scale as f32).to_pos2())
+    };
+
+    let mesh = &data.game.mesh;
+    let points: Vec<_> = mesh.points.iter().map(|p| to_point(&[p.x, p.y])).collect();
+    let triangles = &mesh.triangulation.triangles;
+
+    let mut rng = Xor128::new(616516);
+    let max_label = mesh.triangle_labels.iter().max().map_or(0, |l| l + 1);
+    let label_colors: Vec<_> = (0..max_label)
+        .map(|_| {
+            Color32::from_rgb(
+                (rng.nexti() % 0x80 + 0x7f) as u8,
+                (rng.nexti() % 0x80 + 0x7f) as u8,
+                (rng.nexti() % 0x80 + 0x7f) as u8,
+            )
+        })
+        .collect();
+
+    for (i, triangle) in triangles.chunks(3).enumerate() {
+        // `delaunator::EMPTY` marks a missing vertex
+        if triangle.contains(&usize::MAX) {
+            continue;
+        }
+        let label = mesh.triangle_labels[i];
+        let color = if mesh.triangle_passable[i] && 0 <= label {
+            label_colors[label as usize]
+        } else {
+            Color32::from_rgba_unmultiplied(255, 0, 0, 127)
+        };
+        for (start, end) in [(0, 1), (1, 2), (2, 0)] {
+            painter.line_segment(
+                [points[triangle[start]], points[triangle[end]]],
+                (1., color),
+            );
+        }
+    }
+}
+
 fn render_search_tree(data: &AppData, response: &Response, painter: &Painter) {
     if !data.qtree_search_visible {
         return;
@@ -307,10 +405,92 @@ fn render_search_tree(data: &AppData, response: &Response, painter: &Painter) {
     }
 }
 
-const AGENT_COLORS: [Color32; 2] = [
-    Color32::from_rgb(0, 255, 127),
-    Color32::from_rgb(255, 0, 63),
-];
+fn paint_sight_lines(response: &Response, painter: &Painter, data: &AppData) {
+    if !data.show_sight_lines {
+        return;
+    }
+    let Some(entity) = data.selected_entity.and_then(|id| data.game.get_entity(id)) else {
+        return;
+    };
+
+    let to_screen = egui::emath::RectTransform::from_to(
+        Rect::from_min_size(Pos2::ZERO, response.rect.size()),
+        response.rect,
+    );
+    let offset = Vec2::new(data.origin[0] as f32, data.origin[1] as f32);
+    let to_point = |pos: [f64; 2]| {
+        let pos = Vec2::new(pos[0] as f32, pos[1] as f32);
+        to_screen.transform_pos(((pos + offset) * data.scale as f32).to_pos2())
+    };
+
+    for line in entity.sight_lines(&data.game) {
+        if let Some(hit) = line.hit {
+            painter.line_segment(
+                [to_point(line.from), to_point(hit)],
+                (1.5, Color32::from_rgb(255, 63, 63)),
+            );
+            painter.line_segment(
+                [to_point(hit), to_point(line.to)],
+                (1., Color32::from_rgba_unmultiplied(255, 63, 63, 63)),
+            );
+            painter.circle_stroke(to_point(hit), 3., (1., Color32::from_rgb(255, 63, 63)));
+        } else {
+            painter.line_segment(
+                [to_point(line.from), to_point(line.to)],
+                (1.5, Color32::from_rgb(127, 255, 255)),
+            );
+        }
+    }
+}
+
+fn selected_dijkstra(data: &AppData) -> Option<DijkstraResult> {
+    let entity = data
+        .selected_entity
+        .and_then(|id| data.game.get_entity(id))?;
+    let goal = entity.get_target_pos(&data.game)?;
+    data.game.dijkstra(entity.get_pos(), goal)
+}
+
+fn paint_dijkstra_path(
+    response: &Response,
+    painter: &Painter,
+    data: &AppData,
+    dijkstra: &DijkstraResult,
+) {
+    let to_screen = egui::emath::RectTransform::from_to(
+        Rect::from_min_size(Pos2::ZERO, response.rect.size()),
+        response.rect,
+    );
+    let offset = Vec2::new(data.origin[0] as f32, data.origin[1] as f32);
+    let to_point = |cell: &[i32; 2]| {
+        let pos = Vec2::new(cell[0] as f32 + 0.5, cell[1] as f32 + 0.5);
+        to_screen.transform_pos(((pos + offset) * data.scale as f32).to_pos2())
+    };
+
+    let color = Color32::from_rgb(255, 127, 0);
+    painter.add(PathShape::line(
+        dijkstra.path.iter().map(to_point).collect(),
+        (2., color),
+    ));
+
+    let text = if dijkstra.path.is_empty() {
+        "Dijkstra: unreachable".to_string()
+    } else {
+        format!("Dijkstra: {} steps", dijkstra.path.len() - 1)
+    };
+    painter.text(
+        response.rect.left_bottom() + Vec2::new(10., -10.),
+        Align2::LEFT_BOTTOM,
+        text,
+        FontId::proportional(16.),
+        color,
+    );
+}
+
+fn team_color(data: &AppData, team: usize) -> Color32 {
+    let [r, g, b] = data.team_colors[team % data.team_colors.len()];
+    Color32::from_rgb(r, g, b)
+}
 
 const SELECTED_COLOR: Color32 = Color32::WHITE;
 
@@ -381,7 +561,7 @@ fn paint_real_agent(
     to_screen: RectTransform,
 ) {
     let data = &app.app_data;
-    let draw_rectangle = 1. / AGENT_HALFLENGTH < data.scale;
+    let px = data.render_pixel_scale();
 
     let agent = agent.borrow();
     let agent_pos = agent.get_pos();
@@ -389,17 +569,17 @@ fn paint_real_agent(
     let brush = if app.app_data.selected_entity == Some(agent.get_id()) {
         SELECTED_COLOR
     } else {
-        AGENT_COLORS[agent.get_team() % AGENT_COLORS.len()]
+        team_color(data, agent.get_team())
     };
-    painter.circle_filled(pos, 5., brush);
+    painter.circle_filled(pos, 5. * px, brush);
 
     if !agent.is_agent() {
         painter.circle_stroke(
             pos,
-            10.,
+            10. * px,
             Stroke {
                 color: brush,
-                width: 3.,
+                width: 3. * px,
             },
         );
     }
@@ -426,7 +606,13 @@ fn paint_real_agent(
     let resource = agent.resource();
     if 0 < resource {
         let f = resource as f32 / agent.max_resource() as f32;
-        draw_arc(painter, pos.to_vec2(), 7.5, f, (2.5, Color32::YELLOW));
+        draw_arc(
+            painter,
+            pos.to_vec2(),
+            7.5 * px,
+            f,
+            (2.5 * px, Color32::YELLOW),
+        );
     }
 
     if let Entity::Spawner(spawner) = &agent as &Entity {
@@ -434,9 +620,9 @@ fn paint_real_agent(
         draw_arc(
             painter,
             pos.to_vec2(),
-            13.,
+            13. * px,
             progress,
-            (2., Color32::from_rgb(0, 191, 191)),
+            (2. * px, Color32::from_rgb(0, 191, 191)),
         );
     }
 
@@ -450,7 +636,7 @@ fn paint_real_agent(
             20.
         } else {
             10.
-        };
+        } * px as f64;
         let dest = egui::pos2(
             view_pos.x + (orient.cos() * length) as f32,
             view_pos.y + (orient.sin() * length) as f32,
@@ -460,18 +646,19 @@ fn paint_real_agent(
             orient_line,
             Stroke {
                 color: brush,
-                width: 3.,
+                width: 3. * px,
             },
         );
 
-        if draw_rectangle {
+        // Draw the outline only if the agent is big enough on the screen
+        if 1. / class.shape().0 < data.scale {
             let mut path = vec![];
             let rotation = Matrix2::from_angle(Rad(orient));
             class.vertices(|v| {
                 let vertex = rotation * Vector2::from(v) + agent_pos;
                 path.push(to_point(vertex.into()));
             });
-            painter.add(PathShape::closed_line(path, (1., brush)));
+            painter.add(PathShape::closed_line(path, (px, brush)));
         }
     } else {
         let aabb = agent.get_aabb();
@@ -484,7 +671,7 @@ fn paint_real_agent(
             0.,
             Stroke {
                 color: brush,
-                width: 1.,
+                width: px,
             },
         );
     }
@@ -493,7 +680,7 @@ fn paint_real_agent(
         if let Some(target_pos) = agent.get_target_pos(&data.game) {
             let line = [pos, to_point(target_pos)];
 
-            painter.line_segment(line, (1., brush));
+            painter.line_segment(line, (px, brush));
         }
     }
 
@@ -522,11 +709,28 @@ fn paint_real_agent(
             if app.draw_circle {
                 for point in global_path {
                     let circle = to_point(point.pos);
-                    painter.circle_stroke(circle, (point.radius * data.scale) as f32, (1., brush));
+                    let radius = (point.radius * data.scale) as f32;
+                    painter.circle_stroke(circle, radius, (px, brush));
                 }
             }
         }
-        painter.add(PathShape::line(path, (1., brush)));
+        painter.add(PathShape::line(path, (px, brush)));
+    }
+
+    if data.show_velocity {
+        if let Some(velocity) = agent.get_velocity() {
+            let velocity = Vector2::from(velocity);
+            if velocity != Vector2::zero() {
+                let tip = agent_pos + velocity * VELOCITY_ARROW_SCALE;
+                let head = velocity.normalize() * 0.5;
+                let side = Vector2::new(-head.y, head.x);
+                let stroke = (1.5 * px, Color32::from_rgb(255, 127, 255));
+                let tip_point = to_point(tip.into());
+                painter.line_segment([view_pos, tip_point], stroke);
+                painter.line_segment([tip_point, to_point((tip - head + side).into())], stroke);
+                painter.line_segment([tip_point, to_point((tip - head - side).into())], stroke);
+            }
+        }
     }
 
     if data.entity_trace_visible {
@@ -535,7 +739,7 @@ fn paint_real_agent(
             let path = PathShape::line(
                 iter,
                 (
-                    0.5,
+                    0.5 * px,
                     Color32::from_rgba_unmultiplied(brush.r(), brush.g(), brush.b(), 127),
                 ),
             );
@@ -552,6 +756,21 @@ fn paint_real_agent(
         painter.text(pos, Align2::CENTER_TOP, text, FontId::monospace(16.), brush);
     }
 
+    if data.show_roles {
+        if let Some(role) = agent.get_role() {
+            let color = role_color(role);
+            let marker = pos + Vec2::new(0., -12. * px);
+            painter.circle_filled(marker, 3. * px, color);
+            painter.text(
+                marker + Vec2::new(5. * px, 0.),
+                Align2::LEFT_CENTER,
+                role,
+                FontId::proportional(12.),
+                color,
+            );
+        }
+    }
+
     if 5. < data.scale {
         let health = agent.get_health_rate() as f32;
         let view_pos_left = transform_point(view_transform, [agent_pos.x - 1., agent_pos.y - 1.]);
@@ -605,22 +824,23 @@ fn paint_shadow_agents(
     team: usize,
     to_point: impl Fn([f64; 2]) -> Pos2,
 ) {
+    let px = app.app_data.render_pixel_scale();
     for entity in &app.app_data.game.fog[team].entities {
         let agent_pos = entity.pos;
         let pos = to_point(agent_pos);
         let brush = if app.app_data.selected_entity == Some(entity.id) {
             SELECTED_COLOR
         } else {
-            AGENT_COLORS[(team + 1) % AGENT_COLORS.len()]
+            team_color(&app.app_data, team + 1)
         };
-        painter.circle_filled(pos, 5., brush);
+        painter.circle_filled(pos, 5. * px, brush);
 
         painter.circle_stroke(
             pos,
-            10.,
+            10. * px,
             Stroke {
                 color: brush,
-                width: 3.,
+                width: 3. * px,
             },
         );
     }
@@ -639,18 +859,16 @@ fn paint_bullets(response: &Response, painter: &Painter, data: &AppData) {
         to_screen.transform_pos(((pos + offset) * data.scale as f32).to_pos2())
     };
 
+    let px = data.render_pixel_scale();
+
     let draw_bullet = |painter: &Painter, bullet: &Bullet, radius: f64| {
         painter.circle(
             to_point(bullet.pos),
             radius as f32,
-            if bullet.team == 0 {
-                Color32::WHITE
-            } else {
-                Color32::from_rgb(255, 0, 255)
-            },
+            team_color(data, bullet.team),
             Stroke {
                 color: Color32::YELLOW,
-                width: 1.,
+                width: px,
             },
         );
     };
@@ -661,45 +879,65 @@ fn paint_bullets(response: &Response, painter: &Painter, data: &AppData) {
 
     let draw_small = data.scale < TARGET_PIXELS / BULLET_RADIUS;
 
+    let style_of = |bullet: &Bullet| match data.bullet_style {
+        BulletStyle::ByClass => {
+            if matches!(bullet.shooter_class, AgentClass::Fighter) {
+                BulletStyle::Tracer
+            } else {
+                BulletStyle::Beam
+            }
+        }
+        style => style,
+    };
+
     for bullet in game.bullets.iter() {
         let pos = Vector2::from(bullet.pos);
         let velo = Vector2::from(bullet.velo).normalize();
-        let length = bullet
-            .traveled
-            .min(2. * Vector2::from(bullet.velo).magnitude());
-        let tail = pos - velo * length;
-        if matches!(bullet.shooter_class, AgentClass::Fighter) {
-            let perp = Vector2::new(velo.y, -velo.x) * BULLET_RADIUS;
-            let trail = epaint::PathShape {
-                points: vec![
-                    to_point((pos + perp).into()),
-                    to_point((pos - perp).into()),
-                    to_point(tail.into()),
-                ],
-                closed: true,
-                fill: Color32::from_rgb(255, 191, 63),
-                stroke: Default::default(),
-            };
-            painter.add(trail);
-            if !draw_small {
-                draw_bullet(painter, bullet, BULLET_RADIUS * data.scale);
+        match style_of(bullet) {
+            BulletStyle::Tracer => {
+                let length = bullet
+                    .traveled
+                    .min(2. * Vector2::from(bullet.velo).magnitude());
+                let tail = pos - velo * length;
+                let perp = Vector2::new(velo.y, -velo.x) * BULLET_RADIUS;
+                let trail = epaint::PathShape {
+                    points: vec![
+                        to_point((pos + perp).into()),
+                        to_point((pos - perp).into()),
+                        to_point(tail.into()),
+                    ],
+                    closed: true,
+                    fill: Color32::from_rgb(255, 191, 63),
+                    stroke: Default::default(),
+                };
+                painter.add(trail);
+                if !draw_small {
+                    draw_bullet(painter, bullet, BULLET_RADIUS * data.scale);
+                }
             }
-        } else {
-            let trail = [to_point((pos + velo).into()), to_point((pos - velo).into())];
-            painter.line_segment(
-                trail,
-                Stroke {
-                    color: Color32::from_rgb(255, 191, 63),
-                    width: 0.075 * data.scale as f32,
-                },
-            );
-        };
+            BulletStyle::Dot => {
+                if !draw_small {
+                    draw_bullet(painter, bullet, BULLET_RADIUS * data.scale);
+                }
+            }
+            BulletStyle::Beam | BulletStyle::ByClass => {
+                let trail = [to_point((pos + velo).into()), to_point((pos - velo).into())];
+                painter.line_segment(
+                    trail,
+                    Stroke {
+                        color: Color32::from_rgb(255, 191, 63),
+                        width: 0.075 * data.scale as f32,
+                    },
+                );
+            }
+        }
     }
 
+    // Draw the heads on top of all trails in a size that stays visible when zoomed out
     if draw_small {
         for bullet in game.bullets.iter() {
-            if matches!(bullet.shooter_class, AgentClass::Fighter) {
-                draw_bullet(painter, bullet, TARGET_PIXELS);
+            if matches!(style_of(bullet), BulletStyle::Tracer | BulletStyle::Dot) {
+                draw_bullet(painter, bullet, TARGET_PIXELS * px as f64);
             }
         }
     }
@@ -713,13 +951,18 @@ fn paint_resources(response: &Response, painter: &Painter, data: &AppData) {
 
     const TARGET_PIXELS: f64 = 10.;
 
+    let game = &data.game;
+
     let draw_resource = |resource: &Resource, pos: Pos2| {
         let radius = ((resource.amount as f64).sqrt() / TARGET_PIXELS * data.scale) as f32;
-        painter.circle_filled(pos, radius, Color32::YELLOW);
+        let color = data
+            .show_contest
+            .then(|| game.resource_contest(resource))
+            .flatten()
+            .map_or(Color32::YELLOW, |team| team_color(data, team));
+        painter.circle_filled(pos, radius, color);
     };
 
-    let game = &data.game;
-
     let offset = Vec2::new(data.origin[0] as f32, data.origin[1] as f32);
 
     let to_point = |pos: [f64; 2]| {
@@ -755,8 +998,24 @@ fn paint_resources(response: &Response, painter: &Painter, data: &AppData) {
     }
 }
 
+/// Pick a color for a role name, so that agents with the same role share a color.
+fn role_color(role: &str) -> Color32 {
+    const ROLE_COLORS: [Color32; 6] = [
+        Color32::from_rgb(255, 255, 0),
+        Color32::from_rgb(0, 255, 255),
+        Color32::from_rgb(255, 127, 255),
+        Color32::from_rgb(255, 160, 64),
+        Color32::from_rgb(160, 160, 255),
+        Color32::from_rgb(255, 255, 255),
+    ];
+    let hash = role.bytes().fold(0usize, |acc, b| {
+        acc.wrapping_mul(31).wrapping_add(b as usize)
+    });
+    ROLE_COLORS[hash % ROLE_COLORS.len()]
+}
+
 fn paint_big_message(_response: &Response, painter: &Painter, data: &AppData, size: Vec2) {
-    if 0. < data.big_message_time {
+    if data.show_big_message && 0. < data.big_message_time {
         let color = Color32::from_rgba_unmultiplied(
             255,
             255,
@@ -779,3 +1038,17 @@ fn paint_big_message(_response: &Response, painter: &Painter, data: &AppData, si
         );
     }
 }
+
+fn paint_navigation_status(painter: &Painter, data: &AppData) {
+    data.with_qtree(|qtree| {
+        if qtree.is_building() {
+            painter.text(
+                painter.clip_rect().left_top() + Vec2::new(10., 10.),
+                Align2::LEFT_TOP,
+                "Building navigation...",
+                FontId::proportional(20.),
+                Color32::YELLOW,
+            );
+        }
+    });
+}
@@ -1,10 +1,15 @@
 use ::swarm_rs::{
-    behavior_tree_lite::parse_file,
-    game::{BoardParams, BoardType, Game, GameParams, TeamConfig},
+    game::{BoardParams, BoardType, Game, GameParams, TeamConfig, DEFAULT_TEAM_COLORS},
+    parse_tree_source,
     qtree::QTreeSearcher,
+    replay::{Replay, ReplayPlayer},
+    BuildTreeError,
 };
 
-use swarm_rs::{game::UpdateResult, vfs::Vfs};
+use swarm_rs::{
+    game::{GameEventRecord, UpdateResult},
+    vfs::Vfs,
+};
 
 #[cfg(not(target_arch = "wasm32"))]
 use swarm_rs::vfs::FileVfs;
@@ -21,6 +26,20 @@ pub enum BtType {
 
 pub type BtTarget = (usize, BtType);
 
+/// How bullets are drawn in the game view
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum BulletStyle {
+    /// Tracers for fighters and beams for the other classes
+    #[default]
+    ByClass,
+    /// A head with a tapering trail behind it
+    Tracer,
+    /// Only the head
+    Dot,
+    /// A thin line along the direction of travel
+    Beam,
+}
+
 /// An error type used internally in this GUI. It contains title for short summary,
 /// and an optional detailed explanation of the error (such as stack trace).
 /// An empty `detail` payload indicates this error has only title.
@@ -53,12 +72,45 @@ impl From<String> for MessageError {
     }
 }
 
+/// The camera and the view options that are persisted across sessions.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ViewSettings {
+    pub origin: [f64; 2],
+    pub scale: f64,
+    pub path_visible: bool,
+    pub qtree_visible: bool,
+    pub qtree_search_visible: bool,
+    pub target_visible: bool,
+    pub fog_active: [bool; 2],
+    pub colored_fog: bool,
+    pub team_colors: [[u8; 3]; 2],
+    pub entity_label_visible: bool,
+    pub entity_trace_visible: bool,
+    pub show_sight_lines: bool,
+    pub show_dijkstra: bool,
+    pub show_velocity: bool,
+    #[serde(default)]
+    pub show_contest: bool,
+    #[serde(default)]
+    pub show_roles: bool,
+    #[serde(default)]
+    pub mesh_visible: bool,
+    #[serde(default)]
+    pub bullet_style: BulletStyle,
+    #[serde(default)]
+    pub clamp_camera: bool,
+}
+
 pub struct AppData {
     pub game: Game,
     pub game_params: GameParams,
     pub(crate) selected_entity: Option<usize>,
     pub origin: [f64; 2],
     pub scale: f64,
+    /// The scale that `scale` is animated toward
+    pub target_scale: f64,
+    /// The point in canvas coordinates that stays fixed on screen while zooming
+    pub(crate) zoom_anchor: [f64; 2],
     message: String,
     /// Optional payload for detailed data about the error, can be long
     message_payload: String,
@@ -67,6 +119,9 @@ pub struct AppData {
     confirmed: Option<Box<dyn FnOnce(&mut Self)>>,
     pub(crate) big_message: String,
     pub big_message_time: f64,
+    /// Show the built-in overlay announcing the game start and the winner. Turn it off to
+    /// present them in another way with the events from `take_events`.
+    pub show_big_message: bool,
     pub path_visible: bool,
     // pub(crate) avoidance_render_params: AvoidanceRenderParams,
     pub qtree_visible: bool,
@@ -74,8 +129,36 @@ pub struct AppData {
     pub target_visible: bool,
     pub(crate) fog_active: [bool; 2],
     pub(crate) colored_fog: bool,
+    /// Colors of agents, bullets and the colored fog of each team in RGB
+    pub team_colors: [[u8; 3]; 2],
     pub(crate) entity_label_visible: bool,
     pub(crate) entity_trace_visible: bool,
+    /// Show lines of sight of the selected agent
+    pub(crate) show_sight_lines: bool,
+    /// Show the cost field and the path of a grid Dijkstra search from the selected agent to
+    /// its target, to compare against the qtree path
+    pub show_dijkstra: bool,
+    /// Show velocity vectors of agents
+    pub(crate) show_velocity: bool,
+    /// Color resources by the team whose agent is nearest to them
+    pub(crate) show_contest: bool,
+    /// Show the roles given to agents by `SetRole`
+    pub(crate) show_roles: bool,
+    /// Show the triangulated navigation mesh
+    pub(crate) mesh_visible: bool,
+    pub bullet_style: BulletStyle,
+    /// Keep the camera from panning the board out of the view
+    pub clamp_camera: bool,
+    /// Slow down the simulation while an entity is selected
+    pub bullet_time: bool,
+    /// Game ticks per frame while the bullet time is in effect
+    pub bullet_time_rate: f64,
+    /// Accumulated fraction of a tick toward the next update in the bullet time
+    bullet_time_progress: f64,
+    /// The `scale` at which markers and lines are drawn in their nominal pixel sizes
+    pub render_reference_scale: f64,
+    /// Lower and upper bounds of the factor that marker sizes and line widths are scaled by
+    pub render_scale_range: [f32; 2],
     pub(crate) global_render_time: f64,
     pub(crate) selected_bt: BtTarget,
     pub(crate) new_file_name: String,
@@ -87,6 +170,16 @@ pub struct AppData {
 
     pub(crate) bt_visible: bool,
     pub(crate) bt_widget: BTWidget,
+
+    /// The replay of the previous match, kept when a new game starts
+    pub(crate) last_replay: Option<Replay>,
+    /// The player of the replay shown in place of the game, with the live game put aside until
+    /// the replay is closed
+    pub(crate) replay_player: Option<(ReplayPlayer, Game)>,
+    /// The tick that the replay slider points to
+    pub(crate) replay_tick: i32,
+    /// The tick that the replayed game is being brought to over the frames
+    pub(crate) replay_seek: Option<i32>,
 }
 
 impl AppData {
@@ -102,6 +195,7 @@ impl AppData {
                 spawner_source: Rc::new(collapse_newlines(include_str!(
                     "../../behavior_tree_config/green/spawner.btc"
                 ))),
+                ..TeamConfig::default()
             },
             TeamConfig {
                 agent_source: Rc::new(collapse_newlines(include_str!(
@@ -110,6 +204,7 @@ impl AppData {
                 spawner_source: Rc::new(collapse_newlines(include_str!(
                     "../../behavior_tree_config/red/spawner.btc"
                 ))),
+                ..TeamConfig::default()
             },
         ];
 
@@ -129,6 +224,8 @@ impl AppData {
         game_params.teams = teams.clone();
 
         game.set_params(&game_params);
+        game.set_event_recording(true);
+        game.set_replay_recording(true);
         game.init();
 
         Self {
@@ -137,6 +234,8 @@ impl AppData {
             selected_entity: None,
             origin: [0., 0.],
             scale,
+            target_scale: scale,
+            zoom_anchor: [0., 0.],
             message: "".to_string(),
             message_payload: "".to_string(),
             message_visible: false,
@@ -144,14 +243,29 @@ impl AppData {
             confirmed: None,
             big_message: "Game Start".to_string(),
             big_message_time: 5000.,
+            show_big_message: true,
             path_visible: true,
             qtree_visible: false,
             qtree_search_visible: false,
             target_visible: false,
             fog_active: [true; 2],
             colored_fog: false,
+            team_colors: DEFAULT_TEAM_COLORS,
             entity_label_visible: true,
             entity_trace_visible: false,
+            show_sight_lines: false,
+            show_dijkstra: false,
+            show_velocity: false,
+            show_contest: false,
+            show_roles: false,
+            mesh_visible: false,
+            bullet_style: BulletStyle::ByClass,
+            clamp_camera: false,
+            bullet_time: false,
+            bullet_time_rate: 0.2,
+            bullet_time_progress: 0.,
+            render_reference_scale: scale,
+            render_scale_range: [0.5, 3.],
             global_render_time: 0.,
             selected_bt: (0, BtType::Agent),
             new_file_name: "agent.txt".to_owned(),
@@ -162,18 +276,90 @@ impl AppData {
 
             bt_visible: false,
             bt_widget: BTWidget::new(),
+
+            last_replay: None,
+            replay_player: None,
+            replay_tick: 0,
+            replay_seek: None,
         }
     }
 
+    /// The factor to scale pixel sizes of markers and lines by, so that they keep readable
+    /// proportions to the map across zoom levels.
+    pub(crate) fn render_pixel_scale(&self) -> f32 {
+        // The range is a public setting, so tolerate the bounds given in either order
+        let [a, b] = self.render_scale_range;
+        ((self.scale / self.render_reference_scale) as f32).clamp(a.min(b), a.max(b))
+    }
+
+    pub fn view_settings(&self) -> ViewSettings {
+        ViewSettings {
+            origin: self.origin,
+            scale: self.scale,
+            path_visible: self.path_visible,
+            qtree_visible: self.qtree_visible,
+            qtree_search_visible: self.qtree_search_visible,
+            target_visible: self.target_visible,
+            fog_active: self.fog_active,
+            colored_fog: self.colored_fog,
+            team_colors: self.team_colors,
+            entity_label_visible: self.entity_label_visible,
+            entity_trace_visible: self.entity_trace_visible,
+            show_sight_lines: self.show_sight_lines,
+            show_dijkstra: self.show_dijkstra,
+            show_velocity: self.show_velocity,
+            show_contest: self.show_contest,
+            show_roles: self.show_roles,
+            mesh_visible: self.mesh_visible,
+            bullet_style: self.bullet_style,
+            clamp_camera: self.clamp_camera,
+        }
+    }
+
+    /// Restore the camera and the view options. The zoom animation is reset to the restored scale.
+    pub fn apply_view_settings(&mut self, settings: &ViewSettings) {
+        self.origin = settings.origin;
+        self.scale = settings.scale;
+        self.target_scale = settings.scale;
+        self.path_visible = settings.path_visible;
+        self.qtree_visible = settings.qtree_visible;
+        self.qtree_search_visible = settings.qtree_search_visible;
+        self.target_visible = settings.target_visible;
+        self.fog_active = settings.fog_active;
+        self.colored_fog = settings.colored_fog;
+        self.team_colors = settings.team_colors;
+        self.entity_label_visible = settings.entity_label_visible;
+        self.entity_trace_visible = settings.entity_trace_visible;
+        self.show_sight_lines = settings.show_sight_lines;
+        self.show_dijkstra = settings.show_dijkstra;
+        self.show_velocity = settings.show_velocity;
+        self.show_contest = settings.show_contest;
+        self.show_roles = settings.show_roles;
+        self.mesh_visible = settings.mesh_visible;
+        self.bullet_style = settings.bullet_style;
+        self.clamp_camera = settings.clamp_camera;
+    }
+
     pub fn update(&mut self, delta_time: f64, agent_count: usize) -> Option<UpdateResult> {
+        if self.replay_player.is_some() {
+            // The replay slider drives the replayed game instead
+            self.step_replay_seek();
+            return None;
+        }
         self.game_params.agent_count = agent_count;
-        let game = &mut self.game;
-        game.set_params(&self.game_params);
-        let interval = game.interval;
-        let update_res = if !self.game_params.paused {
-            let update_res = game.update();
-            if let UpdateResult::TeamWon(team) = update_res {
-                self.big_message = ["Green team won!!", "Red team won!!"][team].to_string();
+        self.game.set_params(&self.game_params);
+        let interval = self.game.interval;
+        let update_res = if !self.game_params.paused && self.bullet_time_step() {
+            let update_res = self.game.update();
+            let message = match update_res {
+                UpdateResult::TeamWon(result) => {
+                    Some(["Green team won!!", "Red team won!!"][result.won_team])
+                }
+                UpdateResult::Draw { .. } => Some("Draw!!"),
+                UpdateResult::Running => None,
+            };
+            if let Some(message) = message.filter(|_| self.show_big_message) {
+                self.big_message = message.to_string();
                 self.big_message_time = 5000.;
             }
             Some(update_res)
@@ -187,17 +373,86 @@ impl AppData {
         update_res
     }
 
+    /// Advance the bullet time accumulator and return whether the game should be updated in this
+    /// frame. The game runs at full speed unless the bullet time is on and an entity is selected.
+    fn bullet_time_step(&mut self) -> bool {
+        if !self.bullet_time || self.selected_entity.is_none() {
+            self.bullet_time_progress = 0.;
+            return true;
+        }
+        self.bullet_time_progress += self.bullet_time_rate;
+        if 1. <= self.bullet_time_progress {
+            self.bullet_time_progress -= 1.;
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn new_game(&mut self, board_type: BoardType, params: BoardParams, show_message: bool) {
+        self.close_replay();
+        self.last_replay = self.game.replay().cloned();
         let ref mut game = self.game;
         game.new_board(board_type, &params);
         game.init();
 
-        if show_message {
+        if let Some(message) = game
+            .qtree_error()
+            .map(|e| format!("QTree failed to initialize, using grid path finding: {e}"))
+        {
+            self.set_message(message);
+        } else if show_message && self.show_big_message {
             self.big_message = "Game Start".to_string();
             self.big_message_time = 5000.;
         }
     }
 
+    /// Show `replay` from its start in place of the game, which is put aside until
+    /// `close_replay`.
+    pub(crate) fn open_replay(&mut self, replay: Replay) {
+        self.close_replay();
+        let mut player = ReplayPlayer::new(replay);
+        let live_game = std::mem::replace(&mut self.game, player.start());
+        self.replay_player = Some((player, live_game));
+        self.replay_tick = 0;
+        self.replay_seek = None;
+        self.selected_entity = None;
+    }
+
+    /// Start bringing the replayed game to `tick`. A long seek continues in the following updates,
+    /// so that it won't freeze the app.
+    pub(crate) fn seek_replay(&mut self, tick: i32) {
+        self.replay_seek = Some(tick);
+        self.step_replay_seek();
+    }
+
+    fn step_replay_seek(&mut self) {
+        /// The number of game updates to seek the replay by in a frame
+        const SEEK_UPDATES_PER_FRAME: usize = 100;
+
+        let (Some((player, _)), Some(tick)) = (&mut self.replay_player, self.replay_seek) else {
+            return;
+        };
+        if player.seek_by(&mut self.game, tick, SEEK_UPDATES_PER_FRAME) {
+            self.replay_tick = self.game.global_time;
+            self.replay_seek = None;
+        }
+    }
+
+    /// Return to the live game from the replay, if it is shown.
+    pub(crate) fn close_replay(&mut self) {
+        if let Some((_, live_game)) = self.replay_player.take() {
+            self.game = live_game;
+            self.selected_entity = None;
+        }
+    }
+
+    /// Take the events of the game that happened since the last call, such as a team winning
+    /// the game.
+    pub fn take_events(&mut self) -> Vec<GameEventRecord> {
+        self.game.take_events()
+    }
+
     pub fn with_qtree(&self, f: impl FnOnce(&QTreeSearcher)) {
         let game = &self.game;
         f(&game.qtree);
@@ -208,26 +463,16 @@ impl AppData {
         src: Rc<String>,
         setter: &impl Fn(&mut GameParams) -> &mut Rc<String>,
     ) -> Result<(), MessageError> {
-        fn count_newlines(src: &str) -> usize {
-            src.lines().count()
-        }
-
         // Check the syntax before applying
-        match parse_file(&src) {
-            Ok(("", _)) => {
+        match parse_tree_source(&src) {
+            Ok(_) => {
                 *setter(&mut self.game_params) = src.clone();
                 Ok(())
             }
-            Ok((rest, _)) => {
-                let parsed_src = &src[..rest.as_ptr() as usize - src.as_ptr() as usize];
-                Err(MessageError::new(
-                    format!(
-                        "Behavior tree source ended unexpectedly at ({})",
-                        count_newlines(parsed_src)
-                    ),
-                    format!("Rest: {rest}"),
-                ))
-            }
+            Err(BuildTreeError::Parse { line, column, rest }) => Err(MessageError::new(
+                format!("Behavior tree failed to parse at line {line}, column {column}"),
+                format!("Rest: {rest}"),
+            )),
             Err(e) => Err(format!("Behavior tree failed to parse: {}", e).into()),
         }
     }
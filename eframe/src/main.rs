@@ -4,22 +4,26 @@
 mod app;
 mod app_data;
 mod bg_image;
-pub use app::SwarmRsApp;
+pub use app::{AppOptions, SwarmRsApp};
 
 #[cfg(target_arch = "wasm32")]
 mod wasm_utils;
 
-
 #[cfg(not(target_arch = "wasm32"))]
 fn main() {
     // Log to stdout (if you run with `RUST_LOG=debug`).
     // tracing_subscriber::fmt::init();
 
-    let native_options = eframe::NativeOptions::default();
+    let options = AppOptions::default();
+    let native_options = eframe::NativeOptions {
+        initial_window_size: options.window_size.map(egui::Vec2::from),
+        ..Default::default()
+    };
+    let title = options.title.clone();
     eframe::run_native(
-        "swarm-rs application in eframe",
+        &title,
         native_options,
-        Box::new(|cc| Box::new(SwarmRsApp::new(cc))),
+        Box::new(|cc| Box::new(SwarmRsApp::new(cc, options))),
     );
 }
 
@@ -42,7 +46,7 @@ fn main() {
         eframe::start_web(
             "the_canvas_id", // hardcode it
             web_options,
-            Box::new(|cc| Box::new(SwarmRsApp::new(cc))),
+            Box::new(|cc| Box::new(SwarmRsApp::new(cc, AppOptions::default()))),
         )
         .await
         .expect("failed to start eframe");
@@ -4,42 +4,52 @@ mod behavior_nodes;
 mod find_path;
 pub mod interpolation;
 mod motion;
+#[cfg(feature = "training")]
+pub mod training;
 
 pub use self::agent_class::AgentClass;
+use self::avoidance::AvoidanceStats;
 pub(crate) use self::avoidance::{AgentState, PathNode, SearchState};
+pub use self::behavior_nodes::describe_nodes;
+pub(crate) use self::behavior_nodes::TargetPolicy;
 use self::{
     behavior_nodes::{
-        build_tree, AvoidanceCommand, ClearAvoidanceCommand, ClearPathNode, ClearTarget,
-        CollectResource, DepositResource, DriveCommand, FaceToTargetCommand, FindEnemyCommand,
-        FindFog, FindPathCommand, FindResource, FindSpawner, FollowPathCommand, GetClass,
-        GetPathNextNodeCommand, GetStateCommand, GetTargetTypeNode, HasPathNode, HasTargetNode,
-        IsResourceFull, IsSpawnerResourceFull, IsTargetVisibleCommand, MoveToCommand, ShootCommand,
-        SimpleAvoidanceCommand, TargetIdNode, TargetPosCommand,
+        build_tree, build_trees, AbandonUnreachableCommand, AttackSpawnerCommand, AvoidanceCommand,
+        BaitCommand, ClearAvoidanceCommand, ClearPathNode, ClearTarget, CollectResource,
+        DepositResource, DodgeBulletCommand, DriveCommand, EscortCommand, FaceToTargetCommand,
+        FightingRetreatCommand, FindEnemyCommand, FindFog, FindPathCommand, FindResource,
+        FindSpawner, FlankPosCommand, FogCoverageCommand, FollowPathCommand, FrontierPosCommand,
+        GetClass, GetPathNextNodeCommand, GetStateCommand, GetTargetTypeNode,
+        GoToLastKnownTargetCommand, GoToPositionCommand, HasAmmoNode, HasPathNode, HasTargetNode,
+        InEnemyTerritoryCommand, InterceptCommand, IsResourceFull, IsSpawnerResourceFull,
+        IsStuckCommand, IsTargetVisibleCommand, MoveToCommand, NearestReachableCommand,
+        OrientAlongPathCommand, PathDeadlockCommand, PathReadyCommand, PathStaleCommand,
+        PerimeterCommand, RandomizeCommand, RegroupCommand, ReloadNode, RemainingEnemiesCommand,
+        SeekRepairCommand, SeekTerrainCommand, SelectTargetCommand, SeparateCommand,
+        SetRoleCommand, ShootCommand, SimpleAvoidanceCommand, TargetDistanceCommand, TargetIdNode,
+        TargetPosCommand, TeamStat, TeamStatCommand, TurretCommand, WaitForSquadCommand,
     },
     motion::{MotionCommandResult, OrientToResult},
 };
 use crate::{
-    behavior_tree_adapt::{BehaviorTree, GetIdCommand, GetResource, PrintCommand},
-    collision::{aabb_intersects, CollisionShape, Obb},
+    behavior_tree_adapt::{BehaviorTree, BuildTreeError, GetIdCommand, GetResource, PrintCommand},
+    collision::{aabb_intersects, layers, layers_collide, CollisionShape, Obb},
     entity::{Entity, MAX_LOG_ENTRIES},
-    fog_of_war::{EntityShadow, FOG_MAX_AGE},
-    game::{is_passable_at_i, Game, Profiler, Resource},
+    fog_of_war::FOG_MAX_AGE,
+    game::{is_passable_at_i, separating_axis, Game, Profiler, Resource, Terrain},
     measure_time,
-    qtree::{PathFindResponse, QTreePath, SearchTree},
+    perlin_noise::Xor128,
+    qtree::{CellState, PathFindResponse, QTreePath, SearchTree},
     spawner::{SPAWNER_MAX_RESOURCE, SPAWNER_RADIUS},
 };
 use ::behavior_tree_lite::Context;
 use ::cgmath::{InnerSpace, MetricSpace, Vector2, Zero};
-use behavior_tree_lite::{error::LoadError, BehaviorResult, Blackboard, Lazy};
+use behavior_tree_lite::{BehaviorResult, Blackboard};
 
 use std::{
-    cell::RefCell,
+    cell::{Ref, RefCell},
     collections::{HashSet, VecDeque},
     rc::Rc,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Mutex,
-    },
 };
 
 #[derive(Clone, Debug)]
@@ -51,6 +61,12 @@ pub struct Bullet {
     /// Distance traveled, used for rendering
     pub traveled: f64,
     pub shooter_class: AgentClass,
+    /// Remaining number of ricochets off walls
+    pub bounces_left: u32,
+    /// Collision layer of this bullet, `layers::BULLET` by default
+    pub layer: u32,
+    /// Collision layers this bullet hits, including `layers::WALL` to be stopped by walls
+    pub mask: u32,
 }
 
 impl Bullet {
@@ -62,7 +78,21 @@ impl Bullet {
             damage,
             traveled: 0.,
             shooter_class: class,
+            bounces_left: BULLET_MAX_BOUNCES,
+            layer: layers::BULLET,
+            mask: layers::ALL,
+        }
+    }
+
+    /// The damage this bullet deals when it hits now. With `falloff`, it decreases linearly with
+    /// the distance traveled down to `BULLET_MIN_DAMAGE_RATE` at the shooter class's max range.
+    pub(crate) fn hit_damage(&self, falloff: bool) -> u32 {
+        if !falloff {
+            return self.damage;
         }
+        let range_rate = (self.traveled / self.shooter_class.max_range()).min(1.);
+        let rate = 1. - (1. - BULLET_MIN_DAMAGE_RATE) * range_rate;
+        ((self.damage as f64 * rate).round() as u32).max(1)
     }
 }
 
@@ -73,6 +103,18 @@ pub(crate) enum AgentTarget {
     Fog([f64; 2]),
 }
 
+/// Progress of the `Bait` maneuver against a target
+#[derive(Debug, Clone, Copy)]
+struct BaitState {
+    target: usize,
+    /// Position of the target in the last tick, to tell whether it is closing in
+    target_pos: [f64; 2],
+    /// Consecutive ticks that the target has been closing in
+    closing_ticks: usize,
+    /// Whether the target has committed to the chase and the agent is falling back
+    retreating: bool,
+}
+
 #[derive(Debug)]
 pub struct Agent {
     pub(crate) target: Option<AgentTarget>,
@@ -84,21 +126,54 @@ pub struct Agent {
     pub speed: f64,
     pub team: usize,
     pub(crate) class: AgentClass,
+    /// Collision layer of this agent, derived from the class by default
+    pub layer: u32,
+    /// Collision layers this agent collides with
+    pub mask: u32,
+    /// A label given by the `SetRole` node, for telling what each agent is up to in a large swarm
+    pub role: Option<String>,
     cooldown: f64,
+    /// Remaining shots, consumed only if `GameParams::limited_ammo` is enabled
+    pub ammo: u32,
     pub health: u32,
+    /// The value of `Game::global_time` when this agent was hit last time
+    pub(crate) last_damaged_tick: i32,
+    /// Fractional health accumulated by regeneration
+    regen_progress: f64,
     pub resource: i32,
     pub(crate) goal: Option<AgentState>,
     pub search_state: Option<SearchState>,
     pub(crate) search_tree: Option<SearchTree>,
     pub avoidance_plan: Option<Vec<(f64, f64)>>,
+    /// Statistics of the last avoidance search
+    avoidance_stats: Option<AvoidanceStats>,
     pub(crate) path: QTreePath,
+    /// The goal position of `path` at the time it was computed
+    pub(crate) path_target: Option<[f64; 2]>,
+    /// Whether `path` was found on a fully built qtree. A path found during an incremental build
+    /// searched only a part of the board, so it may not be the complete route to `path_target`.
+    pub(crate) path_complete: bool,
+    /// The target entity if the last `FindPath` node failed to find a path to its position
+    pub(crate) path_failed_target: Option<usize>,
+    /// The id and the position of the target entity where it was last seen out of the fog
+    last_known_target: Option<(usize, [f64; 2])>,
+    /// Whether the `Escort` node is catching up with the escortee
+    escort_catching_up: bool,
+    bait: Option<BaitState>,
     pub trace: VecDeque<[f64; 2]>,
     last_motion_result: Option<MotionCommandResult>,
+    /// The number of consecutive `MoveTo` and `FollowPath` motions that were blocked
+    pub(crate) blocked_motions: usize,
     last_state: Option<AgentState>,
     behavior_source: Rc<String>,
     behavior_tree: Option<BehaviorTree>,
     blackboard: Blackboard,
     log_buffer: VecDeque<String>,
+    /// Random number generator for the behavior tree, independent from other agents so that
+    /// the randomness of each agent is reproducible.
+    pub(crate) rng: Xor128,
+    #[cfg(feature = "training")]
+    training_samples: Vec<training::TrainingSample>,
 }
 
 const AGENT_SCALE: f64 = 1.;
@@ -108,9 +183,57 @@ pub(crate) const AGENT_SPEED: f64 = 0.125;
 pub(crate) const AGENT_MAX_HEALTH: u32 = 100;
 pub(crate) const AGENT_MAX_RESOURCE: i32 = 100;
 const AGENT_VISIBLE_DISTANCE: f64 = 30.;
+/// Rounds of ammo reloaded per tick at a spawner
+const AMMO_RELOAD_RATE: u32 = 1;
+/// Health points per tick that an agent regains at a repair point
+const REPAIR_RATE: u32 = 1;
+/// The role that makes an agent a repair point for its friends
+const REPAIR_ROLE: &str = "repair";
+/// Distance from the regrouping position at which an agent is regarded as regrouped
+const REGROUP_DISTANCE: f64 = 2.;
+/// Distance from a goal position at which an agent is regarded as arrived, which is also the
+/// tolerance of an existing path leading there
+pub(super) const ARRIVE_DISTANCE: f64 = 1.5;
+/// Distance from the last known position of the target at which `GoToLastKnownTarget` arrives,
+/// which is also the tolerance to find a new path to it
+const LAST_KNOWN_ARRIVE_DISTANCE: f64 = 2.;
+/// Distance the repair point needs to move for `SeekRepair` to find a new path to it
+const REPAIR_REPATH_DISTANCE: f64 = 1.;
+/// An escort that fell behind catches up until it is within this fraction of the escort radius,
+/// so that it does not start and stop at the edge of the radius
+const ESCORT_SETTLE_RATE: f64 = 0.5;
+/// Enemies within this many times the escort radius from the escortee are engaged
+const ESCORT_THREAT_RATE: f64 = 2.;
+/// Ticks ahead that enemy bullets are predicted to hit the agent by `DodgeBullet`
+const DODGE_LOOKAHEAD: f64 = 10.;
+/// Distance that an agent steps aside from the path of a bullet
+const DODGE_STEP: f64 = 2.;
+/// Distance from the nearest ally at which `Bait` has lured its target back
+const BAIT_RETURN_DISTANCE: f64 = 2.;
+/// Distance per tick that the target of `Bait` needs to close in to count as chasing
+const BAIT_COMMIT_SPEED: f64 = 0.02;
+/// Consecutive ticks of chasing after which the target of `Bait` is regarded as committed
+const BAIT_COMMIT_TICKS: usize = 5;
+/// Distance that an agent steps away from its allies in a tick of separation
+const SEPARATION_STEP: f64 = 1.;
+/// Maximum ticks ahead that an intercept point is predicted
+const INTERCEPT_MAX_TICKS: f64 = 100.;
+/// Distance the intercept point needs to move to find a new path to it
+const INTERCEPT_REPATH_DISTANCE: f64 = 3.;
+/// Distance ahead of the agent's position that a retreat heads to in a tick
+const RETREAT_STEP: f64 = 3.;
+/// Radius in cells around the agent that `SeekChokepoint` and `SeekOpenGround` look for a position
+const TERRAIN_SEEK_RADIUS: i32 = 10;
+/// Ticks an agent needs to stay out of fire before it starts regenerating health
+pub(crate) const HEALTH_REGEN_DELAY: i32 = 100;
 pub const BULLET_RADIUS: f64 = 0.15;
 pub(crate) const BULLET_SPEED: f64 = 2.;
 pub(crate) const BULLET_DAMAGE: u32 = 10;
+/// The number of times a bullet can bounce off walls if `GameParams::ricochet` is enabled
+pub(crate) const BULLET_MAX_BOUNCES: u32 = 2;
+/// The fraction of the damage that a bullet deals at the maximum range of its shooter class, if
+/// `GameParams::damage_falloff` is enabled
+const BULLET_MIN_DAMAGE_RATE: f64 = 0.25;
 
 struct GameEnv<'a> {
     _game: &'a mut Game,
@@ -125,7 +248,7 @@ impl Agent {
         team: usize,
         class: AgentClass,
         behavior_source: Rc<String>,
-    ) -> Result<Self, LoadError> {
+    ) -> Result<Self, BuildTreeError> {
         let id = *id_gen;
         *id_gen += 1;
 
@@ -143,21 +266,38 @@ impl Agent {
             speed: 0.,
             team,
             class,
+            layer: class.collision_layer(),
+            mask: layers::ALL,
             cooldown: 5.,
+            role: None,
+            ammo: class.ammo(),
             health: class.health(),
+            last_damaged_tick: i32::MIN,
+            regen_progress: 0.,
             resource: 0,
             goal: None,
             search_state: None,
             search_tree: None,
             avoidance_plan: None,
+            avoidance_stats: None,
             path: vec![],
+            path_target: None,
+            path_complete: false,
+            path_failed_target: None,
+            last_known_target: None,
+            escort_catching_up: false,
+            bait: None,
             trace: VecDeque::new(),
             last_motion_result: None,
+            blocked_motions: 0,
             last_state: None,
             behavior_source,
             behavior_tree: Some(tree),
             blackboard: Blackboard::new(),
             log_buffer: VecDeque::new(),
+            rng: Xor128::new(id as u32),
+            #[cfg(feature = "training")]
+            training_samples: vec![],
         })
     }
 
@@ -172,8 +312,14 @@ impl Agent {
     }
 
     pub(crate) fn get_target_pos(&self, game: &Game) -> Option<[f64; 2]> {
+        self.get_target_pos_in(&game.entities)
+    }
+
+    /// Same as `get_target_pos`, but looks up entity targets in `entities`, which is useful while
+    /// the entities are taken out of the game during update.
+    fn get_target_pos_in(&self, entities: &[RefCell<Entity>]) -> Option<[f64; 2]> {
         self.target.and_then(|target| match target {
-            AgentTarget::Entity(id) => game.entities.iter().find_map(|entity| {
+            AgentTarget::Entity(id) => entities.iter().find_map(|entity| {
                 let entity = entity.try_borrow().ok()?;
                 if entity.get_id() == id {
                     Some(entity.get_pos())
@@ -185,11 +331,70 @@ impl Agent {
         })
     }
 
+    /// Remember the position of the target entity while it is visible, so that the agent can
+    /// search for it after losing it in the fog. Returns whether it is visible.
+    fn track_target(&mut self, game: &Game, entities: &[RefCell<Entity>]) -> bool {
+        let Some(AgentTarget::Entity(id)) = self.target else {
+            return false;
+        };
+        match self.get_target_pos_in(entities) {
+            Some(pos) if game.is_clear_fog_at(self.team, pos) => {
+                self.last_known_target = Some((id, pos));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Find a passable position `distance` away from the target entity to its side, or behind it
+    /// if `behind` is true. Spawners have no facing, so the side facing this agent is regarded as
+    /// their front.
+    fn flank_pos(
+        &self,
+        distance: f64,
+        behind: bool,
+        game: &Game,
+        entities: &[RefCell<Entity>],
+    ) -> Option<[f64; 2]> {
+        let Some(AgentTarget::Entity(id)) = self.target else {
+            return None;
+        };
+        let (target_pos, orient) = entities.iter().find_map(|entity| {
+            let entity = entity.try_borrow().ok()?;
+            (entity.get_id() == id).then(|| (entity.get_pos(), entity.get_orient()))
+        })?;
+        let target_pos = Vector2::from(target_pos);
+        let self_pos = Vector2::from(self.pos);
+        let front = match orient {
+            Some(orient) => Vector2::new(orient.cos(), orient.sin()),
+            // Right on top of the spawner, its front is where this agent came from
+            None if (self_pos - target_pos).is_zero() => {
+                -Vector2::new(self.orient.cos(), self.orient.sin())
+            }
+            None => (self_pos - target_pos).normalize(),
+        };
+        let left = Vector2::new(-front.y, front.x);
+        let mut candidates = if behind {
+            vec![-front]
+        } else {
+            vec![left, -left]
+        };
+        candidates.sort_by(|a, b| {
+            let dist = |dir: &Vector2<f64>| (target_pos + dir * distance).distance2(self_pos);
+            dist(a).total_cmp(&dist(b))
+        });
+        candidates
+            .into_iter()
+            .map(|dir| target_pos + dir * distance)
+            .find(|pos| game.is_passable_at((*pos).into()))
+            .map(Into::into)
+    }
+
     pub(crate) fn get_shape(&self) -> CollisionShape {
         CollisionShape::BBox(Obb {
             center: self.pos.into(),
-            xs: AGENT_HALFLENGTH,
-            ys: AGENT_HALFWIDTH,
+            xs: self.class.shape().0,
+            ys: self.class.shape().1,
             orient: self.orient,
         })
     }
@@ -202,6 +407,41 @@ impl Agent {
         self.health as f64 / self.class.health() as f64
     }
 
+    /// Regenerate `rate` health per tick if the agent was not damaged recently.
+    pub(crate) fn regenerate(&mut self, global_time: i32, rate: f64) {
+        if rate <= 0. || global_time.saturating_sub(self.last_damaged_tick) < HEALTH_REGEN_DELAY {
+            self.regen_progress = 0.;
+            return;
+        }
+        let max_health = self.get_max_health();
+        if max_health <= self.health {
+            self.regen_progress = 0.;
+            return;
+        }
+        self.regen_progress += rate;
+        let amount = self.regen_progress.floor();
+        self.regen_progress -= amount;
+        self.health = (self.health + amount as u32).min(max_health);
+    }
+
+    /// Regain `REPAIR_RATE` health per tick within the reach of a friendly repair point, if
+    /// `enabled` and the agent was not damaged recently.
+    pub(crate) fn repair(&mut self, global_time: i32, enabled: bool, entities: &[RefCell<Entity>]) {
+        let max_health = self.get_max_health();
+        if !enabled
+            || max_health <= self.health
+            || global_time.saturating_sub(self.last_damaged_tick) < HEALTH_REGEN_DELAY
+        {
+            return;
+        }
+        let Some((pos, reach)) = self.nearest_repair_point(entities) else {
+            return;
+        };
+        if Vector2::from(pos).distance2(Vector2::from(self.pos)) < reach.powi(2) {
+            self.health = (self.health + REPAIR_RATE).min(max_health);
+        }
+    }
+
     pub(crate) fn get_max_health(&self) -> u32 {
         self.class.health()
     }
@@ -214,10 +454,31 @@ impl Agent {
         self.behavior_source.clone()
     }
 
+    /// Statistics of the last avoidance search, or `None` if it has never searched.
+    pub fn avoidance_stats(&self) -> Option<&AvoidanceStats> {
+        self.avoidance_stats.as_ref()
+    }
+
     pub(crate) fn behavior_tree(&self) -> Option<&BehaviorTree> {
         self.behavior_tree.as_ref()
     }
 
+    /// Build the tree in `source` for `count` agents, see `behavior_nodes::build_trees`.
+    pub(crate) fn build_behavior_trees(
+        source: &str,
+        count: usize,
+    ) -> Result<Vec<BehaviorTree>, BuildTreeError> {
+        build_trees(source, count)
+    }
+
+    /// Replace the behavior tree while the agent is alive. The blackboard is cleared since its
+    /// variables belong to the old tree.
+    pub(crate) fn set_behavior_tree(&mut self, source: Rc<String>, tree: BehaviorTree) {
+        self.behavior_source = source;
+        self.behavior_tree = Some(tree);
+        self.blackboard = Blackboard::new();
+    }
+
     /// Check collision in qtree bounding boxes
     pub(crate) fn qtree_collision(
         ignore: Option<usize>,
@@ -246,24 +507,37 @@ impl Agent {
         ignore: Option<usize>,
         newpos: AgentState,
         class: AgentClass,
+        (layer, mask): (u32, u32),
         others: &[RefCell<Entity>],
         prediction: bool,
     ) -> bool {
-        Self::collision_check_fn(|id| Some(id) == ignore, newpos, class, others, prediction)
+        Self::collision_check_fn(
+            |id| Some(id) == ignore,
+            newpos,
+            class,
+            (layer, mask),
+            others,
+            prediction,
+        )
     }
 
-    /// Check collision with other entities, but not walls
+    /// Check collision with other entities, but not walls. Entities whose layers do not
+    /// collide with `(layer, mask)` are ignored.
     pub(crate) fn collision_check_fn(
         ignore: impl Fn(usize) -> bool,
         newpos: AgentState,
         class: AgentClass,
+        (layer, mask): (u32, u32),
         others: &[RefCell<Entity>],
         prediction: bool,
     ) -> bool {
         let shape = newpos.collision_shape(class);
+        let radius = shape.to_bounding_circle().radius;
         for entity in others.iter() {
             if let Ok(entity) = entity.try_borrow() {
-                if ignore(entity.get_id()) {
+                if ignore(entity.get_id())
+                    || !layers_collide(layer, mask, entity.get_layer(), entity.get_mask())
+                {
                     continue;
                 }
                 let buffer = if prediction && entity.get_speed() != 0. {
@@ -272,8 +546,9 @@ impl Agent {
                     0.
                 };
                 let dist2 = Vector2::from(entity.get_pos()).distance2(Vector2::from(newpos));
-                if dist2 < ((AGENT_HALFLENGTH + buffer) * 2.).powf(2.) {
-                    let mut entity_shape = entity.get_shape();
+                let mut entity_shape = entity.get_shape();
+                let reach = radius + entity_shape.to_bounding_circle().radius + buffer;
+                if dist2 < reach.powf(2.) {
                     if buffer != 0. {
                         entity_shape = entity_shape.buffer(buffer);
                     }
@@ -288,7 +563,17 @@ impl Agent {
     }
 
     pub(crate) fn find_enemy(&mut self, game: &Game, agents: &[RefCell<Entity>]) {
-        let best_agent = agents
+        self.select_target(TargetPolicy::Nearest, game, agents);
+    }
+
+    /// Select an enemy target by the given policy. Returns true if a target was found.
+    pub(crate) fn select_target(
+        &mut self,
+        policy: TargetPolicy,
+        game: &Game,
+        agents: &[RefCell<Entity>],
+    ) -> bool {
+        let visible = agents
             .iter()
             .filter_map(|a| a.try_borrow().ok())
             .filter(|a| {
@@ -296,62 +581,45 @@ impl Agent {
                 let ateam = a.get_team();
                 let apos = a.get_pos();
                 game.is_clear_fog_at(self.team, apos)
+                    && (!game.params.sensor_cone || self.is_in_sensor_cone(apos))
                     && !self.unreachables.contains(&aid)
                     && aid != self.id
                     && ateam != self.team
             })
-            .filter_map(|a| {
-                let distance = Vector2::from(a.get_pos()).distance(Vector2::from(self.pos));
-                Some((distance, a))
+            .map(|a| TargetCandidate {
+                id: a.get_id(),
+                distance: Vector2::from(a.get_pos()).distance(Vector2::from(self.pos)),
+                health: a.get_health(),
+                class: a.get_class(),
             })
-            .fold(None, |acc: Option<(f64, _)>, cur| {
-                if let Some(acc) = acc {
-                    if cur.0 < acc.0 {
-                        Some(cur)
-                    } else {
-                        Some(acc)
-                    }
-                } else {
-                    Some(cur)
-                }
-            });
+            .collect::<Vec<_>>();
 
         // Theoretically, a shadow entity could have shorter distance than know entities.
-        let best_shadow = game.fog[self.team]
+        // We do not know the class of a shadow, so it is treated as a non-fighter.
+        let shadows = game.fog[self.team]
             .entities
             .iter()
-            .map(|a| {
-                let distance = Vector2::from(a.pos).distance(Vector2::from(self.pos));
-                (distance, a)
-            })
-            .fold(None, |acc: Option<(f64, &EntityShadow)>, cur| {
-                if let Some(acc) = acc {
-                    if cur.0 < acc.0 {
-                        Some(cur)
-                    } else {
-                        Some(acc)
-                    }
-                } else {
-                    Some(cur)
-                }
+            .map(|a| TargetCandidate {
+                id: a.id,
+                distance: Vector2::from(a.pos).distance(Vector2::from(self.pos)),
+                health: a.health,
+                class: None,
             });
 
-        match (best_agent, best_shadow) {
-            (Some(agent), Some(shadow)) => {
-                self.target = if agent.0 < shadow.0 {
-                    Some(AgentTarget::Entity(agent.1.get_id()))
-                } else {
-                    Some(AgentTarget::Entity(shadow.1.id))
-                };
-            }
-            (Some((_, agent)), None) => {
-                self.target = Some(AgentTarget::Entity(agent.get_id()));
-            }
-            (None, Some((_, shadow))) => {
-                self.target = Some(AgentTarget::Entity(shadow.id));
-            }
-            _ => self.target = None,
+        let best = select_best_target(policy, visible.into_iter().chain(shadows));
+        self.target = best.map(|best| AgentTarget::Entity(best.id));
+        self.target.is_some()
+    }
+
+    /// Check if the position is in the forward cone of the sensor of this agent's class.
+    fn is_in_sensor_cone(&self, pos: [f64; 2]) -> bool {
+        let half_fov = self.class.sensor_fov() / 2.;
+        if std::f64::consts::PI <= half_fov {
+            return true;
         }
+        let delta = Vector2::from(pos) - Vector2::from(self.pos);
+        let angle = wrap_angle(delta.y.atan2(delta.x) - self.orient);
+        angle.abs() <= half_fov
     }
 
     fn has_target(&self, entities: &[RefCell<Entity>]) -> bool {
@@ -380,6 +648,27 @@ impl Agent {
         ))
     }
 
+    /// Returns true if all 8 cells surrounding the agent are either impassable or occupied by
+    /// other entities, i.e. the agent has nowhere to move.
+    fn is_stuck(&self, game: &Game) -> bool {
+        let center = [self.pos[0].floor() as i32, self.pos[1].floor() as i32];
+        (-1..=1)
+            .flat_map(|dy| (-1..=1).map(move |dx| [dx, dy]))
+            .filter(|&[dx, dy]| dx != 0 || dy != 0)
+            .all(|[dx, dy]| {
+                let cell = [center[0] + dx, center[1] + dy];
+                if !is_passable_at_i(&game.board, game.shape(), cell) {
+                    return true;
+                }
+                let pos = [cell[0] as f64 + 0.5, cell[1] as f64 + 0.5];
+                match game.qtree.find(pos) {
+                    Some((_, CellState::Obstacle)) => true,
+                    Some((_, CellState::Occupied(id))) => id != self.id,
+                    _ => false,
+                }
+            })
+    }
+
     fn is_spawner_resource_full(&self, entities: &[RefCell<Entity>]) -> bool {
         entities
             .iter()
@@ -401,6 +690,297 @@ impl Agent {
             })
     }
 
+    fn team_stat(&self, game: &Game, entities: &[RefCell<Entity>]) -> TeamStat {
+        let (agents, resource) = entities
+            .iter()
+            .filter_map(|a| a.try_borrow().ok())
+            .filter(|a| a.get_id() != self.id && a.get_team() == self.team && a.get_active())
+            .fold((0, 0), |(agents, resource), a| {
+                if a.is_agent() {
+                    (agents + 1, resource)
+                } else {
+                    (agents, resource + a.resource())
+                }
+            });
+        TeamStat {
+            // This agent is left out above whether or not it is borrowed, so count it here
+            agents: agents + 1,
+            resource,
+            kills: game.match_kills(self.team) as i32,
+        }
+    }
+
+    /// Number of living enemy entities, agents and spawners alike. Unlike most queries, this one
+    /// ignores the fog of war, since the count alone does not reveal where the enemies are.
+    fn remaining_enemies(&self, entities: &[RefCell<Entity>]) -> i32 {
+        entities
+            .iter()
+            .filter_map(|a| a.try_borrow().ok())
+            .filter(|a| a.get_team() != self.team && a.get_active())
+            .count() as i32
+    }
+
+    /// Active entities other than this agent within `radius`, excluding enemies hidden in the fog.
+    fn entities_in_radius<'a>(
+        &'a self,
+        radius: f64,
+        game: &'a Game,
+        entities: &'a [RefCell<Entity>],
+    ) -> impl Iterator<Item = Ref<'a, Entity>> + 'a {
+        let self_pos = Vector2::from(self.pos);
+        entities
+            .iter()
+            .filter_map(|a| a.try_borrow().ok())
+            .filter(move |a| {
+                let pos = a.get_pos();
+                a.get_id() != self.id
+                    && a.get_active()
+                    && Vector2::from(pos).distance2(self_pos) < radius.powi(2)
+                    && (a.get_team() == self.team || game.is_clear_fog_at(self.team, pos))
+            })
+    }
+
+    /// Returns the position to fall back to if the visible enemy agents within `radius` outnumber
+    /// the allied agents including this one by `ratio` or more. It is the center of the allies
+    /// around the nearest allied agent, or the nearest allied spawner if there are no other agents.
+    fn regroup_pos(
+        &self,
+        ratio: f64,
+        radius: f64,
+        game: &Game,
+        entities: &[RefCell<Entity>],
+    ) -> Option<[f64; 2]> {
+        let (allies, enemies) = self
+            .entities_in_radius(radius, game, entities)
+            .filter(|a| a.is_agent())
+            .fold((1, 0), |(allies, enemies), a| {
+                if a.get_team() == self.team {
+                    (allies + 1, enemies)
+                } else {
+                    (allies, enemies + 1)
+                }
+            });
+        if enemies == 0 || (enemies as f64) < allies as f64 * ratio {
+            return None;
+        }
+
+        let self_pos = Vector2::from(self.pos);
+        let friends: Vec<_> = entities
+            .iter()
+            .filter_map(|a| a.try_borrow().ok())
+            .filter(|a| a.get_team() == self.team && a.get_active() && a.get_id() != self.id)
+            .map(|a| (a.is_agent(), Vector2::from(a.get_pos())))
+            .collect();
+        let nearest = |agent: bool| {
+            friends
+                .iter()
+                .filter(|(is_agent, _)| *is_agent == agent)
+                .map(|(_, pos)| *pos)
+                .min_by(|a, b| a.distance2(self_pos).total_cmp(&b.distance2(self_pos)))
+        };
+
+        if let Some(nearest) = nearest(true) {
+            let (sum, count) = friends
+                .iter()
+                .filter(|(is_agent, pos)| *is_agent && pos.distance2(nearest) < radius.powi(2))
+                .fold((Vector2::zero(), 0.), |(sum, count), (_, pos)| {
+                    (sum + pos, count + 1.)
+                });
+            Some((sum / count).into())
+        } else {
+            nearest(false).map(Into::into)
+        }
+    }
+
+    /// Signal that this agent is ready and return whether at least `fraction` of the allied agents
+    /// within `radius` have signaled too. Always true if there are no allies around.
+    fn squad_ready(
+        &self,
+        fraction: f64,
+        radius: f64,
+        game: &mut Game,
+        entities: &[RefCell<Entity>],
+    ) -> bool {
+        game.squad_ready[self.team].signal(self.id, game.global_time);
+        let readiness = &game.squad_ready[self.team];
+        let (allies, ready) = self
+            .entities_in_radius(radius, game, entities)
+            .filter(|a| a.is_agent() && a.get_team() == self.team)
+            .fold((0, 0), |(allies, ready), a| {
+                let is_ready = readiness.is_ready(a.get_id(), game.global_time);
+                (allies + 1, ready + is_ready as usize)
+            });
+        allies as f64 * fraction <= ready as f64
+    }
+
+    /// The position a step aside from the path of the nearest enemy bullet that would hit this
+    /// agent within `DODGE_LOOKAHEAD` ticks if it kept going straight. The agent steps to the side
+    /// of the path it is already on, or the other side if that is a wall. Returns `None` if no
+    /// bullet threatens the agent or it has nowhere to go.
+    fn dodge_pos(&self, bullets: &[Bullet], game: &Game) -> Option<[f64; 2]> {
+        let self_pos = Vector2::from(self.pos);
+        let self_velo = Vector2::new(self.orient.cos(), self.orient.sin()) * self.speed;
+        let vertices = self.get_shape().to_vertices()?;
+        let bullet = bullets
+            .iter()
+            .filter(|bullet| {
+                bullet.team != self.team
+                    && layers_collide(bullet.layer, bullet.mask, self.layer, self.mask)
+                    && separating_axis(
+                        &Vector2::from(bullet.pos),
+                        &((Vector2::from(bullet.velo) - self_velo) * DODGE_LOOKAHEAD),
+                        vertices.into_iter().map(Vector2::from),
+                    )
+            })
+            .min_by(|a, b| {
+                let distance2 = |bullet: &Bullet| Vector2::from(bullet.pos).distance2(self_pos);
+                distance2(a).total_cmp(&distance2(b))
+            })?;
+        let velo = Vector2::from(bullet.velo);
+        let side = Vector2::new(-velo.y, velo.x).normalize();
+        let side = if side.dot(self_pos - Vector2::from(bullet.pos)) < 0. {
+            -side
+        } else {
+            side
+        };
+        [side, -side].into_iter().find_map(|side| {
+            let goal = (self_pos + side * DODGE_STEP).into();
+            game.is_passable_at(goal).then_some(goal)
+        })
+    }
+
+    /// The position a step away from the allied agents within `radius`, pushed harder by the
+    /// closer ones. Returns `None` if there are no allies around or the step would hit a wall.
+    fn separation_pos(
+        &self,
+        radius: f64,
+        game: &Game,
+        entities: &[RefCell<Entity>],
+    ) -> Option<[f64; 2]> {
+        let self_pos = Vector2::from(self.pos);
+        let push = self
+            .entities_in_radius(radius, game, entities)
+            .filter(|a| a.is_agent() && a.get_team() == self.team)
+            .fold(Vector2::zero(), |sum, a| {
+                let delta = self_pos - Vector2::from(a.get_pos());
+                let distance = delta.magnitude();
+                // Agents at the same position are pulled apart in directions given by their ids
+                let direction = if distance < 1e-6 {
+                    let angle =
+                        (self.id as f64 - a.get_id() as f64).signum() * std::f64::consts::FRAC_PI_2;
+                    Vector2::new(angle.cos(), angle.sin())
+                } else {
+                    delta / distance
+                };
+                sum + direction * (radius - distance) / radius
+            });
+        if push.magnitude2() < 1e-12 {
+            return None;
+        }
+        let goal = self_pos + push.normalize() * SEPARATION_STEP;
+        game.is_passable_at(goal.into()).then(|| goal.into())
+    }
+
+    /// Predict where the target entity will be when this agent can reach it, assuming both move
+    /// in straight lines at their current velocity and the maximum speed respectively. If the
+    /// predicted position is not passable, it is pulled back along the target's course.
+    fn intercept_pos(&self, game: &Game, entities: &[RefCell<Entity>]) -> Option<[f64; 2]> {
+        let Some(AgentTarget::Entity(id)) = self.target else {
+            return None;
+        };
+        let (target_pos, velo) = entities.iter().find_map(|entity| {
+            let entity = entity.try_borrow().ok()?;
+            (entity.get_id() == id).then(|| {
+                (
+                    Vector2::from(entity.get_pos()),
+                    Vector2::from(entity.get_velocity().unwrap_or([0.; 2])),
+                )
+            })
+        })?;
+
+        // Solve |target_pos + velo * t - pos| = speed * t for the smallest positive t
+        let delta = target_pos - Vector2::from(self.pos);
+        let speed = self.class.speed();
+        let a = velo.magnitude2() - speed.powi(2);
+        let b = 2. * delta.dot(velo);
+        let c = delta.magnitude2();
+        let t = if a.abs() < 1e-9 {
+            (b < 0.).then(|| -c / b)
+        } else {
+            let disc = b * b - 4. * a * c;
+            (0. <= disc)
+                .then(|| {
+                    let sqrt = disc.sqrt();
+                    [(-b - sqrt) / (2. * a), (-b + sqrt) / (2. * a)]
+                        .into_iter()
+                        .filter(|t| 0. < *t)
+                        .reduce(f64::min)
+                })
+                .flatten()
+        };
+        // If the target is too fast to catch, head to where it will be at the horizon
+        let t = t.unwrap_or(INTERCEPT_MAX_TICKS).min(INTERCEPT_MAX_TICKS);
+
+        let passable = |pos: Vector2<f64>| {
+            matches!(
+                game.qtree.find(pos.into()),
+                Some((_, CellState::Free)) | Some((_, CellState::Occupied(_)))
+            )
+        };
+        let steps = t.ceil() as usize;
+        (0..=steps)
+            .rev()
+            .map(|step| target_pos + velo * (t * step as f64 / steps.max(1) as f64))
+            .find(|pos| passable(*pos))
+            .map(Into::into)
+    }
+
+    /// Points `distance` away from the nearest allied spawner that loop around it, reachable
+    /// from the spawner without going farther than that.
+    fn perimeter_points(
+        &self,
+        distance: f64,
+        game: &Game,
+        entities: &[RefCell<Entity>],
+    ) -> Vec<[f64; 2]> {
+        let self_pos = Vector2::from(self.pos);
+        let spawner = entities
+            .iter()
+            .filter_map(|a| a.try_borrow().ok())
+            .filter(|a| a.get_team() == self.team && !a.is_agent() && a.get_active())
+            .map(|a| (a.get_id(), a.get_pos()))
+            .min_by(|(a_id, a), (b_id, b)| {
+                let dist = |pos: &[f64; 2]| Vector2::from(*pos).distance2(self_pos);
+                dist(a).total_cmp(&dist(b)).then(a_id.cmp(b_id))
+            })
+            .map(|(_, pos)| pos);
+        // Agents move around, so only the static obstacles matter for the route
+        spawner.map_or(vec![], |spawner| {
+            game.qtree.find_contour(|_| true, spawner, distance)
+        })
+    }
+
+    /// Find a path to `goal` unless the current path already leads within `tolerance` of it.
+    /// Returns whether there is a path to follow.
+    fn path_toward(&mut self, goal: [f64; 2], tolerance: f64, game: &mut Game) -> bool {
+        let repath = self.path.is_empty()
+            || self.path_target.is_none_or(|target| {
+                tolerance < Vector2::from(target).distance(Vector2::from(goal))
+            });
+        !repath
+            || self
+                .find_path(
+                    &FindPathCommand {
+                        target: goal,
+                        ignore_obstacles: false,
+                        stealth: false,
+                        grid: false,
+                    },
+                    game,
+                )
+                .is_ok()
+    }
+
     fn find_spawner(&mut self, agents: &[RefCell<Entity>]) {
         let best_spawner = agents
             .iter()
@@ -415,11 +995,12 @@ impl Agent {
             })
             .filter_map(|a| {
                 let distance = Vector2::from(a.get_pos()).distance(Vector2::from(self.pos));
-                Some((distance, a))
+                Some((distance, a.get_id()))
             })
-            .fold(None, |acc: Option<(f64, _)>, cur| {
+            .fold(None, |acc: Option<(f64, usize)>, cur| {
                 if let Some(acc) = acc {
-                    if cur.0 < acc.0 {
+                    // Break ties by the id to be independent of the order of entities
+                    if cur < acc {
                         Some(cur)
                     } else {
                         Some(acc)
@@ -430,8 +1011,180 @@ impl Agent {
             });
 
         if let Some((_dist, spawner)) = best_spawner {
-            self.target = Some(AgentTarget::Entity(spawner.get_id()));
+            self.target = Some(AgentTarget::Entity(spawner));
+        }
+    }
+
+    /// Refill ammo by `AMMO_RELOAD_RATE` if the agent is next to a friendly spawner. Succeeds when
+    /// the ammo is full and fails if there is no spawner nearby.
+    fn reload(&mut self, entities: &[RefCell<Entity>]) -> BehaviorResult {
+        let max_ammo = self.class.ammo();
+        if max_ammo <= self.ammo {
+            return BehaviorResult::Success;
+        }
+        let at_spawner = entities
+            .iter()
+            .filter_map(|ent| ent.try_borrow().ok())
+            .any(|entity| {
+                let Entity::Spawner(ref spawner) = &entity as &Entity else {
+                    return false;
+                };
+                spawner.team == self.team
+                    && Vector2::from(spawner.pos).distance2(Vector2::from(self.pos))
+                        < ((self.class.shape().0 + SPAWNER_RADIUS) * 1.5).powf(2.)
+            });
+        if !at_spawner {
+            return BehaviorResult::Fail;
+        }
+        self.ammo = (self.ammo + AMMO_RELOAD_RATE).min(max_ammo);
+        if max_ammo <= self.ammo {
+            BehaviorResult::Success
+        } else {
+            BehaviorResult::Running
+        }
+    }
+
+    /// Find the nearest friendly repair point, i.e. a spawner or an agent with `REPAIR_ROLE`, and
+    /// return its position and the distance within which it repairs this agent.
+    fn nearest_repair_point(&self, entities: &[RefCell<Entity>]) -> Option<([f64; 2], f64)> {
+        let self_pos = Vector2::from(self.pos);
+        entities
+            .iter()
+            .filter_map(|a| a.try_borrow().ok())
+            .filter(|a| {
+                a.get_team() == self.team
+                    && a.get_active()
+                    && a.get_id() != self.id
+                    && (!a.is_agent() || a.get_role() == Some(REPAIR_ROLE))
+            })
+            .map(|a| {
+                let radius = a
+                    .get_class()
+                    .map_or(SPAWNER_RADIUS, |class| class.shape().0);
+                let distance2 = Vector2::from(a.get_pos()).distance2(self_pos);
+                (distance2, a.get_id(), a.get_pos(), radius)
+            })
+            .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)))
+            .map(|(_, _, pos, radius)| (pos, (self.class.shape().0 + radius) * 1.5))
+    }
+
+    /// The position of the allied entity `id` to escort, if it is still alive.
+    fn escortee_pos(&self, id: usize, entities: &[RefCell<Entity>]) -> Option<[f64; 2]> {
+        entities.iter().find_map(|a| {
+            let a = a.try_borrow().ok()?;
+            (a.get_id() == id && a.get_team() == self.team && a.get_active()).then(|| a.get_pos())
+        })
+    }
+
+    /// The visible enemy nearest to the escortee at `ally_pos` within `ESCORT_THREAT_RATE` times
+    /// the escort radius, ties broken by id.
+    fn escort_threat(
+        &self,
+        ally_pos: [f64; 2],
+        radius: f64,
+        game: &Game,
+        entities: &[RefCell<Entity>],
+    ) -> Option<usize> {
+        let ally_pos = Vector2::from(ally_pos);
+        let threat_range = radius * ESCORT_THREAT_RATE;
+        entities
+            .iter()
+            .filter_map(|a| a.try_borrow().ok())
+            .filter(|a| {
+                a.get_team() != self.team
+                    && a.get_active()
+                    && !self.unreachables.contains(&a.get_id())
+                    && game.is_clear_fog_at(self.team, a.get_pos())
+            })
+            .map(|a| (Vector2::from(a.get_pos()).distance2(ally_pos), a.get_id()))
+            .filter(|(distance2, _)| *distance2 < threat_range.powi(2))
+            .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)))
+            .map(|(_, id)| id)
+    }
+
+    /// The position of the nearest allied entity, agents and spawners alike.
+    fn nearest_ally_pos(&self, entities: &[RefCell<Entity>]) -> Option<[f64; 2]> {
+        let self_pos = Vector2::from(self.pos);
+        entities
+            .iter()
+            .filter_map(|a| a.try_borrow().ok())
+            .filter(|a| a.get_team() == self.team && a.get_active() && a.get_id() != self.id)
+            .map(|a| {
+                (
+                    Vector2::from(a.get_pos()).distance2(self_pos),
+                    a.get_id(),
+                    a.get_pos(),
+                )
+            })
+            .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)))
+            .map(|(_, _, pos)| pos)
+    }
+
+    /// Track the movement of the `Bait` target and return whether it has committed to chasing
+    /// this agent, i.e. kept closing in for `BAIT_COMMIT_TICKS`.
+    fn update_bait(&mut self, target: usize, target_pos: [f64; 2]) -> bool {
+        let mut bait = match self.bait {
+            Some(bait) if bait.target == target => bait,
+            _ => BaitState {
+                target,
+                target_pos,
+                closing_ticks: 0,
+                retreating: false,
+            },
+        };
+        if !bait.retreating {
+            let toward = Vector2::from(self.pos) - Vector2::from(bait.target_pos);
+            let moved = Vector2::from(target_pos) - Vector2::from(bait.target_pos);
+            let closing = if toward.is_zero() {
+                0.
+            } else {
+                moved.dot(toward.normalize())
+            };
+            if BAIT_COMMIT_SPEED < closing {
+                bait.closing_ticks += 1;
+            } else {
+                bait.closing_ticks = 0;
+            }
+            bait.retreating = BAIT_COMMIT_TICKS <= bait.closing_ticks;
         }
+        bait.target_pos = target_pos;
+        self.bait = Some(bait);
+        bait.retreating
+    }
+
+    /// The position `RETREAT_STEP` away from the agent in the direction `away`, or straight behind
+    /// it if `away` is zero.
+    fn retreat_goal(&self, away: Vector2<f64>) -> [f64; 2] {
+        let away = if away.is_zero() {
+            -Vector2::new(self.orient.cos(), self.orient.sin())
+        } else {
+            away.normalize()
+        };
+        (Vector2::from(self.pos) + away * RETREAT_STEP).into()
+    }
+
+    /// Find the visible enemy spawner with the least health, ties broken by distance, and return
+    /// its id and position.
+    fn weakest_enemy_spawner(
+        &self,
+        game: &Game,
+        entities: &[RefCell<Entity>],
+    ) -> Option<(usize, [f64; 2])> {
+        entities
+            .iter()
+            .filter_map(|a| a.try_borrow().ok())
+            .filter(|a| {
+                !a.is_agent()
+                    && a.get_team() != self.team
+                    && !self.unreachables.contains(&a.get_id())
+                    && game.is_clear_fog_at(self.team, a.get_pos())
+            })
+            .map(|a| {
+                let distance = Vector2::from(a.get_pos()).distance(Vector2::from(self.pos));
+                (a.get_health(), distance, a.get_id(), a.get_pos())
+            })
+            .min_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)).then(a.2.cmp(&b.2)))
+            .map(|(_, _, id, pos)| (id, pos))
     }
 
     pub(crate) fn find_resource(&mut self, resources: &[Resource]) -> bool {
@@ -468,7 +1221,7 @@ impl Agent {
         }
         for resource in resources {
             if Vector2::from(resource.pos).distance2(Vector2::from(self.pos))
-                < (AGENT_HALFLENGTH * 2.).powf(2.)
+                < (self.class.shape().0 * 2.).powf(2.)
                 && 0 < resource.amount
             {
                 let moved = resource
@@ -495,7 +1248,7 @@ impl Agent {
                 continue;
             }
             if Vector2::from(spawner.pos).distance2(Vector2::from(self.pos))
-                < ((AGENT_HALFLENGTH + SPAWNER_RADIUS) * 1.5).powf(2.)
+                < ((self.class.shape().0 + SPAWNER_RADIUS) * 1.5).powf(2.)
                 && spawner.resource < SPAWNER_MAX_RESOURCE
             {
                 let moved = self
@@ -518,13 +1271,12 @@ impl Agent {
     pub(crate) fn find_fog(&mut self, game: &mut Game) -> bool {
         let team = self.team;
         let qtree = &game.qtree;
-        let found_path = self.find_path_many(qtree, &game.path_find_profiler, |pos| {
-            if game.is_passable_at(pos) && game.is_fog_older_than(team, pos, FOG_MAX_AGE) {
-                PathFindResponse::Goal
-            } else {
-                PathFindResponse::Continue
-            }
-        });
+        let profiler = game
+            .params
+            .profiling_enabled
+            .then_some(&game.path_find_profiler);
+        let found_path =
+            self.find_path_many(qtree, profiler, |pos| frontier_response(game, team, pos));
         let Ok(path) = found_path else { return false };
         match path.first().copied() {
             Some(node) => {
@@ -536,17 +1288,105 @@ impl Agent {
         }
     }
 
-    pub(crate) fn shoot_bullet(&mut self, bullets: &mut Vec<Bullet>, target_pos: [f64; 2]) -> bool {
-        if 0. < self.cooldown {
+    /// Find the nearest reachable exploration frontier, i.e. a passable position with fog older
+    /// than `FOG_MAX_AGE`, without committing a path to it.
+    fn frontier_pos(&self, game: &Game) -> Option<[f64; 2]> {
+        let team = self.team;
+        let (found_path, _) = game.qtree.path_find_many(
+            |id| id == self.id,
+            self.pos,
+            |pos| frontier_response(game, team, pos),
+            1.,
+        );
+        found_path.ok()?.first().map(|node| node.pos)
+    }
+
+    /// Find the cell with the best terrain among those reachable within `TERRAIN_SEEK_RADIUS`
+    /// cells on the path finding board, judged by the distance to the nearest wall.
+    ///
+    /// With `open`, it is the cell farthest from walls. Otherwise it is the narrowest point of a
+    /// passage, which is the cell with the least distance among the ridges of the distance field,
+    /// because a cell just next to a wall is not a chokepoint. Ties are broken by the distance
+    /// from the agent.
+    fn terrain_pos(&self, open: bool, game: &Game) -> Option<[f64; 2]> {
+        let field = game.distance_field();
+        let shape = game.shape();
+        let board = game.path_board();
+        let field_at = |[x, y]: [i32; 2]| {
+            if is_passable_at_i(&game.board, shape, [x, y]) {
+                field[x as usize + y as usize * shape.0]
+            } else {
+                0.
+            }
+        };
+        // A cell is on a ridge if it is farther from walls than the cells on both sides along
+        // either axis. A passage with even width has a ridge of 2 cells with the same distance.
+        let is_ridge = |[x, y]: [i32; 2]| {
+            let value = field_at([x, y]);
+            [[1, 0], [0, 1]].iter().any(|&[dx, dy]| {
+                let at = |i: i32| field_at([x + dx * i, y + dy * i]);
+                let (prev, next) = (at(-1), at(1));
+                prev < value && next < value
+                    || prev < value && next == value && at(2) < value
+                    || prev == value && at(-2) < value && next < value
+            })
+        };
+
+        // Flood fill from the agent to find reachable cells
+        let center = [self.pos[0] as i32, self.pos[1] as i32];
+        if !is_passable_at_i(board, shape, center) {
+            return None;
+        }
+        let mut visited = HashSet::from([center]);
+        let mut queue = VecDeque::from([center]);
+        let mut best: Option<([i32; 2], f32, i32)> = None;
+        while let Some(cell) = queue.pop_front() {
+            let distance2 = (cell[0] - center[0]).pow(2) + (cell[1] - center[1]).pow(2);
+            if open || is_ridge(cell) {
+                let value = if open {
+                    -field_at(cell)
+                } else {
+                    field_at(cell)
+                };
+                if !matches!(best, Some((_, best_value, best_distance2))
+                    if (best_value, best_distance2) <= (value, distance2))
+                {
+                    best = Some((cell, value, distance2));
+                }
+            }
+            for [dx, dy] in [[-1, 0], [1, 0], [0, -1], [0, 1]] {
+                let next = [cell[0] + dx, cell[1] + dy];
+                if TERRAIN_SEEK_RADIUS.pow(2)
+                    < (next[0] - center[0]).pow(2) + (next[1] - center[1]).pow(2)
+                    || !is_passable_at_i(board, shape, next)
+                    || !visited.insert(next)
+                {
+                    continue;
+                }
+                queue.push_back(next);
+            }
+        }
+        best.map(|(cell, _, _)| [cell[0] as f64 + 0.5, cell[1] as f64 + 0.5])
+    }
+
+    pub(crate) fn shoot_bullet(
+        &mut self,
+        bullets: &mut Vec<Bullet>,
+        target_pos: [f64; 2],
+        limited_ammo: bool,
+    ) -> bool {
+        if 0. < self.cooldown || limited_ammo && self.ammo == 0 {
             return false;
         }
         let dir = Vector2::new(self.orient.cos(), self.orient.sin());
         if dir.dot((Vector2::from(target_pos) - Vector2::from(self.pos)).normalize()) < 0.5 {
             return false;
         }
+        let angle = self.orient + (self.rng.next() * 2. - 1.) * self.class.spread();
+        let bullet_dir = Vector2::new(angle.cos(), angle.sin());
         let bullet = Bullet::new(
             self.pos,
-            (dir * self.class.bullet_speed()).into(),
+            (bullet_dir * self.class.bullet_speed()).into(),
             self.team,
             self.class.damage(),
             self.class,
@@ -555,23 +1395,31 @@ impl Agent {
         bullets.push(bullet);
 
         self.cooldown += self.class.cooldown();
+        if limited_ammo {
+            self.ammo -= 1;
+        }
         true
     }
 
+    /// The pair of the collision layer and mask of this agent.
+    pub(crate) fn collision_layers(&self) -> (u32, u32) {
+        (self.layer, self.mask)
+    }
+
+    /// Displacement per tick in the last drive, derived from `speed` and `orient`.
+    pub fn velocity(&self) -> [f64; 2] {
+        let max_speed = self.class.speed();
+        let speed = self.speed.min(max_speed).max(-max_speed);
+        [speed * self.orient.cos(), speed * self.orient.sin()]
+    }
+
     pub fn get_avoidance_state(&self, (drive, steer): (f64, f64)) -> Vector2<f64> {
         let desired_angle = wrap_angle(self.orient + steer);
         drive * Vector2::new(desired_angle.cos(), desired_angle.sin()) + Vector2::from(self.pos)
     }
 
-    pub(super) fn get_avoidance_agent_state(&self, (drive, steer): (f64, f64)) -> AgentState {
-        let desired_angle = wrap_angle(self.orient + steer);
-        let pos = drive * Vector2::new(desired_angle.cos(), desired_angle.sin())
-            + Vector2::from(self.pos);
-        AgentState {
-            x: pos.x,
-            y: pos.y,
-            heading: desired_angle,
-        }
+    pub(super) fn get_avoidance_agent_state(&self, steer: (f64, f64)) -> AgentState {
+        self.to_state().avoidance_step(steer)
     }
 
     fn do_simple_avoidance(
@@ -604,28 +1452,31 @@ impl Agent {
         entities: &[RefCell<Entity>],
         cmd: &AvoidanceCommand,
     ) -> Box<dyn std::any::Any> {
-        static TIME_WINDOW: Lazy<Mutex<VecDeque<f64>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
-        static AVG_COUNT: AtomicUsize = AtomicUsize::new(0);
         self.goal = Some(avoidance::AgentState::new(
             cmd.goal[0],
             cmd.goal[1],
             self.orient,
         ));
+        if !game
+            .avoidance_quota
+            .try_acquire(self.id, game.params.max_avoidance_searches)
+        {
+            // Keep following the path found so far until a later tick runs the search
+            let res = self
+                .search_state
+                .as_ref()
+                .is_some_and(|ss| ss.found_path.is_some());
+            return Box::new(res);
+        }
         let (res, time) = measure_time(|| {
             self.avoidance_search(game, entities, cmd.back, false, game.params.avoidance_mode)
         });
         // println!("Avoidance goal set to {:?}, returns {res:?}", self.goal);
-        if let Ok(mut time_window) = TIME_WINDOW.lock() {
-            time_window.push_back(time);
-            while 100 < time_window.len() {
-                time_window.pop_front();
-            }
-            let count = AVG_COUNT.fetch_add(1, Ordering::Relaxed);
-            if count % 100 == 0 && !time_window.is_empty() {
-                let avg = time_window.iter().sum::<f64>() / time_window.len() as f64;
-                println!("Avoidance search ({count}): {avg:.06}s");
-            }
+        if let Some(stats) = &mut self.avoidance_stats {
+            stats.last_time = time;
         }
+        game.avoidance_load
+            .record(time, game.params.avoidance_budget);
         Box::new(res)
     }
 
@@ -635,14 +1486,19 @@ impl Agent {
         entities: &[RefCell<Entity>],
         bullets: &mut Vec<Bullet>,
     ) {
+        self.track_target(game, entities);
+
         if let Some(mut tree) = self.behavior_tree.take() {
             enum Command {
                 Drive(DriveCommand),
                 MoveTo(MoveToCommand),
+                MoveBackTo(MoveToCommand),
                 FollowPath(FollowPathCommand),
                 FaceToTarget(FaceToTargetCommand),
             }
             let mut command = None;
+            #[cfg(feature = "training")]
+            let mut shoot = false;
             let mut ctx = Context::new(std::mem::take(&mut self.blackboard));
             ctx.set("target", self.target);
             ctx.set("has_path", !self.path.is_empty());
@@ -651,6 +1507,9 @@ impl Agent {
                     return Some(Box::new(self.id) as Box<dyn std::any::Any>);
                 } else if let Some(s) = f.downcast_ref::<PrintCommand>() {
                     self.log(s.0.clone());
+                } else if let Some(com) = f.downcast_ref::<RandomizeCommand>() {
+                    let value = com.min + self.rng.nexti() as usize % (com.max - com.min);
+                    return Some(Box::new(value));
                 } else if f.downcast_ref::<GetResource>().is_some() {
                     return Some(Box::new(self.resource));
                 } else if let Some(com) = f.downcast_ref::<DriveCommand>() {
@@ -669,12 +1528,21 @@ impl Agent {
                     return Some(Box::new(self.target));
                 } else if f.downcast_ref::<FindEnemyCommand>().is_some() {
                     self.find_enemy(game, entities)
+                } else if let Some(com) = f.downcast_ref::<SelectTargetCommand>() {
+                    return Some(Box::new(self.select_target(com.0, game, entities)));
                 } else if f.downcast_ref::<FindSpawner>().is_some() {
                     self.find_spawner(entities)
                 } else if f.downcast_ref::<FindResource>().is_some() {
                     return Some(Box::new(self.find_resource(&game.fog[self.team].resources)));
                 } else if f.downcast_ref::<FindFog>().is_some() {
                     return Some(Box::new(self.find_fog(game)));
+                } else if f.downcast_ref::<FrontierPosCommand>().is_some() {
+                    return Some(Box::new(self.frontier_pos(game)));
+                } else if let Some(com) = f.downcast_ref::<SetRoleCommand>() {
+                    self.role = (!com.0.is_empty()).then(|| com.0.clone());
+                    return Some(Box::new(()));
+                } else if f.downcast_ref::<AbandonUnreachableCommand>().is_some() {
+                    return Some(Box::new(self.abandon_unreachable_target()));
                 } else if f.downcast_ref::<ClearTarget>().is_some() {
                     let had_target = self.target.is_some();
                     self.target = None;
@@ -683,16 +1551,206 @@ impl Agent {
                     return Some(Box::new(self.collect_resource(&mut game.resources)));
                 } else if f.downcast_ref::<DepositResource>().is_some() {
                     return Some(Box::new(self.deposit_resource(&entities)));
+                } else if f.downcast_ref::<HasAmmoNode>().is_some() {
+                    return Some(Box::new(0 < self.ammo));
+                } else if f.downcast_ref::<ReloadNode>().is_some() {
+                    return Some(Box::new(self.reload(entities)));
                 } else if f.downcast_ref::<IsResourceFull>().is_some() {
                     return Some(Box::new(AGENT_MAX_RESOURCE <= self.resource));
                 } else if f.downcast_ref::<IsSpawnerResourceFull>().is_some() {
                     return Some(Box::new(self.is_spawner_resource_full(&entities)));
+                } else if f.downcast_ref::<PathDeadlockCommand>().is_some() {
+                    return Some(Box::new(self.blocked_motions));
+                } else if f.downcast_ref::<IsStuckCommand>().is_some() {
+                    return Some(Box::new(self.is_stuck(game)));
+                } else if let Some(com) = f.downcast_ref::<PathStaleCommand>() {
+                    return Some(Box::new(self.is_path_stale(
+                        com.target,
+                        com.threshold,
+                        game,
+                    )));
+                } else if let Some(com) = f.downcast_ref::<PathReadyCommand>() {
+                    return Some(Box::new(self.is_path_ready(com.0)));
                 } else if f.downcast_ref::<HasPathNode>().is_some() {
                     return Some(Box::new(!self.path.is_empty()));
                 } else if f.downcast_ref::<ClearPathNode>().is_some() {
                     let ret = !self.path.is_empty();
                     self.path.clear();
                     return Some(Box::new(ret));
+                } else if let Some(com) = f.downcast_ref::<FlankPosCommand>() {
+                    let pos = self.flank_pos(com.distance, com.behind, game, entities)?;
+                    return Some(Box::new(pos));
+                } else if let Some(com) = f.downcast_ref::<RegroupCommand>() {
+                    let goal = self.regroup_pos(com.ratio, com.radius, game, entities)?;
+                    if Vector2::from(goal).distance(Vector2::from(self.pos)) < REGROUP_DISTANCE {
+                        return None;
+                    }
+                    command = Some(if self.path_toward(goal, REGROUP_DISTANCE, game) {
+                        Command::FollowPath(FollowPathCommand)
+                    } else {
+                        Command::MoveTo(MoveToCommand(goal))
+                    });
+                    return Some(Box::new(goal));
+                } else if f.downcast_ref::<DodgeBulletCommand>().is_some() {
+                    let goal = self.dodge_pos(bullets, game)?;
+                    command = Some(Command::MoveTo(MoveToCommand(goal)));
+                    return Some(Box::new(goal));
+                } else if let Some(com) = f.downcast_ref::<SeparateCommand>() {
+                    let goal = self.separation_pos(com.0, game, entities)?;
+                    command = Some(Command::MoveTo(MoveToCommand(goal)));
+                    return Some(Box::new(goal));
+                } else if f.downcast_ref::<InterceptCommand>().is_some() {
+                    let goal = self.intercept_pos(game, entities)?;
+                    command = Some(if self.path_toward(goal, INTERCEPT_REPATH_DISTANCE, game) {
+                        Command::FollowPath(FollowPathCommand)
+                    } else {
+                        Command::MoveTo(MoveToCommand(goal))
+                    });
+                    return Some(Box::new(goal));
+                } else if let Some(com) = f.downcast_ref::<WaitForSquadCommand>() {
+                    return Some(Box::new(self.squad_ready(
+                        com.fraction,
+                        com.radius,
+                        game,
+                        entities,
+                    )));
+                } else if f.downcast_ref::<SeekRepairCommand>().is_some() {
+                    if self.class.health() <= self.health {
+                        return Some(Box::new(BehaviorResult::Fail));
+                    }
+                    let Some((goal, reach)) = self.nearest_repair_point(entities) else {
+                        return Some(Box::new(BehaviorResult::Fail));
+                    };
+                    // The repair itself happens in `repair` while the agent stays in reach
+                    if reach.powi(2) <= Vector2::from(goal).distance2(Vector2::from(self.pos)) {
+                        command = Some(if self.path_toward(goal, REPAIR_REPATH_DISTANCE, game) {
+                            Command::FollowPath(FollowPathCommand)
+                        } else {
+                            Command::MoveTo(MoveToCommand(goal))
+                        });
+                    }
+                    return Some(Box::new(BehaviorResult::Running));
+                } else if f.downcast_ref::<GoToLastKnownTargetCommand>().is_some() {
+                    let Some((id, goal)) = self.last_known_target else {
+                        return Some(Box::new(BehaviorResult::Fail));
+                    };
+                    // The position of a former target is no clue to the current one
+                    if !matches!(self.target, Some(AgentTarget::Entity(target)) if target == id) {
+                        self.last_known_target = None;
+                        return Some(Box::new(BehaviorResult::Fail));
+                    }
+                    if self.track_target(game, entities) {
+                        return Some(Box::new(BehaviorResult::Fail));
+                    }
+                    let distance = Vector2::from(goal).distance(Vector2::from(self.pos));
+                    if distance < LAST_KNOWN_ARRIVE_DISTANCE {
+                        self.last_known_target = None;
+                        return Some(Box::new(BehaviorResult::Success));
+                    }
+                    command = Some(
+                        if self.path_toward(goal, LAST_KNOWN_ARRIVE_DISTANCE, game) {
+                            Command::FollowPath(FollowPathCommand)
+                        } else {
+                            Command::MoveTo(MoveToCommand(goal))
+                        },
+                    );
+                    return Some(Box::new(BehaviorResult::Running));
+                } else if let Some(com) = f.downcast_ref::<BaitCommand>() {
+                    let (Some(AgentTarget::Entity(target)), Some(target_pos)) =
+                        (self.target, self.get_target_pos_in(entities))
+                    else {
+                        self.bait = None;
+                        return Some(Box::new(BehaviorResult::Fail));
+                    };
+                    if self.update_bait(target, target_pos) {
+                        // Lure the target back toward the allies, shooting at it on the way
+                        let ally_pos = self.nearest_ally_pos(entities);
+                        if let Some(ally_pos) = ally_pos {
+                            let distance =
+                                Vector2::from(ally_pos).distance(Vector2::from(self.pos));
+                            if distance < BAIT_RETURN_DISTANCE {
+                                self.bait = None;
+                                return Some(Box::new(BehaviorResult::Success));
+                            }
+                        }
+                        let away = match ally_pos {
+                            Some(ally_pos) => Vector2::from(ally_pos) - Vector2::from(self.pos),
+                            None => Vector2::from(self.pos) - Vector2::from(target_pos),
+                        };
+                        let goal = self.retreat_goal(away);
+                        command = Some(Command::MoveBackTo(MoveToCommand(goal)));
+                        self.shoot_bullet(bullets, target_pos, game.params.limited_ammo);
+                    } else if com.distance
+                        < Vector2::from(target_pos).distance(Vector2::from(self.pos))
+                    {
+                        command = Some(Command::MoveTo(MoveToCommand(target_pos)));
+                    } else {
+                        command = Some(Command::FaceToTarget(FaceToTargetCommand(target_pos)));
+                    }
+                    return Some(Box::new(BehaviorResult::Running));
+                } else if let Some(com) = f.downcast_ref::<EscortCommand>() {
+                    let Some(ally_pos) = self.escortee_pos(com.ally, entities) else {
+                        return Some(Box::new(BehaviorResult::Fail));
+                    };
+                    if let Some(threat) = self.escort_threat(ally_pos, com.radius, game, entities) {
+                        self.target = Some(AgentTarget::Entity(threat));
+                    }
+                    let distance = Vector2::from(ally_pos).distance(Vector2::from(self.pos));
+                    if com.radius < distance {
+                        self.escort_catching_up = true;
+                    } else if distance < com.radius * ESCORT_SETTLE_RATE {
+                        self.escort_catching_up = false;
+                    }
+                    if self.escort_catching_up {
+                        let tolerance = com.radius * ESCORT_SETTLE_RATE;
+                        command = Some(if self.path_toward(ally_pos, tolerance, game) {
+                            Command::FollowPath(FollowPathCommand)
+                        } else {
+                            Command::MoveTo(MoveToCommand(ally_pos))
+                        });
+                    }
+                    return Some(Box::new(BehaviorResult::Running));
+                } else if f.downcast_ref::<AttackSpawnerCommand>().is_some() {
+                    let (id, goal) = self.weakest_enemy_spawner(game, entities)?;
+                    self.target = Some(AgentTarget::Entity(id));
+                    command = Some(if self.path_toward(goal, ARRIVE_DISTANCE, game) {
+                        Command::FollowPath(FollowPathCommand)
+                    } else {
+                        Command::MoveTo(MoveToCommand(goal))
+                    });
+                    return Some(Box::new(id));
+                } else if let Some(com) = f.downcast_ref::<GoToPositionCommand>() {
+                    command = Some(if self.path_toward(com.0, ARRIVE_DISTANCE, game) {
+                        Command::FollowPath(FollowPathCommand)
+                    } else {
+                        Command::MoveTo(MoveToCommand(com.0))
+                    });
+                } else if let Some(com) = f.downcast_ref::<NearestReachableCommand>() {
+                    let clearance = self.class.shape().0 * 1.5;
+                    let goal = game
+                        .nearest_free_cell(com.0, self.pos, clearance)
+                        .filter(|goal| self.path_toward(*goal, ARRIVE_DISTANCE, game));
+                    return Some(Box::new(goal));
+                } else if let Some(com) = f.downcast_ref::<SeekTerrainCommand>() {
+                    return Some(Box::new(self.terrain_pos(com.open, game)));
+                } else if let Some(com) = f.downcast_ref::<PerimeterCommand>() {
+                    return Some(Box::new(self.perimeter_points(
+                        com.distance,
+                        game,
+                        entities,
+                    )));
+                } else if f.downcast_ref::<TeamStatCommand>().is_some() {
+                    return Some(Box::new(self.team_stat(game, entities)));
+                } else if f.downcast_ref::<FogCoverageCommand>().is_some() {
+                    return Some(Box::new(game.fog_coverage(self.team)));
+                } else if f.downcast_ref::<InEnemyTerritoryCommand>().is_some() {
+                    return Some(Box::new(game.is_enemy_territory(self.team, self.pos)));
+                } else if f.downcast_ref::<RemainingEnemiesCommand>().is_some() {
+                    return Some(Box::new(self.remaining_enemies(entities)));
+                } else if f.downcast_ref::<TargetDistanceCommand>().is_some() {
+                    let target_pos = self.get_target_pos_in(entities)?;
+                    let distance = Vector2::from(target_pos).distance(Vector2::from(self.pos));
+                    return Some(Box::new(distance));
                 } else if f.downcast_ref::<TargetPosCommand>().is_some() {
                     match self.target {
                         Some(AgentTarget::Entity(target)) => {
@@ -715,17 +1773,60 @@ impl Agent {
                     }
                 } else if let Some(com) = f.downcast_ref::<FindPathCommand>() {
                     let found_path = self.find_path(com, game);
+                    // Only a failure to reach the target itself makes it unreachable, not one to
+                    // reach another goal on the way, e.g. a flanking position.
+                    self.path_failed_target = match self.target {
+                        Some(AgentTarget::Entity(id))
+                            if found_path.is_err()
+                                && self.get_target_pos_in(entities) == Some(com.target) =>
+                        {
+                            Some(id)
+                        }
+                        _ => None,
+                    };
                     return Some(Box::new(found_path));
                 } else if let Some(cmd) = f.downcast_ref::<FollowPathCommand>() {
                     command = Some(Command::FollowPath(*cmd));
                     return MotionCommandResult::as_follow_path(&self.last_motion_result);
                 } else if f.downcast_ref::<ShootCommand>().is_some() {
+                    #[cfg(feature = "training")]
+                    {
+                        shoot = true;
+                    }
                     let forward = Vector2::new(self.orient.cos(), self.orient.sin());
-                    self.shoot_bullet(bullets, (Vector2::from(self.pos) + forward).into());
+                    self.shoot_bullet(
+                        bullets,
+                        (Vector2::from(self.pos) + forward).into(),
+                        game.params.limited_ammo,
+                    );
+                } else if f.downcast_ref::<FightingRetreatCommand>().is_some() {
+                    let target_pos = self.get_target_pos_in(entities)?;
+                    let goal =
+                        self.retreat_goal(Vector2::from(self.pos) - Vector2::from(target_pos));
+                    command = Some(Command::MoveBackTo(MoveToCommand(goal)));
+                    let shot = self.shoot_bullet(bullets, target_pos, game.params.limited_ammo);
+                    #[cfg(feature = "training")]
+                    {
+                        shoot |= shot;
+                    }
+                    return Some(Box::new(shot));
+                } else if let Some(com) = f.downcast_ref::<TurretCommand>() {
+                    self.speed = 0.;
+                    self.find_enemy(game, entities);
+                    let target_pos = self.get_target_pos_in(entities).filter(|pos| {
+                        Vector2::from(*pos).distance(Vector2::from(self.pos)) <= com.range
+                    })?;
+                    command = Some(Command::FaceToTarget(FaceToTargetCommand(target_pos)));
+                    return Some(Box::new(self.shoot_bullet(
+                        bullets,
+                        target_pos,
+                        game.params.limited_ammo,
+                    )));
                 } else if let Some(goal) = f.downcast_ref::<AvoidanceCommand>() {
                     return Some(self.do_avoidance(game, entities, goal));
                 } else if let Some(cmd) = f.downcast_ref::<SimpleAvoidanceCommand>() {
-                    let routes = self.plan_simple_avoidance(cmd.0, entities);
+                    let routes =
+                        self.plan_simple_avoidance(cmd.0, game.params.lookahead_depth, entities);
                     self.avoidance_plan = Some(routes);
                     return Some(Box::new(true));
                 } else if f.downcast_ref::<ClearAvoidanceCommand>().is_some() {
@@ -745,6 +1846,14 @@ impl Agent {
                         &mut game.triangle_profiler.borrow_mut(),
                     ));
                     return Some(ret);
+                } else if f.downcast_ref::<OrientAlongPathCommand>().is_some() {
+                    let next = self.path.last()?;
+                    command = Some(Command::FaceToTarget(FaceToTargetCommand(next.pos)));
+                    let res = match self.last_motion_result {
+                        Some(MotionCommandResult::FaceToTarget(res)) => Some(res),
+                        _ => None,
+                    };
+                    return Some(Box::new(res));
                 } else if let Some(com) = f.downcast_ref::<FaceToTargetCommand>() {
                     command = Some(Command::FaceToTarget(*com));
                     return MotionCommandResult::as_face_to_target(&self.last_motion_result);
@@ -759,6 +1868,27 @@ impl Agent {
             self.behavior_tree = Some(tree);
             self.blackboard = ctx.take_blackboard();
 
+            #[cfg(feature = "training")]
+            if game.training {
+                let action = match command {
+                    Some(Command::Drive(com)) => training::TrainingAction::Drive(com.0),
+                    Some(Command::MoveTo(com)) => training::TrainingAction::MoveTo(com.0),
+                    Some(Command::MoveBackTo(com)) => training::TrainingAction::MoveBackTo(com.0),
+                    Some(Command::FollowPath(_)) => training::TrainingAction::FollowPath,
+                    Some(Command::FaceToTarget(com)) => {
+                        training::TrainingAction::FaceToTarget(com.0)
+                    }
+                    None => training::TrainingAction::Idle,
+                };
+                let observation = self.observe(game, entities);
+                self.training_samples.push(training::TrainingSample {
+                    tick: game.global_time,
+                    observation,
+                    action,
+                    shoot,
+                });
+            }
+
             if command.is_some() {
                 self.last_state = Some(self.to_state());
             } else {
@@ -773,10 +1903,17 @@ impl Agent {
                 }
                 Some(Command::MoveTo(com)) => {
                     let res = self.move_to(game, com.0, false, entities);
+                    self.count_blocked_motion(res);
+                    self.last_motion_result = Some(MotionCommandResult::MoveTo(res));
+                }
+                Some(Command::MoveBackTo(com)) => {
+                    let res = self.move_to(game, com.0, true, entities);
+                    self.count_blocked_motion(res);
                     self.last_motion_result = Some(MotionCommandResult::MoveTo(res));
                 }
                 Some(Command::FollowPath(_com)) => {
                     let res = self.follow_path(game, entities);
+                    self.count_blocked_motion(res);
                     self.last_motion_result = Some(MotionCommandResult::FollowPath(res));
                 }
                 Some(Command::FaceToTarget(com)) => {
@@ -827,6 +1964,16 @@ impl Agent {
         self.cooldown = (self.cooldown - 1.).max(0.);
     }
 
+    /// Other motions, like reversing to get unstuck, leave the count as is, so that
+    /// a deadlock is detected across attempts to recover from it.
+    fn count_blocked_motion(&mut self, res: MotionResult) {
+        if matches!(res, MotionResult::Blocked) {
+            self.blocked_motions += 1;
+        } else {
+            self.blocked_motions = 0;
+        }
+    }
+
     fn log(&mut self, msg: String) {
         self.log_buffer.push_back(msg);
         while MAX_LOG_ENTRIES < self.log_buffer.len() {
@@ -883,11 +2030,9 @@ impl Agent {
     }
 
     fn is_position_visible(&self, target: [f64; 2], game: &Game, _profiler: &mut Profiler) -> bool {
-        let board = &game.board;
         let shape = (game.xs, game.ys);
 
-        let self_pos = self.pos;
-        let self_vec = Vector2::from(self_pos);
+        let self_vec = Vector2::from(self.pos);
         let target_vec = Vector2::from(target);
 
         let distance = self_vec.distance(target_vec);
@@ -900,13 +2045,6 @@ impl Agent {
         let Some(target_veci) = target_vec.cast::<i32>() else {
             return false;
         };
-        let delta = self_veci - target_veci;
-        let horizontal = delta.y.abs() < delta.x.abs();
-        let check_shape = if horizontal {
-            [Vector2::new(0, -1), Vector2::zero(), Vector2::new(0, 1)]
-        } else {
-            [Vector2::new(-1, 0), Vector2::zero(), Vector2::new(1, 0)]
-        };
 
         let mut raycast_board = if game.enable_raycast_board {
             Some(game.raycast_board.borrow_mut())
@@ -914,19 +2052,140 @@ impl Agent {
             None
         };
 
-        !interpolation::interpolate_i(self_veci, target_veci, |point| {
-            let point = Vector2::from(point);
-            check_shape.iter().any(|check_pix| {
-                let pix = point + check_pix;
-                if let Some(raycast_board) = &mut raycast_board {
-                    raycast_board[pix.x as usize + pix.y as usize * shape.0] += 1;
-                }
-                !is_passable_at_i(board, shape, pix)
-            })
+        cast_sight_ray(&game.board, shape, self_veci, target_veci, |pix| {
+            if let Some(raycast_board) = &mut raycast_board {
+                raycast_board[pix.x as usize + pix.y as usize * shape.0] += 1;
+            }
         })
+        .is_none()
+    }
+
+    /// Recompute the lines of sight to the current target and all enemies in visible distance.
+    /// It is expensive, so it is intended only for debug visualization of a single agent.
+    pub(crate) fn sight_lines(&self, game: &Game) -> Vec<SightLine> {
+        let self_vec = Vector2::from(self.pos);
+        let target_pos = self.get_target_pos(game);
+        let enemies = game.entities.iter().filter_map(|entity| {
+            let entity = entity.try_borrow().ok()?;
+            if entity.get_team() == self.team {
+                return None;
+            }
+            let pos = entity.get_pos();
+            if AGENT_VISIBLE_DISTANCE < self_vec.distance(Vector2::from(pos)) {
+                return None;
+            }
+            Some(pos)
+        });
+        target_pos
+            .into_iter()
+            .chain(enemies)
+            .filter_map(|target| {
+                let self_veci = self_vec.cast::<i32>()?;
+                let target_veci = Vector2::from(target).cast::<i32>()?;
+                let hit = cast_sight_ray(
+                    &game.board,
+                    (game.xs, game.ys),
+                    self_veci,
+                    target_veci,
+                    |_| (),
+                );
+                Some(SightLine {
+                    from: self.pos,
+                    to: target,
+                    hit: hit.map(|pix| [pix[0] as f64 + 0.5, pix[1] as f64 + 0.5]),
+                })
+            })
+            .collect()
     }
 }
 
+#[derive(Clone, Copy, Debug)]
+struct TargetCandidate {
+    id: usize,
+    distance: f64,
+    health: u32,
+    class: Option<AgentClass>,
+}
+
+/// Pick the best candidate according to the policy.
+fn select_best_target(
+    policy: TargetPolicy,
+    candidates: impl Iterator<Item = TargetCandidate>,
+) -> Option<TargetCandidate> {
+    // Ties are broken by the id, so that the choice does not depend on the order of candidates
+    let is_better = |cur: &TargetCandidate, acc: &TargetCandidate| match policy {
+        TargetPolicy::Nearest => (cur.distance, cur.id) < (acc.distance, acc.id),
+        TargetPolicy::LowestHealth => {
+            (cur.health, cur.distance, cur.id) < (acc.health, acc.distance, acc.id)
+        }
+        TargetPolicy::FightersFirst => {
+            let rank = |c: &TargetCandidate| (c.class != Some(AgentClass::Fighter)) as u8;
+            (rank(cur), cur.distance, cur.id) < (rank(acc), acc.distance, acc.id)
+        }
+    };
+    candidates.fold(None, |acc: Option<TargetCandidate>, cur| {
+        if let Some(acc) = acc {
+            if is_better(&cur, &acc) {
+                Some(cur)
+            } else {
+                Some(acc)
+            }
+        } else {
+            Some(cur)
+        }
+    })
+}
+
+/// A line of sight from an agent, used for debug visualization.
+#[derive(Clone, Copy, Debug)]
+pub struct SightLine {
+    pub from: [f64; 2],
+    pub to: [f64; 2],
+    /// The center of the first pixel that blocked the sight, if any
+    pub hit: Option<[f64; 2]>,
+}
+
+fn frontier_response(game: &Game, team: usize, pos: [f64; 2]) -> PathFindResponse {
+    if game.is_passable_at(pos) && game.is_fog_older_than(team, pos, FOG_MAX_AGE) {
+        PathFindResponse::Goal
+    } else {
+        PathFindResponse::Continue
+    }
+}
+
+/// Cast a ray with 3 pixels width on the board and return the first blocking pixel if any.
+/// `visit` is called for each checked pixel.
+fn cast_sight_ray(
+    board: &[Terrain],
+    shape: (usize, usize),
+    from: Vector2<i32>,
+    to: Vector2<i32>,
+    mut visit: impl FnMut(Vector2<i32>),
+) -> Option<[i32; 2]> {
+    let delta = from - to;
+    let horizontal = delta.y.abs() < delta.x.abs();
+    let check_shape = if horizontal {
+        [Vector2::new(0, -1), Vector2::zero(), Vector2::new(0, 1)]
+    } else {
+        [Vector2::new(-1, 0), Vector2::zero(), Vector2::new(1, 0)]
+    };
+
+    let mut hit = None;
+    interpolation::interpolate_i(from, to, |point| {
+        check_shape.iter().any(|check_pix| {
+            let pix = point + check_pix;
+            visit(pix);
+            if !is_passable_at_i(board, shape, pix) {
+                hit = Some(pix.into());
+                true
+            } else {
+                false
+            }
+        })
+    });
+    hit
+}
+
 /// Wrap the angle value in [-pi, pi)
 pub(crate) fn wrap_angle(x: f64) -> f64 {
     use std::f64::consts::PI;
@@ -934,3 +2193,1747 @@ pub(crate) fn wrap_angle(x: f64) -> f64 {
     // ((x + PI) - ((x + PI) / TWOPI).floor() * TWOPI) - PI
     x - (x + PI).div_euclid(TWOPI) * TWOPI
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        game::{AgentSpawn, GameParams},
+        qtree::{qtree::PathFindError, QTreePathNode, QTreeSearcher},
+    };
+
+    const IDLE_SOURCE: &str = "tree main = Print (input <- \"idle\")";
+
+    /// A game on the default board with the fog of war disabled
+    fn game_without_fog() -> Game {
+        disable_fog(Game::new())
+    }
+
+    /// `game` with the fog of war disabled, e.g. for a board built by `Game::from_board`
+    fn disable_fog(mut game: Game) -> Game {
+        let mut params = GameParams::new();
+        params.fow = false;
+        game.set_params(&params);
+        game
+    }
+
+    /// The center of the first cell around which all the cells within `radius` are passable
+    fn open_area(game: &Game, radius: i32) -> [f64; 2] {
+        let (xs, ys) = game.shape();
+        (0..ys)
+            .flat_map(|y| (0..xs).map(move |x| [x as f64 + 0.5, y as f64 + 0.5]))
+            .find(|pos| {
+                (-radius..=radius).all(|dx| {
+                    (-radius..=radius)
+                        .all(|dy| game.is_passable_at([pos[0] + dx as f64, pos[1] + dy as f64]))
+                })
+            })
+            .unwrap()
+    }
+
+    fn spawn_agent(
+        game: &mut Game,
+        pos: [f64; 2],
+        team: usize,
+        class: AgentClass,
+        source: &str,
+    ) -> Agent {
+        Agent::new(
+            &mut game.id_gen,
+            pos,
+            0.,
+            team,
+            class,
+            Rc::new(source.to_string()),
+        )
+        .unwrap()
+    }
+
+    fn path_command(target: [f64; 2]) -> FindPathCommand {
+        FindPathCommand {
+            target,
+            ignore_obstacles: false,
+            stealth: false,
+            grid: false,
+        }
+    }
+
+    /// Put `agent` on the first free cell from which it finds a path to the free cell 10 cells to
+    /// the right, and return the command that found it.
+    fn connectable_path(game: &mut Game, agent: &mut Agent) -> FindPathCommand {
+        let (xs, ys) = game.shape();
+        let is_free = |game: &Game, pos| matches!(game.qtree.find(pos), Some((_, CellState::Free)));
+        let candidates: Vec<_> = (0..ys)
+            .flat_map(|y| (0..xs).map(move |x| [x as f64 + 0.5, y as f64 + 0.5]))
+            .filter(|pos| is_free(game, *pos) && is_free(game, [pos[0] + 10., pos[1]]))
+            .collect();
+        candidates
+            .into_iter()
+            .find_map(|pos| {
+                agent.pos = pos;
+                let com = path_command([pos[0] + 10., pos[1]]);
+                agent.find_path(&com, game).ok().map(|_| com)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_footprint_by_class() {
+        let mut id_gen = 0;
+        // Two workers lying along the x axis with a gap slightly wider than a worker between them
+        let offset = AGENT_HALFWIDTH * 2.25;
+        let entities = [-offset, offset]
+            .into_iter()
+            .map(|y| {
+                let agent = Agent::new(
+                    &mut id_gen,
+                    [0., y],
+                    0.,
+                    0,
+                    AgentClass::Worker,
+                    Rc::new(IDLE_SOURCE.to_string()),
+                )
+                .unwrap();
+                RefCell::new(Entity::Agent(agent))
+            })
+            .collect::<Vec<_>>();
+        let layer = entities[0].borrow().get_layer();
+        let mask = entities[0].borrow().get_mask();
+        let state = AgentState::new(0., 0., 0.);
+
+        assert!(!Agent::collision_check(
+            None,
+            state,
+            AgentClass::Worker,
+            (layer, mask),
+            &entities,
+            false
+        ));
+        assert!(Agent::collision_check(
+            None,
+            state,
+            AgentClass::Fighter,
+            (layer, mask),
+            &entities,
+            false
+        ));
+    }
+
+    #[test]
+    fn test_lowest_health_target() {
+        let candidates = [
+            TargetCandidate {
+                id: 0,
+                distance: 3.,
+                health: AGENT_MAX_HEALTH,
+                class: Some(AgentClass::Fighter),
+            },
+            TargetCandidate {
+                id: 1,
+                distance: 20.,
+                health: AGENT_MAX_HEALTH / 4,
+                class: Some(AgentClass::Fighter),
+            },
+        ];
+        let nearest = select_best_target(TargetPolicy::Nearest, candidates.into_iter());
+        assert_eq!(nearest.map(|c| c.id), Some(0));
+        let weakest = select_best_target(TargetPolicy::LowestHealth, candidates.into_iter());
+        assert_eq!(weakest.map(|c| c.id), Some(1));
+    }
+
+    fn boxed_in_agent(open_cell: Option<[i32; 2]>) -> (Game, Agent) {
+        let mut game = Game::new();
+        let (xs, ys) = game.shape();
+        // Find a cell whose surroundings are all passable to start with
+        let center = (1..ys as i32 - 1)
+            .flat_map(|y| (1..xs as i32 - 1).map(move |x| [x, y]))
+            .find(|&[x, y]| {
+                (-1..=1).all(|dy| {
+                    (-1..=1).all(|dx| is_passable_at_i(&game.board, (xs, ys), [x + dx, y + dy]))
+                })
+            })
+            .unwrap();
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                if (dx != 0 || dy != 0) && open_cell != Some([dx, dy]) {
+                    let cell = [center[0] + dx, center[1] + dy];
+                    game.board[cell[0] as usize + cell[1] as usize * xs] = Terrain::Wall;
+                }
+            }
+        }
+        let pos = [center[0] as f64 + 0.5, center[1] as f64 + 0.5];
+        let agent = spawn_agent(&mut game, pos, 0, AgentClass::Worker, "tree main = IsStuck");
+        (game, agent)
+    }
+
+    #[test]
+    fn test_is_stuck() {
+        let (game, agent) = boxed_in_agent(None);
+        assert!(agent.is_stuck(&game));
+
+        let (game, agent) = boxed_in_agent(Some([1, 0]));
+        assert!(!agent.is_stuck(&game));
+    }
+
+    #[test]
+    fn test_detect_path_deadlock() {
+        const SOURCE: &str = "tree main = Sequence {
+            ForceSuccess { FollowPath }
+            DetectPathDeadlock (threshold <- \"5\")
+            Print (input <- \"deadlock\")
+        }";
+        let mut game = game_without_fog();
+        let pos = open_area(&game, 8);
+        let mut agent = spawn_agent(&mut game, pos, 0, AgentClass::Worker, SOURCE);
+        agent.path = vec![QTreePathNode::new([pos[0] + 8., pos[1]], 0.5)];
+        // Another agent blocks the way right in front
+        let blocker = spawn_agent(
+            &mut game,
+            [pos[0] + 1.3, pos[1]],
+            0,
+            AgentClass::Worker,
+            IDLE_SOURCE,
+        );
+        let entities = [RefCell::new(Entity::Agent(blocker))];
+        let mut bullets = vec![];
+        let deadlocked = |agent: &Agent| agent.log_buffer().iter().any(|log| log == "deadlock");
+
+        for _ in 0..5 {
+            agent.update(&mut game, &entities, &mut bullets);
+        }
+        assert_eq!(agent.blocked_motions, 5);
+        assert!(!deadlocked(&agent));
+
+        agent.update(&mut game, &entities, &mut bullets);
+        assert!(deadlocked(&agent));
+
+        // The count resets once the way is clear
+        agent.update(&mut game, &[], &mut bullets);
+        assert_eq!(agent.blocked_motions, 0);
+    }
+
+    #[test]
+    fn test_path_stale() {
+        let mut game = game_without_fog();
+        let mut agent = spawn_agent(&mut game, [0., 0.], 0, AgentClass::Worker, IDLE_SOURCE);
+        assert!(agent.is_path_stale([0., 0.], 1., &game));
+
+        let target = connectable_path(&mut game, &mut agent).target;
+
+        assert!(!agent.is_path_stale(target, 5., &game));
+        assert!(!agent.is_path_stale([target[0], target[1] + 1.], 5., &game));
+        assert!(agent.is_path_stale([target[0], target[1] + 10.], 5., &game));
+    }
+
+    #[test]
+    fn test_path_ready() {
+        let mut game = game_without_fog();
+        let mut agent = spawn_agent(&mut game, [0., 0.], 0, AgentClass::Worker, IDLE_SOURCE);
+        let com = connectable_path(&mut game, &mut agent);
+        let (start, target) = (agent.pos, com.target);
+        assert!(agent.is_path_ready(target));
+        assert!(!agent.is_path_ready([target[0], target[1] + 5.]));
+
+        // Rebuild the qtree incrementally, during which paths may be partial
+        let mut params = game.params.clone();
+        params.qtree_build_budget = 7;
+        params.path_clearance = 0.1;
+        game.set_params(&params);
+        assert!(game.qtree.is_building());
+        agent.pos = start;
+        agent.path.clear();
+        // Search as soon as the built part of the tree allows
+        while agent.find_path(&com, &mut game).is_err() {
+            game.qtree.step_initialize(7);
+        }
+        assert!(game.qtree.is_building());
+        assert!(!agent.is_path_ready(target));
+
+        while game.qtree.is_building() {
+            game.update();
+        }
+        agent.find_path(&com, &mut game).unwrap();
+        assert!(agent.is_path_ready(target));
+    }
+
+    #[test]
+    fn test_qtree_unavailable() {
+        // A single cell board is too small to build a qtree on
+        let game = Game::from_board(vec![true], (1, 1));
+        assert!(game.qtree_error().is_some());
+        assert!(!game.qtree.is_initialized());
+        let (path, _) =
+            game.qtree
+                .path_find(|_| false, [0.5, 0.5], [0.5, 0.5], &|_| false, 0.5, None);
+        assert!(matches!(path, Err(PathFindError::Uninitialized)));
+
+        // Path finding falls back to grid A* without a qtree
+        let mut game = Game::from_board(vec![true; 16 * 16], (16, 16));
+        assert_eq!(game.qtree_error(), None);
+        game.qtree = QTreeSearcher::new();
+        let mut agent = spawn_agent(&mut game, [1.5, 1.5], 0, AgentClass::Worker, IDLE_SOURCE);
+        let path = agent
+            .find_path(&path_command([14.5, 14.5]), &mut game)
+            .unwrap();
+        assert!(1 < path.len());
+    }
+
+    #[test]
+    fn test_abandon_unreachable_target() {
+        const SHAPE: (usize, usize) = (32, 32);
+        // A wall at x = 20 cuts off the right side but for a gap too narrow for agents to pass
+        let board: Vec<_> = (0..SHAPE.0 * SHAPE.1)
+            .map(|i| i % SHAPE.0 != 20 || i / SHAPE.0 == 2)
+            .collect();
+        let mut game = disable_fog(Game::from_board(board, SHAPE));
+        let enemy = spawn_agent(&mut game, [25.5, 16.5], 1, AgentClass::Worker, IDLE_SOURCE);
+        let enemy_id = enemy.id;
+        let entities = [RefCell::new(Entity::Agent(enemy))];
+        let mut agent = spawn_agent(
+            &mut game,
+            [5.5, 16.5],
+            0,
+            AgentClass::Worker,
+            "tree main = Sequence { AbandonUnreachableTarget }",
+        );
+        agent.target = Some(AgentTarget::Entity(enemy_id));
+        let mut bullets = vec![];
+
+        // No path finding has failed yet
+        agent.update(&mut game, &entities, &mut bullets);
+        assert!(matches!(agent.target, Some(AgentTarget::Entity(id)) if id == enemy_id));
+
+        agent.behavior_tree = Some(
+            build_tree(
+                "tree main = Fallback {
+                    Sequence {
+                        TargetPos (pos -> target_pos)
+                        FindPath (target <- target_pos)
+                    }
+                    AbandonUnreachableTarget
+                }",
+            )
+            .unwrap(),
+        );
+        agent.update(&mut game, &entities, &mut bullets);
+        assert!(agent.target.is_none());
+        assert!(agent.unreachables.contains(&enemy_id));
+
+        // Failing to reach a position behind a reachable target does not give up on the target
+        let mut enemy = spawn_agent(&mut game, [17.5, 16.5], 1, AgentClass::Worker, IDLE_SOURCE);
+        enemy.orient = std::f64::consts::PI;
+        let enemy_id = enemy.id;
+        let entities = [RefCell::new(Entity::Agent(enemy))];
+        let mut agent = spawn_agent(
+            &mut game,
+            [5.5, 16.5],
+            0,
+            AgentClass::Worker,
+            "tree main = Fallback {
+                Sequence {
+                    FlankPos (behind <- \"true\", pos -> flank_pos)
+                    FindPath (target <- flank_pos)
+                }
+                AbandonUnreachableTarget
+            }",
+        );
+        agent.target = Some(AgentTarget::Entity(enemy_id));
+        agent.update(&mut game, &entities, &mut bullets);
+        assert!(agent.path.is_empty());
+        assert!(matches!(agent.target, Some(AgentTarget::Entity(id)) if id == enemy_id));
+        assert!(!agent.unreachables.contains(&enemy_id));
+    }
+
+    #[test]
+    fn test_orient_along_path() {
+        let mut game = game_without_fog();
+        let (xs, ys) = game.shape();
+        let pos = (0..ys)
+            .flat_map(|y| (0..xs).map(move |x| [x as f64 + 0.5, y as f64 + 0.5]))
+            .find(|pos| matches!(game.qtree.find(*pos), Some((_, CellState::Free))))
+            .unwrap();
+        let mut agent = spawn_agent(
+            &mut game,
+            pos,
+            0,
+            AgentClass::Worker,
+            "tree main = OrientAlongPath",
+        );
+        // The next node is right below the agent, perpendicular to its heading
+        let path_angle = std::f64::consts::PI / 2.;
+        agent.path = vec![QTreePathNode::new([pos[0], pos[1] + 10.], 1.)];
+        game.entities.push(RefCell::new(Entity::Agent(agent)));
+
+        let heading_error = |game: &Game| {
+            let orient = game.entities[0].borrow().get_orient().unwrap();
+            wrap_angle(orient - path_angle).abs()
+        };
+        let mut last_error = heading_error(&game);
+        for _ in 0..10 {
+            game.update();
+            let error = heading_error(&game);
+            assert!(error < last_error);
+            last_error = error;
+        }
+        for _ in 0..50 {
+            game.update();
+        }
+        assert!(heading_error(&game) < 1e-6);
+    }
+
+    #[test]
+    fn test_frontier_pos() {
+        let mut game = Game::new();
+        let mut params = GameParams::new();
+        params.fow = true;
+        game.set_params(&params);
+        let (xs, ys) = game.shape();
+        let pos = (0..ys)
+            .flat_map(|y| (0..xs).map(move |x| [x as f64 + 0.5, y as f64 + 0.5]))
+            .find(|pos| matches!(game.qtree.find(*pos), Some((_, CellState::Free))))
+            .unwrap();
+        let agent = spawn_agent(
+            &mut game,
+            pos,
+            0,
+            AgentClass::Worker,
+            "tree main = FrontierPos",
+        );
+
+        // Everything is freshly explored
+        let global_time = game.global_time;
+        game.fog[0].fow.fill(global_time);
+        assert_eq!(agent.frontier_pos(&game), None);
+
+        // Leave the right half of the map unexplored
+        for y in 0..ys {
+            for x in xs / 2..xs {
+                game.fog[0].fow[x + y * xs] = i32::MIN;
+            }
+        }
+        let frontier = agent.frontier_pos(&game).expect("Frontier should be found");
+        assert!(game.is_fog_older_than(0, frontier, FOG_MAX_AGE));
+        assert!((xs / 2) as f64 <= frontier[0]);
+    }
+
+    #[test]
+    fn test_sensor_cone() {
+        let mut game = game_without_fog();
+        let mut params = game.params.clone();
+        let pos = [64.5, 64.5];
+        let enemy = spawn_agent(
+            &mut game,
+            [pos[0] - 5., pos[1]],
+            1,
+            AgentClass::Worker,
+            "tree main = FindEnemy",
+        );
+        let entities = [RefCell::new(Entity::Agent(enemy))];
+
+        for (sensor_cone, detected) in [(true, false), (false, true)] {
+            params.sensor_cone = sensor_cone;
+            game.set_params(&params);
+            // The enemy is right behind the agent facing +x
+            let mut agent = spawn_agent(
+                &mut game,
+                pos,
+                0,
+                AgentClass::Fighter,
+                "tree main = FindEnemy",
+            );
+            assert_eq!(
+                agent.select_target(TargetPolicy::Nearest, &game, &entities),
+                detected,
+                "sensor_cone: {sensor_cone}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_simple_avoidance_lookahead() {
+        let mut id_gen = 0;
+        let new_agent = |id_gen: &mut usize, pos: [f64; 2]| {
+            Agent::new(
+                id_gen,
+                pos,
+                0.,
+                0,
+                AgentClass::Worker,
+                Rc::new(IDLE_SOURCE.to_string()),
+            )
+            .unwrap()
+        };
+        let mut agent = new_agent(&mut id_gen, [10., 10.]);
+        let drive = avoidance::DIST_RADIUS * 2.5;
+        // Block every second step after driving straight, forming a dead end ahead
+        // with a gap that turns sideways.
+        let center = agent.to_state().avoidance_step((drive, 0.));
+        let entities = (-2..=2)
+            .map(|i| {
+                let state = center.avoidance_step((drive, i as f64 * std::f64::consts::PI / 6.));
+                RefCell::new(Entity::Agent(new_agent(&mut id_gen, state.into())))
+            })
+            .collect::<Vec<_>>();
+
+        // A single step prefers driving straight into the dead end
+        let routes = agent.plan_simple_avoidance(false, 1, &entities);
+        assert!(routes.contains(&(drive, 0.)));
+
+        // Two steps see that only the sharp turns lead somewhere
+        let routes = agent.plan_simple_avoidance(false, 2, &entities);
+        assert!(!routes.is_empty());
+        assert!(!routes.contains(&(drive, 0.)));
+        for &route in &routes {
+            let state = agent.get_avoidance_agent_state(route);
+            assert!(avoidance::SIMPLE_AVOIDANCE_STEERS.iter().any(|&steer| {
+                !Agent::collision_check_fn(
+                    |id| id == agent.id,
+                    state.avoidance_step((drive, steer)),
+                    agent.class,
+                    agent.collision_layers(),
+                    &entities,
+                    true,
+                )
+            }));
+        }
+    }
+
+    #[test]
+    fn test_randomize_reproducible() {
+        let run = || {
+            let mut game = game_without_fog();
+            let mut params = game.params.clone();
+            params.teams[0].agent_source = Rc::new(
+                "tree main = Sequence {
+    Randomize (max <- \"1000000\", value -> value)
+    Print (input <- \"{}\", arg0 <- value)
+}"
+                .to_string(),
+            );
+            game.set_params(&params);
+            let (xs, ys) = game.shape();
+            let pos = (0..ys)
+                .flat_map(|y| (0..xs).map(move |x| [x as f64 + 0.5, y as f64 + 0.5]))
+                .find(|pos| matches!(game.qtree.find(*pos), Some((_, CellState::Free))))
+                .unwrap();
+            let spawn = AgentSpawn {
+                pos,
+                team: 0,
+                class: AgentClass::Worker,
+                static_: false,
+                source: None,
+                randomness: 0.,
+            };
+            let agent = game.try_new_agent(&spawn, &[]).unwrap();
+            game.entities.push(RefCell::new(agent));
+            for _ in 0..10 {
+                game.update();
+            }
+            let entity = game.entities[0].borrow();
+            let Entity::Agent(agent) = &*entity else {
+                unreachable!()
+            };
+            agent.log_buffer().iter().cloned().collect::<Vec<_>>()
+        };
+
+        let first = run();
+        assert_eq!(first.len(), 10);
+        assert!(first.iter().any(|value| *value != first[0]));
+        assert_eq!(first, run());
+    }
+
+    #[test]
+    fn test_randomize_range() {
+        let mut game = game_without_fog();
+        let pos = open_area(&game, 1);
+        let mut bullets = vec![];
+        let run = |game: &mut Game, bullets: &mut Vec<Bullet>, min: &str, max: &str| {
+            let source = format!(
+                "tree main = Sequence {{
+    Randomize (min <- \"{min}\", max <- \"{max}\", value -> value)
+    Print (input <- \"{{}}\", arg0 <- value)
+}}"
+            );
+            let mut agent = spawn_agent(game, pos, 0, AgentClass::Worker, &source);
+            agent.update(game, &[], bullets);
+            agent.log_buffer().iter().cloned().collect::<Vec<_>>()
+        };
+
+        // An empty range yields its bound
+        assert_eq!(run(&mut game, &mut bullets, "5", "5"), ["5"]);
+        // A reversed range is reported instead of yielding a value
+        assert_eq!(
+            run(&mut game, &mut bullets, "5", "3"),
+            ["Randomize: max (3) is less than min (5)"]
+        );
+    }
+
+    #[test]
+    fn test_assign_worker_role() {
+        let mut game = Game::new();
+        let mut params = GameParams::new();
+        params.fow = true;
+        game.set_params(&params);
+        let (xs, ys) = game.shape();
+        let mut free_positions = (0..ys / 4)
+            .flat_map(|y| (0..xs / 4).map(move |x| [x as f64 * 4. + 2., y as f64 * 4. + 2.]))
+            .filter(|pos| matches!(game.qtree.find(*pos), Some((_, CellState::Free))));
+        let mut spawner = crate::spawner::Spawner::new(
+            &mut game.id_gen,
+            free_positions.next().unwrap(),
+            0,
+            Rc::new("tree main = Sequence { }".to_string()),
+        )
+        .unwrap();
+        spawner.resource = 500;
+        let mut agent = Agent::new(
+            &mut game.id_gen,
+            free_positions.next().unwrap(),
+            0.,
+            0,
+            AgentClass::Worker,
+            Rc::new(
+                "tree main = Sequence {
+    AssignWorkerRole (role -> role)
+    Print (input <- role)
+}"
+                .to_string(),
+            ),
+        )
+        .unwrap();
+        let entities = [RefCell::new(Entity::Spawner(spawner))];
+        let mut bullets = vec![];
+
+        // Nothing explored yet
+        assert_eq!(game.fog_coverage(0), 0.);
+        agent.update(&mut game, &entities, &mut bullets);
+        assert_eq!(agent.log_buffer().back().map(|s| s.as_str()), Some("scout"));
+
+        // Everything is visible and the spawner is full
+        game.params.fow = false;
+        if let Entity::Spawner(spawner) = &mut *entities[0].borrow_mut() {
+            spawner.resource = crate::spawner::SPAWNER_MAX_RESOURCE;
+        }
+        agent.update(&mut game, &entities, &mut bullets);
+        assert_eq!(
+            agent.log_buffer().back().map(|s| s.as_str()),
+            Some("gather")
+        );
+    }
+
+    #[test]
+    fn test_in_enemy_territory() {
+        const SOURCE: &str = "tree main = Sequence {
+    InEnemyTerritory
+    Print (input <- \"enemy\")
+}";
+        let mut game = Game::new();
+        let mut params = GameParams::new();
+        params.fow = true;
+        params.fow_raycasting = false;
+        game.set_params(&params);
+        let (xs, ys) = game.shape();
+        let free_positions = (0..ys / 4)
+            .flat_map(|y| (0..xs / 4).map(move |x| [x as f64 * 4. + 2., y as f64 * 4. + 2.]))
+            .filter(|pos| matches!(game.qtree.find(*pos), Some((_, CellState::Free))))
+            .collect::<Vec<_>>();
+        let own_pos = free_positions[0];
+        let enemy_pos = *free_positions.last().unwrap();
+        let mut entities = vec![];
+        for (team, pos) in [(0, own_pos), (1, enemy_pos)] {
+            let spawner = crate::spawner::Spawner::new(
+                &mut game.id_gen,
+                pos,
+                team,
+                Rc::new("tree main = Sequence { }".to_string()),
+            )
+            .unwrap();
+            entities.push(RefCell::new(Entity::Spawner(spawner)));
+        }
+
+        // Let the spawners reveal the fog around themselves
+        let mut bullets = vec![];
+        for entity in &entities {
+            entity
+                .borrow_mut()
+                .update(&mut game, &entities, &mut bullets);
+        }
+
+        let mut results = vec![];
+        for pos in [
+            [own_pos[0] + 1., own_pos[1]],
+            [enemy_pos[0] + 1., enemy_pos[1]],
+        ] {
+            let mut agent = spawn_agent(&mut game, pos, 0, AgentClass::Worker, SOURCE);
+            agent.update(&mut game, &entities, &mut bullets);
+            results.push(agent.log_buffer().back().cloned());
+        }
+        assert_eq!(results, [None, Some("enemy".to_string())]);
+    }
+
+    #[test]
+    fn test_team_stat() {
+        let mut game = game_without_fog();
+        let (xs, ys) = game.shape();
+        let mut free_positions = (0..ys / 4)
+            .flat_map(|y| (0..xs / 4).map(move |x| [x as f64 * 4. + 2., y as f64 * 4. + 2.]))
+            .filter(|pos| matches!(game.qtree.find(*pos), Some((_, CellState::Free))))
+            .collect::<Vec<_>>()
+            .into_iter();
+
+        // Two allies besides the querying agent, a spawner on each team and three enemies
+        let composition = [(0, 2), (1, 3)];
+        for (team, count) in composition {
+            for _ in 0..count {
+                let agent = Agent::new(
+                    &mut game.id_gen,
+                    free_positions.next().unwrap(),
+                    0.,
+                    team,
+                    AgentClass::Worker,
+                    Rc::new(IDLE_SOURCE.to_string()),
+                )
+                .unwrap();
+                game.entities.push(RefCell::new(Entity::Agent(agent)));
+            }
+            let mut spawner = crate::spawner::Spawner::new(
+                &mut game.id_gen,
+                free_positions.next().unwrap(),
+                team,
+                Rc::new(IDLE_SOURCE.to_string()),
+            )
+            .unwrap();
+            spawner.resource = 40 + team as i32;
+            game.entities.push(RefCell::new(Entity::Spawner(spawner)));
+        }
+        let agent = Agent::new(
+            &mut game.id_gen,
+            free_positions.next().unwrap(),
+            0.,
+            0,
+            AgentClass::Worker,
+            Rc::new(
+                "tree main = Sequence {
+    TeamStat (agents -> agents, resource -> resource, kills -> kills)
+    Print (input <- \"{} {}\", arg0 <- agents, arg1 <- resource)
+    Print (input <- \"kills {}\", arg0 <- kills)
+}"
+                .to_string(),
+            ),
+        )
+        .unwrap();
+        let id = agent.id;
+        game.entities.push(RefCell::new(Entity::Agent(agent)));
+        // Kills of the previous matches do not count
+        game.stats[0].kills = 5;
+
+        game.update();
+
+        // Spawners accumulate resource by themselves, so compare with the value after the tick
+        let resource: i32 = game
+            .entities
+            .iter()
+            .map(|entity| entity.borrow())
+            .filter(|entity| !entity.is_agent() && entity.get_team() == 0)
+            .map(|entity| entity.resource())
+            .sum();
+        let entity = game
+            .entities
+            .iter()
+            .find(|entity| entity.borrow().get_id() == id)
+            .unwrap()
+            .borrow();
+        let Entity::Agent(agent) = &*entity else {
+            unreachable!()
+        };
+        let logs: Vec<_> = agent.log_buffer().iter().rev().take(2).collect();
+        assert_eq!(logs, ["kills 0", &format!("3 {resource}")]);
+    }
+
+    #[test]
+    fn test_remaining_enemies() {
+        let mut game = game_without_fog();
+        let (xs, ys) = game.shape();
+        let mut free_positions = (0..ys / 4)
+            .flat_map(|y| (0..xs / 4).map(move |x| [x as f64 * 4. + 2., y as f64 * 4. + 2.]))
+            .filter(|pos| matches!(game.qtree.find(*pos), Some((_, CellState::Free))))
+            .collect::<Vec<_>>()
+            .into_iter();
+
+        // One ally and a spawner on our team, three enemy agents and two enemy spawners
+        let composition = [(0, 1, 1), (1, 3, 2)];
+        for (team, agents, spawners) in composition {
+            for _ in 0..agents {
+                let agent = Agent::new(
+                    &mut game.id_gen,
+                    free_positions.next().unwrap(),
+                    0.,
+                    team,
+                    AgentClass::Worker,
+                    Rc::new(IDLE_SOURCE.to_string()),
+                )
+                .unwrap();
+                game.entities.push(RefCell::new(Entity::Agent(agent)));
+            }
+            for _ in 0..spawners {
+                let spawner = crate::spawner::Spawner::new(
+                    &mut game.id_gen,
+                    free_positions.next().unwrap(),
+                    team,
+                    Rc::new(IDLE_SOURCE.to_string()),
+                )
+                .unwrap();
+                game.entities.push(RefCell::new(Entity::Spawner(spawner)));
+            }
+        }
+        let agent = Agent::new(
+            &mut game.id_gen,
+            free_positions.next().unwrap(),
+            0.,
+            0,
+            AgentClass::Worker,
+            Rc::new(
+                "tree main = Sequence {
+    RemainingEnemies (count -> count)
+    Print (input <- \"{}\", arg0 <- count)
+}"
+                .to_string(),
+            ),
+        )
+        .unwrap();
+        let id = agent.id;
+        game.entities.push(RefCell::new(Entity::Agent(agent)));
+
+        game.update();
+
+        let entity = game
+            .entities
+            .iter()
+            .find(|entity| entity.borrow().get_id() == id)
+            .unwrap()
+            .borrow();
+        let Entity::Agent(agent) = &*entity else {
+            unreachable!()
+        };
+        assert_eq!(agent.log_buffer().back(), Some(&"5".to_string()));
+    }
+
+    #[test]
+    fn test_flank_pos() {
+        const DISTANCE: f64 = 5.;
+        let mut game = Game::new();
+        let pos = open_area(&game, 8);
+        // The target faces east
+        let target = spawn_agent(&mut game, pos, 0, AgentClass::Worker, IDLE_SOURCE);
+        let mut agent = spawn_agent(
+            &mut game,
+            [pos[0] - 6., pos[1] + 1.],
+            0,
+            AgentClass::Worker,
+            IDLE_SOURCE,
+        );
+        agent.target = Some(AgentTarget::Entity(target.id));
+        let entities = vec![RefCell::new(Entity::Agent(target))];
+
+        let flank = agent.flank_pos(DISTANCE, false, &game, &entities).unwrap();
+        assert!((flank[0] - pos[0]).abs() < 1e-6);
+        assert!(((flank[1] - pos[1]).abs() - DISTANCE).abs() < 1e-6);
+        // The agent is slightly to the south (+y), so is the chosen side
+        assert!(pos[1] < flank[1]);
+        assert!(game.is_passable_at(flank));
+
+        let behind = agent.flank_pos(DISTANCE, true, &game, &entities).unwrap();
+        assert!((behind[0] - (pos[0] - DISTANCE)).abs() < 1e-6);
+
+        // A spawner has no facing, which is still defined with the agent right on top of it
+        let spawner = crate::spawner::Spawner::new(
+            &mut game.id_gen,
+            pos,
+            1,
+            Rc::new(IDLE_SOURCE.to_string()),
+        )
+        .unwrap();
+        agent.target = Some(AgentTarget::Entity(spawner.id));
+        agent.pos = pos;
+        let entities = vec![RefCell::new(Entity::Spawner(spawner))];
+        let behind = agent.flank_pos(DISTANCE, true, &game, &entities).unwrap();
+        assert!(behind[0].is_finite() && behind[1].is_finite());
+        assert!((behind[0] - (pos[0] + DISTANCE)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_regroup_if_outnumbered() {
+        let mut game = game_without_fog();
+        let pos = open_area(&game, 8);
+        let ally_pos = [pos[0] - 5., pos[1]];
+        let mut entities = vec![RefCell::new(Entity::Agent(spawn_agent(
+            &mut game,
+            ally_pos,
+            0,
+            AgentClass::Worker,
+            IDLE_SOURCE,
+        )))];
+
+        let mut agent = spawn_agent(
+            &mut game,
+            pos,
+            0,
+            AgentClass::Worker,
+            "tree main = RegroupIfOutnumbered",
+        );
+        // One ally against no enemies does not trigger regrouping
+        assert!(agent.regroup_pos(1.5, 10., &game, &entities).is_none());
+
+        for dy in [-2., 0., 2.] {
+            let enemy = spawn_agent(
+                &mut game,
+                [pos[0] + 4., pos[1] + dy],
+                1,
+                AgentClass::Worker,
+                IDLE_SOURCE,
+            );
+            entities.push(RefCell::new(Entity::Agent(enemy)));
+        }
+        let goal = agent.regroup_pos(1.5, 10., &game, &entities).unwrap();
+        assert!(Vector2::from(goal).distance(Vector2::from(ally_pos)) < 1e-6);
+
+        let distance = |agent: &Agent| Vector2::from(agent.pos).distance(Vector2::from(ally_pos));
+        let start_distance = distance(&agent);
+        let mut bullets = vec![];
+        // It takes a while to turn around toward the ally
+        for _ in 0..100 {
+            agent.update(&mut game, &entities, &mut bullets);
+        }
+        assert!(distance(&agent) < start_distance - 1.);
+    }
+
+    #[test]
+    fn test_turret() {
+        let mut game = game_without_fog();
+        let pos = open_area(&game, 8);
+        let mut turret = spawn_agent(
+            &mut game,
+            pos,
+            0,
+            AgentClass::Fighter,
+            "tree main = Turret (range <- \"10\")",
+        );
+        let enemy = spawn_agent(
+            &mut game,
+            [pos[0] - 30., pos[1]],
+            1,
+            AgentClass::Worker,
+            IDLE_SOURCE,
+        );
+        let entities = [RefCell::new(Entity::Agent(enemy))];
+        let mut bullets = vec![];
+
+        for _ in 0..20 {
+            turret.update(&mut game, &entities, &mut bullets);
+        }
+        assert!(bullets.is_empty(), "The enemy is out of range");
+        assert_eq!(turret.pos, pos);
+
+        // The enemy comes into range behind the turret
+        if let Entity::Agent(enemy) = &mut *entities[0].borrow_mut() {
+            enemy.pos = [pos[0] - 6., pos[1]];
+        }
+        for _ in 0..100 {
+            turret.update(&mut game, &entities, &mut bullets);
+            assert_eq!(turret.pos, pos);
+        }
+        assert!(!bullets.is_empty(), "The turret should engage the enemy");
+    }
+
+    #[test]
+    fn test_attack_spawner() {
+        let mut game = game_without_fog();
+        let pos = open_area(&game, 8);
+        let mut attacker = spawn_agent(
+            &mut game,
+            pos,
+            0,
+            AgentClass::Fighter,
+            "tree main = AttackSpawner",
+        );
+        let new_spawner = |game: &mut Game, pos: [f64; 2], health: u32| {
+            let mut spawner = crate::spawner::Spawner::new(
+                &mut game.id_gen,
+                pos,
+                1,
+                Rc::new(IDLE_SOURCE.to_string()),
+            )
+            .unwrap();
+            spawner.health = health;
+            spawner
+        };
+        let near_agent = spawn_agent(
+            &mut game,
+            [pos[0] + 2., pos[1]],
+            1,
+            AgentClass::Fighter,
+            IDLE_SOURCE,
+        );
+        let far_agent = spawn_agent(
+            &mut game,
+            [pos[0], pos[1] + 3.],
+            1,
+            AgentClass::Fighter,
+            IDLE_SOURCE,
+        );
+        let healthy_spawner = new_spawner(&mut game, [pos[0] + 4., pos[1]], 1000);
+        let weak_spawner = new_spawner(&mut game, [pos[0] - 7., pos[1]], 100);
+        let weak_id = weak_spawner.id;
+        let weak_pos = weak_spawner.pos;
+        let entities = [
+            RefCell::new(Entity::Agent(near_agent)),
+            RefCell::new(Entity::Agent(far_agent)),
+            RefCell::new(Entity::Spawner(healthy_spawner)),
+            RefCell::new(Entity::Spawner(weak_spawner)),
+        ];
+        let mut bullets = vec![];
+
+        let distance = |agent: &Agent| Vector2::from(agent.pos).distance(Vector2::from(weak_pos));
+        let start_distance = distance(&attacker);
+        for _ in 0..150 {
+            attacker.update(&mut game, &entities, &mut bullets);
+        }
+        assert!(matches!(attacker.target, Some(AgentTarget::Entity(id)) if id == weak_id));
+        assert!(distance(&attacker) < start_distance - 1.);
+    }
+
+    #[test]
+    fn test_go_to_saved_position() {
+        let mut game = game_without_fog();
+        let pos = open_area(&game, 8);
+        let mut agent = spawn_agent(
+            &mut game,
+            pos,
+            0,
+            AgentClass::Worker,
+            "tree main = Sequence { SavePosition (pos -> home) }",
+        );
+        let entities = [];
+        let mut bullets = vec![];
+        agent.update(&mut game, &entities, &mut bullets);
+
+        // Swap the tree without clearing the blackboard, then move the agent away
+        agent.behavior_tree =
+            Some(build_tree("tree main = Sequence { GoToSavedPosition (pos <- home) }").unwrap());
+        agent.pos = [pos[0] + 6., pos[1] + 6.];
+        let distance = |agent: &Agent| Vector2::from(agent.pos).distance(Vector2::from(pos));
+        assert!(5. < distance(&agent));
+        for _ in 0..300 {
+            agent.update(&mut game, &entities, &mut bullets);
+        }
+        assert!(distance(&agent) < 2.);
+    }
+
+    #[test]
+    fn test_move_to_nearest_reachable() {
+        const SHAPE: (usize, usize) = (32, 32);
+        // An enclosed region walled off by a ring of width 2
+        let is_ring = |x: usize, y: usize| {
+            (16..24).contains(&x)
+                && (8..16).contains(&y)
+                && !((18..22).contains(&x) && (10..14).contains(&y))
+        };
+        let board = (0..SHAPE.0 * SHAPE.1)
+            .map(|i| !is_ring(i % SHAPE.0, i / SHAPE.0))
+            .collect();
+        let mut game = disable_fog(Game::from_board(board, SHAPE));
+        let target = [20., 12.];
+        let start = [4.5, 12.5];
+
+        let clearance = AgentClass::Worker.shape().0 * 1.5;
+        let goal = game.nearest_free_cell(target, start, clearance).unwrap();
+        assert!(!is_ring(goal[0] as usize, goal[1] as usize));
+        assert!(Vector2::from(goal).distance(Vector2::from(target)) < 6.);
+
+        // Save the target into the blackboard, then put the agent outside of the region
+        let mut agent = spawn_agent(
+            &mut game,
+            target,
+            0,
+            AgentClass::Worker,
+            "tree main = Sequence { SavePosition (pos -> goal) }",
+        );
+        let mut bullets = vec![];
+        agent.update(&mut game, &[], &mut bullets);
+        agent.behavior_tree = Some(
+            build_tree("tree main = Sequence { MoveToNearestReachable (pos <- goal) }").unwrap(),
+        );
+        agent.pos = start;
+        for _ in 0..300 {
+            agent.update(&mut game, &[], &mut bullets);
+        }
+        assert!(
+            Vector2::from(agent.pos).distance(Vector2::from(goal)) < 2.,
+            "{:?}",
+            agent.pos
+        );
+    }
+
+    #[test]
+    fn test_seek_chokepoint() {
+        const SHAPE: (usize, usize) = (40, 20);
+        // A room with a corridor of width 3 leading out of it to the right
+        let is_floor = |x: usize, y: usize| {
+            (2..20).contains(&x) && (2..18).contains(&y)
+                || (20..38).contains(&x) && (9..12).contains(&y)
+        };
+        let board = (0..SHAPE.0 * SHAPE.1)
+            .map(|i| is_floor(i % SHAPE.0, i / SHAPE.0))
+            .collect();
+        let mut game = disable_fog(Game::from_board(board, SHAPE));
+        let start = [10.5, 10.5];
+        let mut agent = spawn_agent(
+            &mut game,
+            start,
+            0,
+            AgentClass::Worker,
+            "tree main = Sequence { SeekChokepoint }",
+        );
+
+        // Open ground is in the middle of the room, away from the corridor
+        let open = agent.terrain_pos(true, &game).unwrap();
+        assert!(open[0] < 15., "{open:?}");
+
+        let corridor = Vector2::new(20.5, 10.5);
+        let distance = |agent: &Agent| Vector2::from(agent.pos).distance(corridor);
+        assert!(9. < distance(&agent));
+        let entities = [];
+        let mut bullets = vec![];
+        for _ in 0..200 {
+            agent.update(&mut game, &entities, &mut bullets);
+        }
+        assert!(distance(&agent) < 2., "{:?}", agent.pos);
+    }
+
+    #[test]
+    fn test_bullet_spread() {
+        let mut game = Game::new();
+        let mut spread = |class: AgentClass| {
+            let mut agent = spawn_agent(&mut game, [0., 0.], 0, class, IDLE_SOURCE);
+            let mut bullets = vec![];
+            for _ in 0..100 {
+                agent.cooldown = 0.;
+                assert!(agent.shoot_bullet(&mut bullets, [10., 0.], false));
+            }
+            let angles: Vec<_> = bullets
+                .iter()
+                .map(|bullet| bullet.velo[1].atan2(bullet.velo[0]))
+                .collect();
+            assert!(angles.iter().all(|angle| angle.abs() <= class.spread()));
+            let mean = angles.iter().sum::<f64>() / angles.len() as f64;
+            angles
+                .iter()
+                .map(|angle| (angle - mean).powi(2))
+                .sum::<f64>()
+                / angles.len() as f64
+        };
+        let worker = spread(AgentClass::Worker);
+        let fighter = spread(AgentClass::Fighter);
+        assert!(fighter * 4. < worker, "{fighter} vs {worker}");
+    }
+
+    #[test]
+    fn test_set_role() {
+        let mut game = Game::new();
+        let mut agent = spawn_agent(
+            &mut game,
+            [0., 0.],
+            0,
+            AgentClass::Worker,
+            "tree main = Sequence { SetRole (input <- \"scout\") }",
+        );
+        assert_eq!(agent.role, None);
+        let entities = [];
+        let mut bullets = vec![];
+        agent.update(&mut game, &entities, &mut bullets);
+        assert_eq!(agent.role.as_deref(), Some("scout"));
+
+        agent.behavior_tree =
+            Some(build_tree("tree main = Sequence { SetRole (input <- \"\") }").unwrap());
+        agent.update(&mut game, &entities, &mut bullets);
+        assert_eq!(agent.role, None);
+    }
+
+    #[test]
+    fn test_wait_for_squad() {
+        let mut game = Game::new();
+        let allies: Vec<_> = [[1., 0.], [0., 1.]]
+            .into_iter()
+            .map(|pos| spawn_agent(&mut game, pos, 0, AgentClass::Fighter, IDLE_SOURCE))
+            .collect();
+        let ally_ids: Vec<_> = allies.iter().map(|ally| ally.id).collect();
+        let entities: Vec<_> = allies
+            .into_iter()
+            .map(|ally| RefCell::new(Entity::Agent(ally)))
+            .collect();
+        let mut agent = spawn_agent(
+            &mut game,
+            [0., 0.],
+            0,
+            AgentClass::Fighter,
+            "tree main = Sequence {
+                WaitForSquad (fraction <- \"0.5\")
+                Print (input <- \"attack\")
+            }",
+        );
+        let mut bullets = vec![];
+        let attacked = |agent: &Agent| agent.log_buffer().iter().any(|log| log == "attack");
+
+        for _ in 0..10 {
+            agent.update(&mut game, &entities, &mut bullets);
+            game.global_time += 1;
+        }
+        assert!(!attacked(&agent));
+        assert!(game.squad_ready[0].is_ready(agent.id, game.global_time));
+
+        game.squad_ready[0].signal(ally_ids[0], game.global_time);
+        agent.update(&mut game, &entities, &mut bullets);
+        assert!(attacked(&agent));
+
+        // The ally's signal goes stale if it is not renewed
+        game.global_time += 10;
+        agent.log_buffer.clear();
+        agent.update(&mut game, &entities, &mut bullets);
+        assert!(!attacked(&agent));
+    }
+
+    #[test]
+    fn test_separate() {
+        let mut game = Game::new();
+        let pos = open_area(&game, 8);
+        // Side by side, with enough room to turn away from each other
+        let start = [[pos[0], pos[1] - 0.75], [pos[0], pos[1] + 0.75]];
+        let entities: Vec<_> = start
+            .iter()
+            .map(|pos| {
+                let agent = spawn_agent(
+                    &mut game,
+                    *pos,
+                    0,
+                    AgentClass::Worker,
+                    "tree main = Sequence { Separate (radius <- \"3\") }",
+                );
+                RefCell::new(Entity::Agent(agent))
+            })
+            .collect();
+        let mut bullets = vec![];
+        for _ in 0..50 {
+            for entity in &entities {
+                if let Entity::Agent(agent) = &mut *entity.borrow_mut() {
+                    agent.update(&mut game, &entities, &mut bullets);
+                }
+            }
+        }
+        let end: Vec<_> = entities.iter().map(|e| e.borrow().get_pos()).collect();
+        assert!(end[0][1] < start[0][1] - 0.5, "{end:?}");
+        assert!(start[1][1] + 0.5 < end[1][1], "{end:?}");
+    }
+
+    #[test]
+    fn test_dodge_bullet() {
+        let mut game = Game::new();
+        let pos = open_area(&game, 8);
+        let mut new_agent = |pos| {
+            spawn_agent(
+                &mut game,
+                pos,
+                0,
+                AgentClass::Worker,
+                "tree main = Sequence { DodgeBullet }",
+            )
+        };
+        let mut in_line = new_agent(pos);
+        let clear_pos = [pos[0] + 3., pos[1]];
+        let mut clear = new_agent(clear_pos);
+
+        // An enemy bullet coming down along x = pos[0], held still so that it keeps threatening
+        let mut bullets = vec![];
+        bullets.push(Bullet::new(
+            [pos[0], pos[1] - 6.],
+            [0., 1.],
+            1,
+            BULLET_DAMAGE,
+            AgentClass::Worker,
+        ));
+        for _ in 0..100 {
+            in_line.update(&mut game, &[], &mut bullets);
+            clear.update(&mut game, &[], &mut bullets);
+        }
+        assert!(0.5 < (in_line.pos[0] - pos[0]).abs(), "{:?}", in_line.pos);
+        assert_eq!(in_line.dodge_pos(&bullets, &game), None);
+        assert_eq!(clear.pos, clear_pos);
+
+        // A friendly bullet is no threat
+        let mut friendly = vec![];
+        friendly.push(Bullet::new(
+            [clear_pos[0], clear_pos[1] - 6.],
+            [0., 1.],
+            0,
+            BULLET_DAMAGE,
+            AgentClass::Worker,
+        ));
+        clear.update(&mut game, &[], &mut friendly);
+        assert_eq!(clear.pos, clear_pos);
+    }
+
+    #[test]
+    fn test_escort() {
+        const RADIUS: f64 = 4.;
+        let mut game = game_without_fog();
+        let pos = open_area(&game, 8);
+        let idle = IDLE_SOURCE;
+        let escortee = spawn_agent(
+            &mut game,
+            [pos[0] - 6., pos[1]],
+            0,
+            AgentClass::Worker,
+            idle,
+        );
+        let escortee_id = escortee.id;
+        let enemy = spawn_agent(
+            &mut game,
+            [pos[0] + 8., pos[1] - 8.],
+            1,
+            AgentClass::Worker,
+            idle,
+        );
+        let enemy_id = enemy.id;
+        let entities = [
+            RefCell::new(Entity::Agent(escortee)),
+            RefCell::new(Entity::Agent(enemy)),
+        ];
+        let mut agent = spawn_agent(&mut game, [pos[0] - 6., pos[1] + 2.], 0, AgentClass::Worker, &format!(
+                "tree main = Sequence {{ Escort (ally <- \"{escortee_id}\", radius <- \"{RADIUS}\") }}"
+            ));
+        let mut bullets = vec![];
+        let distance = |agent: &Agent| {
+            Vector2::from(agent.pos).distance(Vector2::from(entities[0].borrow().get_pos()))
+        };
+
+        // The escortee walks at half the speed of the escort
+        for _ in 0..160 {
+            if let Entity::Agent(escortee) = &mut *entities[0].borrow_mut() {
+                escortee.pos[0] += AGENT_SPEED / 2.;
+            }
+            agent.update(&mut game, &entities, &mut bullets);
+            assert!(distance(&agent) < RADIUS + 1., "{}", distance(&agent));
+        }
+        assert!(agent.target.is_none());
+
+        // An enemy coming close to the escortee is engaged
+        if let Entity::Agent(enemy) = &mut *entities[1].borrow_mut() {
+            enemy.pos = [pos[0] - 1., pos[1] - 3.];
+        }
+        agent.update(&mut game, &entities, &mut bullets);
+        assert!(matches!(agent.target, Some(AgentTarget::Entity(id)) if id == enemy_id));
+    }
+
+    #[test]
+    fn test_seek_repair() {
+        let mut game = game_without_fog();
+        let mut params = game.params.clone();
+        params.repair = true;
+        game.set_params(&params);
+        let pos = open_area(&game, 8);
+        let mut repair = spawn_agent(&mut game, pos, 0, AgentClass::Worker, IDLE_SOURCE);
+        repair.role = Some(REPAIR_ROLE.to_string());
+        let entities = [RefCell::new(Entity::Agent(repair))];
+
+        let mut agent = spawn_agent(
+            &mut game,
+            [pos[0] + 6., pos[1] + 6.],
+            0,
+            AgentClass::Worker,
+            "tree main = Sequence { SeekRepair }",
+        );
+        let mut bullets = vec![];
+        // Nothing to repair at full health
+        agent.update(&mut game, &entities, &mut bullets);
+        assert_eq!(agent.pos, [pos[0] + 6., pos[1] + 6.]);
+
+        agent.health = 50;
+        let distance = |agent: &Agent| Vector2::from(agent.pos).distance(Vector2::from(pos));
+        let start_distance = distance(&agent);
+        for _ in 0..300 {
+            agent.update(&mut game, &entities, &mut bullets);
+            agent.repair(game.global_time, game.params.repair, &entities);
+        }
+        assert!(distance(&agent) < start_distance / 2., "{:?}", agent.pos);
+        assert_eq!(agent.health, agent.class.health());
+
+        // No repair while the agent is under fire
+        agent.health = 50;
+        agent.last_damaged_tick = game.global_time;
+        agent.repair(game.global_time, game.params.repair, &entities);
+        assert_eq!(agent.health, 50);
+
+        // Nor when repair is disabled, which is the default
+        agent.last_damaged_tick = game.global_time - HEALTH_REGEN_DELAY;
+        agent.repair(game.global_time, GameParams::new().repair, &entities);
+        assert_eq!(agent.health, 50);
+        agent.repair(game.global_time, game.params.repair, &entities);
+        assert_eq!(agent.health, 50 + REPAIR_RATE);
+    }
+
+    #[test]
+    fn test_stance() {
+        let mut game = Game::new();
+        let mut agent = spawn_agent(
+            &mut game,
+            [0., 0.],
+            0,
+            AgentClass::Fighter,
+            "tree main = Sequence { SetStance (value <- \"Defensive\", stance -> stance) }",
+        );
+        let entities = [];
+        let mut bullets = vec![];
+        agent.update(&mut game, &entities, &mut bullets);
+
+        // Swap the tree without clearing the blackboard to read the stance in a later tick
+        agent.behavior_tree = Some(
+            build_tree(
+                "tree main = Sequence {
+    GetStance (stance <- stance, output -> name)
+    StringEq (lhs <- name, rhs <- \"Defensive\")
+    Print (input <- \"{}\", arg0 <- name)
+}",
+            )
+            .unwrap(),
+        );
+        agent.update(&mut game, &entities, &mut bullets);
+        assert_eq!(agent.log_buffer().back(), Some(&"Defensive".to_string()));
+    }
+
+    #[test]
+    fn test_fighting_retreat() {
+        let mut game = game_without_fog();
+        let pos = open_area(&game, 8);
+        let mut agent = spawn_agent(
+            &mut game,
+            pos,
+            0,
+            AgentClass::Fighter,
+            "tree main = Sequence { FightingRetreat }",
+        );
+        let target = spawn_agent(
+            &mut game,
+            [pos[0] + 4., pos[1]],
+            1,
+            AgentClass::Worker,
+            IDLE_SOURCE,
+        );
+        agent.target = Some(AgentTarget::Entity(target.id));
+        let target_pos = target.pos;
+        let entities = [RefCell::new(Entity::Agent(target))];
+        let mut bullets = vec![];
+
+        let distance = |agent: &Agent| Vector2::from(agent.pos).distance(Vector2::from(target_pos));
+        let start_distance = distance(&agent);
+        // Long enough for a fighter to shoot twice
+        for _ in 0..80 {
+            agent.update(&mut game, &entities, &mut bullets);
+        }
+        assert!(start_distance + 2. < distance(&agent));
+        assert_eq!(bullets.len(), 2);
+    }
+
+    #[test]
+    fn test_reload_ammo() {
+        const SOURCE: &str = "tree main = Sequence {
+            ForceSuccess { Reload }
+            Shoot
+        }";
+        let mut game = game_without_fog();
+        let mut params = game.params.clone();
+        params.limited_ammo = true;
+        game.set_params(&params);
+        let pos = open_area(&game, 8);
+        let mut agent = spawn_agent(&mut game, pos, 0, AgentClass::Fighter, SOURCE);
+        agent.ammo = 0;
+        let spawner = crate::spawner::Spawner::new(
+            &mut game.id_gen,
+            [pos[0] - 6., pos[1]],
+            0,
+            Rc::new(IDLE_SOURCE.to_string()),
+        )
+        .unwrap();
+        let spawner_pos = spawner.pos;
+        let entities = [RefCell::new(Entity::Spawner(spawner))];
+        let mut bullets = vec![];
+
+        for _ in 0..50 {
+            agent.update(&mut game, &entities, &mut bullets);
+        }
+        assert!(
+            bullets.is_empty(),
+            "The agent should not shoot without ammo"
+        );
+        assert_eq!(agent.ammo, 0);
+
+        agent.pos = [
+            spawner_pos[0] + SPAWNER_RADIUS + AGENT_HALFLENGTH,
+            spawner_pos[1],
+        ];
+        for _ in 0..50 {
+            agent.update(&mut game, &entities, &mut bullets);
+        }
+        assert!(
+            !bullets.is_empty(),
+            "The agent should shoot after reloading"
+        );
+        assert!(0 < agent.ammo);
+    }
+
+    #[test]
+    fn test_intercept_pos() {
+        let mut game = game_without_fog();
+        let pos = open_area(&game, 8);
+        // The target runs east at a half of the interceptor's speed
+        let mut target = spawn_agent(
+            &mut game,
+            [pos[0] - 4., pos[1]],
+            1,
+            AgentClass::Worker,
+            IDLE_SOURCE,
+        );
+        target.speed = AgentClass::Worker.speed() / 2.;
+        let mut agent = spawn_agent(
+            &mut game,
+            [pos[0] - 4., pos[1] + 4.],
+            0,
+            AgentClass::Worker,
+            IDLE_SOURCE,
+        );
+        agent.target = Some(AgentTarget::Entity(target.id));
+        let target_pos = target.pos;
+        let entities = [RefCell::new(Entity::Agent(target))];
+
+        let goal = agent.intercept_pos(&game, &entities).unwrap();
+        assert!(
+            target_pos[0] + 1. < goal[0],
+            "{goal:?} should be ahead of the target"
+        );
+        assert!((goal[1] - target_pos[1]).abs() < 1e-6);
+        // The agent reaches the goal at the same time as the target
+        let agent_ticks =
+            Vector2::from(goal).distance(Vector2::from(agent.pos)) / AgentClass::Worker.speed();
+        let target_ticks = (goal[0] - target_pos[0]) / (AgentClass::Worker.speed() / 2.);
+        assert!((agent_ticks - target_ticks).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_perimeter_points() {
+        const DISTANCE: f64 = 6.;
+        let mut game = Game::new();
+        let pos = open_area(&game, 8);
+        let spawner = crate::spawner::Spawner::new(
+            &mut game.id_gen,
+            pos,
+            0,
+            Rc::new(IDLE_SOURCE.to_string()),
+        )
+        .unwrap();
+        let agent = spawn_agent(
+            &mut game,
+            [pos[0] + 2., pos[1]],
+            0,
+            AgentClass::Worker,
+            IDLE_SOURCE,
+        );
+        let entities = [RefCell::new(Entity::Spawner(spawner))];
+
+        let points = agent.perimeter_points(DISTANCE, &game, &entities);
+        assert!(4 <= points.len());
+        for point in &points {
+            let distance = Vector2::from(*point).distance(Vector2::from(pos));
+            assert!((distance - DISTANCE).abs() < 1e-6, "{point:?}");
+        }
+        // They are ordered around the spawner
+        let angles: Vec<_> = points
+            .iter()
+            .map(|p| (p[1] - pos[1]).atan2(p[0] - pos[0]))
+            .collect();
+        assert!(angles.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn test_avoidance_stats() {
+        let mut game = Game::new();
+        let pos = open_area(&game, 8);
+        let mut agent = spawn_agent(
+            &mut game,
+            pos,
+            0,
+            AgentClass::Worker,
+            "tree main = Sequence { }",
+        );
+        assert_eq!(agent.avoidance_stats(), None);
+
+        agent.do_avoidance(
+            &mut game,
+            &[],
+            &AvoidanceCommand {
+                goal: [pos[0] + 5., pos[1] + 5.],
+                back: false,
+            },
+        );
+        let stats = agent.avoidance_stats().copied().unwrap();
+        assert!(0 < stats.tree_size);
+        assert!(0 < stats.iterations);
+        assert!(0. <= stats.last_time);
+    }
+
+    #[test]
+    fn test_max_avoidance_searches() {
+        const AGENTS: usize = 5;
+        let mut game = game_without_fog();
+        let mut params = game.params.clone();
+        params.max_avoidance_searches = 1;
+        game.set_params(&params);
+        let pos = open_area(&game, 8);
+        let source = Rc::new(
+            "tree main = Sequence {\n    SavePosition (pos -> goal)\n    Avoidance (goal <- goal)\n}"
+                .to_string(),
+        );
+        let ids: Vec<_> = (0..AGENTS)
+            .map(|i| {
+                let agent = Agent::new(
+                    &mut game.id_gen,
+                    [pos[0] - 4. + 2. * i as f64, pos[1]],
+                    0.,
+                    0,
+                    AgentClass::Worker,
+                    source.clone(),
+                )
+                .unwrap();
+                let id = agent.id;
+                game.entities.push(RefCell::new(Entity::Agent(agent)));
+                id
+            })
+            .collect();
+
+        let searched = |game: &Game| {
+            ids.iter()
+                .filter(|id| {
+                    game.get_entity(**id)
+                        .is_some_and(|e| e.avoidance_stats().is_some())
+                })
+                .count()
+        };
+        for tick in 0..AGENTS {
+            game.update();
+            assert_eq!(game.avoidance_quota.searches(), 1);
+            assert_eq!(searched(&game), tick + 1);
+        }
+
+        // Everyone took a turn, so the first agent is next
+        game.update();
+        assert_eq!(game.avoidance_quota.searches(), 1);
+    }
+
+    #[test]
+    fn test_go_to_last_known_target() {
+        let mut game = game_without_fog();
+        let pos = open_area(&game, 8);
+        let source = Rc::new("tree main = Sequence { GoToLastKnownTarget }".to_string());
+        let mut agent = Agent::new(
+            &mut game.id_gen,
+            pos,
+            0.,
+            0,
+            AgentClass::Worker,
+            source.clone(),
+        )
+        .unwrap();
+        let seen_pos = [pos[0] + 6., pos[1]];
+        let enemy = Agent::new(
+            &mut game.id_gen,
+            seen_pos,
+            0.,
+            1,
+            AgentClass::Worker,
+            source,
+        )
+        .unwrap();
+        agent.target = Some(AgentTarget::Entity(enemy.id));
+        let entities = [RefCell::new(Entity::Agent(enemy))];
+        let mut bullets = vec![];
+
+        // Nothing to search for while the target is in sight
+        agent.update(&mut game, &entities, &mut bullets);
+        assert_eq!(agent.pos, pos);
+
+        // The target slips away under the fog
+        game.params.fow = true;
+        game.global_time = 10;
+        if let Entity::Agent(enemy) = &mut *entities[0].borrow_mut() {
+            enemy.pos = [pos[0] - 6., pos[1]];
+        }
+        for _ in 0..100 {
+            agent.update(&mut game, &entities, &mut bullets);
+        }
+        let distance = Vector2::from(agent.pos).distance(Vector2::from(seen_pos));
+        assert!(
+            distance < LAST_KNOWN_ARRIVE_DISTANCE + 0.5,
+            "{:?}",
+            agent.pos
+        );
+        assert_eq!(agent.last_known_target, None);
+
+        // The position of a former target is forgotten rather than searched
+        agent.last_known_target = Some((entities[0].borrow().get_id(), seen_pos));
+        let other = spawn_agent(&mut game, seen_pos, 1, AgentClass::Worker, IDLE_SOURCE);
+        agent.target = Some(AgentTarget::Entity(other.id));
+        let before = agent.pos;
+        agent.update(&mut game, &entities, &mut bullets);
+        assert_eq!(agent.pos, before);
+        assert_eq!(agent.last_known_target, None);
+    }
+
+    #[test]
+    fn test_bait() {
+        let mut game = game_without_fog();
+        let (xs, ys) = game.shape();
+        let pos = (0..ys)
+            .flat_map(|y| (0..xs).map(move |x| [x as f64 + 0.5, y as f64 + 0.5]))
+            .find(|pos| {
+                (-8..=16).all(|dx| {
+                    (-3..=3).all(|dy| game.is_passable_at([pos[0] + dx as f64, pos[1] + dy as f64]))
+                })
+            })
+            .unwrap();
+        let idle = Rc::new("tree main = Sequence { }".to_string());
+        let mut new_agent = |pos, team, source| {
+            Agent::new(&mut game.id_gen, pos, 0., team, AgentClass::Worker, source).unwrap()
+        };
+        let mut agent = new_agent(pos, 0, Rc::new("tree main = Bait".to_string()));
+        let ally = new_agent([pos[0] - 6., pos[1]], 0, idle.clone());
+        let enemy = new_agent([pos[0] + 14., pos[1]], 1, idle);
+        agent.target = Some(AgentTarget::Entity(enemy.id));
+        let entities = [ally, enemy].map(|a| RefCell::new(Entity::Agent(a)));
+        let mut bullets = vec![];
+
+        // Approach the enemy standing still
+        for _ in 0..60 {
+            agent.update(&mut game, &entities, &mut bullets);
+        }
+        let approached = agent.pos[0];
+        assert!(pos[0] + 2. < approached, "{:?}", agent.pos);
+
+        // The enemy takes the bait and chases the agent, which falls back
+        for _ in 0..60 {
+            if let Entity::Agent(enemy) = &mut *entities[1].borrow_mut() {
+                let toward = (Vector2::from(agent.pos) - Vector2::from(enemy.pos)).normalize();
+                enemy.pos = (Vector2::from(enemy.pos) + toward * 0.1).into();
+            }
+            agent.update(&mut game, &entities, &mut bullets);
+        }
+        assert!(agent.bait.is_some_and(|bait| bait.retreating));
+        assert!(agent.pos[0] < approached - 1., "{:?}", agent.pos);
+    }
+}
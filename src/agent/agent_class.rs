@@ -1,9 +1,12 @@
 use std::fmt::Display;
 
+use crate::collision::layers;
+
 use super::{
     AGENT_HALFLENGTH, AGENT_HALFWIDTH, AGENT_MAX_HEALTH, AGENT_SPEED, BULLET_DAMAGE, BULLET_SPEED,
 };
 
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AgentClass {
     Worker,
@@ -45,6 +48,14 @@ impl AgentClass {
         }
     }
 
+    /// The collision layer that agents of this class belong to by default.
+    pub(crate) fn collision_layer(&self) -> u32 {
+        match self {
+            Self::Worker => layers::WORKER,
+            Self::Fighter => layers::FIGHTER,
+        }
+    }
+
     pub(crate) fn damage(&self) -> u32 {
         match self {
             Self::Worker => BULLET_DAMAGE,
@@ -59,6 +70,22 @@ impl AgentClass {
         }
     }
 
+    /// Distance at which bullets deal the least damage, if `GameParams::damage_falloff` is enabled.
+    pub(crate) fn max_range(&self) -> f64 {
+        match self {
+            Self::Worker => 20.,
+            Self::Fighter => 40.,
+        }
+    }
+
+    /// Maximum angle in radians that a bullet deviates from the agent's orientation either way.
+    pub(crate) fn spread(&self) -> f64 {
+        match self {
+            Self::Worker => 0.1,
+            Self::Fighter => 0.02,
+        }
+    }
+
     pub(crate) fn cooldown(&self) -> f64 {
         match self {
             Self::Worker => 20.,
@@ -66,6 +93,15 @@ impl AgentClass {
         }
     }
 
+    /// The number of shots an agent can carry, which only matters if `GameParams::limited_ammo`
+    /// is enabled.
+    pub(crate) fn ammo(&self) -> u32 {
+        match self {
+            Self::Worker => 20,
+            Self::Fighter => 10,
+        }
+    }
+
     pub(crate) fn speed(&self) -> f64 {
         match self {
             Self::Worker => AGENT_SPEED,
@@ -73,13 +109,23 @@ impl AgentClass {
         }
     }
 
-    pub(crate) fn shape(&self) -> (f64, f64) {
+    /// Half length and half width of the agent's footprint, used for both collision and rendering.
+    pub fn shape(&self) -> (f64, f64) {
         match self {
             Self::Worker => (AGENT_HALFLENGTH, AGENT_HALFWIDTH),
             Self::Fighter => (AGENT_HALFLENGTH * 1.5, AGENT_HALFWIDTH * 1.5),
         }
     }
 
+    /// The angle of the forward cone in which the agent can detect enemies, in radians.
+    /// It only matters if `GameParams::sensor_cone` is enabled.
+    pub(crate) fn sensor_fov(&self) -> f64 {
+        match self {
+            Self::Worker => std::f64::consts::PI,
+            Self::Fighter => std::f64::consts::PI * 2. / 3.,
+        }
+    }
+
     pub fn vertices(&self, mut f: impl FnMut([f64; 2])) {
         let (length, width) = self.shape();
         // Technically, we could return a reference to static array, since there are only 2 kinds of shapes.
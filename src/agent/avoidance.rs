@@ -4,7 +4,7 @@ mod search;
 
 use std::{
     cell::RefCell,
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
 };
 
 use cgmath::{MetricSpace, Vector2};
@@ -51,6 +51,17 @@ impl AgentState {
         copy.heading = orient;
         copy
     }
+
+    /// Returns the state after turning by `steer` and driving `drive` along the new heading.
+    pub(crate) fn avoidance_step(&self, (drive, steer): (f64, f64)) -> Self {
+        let heading = wrap_angle(self.heading + steer);
+        let pos = drive * Vector2::new(heading.cos(), heading.sin()) + Vector2::new(self.x, self.y);
+        Self {
+            x: pos.x,
+            y: pos.y,
+            heading,
+        }
+    }
 }
 
 impl From<AgentState> for [f64; 2] {
@@ -250,12 +261,120 @@ impl Agent {
     }
 }
 
+/// Statistics of the last avoidance search of an agent, for diagnosing slow searches.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AvoidanceStats {
+    /// The number of nodes in the search tree after the search
+    pub tree_size: usize,
+    /// The number of node expansions attempted in the search
+    pub iterations: usize,
+    /// The number of sampled nodes skipped because they were too close to existing ones
+    pub skipped_nodes: usize,
+    /// Time spent in the search in seconds
+    pub last_time: f64,
+}
+
+/// Number of recent avoidance searches to average the time over
+const TIME_WINDOW: usize = 100;
+/// Lower bound of the ratio of expansions `AvoidanceLoad` can scale down to
+const MIN_EXPAND_RATIO: f64 = 0.05;
+
+/// Tracks the time spent in avoidance searches and scales the number of node expansions down
+/// when the average exceeds the budget, and back up when there is headroom.
+#[derive(Debug)]
+pub(crate) struct AvoidanceLoad {
+    time_window: VecDeque<f64>,
+    expand_ratio: f64,
+}
+
+impl AvoidanceLoad {
+    pub(crate) fn new() -> Self {
+        Self {
+            time_window: VecDeque::new(),
+            expand_ratio: 1.,
+        }
+    }
+
+    /// Record the time of a search in seconds and adapt the expansion ratio to `budget`.
+    /// A `budget` of 0 disables the adaptation.
+    pub(crate) fn record(&mut self, time: f64, budget: f64) {
+        self.time_window.push_back(time);
+        while TIME_WINDOW < self.time_window.len() {
+            self.time_window.pop_front();
+        }
+        let avg = self.average();
+        if budget <= 0. {
+            self.expand_ratio = 1.;
+        } else if budget < avg {
+            self.expand_ratio = (self.expand_ratio * 0.9).max(MIN_EXPAND_RATIO);
+        } else if avg < budget * 0.5 {
+            self.expand_ratio = (self.expand_ratio * 1.05).min(1.);
+        }
+    }
+
+    pub(crate) fn average(&self) -> f64 {
+        if self.time_window.is_empty() {
+            0.
+        } else {
+            self.time_window.iter().sum::<f64>() / self.time_window.len() as f64
+        }
+    }
+
+    /// The number of expansions per search scaled from the configured `expands`
+    pub(crate) fn expand_states(&self, expands: f64) -> usize {
+        ((expands * self.expand_ratio) as usize).max(1)
+    }
+}
+
+/// Limits the number of avoidance searches in a tick. Agents are granted searches in the order
+/// of ids starting after the last one granted, so that the deferred ones get their turn in the
+/// following ticks.
+#[derive(Debug, Default)]
+pub(crate) struct AvoidanceQuota {
+    /// Searches granted in this tick
+    searches: usize,
+    /// Id of the agent last granted a search
+    cursor: Option<usize>,
+    /// The largest id of the agents requesting a search in this tick
+    max_requester: Option<usize>,
+}
+
+impl AvoidanceQuota {
+    /// Start a new tick, wrapping around to the smallest ids if no agent after the cursor has
+    /// asked for a search in the last tick.
+    pub(crate) fn begin_tick(&mut self) {
+        if self.max_requester <= self.cursor {
+            self.cursor = None;
+        }
+        self.searches = 0;
+        self.max_requester = None;
+    }
+
+    /// Returns whether the agent `id` may run a search in this tick. A `max` of 0 is unlimited.
+    pub(crate) fn try_acquire(&mut self, id: usize, max: usize) -> bool {
+        self.max_requester = self.max_requester.max(Some(id));
+        if 0 < max && (max <= self.searches || Some(id) <= self.cursor) {
+            return false;
+        }
+        self.searches += 1;
+        self.cursor = Some(id);
+        true
+    }
+
+    #[cfg(test)]
+    pub(crate) fn searches(&self) -> usize {
+        self.searches
+    }
+}
+
 pub(super) struct SearchEnv<'a> {
     game: &'a Game,
     switch_back: bool,
     expand_states: usize,
+    rewire_radius: f64,
     skipped_nodes: usize,
     tree_size: usize,
+    pub(super) iterations: usize,
     entities: &'a [RefCell<Entity>],
 }
 
@@ -274,13 +393,17 @@ impl Agent {
         let mut env = SearchEnv {
             game,
             switch_back,
-            expand_states: game.params.avoidance_expands as usize,
+            expand_states: game
+                .avoidance_load
+                .expand_states(game.params.avoidance_expands),
+            rewire_radius: game.params.rrt_star_radius,
             skipped_nodes: 0,
             tree_size: 0,
+            iterations: 0,
             entities,
         };
 
-        match avoidance_mode {
+        let found = match avoidance_mode {
             AvoidanceMode::Kinematic => {
                 self.avoidance_search_gen::<ForwardKinematicSampler>(&mut env, backward)
             }
@@ -288,7 +411,17 @@ impl Agent {
             AvoidanceMode::RrtStar => {
                 self.avoidance_search_gen::<RrtStarSampler>(&mut env, backward)
             }
-        }
+        };
+        self.avoidance_stats = Some(AvoidanceStats {
+            tree_size: self
+                .search_state
+                .as_ref()
+                .map_or(0, |ss| ss.search_tree.len()),
+            iterations: env.iterations,
+            skipped_nodes: env.skipped_nodes,
+            last_time: 0.,
+        });
+        found
     }
 
     /// Templatized logic for searching avoidance path. The type argument speicfy how to
@@ -473,10 +606,11 @@ impl Agent {
     /// Check existing avoidance search state with actual entity positions, and
     /// prune those states that has new collisions.
     pub(super) fn check_avoidance_collision(&mut self, env: &GameEnv) -> Option<()> {
+        let layers = self.collision_layers();
         let ss = self.search_state.as_mut()?;
 
         let collision_checker = |state: AgentState| {
-            Agent::collision_check(Some(self.id), state, self.class, env.entities, true)
+            Agent::collision_check(Some(self.id), state, self.class, layers, env.entities, true)
         };
 
         /// Assign infinite cost to node i and its subtree, assuming there are no cycles
@@ -562,9 +696,15 @@ impl Agent {
         Some(())
     }
 
+    /// Plans candidate first moves for simple avoidance.
+    ///
+    /// Each steer option is expanded up to `lookahead_depth` steps and only the first moves whose
+    /// branches reach the deepest without collision are returned, so that the agent does not drive
+    /// into a pocket that a single step cannot see.
     pub(crate) fn plan_simple_avoidance(
         &mut self,
         back: bool,
+        lookahead_depth: usize,
         entities: &[RefCell<Entity>],
     ) -> Vec<(f64, f64)> {
         let collision_checker = |state: AgentState| {
@@ -585,35 +725,37 @@ impl Agent {
                     .unwrap_or(false);
                 res
             };
-            Agent::collision_check_fn(ignore, state, self.class, entities, true)
+            Agent::collision_check_fn(
+                ignore,
+                state,
+                self.class,
+                self.collision_layers(),
+                entities,
+                true,
+            )
         };
         let drive = DIST_RADIUS * 2.5 * if back { -1. } else { 1. };
-        let mut all_routes = vec![];
 
-        let center_state = self.get_avoidance_agent_state((drive, 0.));
-        let center_collision = collision_checker(center_state);
-        if !center_collision {
-            // We could return early if the center is available, but we search side options for visualization
-            // return vec![(drive, 0.)];
-            all_routes.push((drive, 0.));
-        }
+        let mut best_reach = 0;
+        let mut all_routes = vec![];
 
-        for limit in 1..3 {
-            let to_steer = |i| limit as f64 * std::f64::consts::PI / 6. * ((i as f64 * 2.) - 1.);
-            let routes = (0..=1)
-                .filter_map(|i| {
-                    let f = to_steer(i);
-                    let state = self.get_avoidance_agent_state((drive, f));
-                    let hit = collision_checker(state);
-                    if !hit {
-                        Some((drive, f))
-                    } else {
-                        None
-                    }
-                })
-                .collect::<Vec<_>>();
-            if !routes.is_empty() {
-                all_routes.extend_from_slice(&routes);
+        for steer in SIMPLE_AVOIDANCE_STEERS {
+            let state = self.get_avoidance_agent_state((drive, steer));
+            if collision_checker(state) {
+                continue;
+            }
+            let reach = 1 + lookahead_reach(
+                state,
+                drive,
+                lookahead_depth.saturating_sub(1),
+                &collision_checker,
+            );
+            if best_reach < reach {
+                best_reach = reach;
+                all_routes.clear();
+            }
+            if reach == best_reach {
+                all_routes.push((drive, steer));
             }
         }
 
@@ -621,6 +763,40 @@ impl Agent {
     }
 }
 
+/// Steer options of the simple avoidance, from the center to the sides.
+pub(super) const SIMPLE_AVOIDANCE_STEERS: [f64; 5] = [
+    0.,
+    -std::f64::consts::PI / 6.,
+    std::f64::consts::PI / 6.,
+    -std::f64::consts::PI / 3.,
+    std::f64::consts::PI / 3.,
+];
+
+/// Returns the number of collision-free steps reachable from `state` in the best branch, up to
+/// `depth`.
+fn lookahead_reach(
+    state: AgentState,
+    drive: f64,
+    depth: usize,
+    collision_checker: &impl Fn(AgentState) -> bool,
+) -> usize {
+    if depth == 0 {
+        return 0;
+    }
+    let mut best = 0;
+    for steer in SIMPLE_AVOIDANCE_STEERS {
+        let next = state.avoidance_step((drive, steer));
+        if collision_checker(next) {
+            continue;
+        }
+        best = best.max(1 + lookahead_reach(next, drive, depth - 1, collision_checker));
+        if best == depth {
+            break;
+        }
+    }
+    best
+}
+
 fn detach_from(nodes: &mut [SearchNode], i: usize) {
     if let Some(from) = nodes[i].from {
         if let Some((to_index, _)) = nodes[from]
@@ -635,3 +811,39 @@ fn detach_from(nodes: &mut [SearchNode], i: usize) {
         nodes[i].from = None;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_avoidance_load() {
+        const EXPANDS: f64 = 20.;
+        const BUDGET: f64 = 0.01;
+        let mut load = AvoidanceLoad::new();
+        for _ in 0..10 {
+            load.record(BUDGET * 0.1, BUDGET);
+        }
+        assert_eq!(load.expand_states(EXPANDS), EXPANDS as usize);
+
+        // Searches taking too long scale down subsequent searches, but not to zero
+        let mut last = load.expand_states(EXPANDS);
+        for _ in 0..10 {
+            load.record(BUDGET * 100., BUDGET);
+            let expand_states = load.expand_states(EXPANDS);
+            assert!(expand_states <= last);
+            last = expand_states;
+        }
+        assert!(last < EXPANDS as usize);
+        for _ in 0..TIME_WINDOW * 2 {
+            load.record(BUDGET * 100., BUDGET);
+        }
+        assert_eq!(load.expand_states(EXPANDS), 1);
+
+        // Recovers once the searches are fast again
+        for _ in 0..TIME_WINDOW * 2 {
+            load.record(0., BUDGET);
+        }
+        assert_eq!(load.expand_states(EXPANDS), EXPANDS as usize);
+    }
+}
@@ -348,11 +348,15 @@ fn find_closest_node<'a, 'b>(
 pub const REWIRE_DISTANCE: f64 = DIST_RADIUS * 5.;
 
 /// RRT* sampler, awkward capitalization in Rust convention
-pub(super) struct RrtStarSampler;
+pub(super) struct RrtStarSampler {
+    rewire_radius: f64,
+}
 
 impl StateSampler for RrtStarSampler {
-    fn new(_env: &SearchEnv) -> Self {
-        Self
+    fn new(env: &SearchEnv) -> Self {
+        Self {
+            rewire_radius: env.rewire_radius,
+        }
     }
 
     fn compare_state(s1: &AgentState, s2: &AgentState) -> bool {
@@ -399,6 +403,7 @@ impl StateSampler for RrtStarSampler {
             start_state,
             next_direction,
             grid_map,
+            self.rewire_radius,
         );
 
         // If this is a "shortcut", i.e. has a lower cost than existing node, "graft" the branch
@@ -458,7 +463,7 @@ impl StateSampler for RrtStarSampler {
             let existing_node = &nodes[i];
             let delta = Vector2::from(new_node_state) - Vector2::from(existing_node.state);
             let dist2 = delta.magnitude2();
-            if REWIRE_DISTANCE.powf(2.) < dist2 {
+            if self.rewire_radius.powf(2.) < dist2 {
                 continue;
             }
             let distance = dist2.sqrt();
@@ -509,6 +514,7 @@ fn find_lowest_cost(
     start_state: AgentState,
     next_direction: f64,
     grid_map: &GridMap,
+    rewire_radius: f64,
 ) -> Option<(usize, f64, Vector2<f64>)> {
     let center = ((state.x / CELL_SIZE) as i32, (state.y / CELL_SIZE) as i32);
     const MAX_SEARCH_CELL_RADIUS: i32 = 10;
@@ -523,7 +529,7 @@ fn find_lowest_cost(
                 closest = cell_nodes.iter().fold(closest, |acc, &i| {
                     let existing_node = &nodes[i];
                     let delta = Vector2::from(state) - Vector2::from(existing_node.state);
-                    if REWIRE_DISTANCE.powf(2.) < delta.magnitude2() {
+                    if rewire_radius.powf(2.) < delta.magnitude2() {
                         return acc;
                     }
                     let distance = delta.magnitude();
@@ -576,3 +582,60 @@ fn update_cost(nodes: &mut [SearchNode], cur: usize, recurse: usize) {
     }
     nodes[cur].to = children;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Build a search tree that detours around a wall and return the cost to the goal node
+    /// after a new node is added and rewired with the given radius.
+    fn rewired_goal_cost(rewire_radius: f64) -> f64 {
+        // The wall occupies x in [4, 6] and y in [-10, 8]
+        let wall_hit = |from: Vector2<f64>, to: Vector2<f64>| {
+            (0..=20).any(|i| {
+                let pos = from + (to - from) * (i as f64 / 20.);
+                (4. ..=6.).contains(&pos.x) && (-10. ..=8.).contains(&pos.y)
+            })
+        };
+        let collision_check =
+            |s1: AgentState, s2: AgentState, _direction: f64, _distance: f64, _steer: f64| {
+                (wall_hit(s1.into(), s2.into()), 0)
+            };
+
+        let points = [[0., 0.], [0., 10.], [10., 10.], [20., 20.], [20., 0.]];
+        let mut nodes: Vec<SearchNode> = vec![];
+        for (i, point) in points.iter().enumerate() {
+            let mut node = SearchNode::new(AgentState::new(point[0], point[1], 0.), 0., 0., 1.);
+            node.id = i;
+            if let Some(prev) = i.checked_sub(1) {
+                let prev_node = &nodes[prev];
+                node.cost =
+                    prev_node.cost + Vector2::from(prev_node.state).distance(Vector2::from(*point));
+                node.from = Some(prev);
+                nodes[prev].to.push(i);
+            }
+            nodes.push(node);
+        }
+        let goal = nodes.len() - 1;
+
+        let mut new_node = SearchNode::new(AgentState::new(12., 8., 0.), 0., 0., 1.);
+        new_node.cost = nodes[2].cost + 8f64.sqrt();
+        new_node.from = Some(2);
+        new_node.id = nodes.len();
+        nodes[2].to.push(new_node.id);
+        nodes.push(new_node);
+
+        let sampler = RrtStarSampler { rewire_radius };
+        sampler.rewire(&mut nodes, 5, 0, collision_check);
+        nodes[goal].cost
+    }
+
+    #[test]
+    fn test_rrt_star_radius() {
+        let short = rewired_goal_cost(5.);
+        let long = rewired_goal_cost(15.);
+        assert!(long <= short, "{long} > {short}");
+        // The goal is within the longer radius, so it should be rewired to a shortcut
+        assert!(long < short);
+    }
+}
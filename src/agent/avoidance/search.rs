@@ -114,6 +114,7 @@ pub(super) fn search<S: StateSampler>(
     grid_map: &mut GridMap,
 ) -> Option<Vec<usize>> {
     'skip: for _i in 0..env.expand_states {
+        env.iterations += 1;
         let mut sampler = S::new(env);
 
         // println!(
@@ -134,7 +135,14 @@ pub(super) fn search<S: StateSampler>(
             const USE_SEPAX: bool = true;
             const USE_STEER: bool = false;
             let collision_checker = |state: AgentState| {
-                if Agent::collision_check(Some(this.id), state, this.class, env.entities, true) {
+                if Agent::collision_check(
+                    Some(this.id),
+                    state,
+                    this.class,
+                    this.collision_layers(),
+                    env.entities,
+                    true,
+                ) {
                     return false;
                 }
                 !env.game.check_hit(
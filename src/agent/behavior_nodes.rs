@@ -1,42 +1,106 @@
 use crate::{
-    behavior_tree_adapt::{common_tree_nodes, BehaviorTree},
-    qtree::{qtree::PathFindError, QTreePathNode},
+    behavior_tree_adapt::{
+        common_tree_nodes, describe_common_nodes, parse_tree_source, BehaviorTree, BuildTreeError,
+        NodeDoc, NodeRegistry, PrintCommand,
+    },
+    qtree::{path_length, path_node_count, qtree::PathFindError, QTreePathNode},
 };
 
-use super::{motion::OrientToResult, AgentClass, AgentState, MotionResult};
+use super::{motion::OrientToResult, AgentClass, AgentState, MotionResult, ARRIVE_DISTANCE};
 use behavior_tree_lite::{
-    boxify, error::LoadError, load, parse_file, BehaviorCallback, BehaviorNode, BehaviorResult,
-    Context, Lazy, PortSpec, Registry, Symbol,
+    boxify, load, BehaviorCallback, BehaviorNode, BehaviorResult, Context, Lazy, PortSpec,
+    Registry, Symbol,
 };
 use cgmath::{Matrix2, MetricSpace, Rad, Vector2};
-use rand::{distributions::Uniform, prelude::Distribution};
 
-pub(super) fn build_tree(source: &str) -> Result<BehaviorTree, LoadError> {
+pub(super) fn build_tree(source: &str) -> Result<BehaviorTree, BuildTreeError> {
     let mut registry = Registry::default();
     common_tree_nodes(&mut registry);
+    register_nodes(&mut registry);
+
+    let tree_source = parse_tree_source(source)?;
+    Ok(BehaviorTree(load(&tree_source, &registry, true)?))
+}
+
+/// Build `count` instances of the tree in `source`, at least one, parsing the source and checking
+/// the ports only once.
+pub(super) fn build_trees(source: &str, count: usize) -> Result<Vec<BehaviorTree>, BuildTreeError> {
+    let mut registry = Registry::default();
+    common_tree_nodes(&mut registry);
+    register_nodes(&mut registry);
+
+    let tree_source = parse_tree_source(source)?;
+    (0..count.max(1))
+        .map(|i| Ok(BehaviorTree(load(&tree_source, &registry, i == 0)?)))
+        .collect()
+}
+
+/// Returns the names and ports of all the nodes available in agent trees.
+pub fn describe_nodes() -> Vec<NodeDoc> {
+    let mut docs = describe_common_nodes();
+    register_nodes(&mut docs);
+    docs
+}
+
+fn register_nodes(registry: &mut impl NodeRegistry) {
     registry.register("GetClass", boxify(|| GetClass));
     registry.register("HasTarget", boxify(|| HasTargetNode));
     registry.register("GetTargetType", boxify(|| GetTargetTypeNode));
+    registry.register("SetStance", boxify(|| SetStanceNode));
+    registry.register("GetStance", boxify(|| GetStanceNode));
+    registry.register("SetRole", boxify(|| SetRoleNode));
     registry.register("TargetId", boxify(|| TargetIdNode));
     registry.register("TargetPos", boxify(|| TargetPosNode));
     registry.register("FindEnemy", boxify(|| FindEnemy));
+    registry.register("SelectTarget", boxify(|| SelectTargetNode));
     registry.register("FindSpawner", boxify(|| FindSpawner));
     registry.register("FindResource", boxify(|| FindResource));
     registry.register("FindFog", boxify(|| FindFog));
+    registry.register("FrontierPos", boxify(|| FrontierPosNode));
     registry.register("ClearTarget", boxify(|| ClearTarget));
+    registry.register(
+        "AbandonUnreachableTarget",
+        boxify(|| AbandonUnreachableTargetNode),
+    );
     registry.register("CollectResource", boxify(|| CollectResource));
     registry.register("DepositResource", boxify(|| DepositResource));
     registry.register("IsResourceFull", boxify(|| IsResourceFull));
     registry.register("IsSpawnerResourceFull", boxify(|| IsSpawnerResourceFull));
+    registry.register("IsStuck", boxify(|| IsStuckNode));
+    registry.register("DetectPathDeadlock", boxify(|| DetectPathDeadlockNode));
     registry.register("HasPath", boxify(|| HasPathNode));
+    registry.register("PathStale", boxify(|| PathStaleNode));
+    registry.register("PathReady", boxify(|| PathReadyNode));
     registry.register("ClearPath", boxify(|| ClearPathNode));
+    registry.register("TargetRange", boxify(|| TargetRangeNode));
+    registry.register("TeamStat", boxify(|| TeamStatNode));
+    registry.register("AssignWorkerRole", boxify(|| AssignWorkerRoleNode));
+    registry.register("InEnemyTerritory", boxify(|| InEnemyTerritoryNode));
+    registry.register("RemainingEnemies", boxify(|| RemainingEnemiesNode));
+    registry.register("FlankPos", boxify(|| FlankPosNode));
+    registry.register("RegroupIfOutnumbered", boxify(|| RegroupIfOutnumberedNode));
+    registry.register("InterceptTarget", boxify(|| InterceptTargetNode));
+    registry.register("WaitForSquad", boxify(|| WaitForSquadNode));
+    registry.register("Separate", boxify(|| SeparateNode));
+    registry.register("DodgeBullet", boxify(|| DodgeBulletNode));
+    registry.register("AttackSpawner", boxify(|| AttackSpawnerNode));
+    registry.register("PatrolPerimeter", boxify(PatrolPerimeterNode::default));
     registry.register("FindPath", boxify(|| FindPathNode));
     registry.register("DigestPath", boxify(|| DigestPathNode));
+    registry.register("PathCost", boxify(|| PathCostNode));
     registry.register("Drive", boxify(|| DriveNode));
     registry.register("MoveTo", boxify(|| MoveToNode));
     registry.register("FollowPath", boxify(|| FollowPath));
     registry.register("Shoot", boxify(|| ShootNode));
+    registry.register("FightingRetreat", boxify(|| FightingRetreatNode));
+    registry.register("Bait", boxify(|| BaitNode));
+    registry.register("HasAmmo", boxify(|| HasAmmoNode));
+    registry.register("Reload", boxify(|| ReloadNode));
+    registry.register("SeekRepair", boxify(|| SeekRepairNode));
+    registry.register("Escort", boxify(|| EscortNode));
+    registry.register("Turret", boxify(|| TurretNode));
     registry.register("Timeout", boxify(|| TimeoutNode(None)));
+    registry.register("EveryNTicks", boxify(|| EveryNTicksNode(0)));
     registry.register("Randomize", boxify(|| RandomizeNode));
     registry.register("Avoidance", boxify(|| AvoidanceNode));
     registry.register("SimpleAvoidance", boxify(|| SimpleAvoidanceNode));
@@ -44,12 +108,18 @@ pub(super) fn build_tree(source: &str) -> Result<BehaviorTree, LoadError> {
     registry.register("PathNextNode", boxify(|| PathNextNode));
     registry.register("PredictForward", boxify(|| PredictForwardNode));
     registry.register("NewPosition", boxify(|| NewPositionNode));
+    registry.register("SavePosition", boxify(|| SavePositionNode));
+    registry.register("GoToSavedPosition", boxify(|| GoToSavedPositionNode));
+    registry.register("GoToLastKnownTarget", boxify(|| GoToLastKnownTargetNode));
+    registry.register(
+        "MoveToNearestReachable",
+        boxify(MoveToNearestReachableNode::default),
+    );
+    registry.register("SeekChokepoint", boxify(|| SeekTerrainNode::new(false)));
+    registry.register("SeekOpenGround", boxify(|| SeekTerrainNode::new(true)));
     registry.register("IsTargetVisible", boxify(|| IsTargetVisibleNode));
     registry.register("FaceToTarget", boxify(|| FaceToTargetNode));
-
-    let (_i, tree_source) = parse_file(source).unwrap();
-    // println!("parse_file rest: {i:?}");
-    Ok(BehaviorTree(load(&tree_source, &registry, true)?))
+    registry.register("OrientAlongPath", boxify(|| OrientAlongPathNode));
 }
 
 pub(super) struct GetClass;
@@ -114,6 +184,75 @@ impl BehaviorNode for GetTargetTypeNode {
     }
 }
 
+/// Whether an agent should engage enemies or hold back, shared through the blackboard so that
+/// a single node can switch the behavior of the rest of the tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Stance {
+    Aggressive,
+    Defensive,
+}
+
+impl std::fmt::Display for Stance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Aggressive => "Aggressive",
+                Self::Defensive => "Defensive",
+            }
+        )
+    }
+}
+
+impl std::str::FromStr for Stance {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Aggressive" => Ok(Self::Aggressive),
+            "Defensive" => Ok(Self::Defensive),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Parses `value` ("Aggressive" or "Defensive") and writes it to `stance`. Fails if `value` is
+/// not a stance.
+struct SetStanceNode;
+
+impl BehaviorNode for SetStanceNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_in("value"), PortSpec::new_out("stance")]
+    }
+
+    fn tick(&mut self, _arg: BehaviorCallback, ctx: &mut Context) -> BehaviorResult {
+        let Some(stance) = ctx.get_parse::<Stance>("value") else {
+            return BehaviorResult::Fail;
+        };
+        ctx.set("stance", stance);
+        BehaviorResult::Success
+    }
+}
+
+/// Reads `stance` written by `SetStance` and outputs its name to `output`, to be compared with
+/// `StringEq`. Fails if no stance has been set.
+struct GetStanceNode;
+
+impl BehaviorNode for GetStanceNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_in("stance"), PortSpec::new_out("output")]
+    }
+
+    fn tick(&mut self, _arg: BehaviorCallback, ctx: &mut Context) -> BehaviorResult {
+        let Some(stance) = ctx.get_parse::<Stance>("stance") else {
+            return BehaviorResult::Fail;
+        };
+        ctx.set("output", stance.to_string());
+        BehaviorResult::Success
+    }
+}
+
 pub(super) struct TargetIdNode;
 
 impl BehaviorNode for TargetIdNode {
@@ -146,6 +285,58 @@ impl BehaviorNode for FindEnemy {
     }
 }
 
+/// A policy to choose a target among visible enemies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TargetPolicy {
+    /// Closest enemy first, same as `FindEnemy`
+    Nearest,
+    /// Enemy with the least health first, ties are broken by distance
+    LowestHealth,
+    /// Fighters before anything else, ties are broken by distance
+    FightersFirst,
+}
+
+impl std::str::FromStr for TargetPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "nearest" => Self::Nearest,
+            "lowest_health" => Self::LowestHealth,
+            "fighters_first" => Self::FightersFirst,
+            _ => return Err(format!("Unknown target policy: {s}")),
+        })
+    }
+}
+
+pub(super) struct SelectTargetCommand(pub TargetPolicy);
+
+pub(super) struct SelectTargetNode;
+
+impl BehaviorNode for SelectTargetNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_in("policy")]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let Some(policy) = ctx.get_parse::<TargetPolicy>("policy") else {
+            return BehaviorResult::Fail;
+        };
+        if arg(&SelectTargetCommand(policy))
+            .and_then(|res| res.downcast_ref().copied())
+            .unwrap_or(false)
+        {
+            BehaviorResult::Success
+        } else {
+            BehaviorResult::Fail
+        }
+    }
+}
+
 pub(super) struct FindSpawner;
 
 impl BehaviorNode for FindSpawner {
@@ -197,6 +388,53 @@ impl BehaviorNode for FindFog {
     }
 }
 
+pub(super) struct FrontierPosCommand;
+
+/// Outputs the nearest reachable position covered by old fog without setting a path to it.
+struct FrontierPosNode;
+
+impl BehaviorNode for FrontierPosNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_out("pos")]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        if let Some(pos) = arg(&FrontierPosCommand)
+            .and_then(|pos| pos.downcast_ref::<Option<[f64; 2]>>().copied())
+            .flatten()
+        {
+            ctx.set("pos", pos);
+            BehaviorResult::Success
+        } else {
+            BehaviorResult::Fail
+        }
+    }
+}
+
+pub(super) struct SetRoleCommand(pub String);
+
+/// Sets the role of the agent to `input`, which is only shown by the frontends. An empty string
+/// clears the role.
+struct SetRoleNode;
+
+impl BehaviorNode for SetRoleNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_in("input")]
+    }
+
+    fn tick(&mut self, arg: BehaviorCallback, ctx: &mut Context) -> BehaviorResult {
+        let Some(role) = ctx.get::<String>("input").cloned() else {
+            return BehaviorResult::Fail;
+        };
+        arg(&SetRoleCommand(role));
+        BehaviorResult::Success
+    }
+}
+
 pub(super) struct ClearTarget;
 
 impl BehaviorNode for ClearTarget {
@@ -216,6 +454,29 @@ impl BehaviorNode for ClearTarget {
     }
 }
 
+pub(super) struct AbandonUnreachableCommand;
+
+/// Clears the target entity and marks it unreachable if the last `FindPath` failed while the agent
+/// was aiming at it, and succeeds. Fails if the last search succeeded or the target changed since.
+struct AbandonUnreachableTargetNode;
+
+impl BehaviorNode for AbandonUnreachableTargetNode {
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        _ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        if arg(&AbandonUnreachableCommand)
+            .and_then(|res| res.downcast_ref().copied())
+            .unwrap_or(false)
+        {
+            BehaviorResult::Success
+        } else {
+            BehaviorResult::Fail
+        }
+    }
+}
+
 pub(super) struct CollectResource;
 
 impl BehaviorNode for CollectResource {
@@ -284,6 +545,65 @@ impl BehaviorNode for IsSpawnerResourceFull {
     }
 }
 
+pub(super) struct IsStuckCommand;
+
+/// Succeeds if the agent is boxed in and has no surrounding cell to move into.
+struct IsStuckNode;
+
+impl BehaviorNode for IsStuckNode {
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        _ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        if arg(&IsStuckCommand)
+            .and_then(|res| res.downcast_ref().copied())
+            .unwrap_or(false)
+        {
+            BehaviorResult::Success
+        } else {
+            BehaviorResult::Fail
+        }
+    }
+}
+
+pub(super) struct PathDeadlockCommand;
+
+/// Default number of consecutive blocked motions regarded as a deadlock
+const DEFAULT_DEADLOCK_THRESHOLD: usize = 30;
+
+/// Succeeds if the last `threshold` or more `MoveTo` or `FollowPath` motions were all blocked,
+/// so that the tree can recover from spinning in place, e.g. by `ClearPath` and `Avoidance`.
+/// Outputs the number of consecutive blocked motions to `count`.
+struct DetectPathDeadlockNode;
+
+impl BehaviorNode for DetectPathDeadlockNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_in("threshold"), PortSpec::new_out("count")]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let threshold = ctx
+            .get_parse::<usize>("threshold")
+            .unwrap_or(DEFAULT_DEADLOCK_THRESHOLD);
+        let Some(count) =
+            arg(&PathDeadlockCommand).and_then(|res| res.downcast_ref::<usize>().copied())
+        else {
+            return BehaviorResult::Fail;
+        };
+        ctx.set("count", count);
+        if threshold <= count {
+            BehaviorResult::Success
+        } else {
+            BehaviorResult::Fail
+        }
+    }
+}
+
 pub(super) struct HasPathNode;
 
 impl BehaviorNode for HasPathNode {
@@ -304,6 +624,76 @@ impl BehaviorNode for HasPathNode {
     }
 }
 
+pub(super) struct PathStaleCommand {
+    pub target: [f64; 2],
+    pub threshold: f64,
+}
+
+/// Distance the target can move before the path to it is regarded as stale
+const PATH_STALE_DISTANCE: f64 = 5.;
+
+/// Succeeds if the agent has no path, the target has moved away from the goal of the path by more
+/// than `threshold`, or the path is blocked. Guard `FindPath` with it to avoid needless searches.
+struct PathStaleNode;
+
+impl BehaviorNode for PathStaleNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![*TARGET_SPEC, PortSpec::new_in("threshold")]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let Some(target) = ctx.get::<[f64; 2]>(*TARGET).copied() else {
+            return BehaviorResult::Fail;
+        };
+        let threshold = ctx
+            .get_parse::<f64>("threshold")
+            .unwrap_or(PATH_STALE_DISTANCE);
+        if arg(&PathStaleCommand { target, threshold })
+            .and_then(|res| res.downcast_ref().copied())
+            .unwrap_or(false)
+        {
+            BehaviorResult::Success
+        } else {
+            BehaviorResult::Fail
+        }
+    }
+}
+
+pub(super) struct PathReadyCommand(pub [f64; 2]);
+
+/// Succeeds if the current path leads all the way to `target`. It fails if there is no path, the
+/// path leads somewhere else, or it was found while the qtree was still being built and may be
+/// partial. Guard `FollowPath` with it to avoid following incomplete paths.
+struct PathReadyNode;
+
+impl BehaviorNode for PathReadyNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![*TARGET_SPEC]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let Some(target) = ctx.get::<[f64; 2]>(*TARGET).copied() else {
+            return BehaviorResult::Fail;
+        };
+        if arg(&PathReadyCommand(target))
+            .and_then(|res| res.downcast_ref().copied())
+            .unwrap_or(false)
+        {
+            BehaviorResult::Success
+        } else {
+            BehaviorResult::Fail
+        }
+    }
+}
+
 pub(super) struct ClearPathNode;
 
 impl BehaviorNode for ClearPathNode {
@@ -319,11 +709,511 @@ impl BehaviorNode for ClearPathNode {
 
 pub(super) struct TargetPosCommand;
 
-struct TargetPosNode;
+struct TargetPosNode;
+
+impl BehaviorNode for TargetPosNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_out("pos")]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        if let Some(pos) =
+            arg(&TargetPosCommand).and_then(|pos| pos.downcast_ref::<[f64; 2]>().copied())
+        {
+            ctx.set("pos", pos);
+            BehaviorResult::Success
+        } else {
+            BehaviorResult::Fail
+        }
+    }
+}
+
+pub(super) struct TargetDistanceCommand;
+
+/// Upper bounds of the "melee", "close" and "medium" ranges of `TargetRange` unless given by ports
+const DEFAULT_RANGE_THRESHOLDS: [f64; 3] = [3., 10., 20.];
+
+/// Classify a distance into a named range by the upper bounds of the first three ranges.
+fn distance_bucket(distance: f64, thresholds: [f64; 3]) -> &'static str {
+    const LABELS: [&str; 3] = ["melee", "close", "medium"];
+    thresholds
+        .iter()
+        .zip(LABELS)
+        .find(|(threshold, _)| distance < **threshold)
+        .map_or("far", |(_, label)| label)
+}
+
+/// Outputs the distance to the current target as one of "melee", "close", "medium" or "far".
+struct TargetRangeNode;
+
+impl BehaviorNode for TargetRangeNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![
+            PortSpec::new_in("melee"),
+            PortSpec::new_in("close"),
+            PortSpec::new_in("medium"),
+            PortSpec::new_out("range"),
+        ]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let Some(distance) =
+            arg(&TargetDistanceCommand).and_then(|res| res.downcast_ref::<f64>().copied())
+        else {
+            return BehaviorResult::Fail;
+        };
+        let mut thresholds = DEFAULT_RANGE_THRESHOLDS;
+        for (threshold, port) in thresholds.iter_mut().zip(["melee", "close", "medium"]) {
+            if let Some(value) = ctx.get_parse::<f64>(port) {
+                *threshold = value;
+            }
+        }
+        ctx.set("range", distance_bucket(distance, thresholds).to_string());
+        BehaviorResult::Success
+    }
+}
+
+pub(super) struct TeamStatCommand;
+
+pub(super) struct TeamStat {
+    /// Number of living agents in the team, including the querying agent
+    pub agents: i32,
+    /// Total resource stored in the team's spawners
+    pub resource: i32,
+    /// Number of kills by the team in the current match
+    pub kills: i32,
+}
+
+/// Outputs statistics of the agent's own team, e.g. to switch to defensive behavior when
+/// outnumbered.
+struct TeamStatNode;
+
+impl BehaviorNode for TeamStatNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![
+            PortSpec::new_out("agents"),
+            PortSpec::new_out("resource"),
+            PortSpec::new_out("kills"),
+        ]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let Some(stat) = arg(&TeamStatCommand).and_then(|res| res.downcast::<TeamStat>().ok())
+        else {
+            return BehaviorResult::Fail;
+        };
+        ctx.set("agents", stat.agents);
+        ctx.set("resource", stat.resource);
+        ctx.set("kills", stat.kills);
+        BehaviorResult::Success
+    }
+}
+
+/// Returns the fraction of the board the team has explored
+pub(super) struct FogCoverageCommand;
+
+/// Default fraction of the board that the team aims to have explored
+const DEFAULT_TARGET_COVERAGE: f64 = 0.8;
+/// Default resource in the team's spawners that the team aims to keep
+const DEFAULT_TARGET_STOCKPILE: f64 = 500.;
+
+/// Decides whether the worker should gather resources or scout the fog in this cycle by
+/// comparing how far the team falls short of the explored fraction `coverage` and of the
+/// resource `stockpile`. Outputs `"scout"` to `role` if exploration is more lacking, and
+/// `"gather"` otherwise, to be branched on before `FindFog` or `FindResource`.
+struct AssignWorkerRoleNode;
+
+impl BehaviorNode for AssignWorkerRoleNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![
+            PortSpec::new_in("coverage"),
+            PortSpec::new_in("stockpile"),
+            PortSpec::new_out("role"),
+        ]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let target_coverage = ctx
+            .get_parse::<f64>("coverage")
+            .unwrap_or(DEFAULT_TARGET_COVERAGE);
+        let target_stockpile = ctx
+            .get_parse::<f64>("stockpile")
+            .unwrap_or(DEFAULT_TARGET_STOCKPILE);
+        let Some(stat) = arg(&TeamStatCommand).and_then(|res| res.downcast::<TeamStat>().ok())
+        else {
+            return BehaviorResult::Fail;
+        };
+        let Some(coverage) =
+            arg(&FogCoverageCommand).and_then(|res| res.downcast_ref::<f64>().copied())
+        else {
+            return BehaviorResult::Fail;
+        };
+        let shortage = |value: f64, target: f64| {
+            if target <= 0. {
+                0.
+            } else {
+                (1. - value / target).clamp(0., 1.)
+            }
+        };
+        let scout =
+            shortage(stat.resource as f64, target_stockpile) < shortage(coverage, target_coverage);
+        ctx.set("role", if scout { "scout" } else { "gather" }.to_string());
+        BehaviorResult::Success
+    }
+}
+
+pub(super) struct InEnemyTerritoryCommand;
+
+/// Succeeds if the enemy team has seen the agent's position at least as recently as the agent's
+/// own team, using the freshness of both teams' fog of war as a proxy of territory.
+struct InEnemyTerritoryNode;
+
+impl BehaviorNode for InEnemyTerritoryNode {
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        _ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        if arg(&InEnemyTerritoryCommand)
+            .and_then(|res| res.downcast_ref().copied())
+            .unwrap_or(false)
+        {
+            BehaviorResult::Success
+        } else {
+            BehaviorResult::Fail
+        }
+    }
+}
+
+pub(super) struct RemainingEnemiesCommand;
+
+/// Outputs the number of living enemy agents and spawners, regardless of the fog of war, e.g. to
+/// switch to an all-out attack when only a few enemies remain.
+struct RemainingEnemiesNode;
+
+impl BehaviorNode for RemainingEnemiesNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_out("count")]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let Some(count) = arg(&RemainingEnemiesCommand).and_then(|res| res.downcast::<i32>().ok())
+        else {
+            return BehaviorResult::Fail;
+        };
+        ctx.set("count", *count);
+        BehaviorResult::Success
+    }
+}
+
+pub(super) struct FlankPosCommand {
+    pub distance: f64,
+    pub behind: bool,
+}
+
+/// Default distance of a flanking position from the target
+const DEFAULT_FLANK_DISTANCE: f64 = 5.;
+
+/// Outputs a passable position to the side of the current target, or behind it if `behind` is
+/// true, relative to the direction the target faces, so that the agent can approach from an angle.
+/// Of the two sides, the one closer to the agent is preferred.
+struct FlankPosNode;
+
+impl BehaviorNode for FlankPosNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![
+            PortSpec::new_in("distance"),
+            PortSpec::new_in("behind"),
+            PortSpec::new_out("pos"),
+        ]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let distance = ctx
+            .get_parse::<f64>("distance")
+            .unwrap_or(DEFAULT_FLANK_DISTANCE);
+        let behind = ctx.get_parse::<bool>("behind").unwrap_or(false);
+        let Some(pos) = arg(&FlankPosCommand { distance, behind })
+            .and_then(|res| res.downcast_ref::<[f64; 2]>().copied())
+        else {
+            return BehaviorResult::Fail;
+        };
+        ctx.set("pos", pos);
+        BehaviorResult::Success
+    }
+}
+
+pub(super) struct RegroupCommand {
+    pub ratio: f64,
+    pub radius: f64,
+}
+
+/// Default ratio of enemies to allies at which agents fall back
+const DEFAULT_REGROUP_RATIO: f64 = 1.5;
+/// Default radius around the agent in which enemies and allies are counted
+const DEFAULT_REGROUP_RADIUS: f64 = 10.;
+
+/// Counts the visible enemy and allied agents within `radius`, and if the enemies outnumber the
+/// allies by `ratio` or more, moves toward the nearest group of allies, or the spawner if there
+/// are none. Returns `Running` with the regrouping position in `pos` while it is moving, and fails
+/// otherwise so that it can be put in front of the usual combat behavior in a fallback.
+struct RegroupIfOutnumberedNode;
+
+impl BehaviorNode for RegroupIfOutnumberedNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![
+            PortSpec::new_in("ratio"),
+            PortSpec::new_in("radius"),
+            PortSpec::new_out("pos"),
+        ]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let ratio = ctx
+            .get_parse::<f64>("ratio")
+            .unwrap_or(DEFAULT_REGROUP_RATIO);
+        let radius = ctx
+            .get_parse::<f64>("radius")
+            .unwrap_or(DEFAULT_REGROUP_RADIUS);
+        let Some(pos) = arg(&RegroupCommand { ratio, radius })
+            .and_then(|res| res.downcast_ref::<[f64; 2]>().copied())
+        else {
+            return BehaviorResult::Fail;
+        };
+        ctx.set("pos", pos);
+        BehaviorResult::Running
+    }
+}
+
+pub(super) struct WaitForSquadCommand {
+    pub fraction: f64,
+    pub radius: f64,
+}
+
+/// Default fraction of the nearby allies that must be ready before the squad attacks
+const DEFAULT_SQUAD_FRACTION: f64 = 0.75;
+/// Default radius around the agent in which allies are counted as the squad
+const DEFAULT_SQUAD_RADIUS: f64 = 10.;
+
+/// Signals that the agent is ready to attack and waits for the allied agents within `radius`.
+/// Succeeds once at least `fraction` of them are also waiting, and runs until then. Put it in
+/// front of an attack in a sequence so that the squad pushes together.
+struct WaitForSquadNode;
+
+impl BehaviorNode for WaitForSquadNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_in("fraction"), PortSpec::new_in("radius")]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let fraction = ctx
+            .get_parse::<f64>("fraction")
+            .unwrap_or(DEFAULT_SQUAD_FRACTION);
+        let radius = ctx
+            .get_parse::<f64>("radius")
+            .unwrap_or(DEFAULT_SQUAD_RADIUS);
+        if arg(&WaitForSquadCommand { fraction, radius })
+            .and_then(|res| res.downcast_ref().copied())
+            .unwrap_or(false)
+        {
+            BehaviorResult::Success
+        } else {
+            BehaviorResult::Running
+        }
+    }
+}
+
+pub(super) struct GoToLastKnownTargetCommand;
+
+/// Follows a path to the position where the target entity was last seen, once it is lost in the
+/// fog. Runs until the agent arrives and succeeds after that, forgetting the position. Fails if
+/// the target is still in sight, the agent has switched targets since, or no position is known.
+struct GoToLastKnownTargetNode;
+
+impl BehaviorNode for GoToLastKnownTargetNode {
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        _ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        arg(&GoToLastKnownTargetCommand)
+            .and_then(|res| res.downcast_ref().copied())
+            .unwrap_or(BehaviorResult::Fail)
+    }
+}
+
+pub(super) struct DodgeBulletCommand;
+
+/// Predicts whether the nearest enemy bullet hits the agent if it stays on its course, and if so,
+/// steps aside perpendicular to the path of the bullet. Returns `Running` with the position it is
+/// moving to in `pos` while dodging, and fails if no bullet threatens the agent.
+struct DodgeBulletNode;
+
+impl BehaviorNode for DodgeBulletNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_out("pos")]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let Some(pos) =
+            arg(&DodgeBulletCommand).and_then(|res| res.downcast_ref::<[f64; 2]>().copied())
+        else {
+            return BehaviorResult::Fail;
+        };
+        ctx.set("pos", pos);
+        BehaviorResult::Running
+    }
+}
+
+pub(super) struct SeparateCommand(pub f64);
+
+/// Default radius around the agent in which allies push it away
+const DEFAULT_SEPARATION_RADIUS: f64 = 3.;
+
+/// Steers away from the allied agents within `radius`, weighted by how close they are, like the
+/// separation rule of boids. Returns `Running` with the position it is moving to in `pos`, and fails
+/// if there are no allies around, so that it can be put in front of other behavior in a fallback.
+struct SeparateNode;
+
+impl BehaviorNode for SeparateNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_in("radius"), PortSpec::new_out("pos")]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let radius = ctx
+            .get_parse::<f64>("radius")
+            .unwrap_or(DEFAULT_SEPARATION_RADIUS);
+        let Some(pos) =
+            arg(&SeparateCommand(radius)).and_then(|res| res.downcast_ref::<[f64; 2]>().copied())
+        else {
+            return BehaviorResult::Fail;
+        };
+        ctx.set("pos", pos);
+        BehaviorResult::Running
+    }
+}
+
+pub(super) struct InterceptCommand;
+
+/// Predicts where the current target will be from its velocity and follows a path to that point
+/// instead of its current position, so that a fleeing target can be cut off. Outputs the intercept
+/// point to `pos` and keeps running while pursuing. Fails if there is no target entity.
+struct InterceptTargetNode;
+
+impl BehaviorNode for InterceptTargetNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_out("pos")]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let Some(pos) =
+            arg(&InterceptCommand).and_then(|res| res.downcast_ref::<[f64; 2]>().copied())
+        else {
+            return BehaviorResult::Fail;
+        };
+        ctx.set("pos", pos);
+        BehaviorResult::Running
+    }
+}
+
+pub(super) struct AttackSpawnerCommand;
+
+/// Targets the visible enemy spawner with the least health, the nearest one among equals, and
+/// follows a path to it. Spawners usually have full health, in which case this is simply the
+/// nearest one, but a spawner that is already damaged is finished off first even if it is farther.
+/// Enemy agents are ignored, so it is meant for rushing the enemy economy.
+/// Outputs the spawner's id to `target` and keeps running while approaching. Fails if no enemy
+/// spawner is visible.
+struct AttackSpawnerNode;
+
+impl BehaviorNode for AttackSpawnerNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_out("target")]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let Some(target) =
+            arg(&AttackSpawnerCommand).and_then(|res| res.downcast_ref::<usize>().copied())
+        else {
+            return BehaviorResult::Fail;
+        };
+        ctx.set("target", target);
+        BehaviorResult::Running
+    }
+}
+
+pub(super) struct PerimeterCommand {
+    pub distance: f64,
+}
+
+/// Default distance of the patrol route from the spawner
+const DEFAULT_PERIMETER_DISTANCE: f64 = 10.;
+/// Distance to a patrol point at which the agent heads to the next one
+const PATROL_ARRIVE_DISTANCE: f64 = 1.5;
+
+/// Patrols a loop of points `distance` away from the agent's own spawner, found with
+/// `QTree::find_contour`, following a path to each point in turn. The route is held by the node
+/// and computed again only when the distance changes. Keeps running while patrolling and fails if
+/// no route is found.
+#[derive(Default)]
+struct PatrolPerimeterNode {
+    distance: f64,
+    points: Vec<[f64; 2]>,
+    next: usize,
+}
 
-impl BehaviorNode for TargetPosNode {
+impl BehaviorNode for PatrolPerimeterNode {
     fn provided_ports(&self) -> Vec<PortSpec> {
-        vec![PortSpec::new_out("pos")]
+        vec![PortSpec::new_in("distance")]
     }
 
     fn tick(
@@ -331,20 +1221,40 @@ impl BehaviorNode for TargetPosNode {
         arg: BehaviorCallback,
         ctx: &mut behavior_tree_lite::Context,
     ) -> BehaviorResult {
-        if let Some(pos) =
-            arg(&TargetPosCommand).and_then(|pos| pos.downcast_ref::<[f64; 2]>().copied())
-        {
-            ctx.set("pos", pos);
-            BehaviorResult::Success
-        } else {
-            BehaviorResult::Fail
+        let distance = ctx
+            .get_parse::<f64>("distance")
+            .unwrap_or(DEFAULT_PERIMETER_DISTANCE);
+        if self.points.is_empty() || self.distance != distance {
+            self.distance = distance;
+            self.points = arg(&PerimeterCommand { distance })
+                .and_then(|res| res.downcast_ref::<Vec<[f64; 2]>>().cloned())
+                .unwrap_or_default();
+            self.next = 0;
+        }
+        if self.points.is_empty() {
+            return BehaviorResult::Fail;
+        }
+        let Some(state) =
+            arg(&GetStateCommand).and_then(|val| val.downcast_ref::<AgentState>().cloned())
+        else {
+            return BehaviorResult::Fail;
+        };
+        let next = Vector2::from(self.points[self.next % self.points.len()]);
+        if next.distance(Vector2::new(state.x, state.y)) < PATROL_ARRIVE_DISTANCE {
+            self.next = (self.next + 1) % self.points.len();
         }
+        arg(&GoToPositionCommand(self.points[self.next]));
+        BehaviorResult::Running
     }
 }
 
 pub(super) struct FindPathCommand {
     pub target: [f64; 2],
     pub ignore_obstacles: bool,
+    /// Prefer routes the enemy team has not seen recently
+    pub stealth: bool,
+    /// Search the board cells with `grid_path_find` instead of the qtree
+    pub grid: bool,
 }
 
 pub(super) struct FindPathNode;
@@ -354,6 +1264,8 @@ impl BehaviorNode for FindPathNode {
         vec![
             *TARGET_SPEC,
             PortSpec::new_in("ignore_obstacles"),
+            PortSpec::new_in("stealth"),
+            PortSpec::new_in("grid"),
             PortSpec::new_out("path"),
             PortSpec::new_out("fail_reason"),
         ]
@@ -370,6 +1282,8 @@ impl BehaviorNode for FindPathNode {
         let path_find_result = arg(&FindPathCommand {
             target: *target,
             ignore_obstacles: ctx.get_parse::<bool>("ignore_obstacles").unwrap_or(false),
+            stealth: ctx.get_parse::<bool>("stealth").unwrap_or(false),
+            grid: ctx.get_parse::<bool>("grid").unwrap_or(false),
         })
         .and_then(|res| {
             res.downcast::<Result<Vec<QTreePathNode>, PathFindError>>()
@@ -402,12 +1316,8 @@ impl BehaviorNode for DigestPathNode {
                 "output",
                 format!(
                     "{{nodes: {}, length: {:.03}}}",
-                    path.len(),
-                    path.iter().zip(path.iter().skip(1)).fold(0., |acc, cur| {
-                        let prev = Vector2::from(cur.0.pos);
-                        let next = Vector2::from(cur.1.pos);
-                        acc + prev.distance(next)
-                    })
+                    path_node_count(path),
+                    path_length(path)
                 ),
             );
             BehaviorResult::Success
@@ -417,6 +1327,27 @@ impl BehaviorNode for DigestPathNode {
     }
 }
 
+/// Outputs the length of the path in `input` to `length`, to decide e.g. whether a target is
+/// too far to chase. Fails if there is no path.
+struct PathCostNode;
+
+impl BehaviorNode for PathCostNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_in("input"), PortSpec::new_out("length")]
+    }
+
+    fn tick(&mut self, _arg: BehaviorCallback, ctx: &mut Context) -> BehaviorResult {
+        let Some(length) = ctx
+            .get::<Vec<QTreePathNode>>("input")
+            .map(|path| path_length(path))
+        else {
+            return BehaviorResult::Fail;
+        };
+        ctx.set("length", length);
+        BehaviorResult::Success
+    }
+}
+
 #[derive(Clone, Copy)]
 pub(super) struct FollowPathCommand;
 
@@ -506,6 +1437,130 @@ impl BehaviorNode for MoveToNode {
     }
 }
 
+pub(super) struct TurretCommand {
+    pub range: f64,
+}
+
+/// Default distance within which a turret engages enemies
+const DEFAULT_TURRET_RANGE: f64 = 15.;
+
+/// Holds the position and engages the nearest enemy within `range`: stops, turns toward the enemy
+/// and shoots once facing it. It never moves or finds a path. Succeeds when a bullet is fired,
+/// runs while turning or reloading and fails if there is no enemy in range.
+struct TurretNode;
+
+impl BehaviorNode for TurretNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_in("range")]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let range = ctx
+            .get_parse::<f64>("range")
+            .unwrap_or(DEFAULT_TURRET_RANGE);
+        match arg(&TurretCommand { range }).and_then(|res| res.downcast_ref::<bool>().copied()) {
+            Some(true) => BehaviorResult::Success,
+            Some(false) => BehaviorResult::Running,
+            None => BehaviorResult::Fail,
+        }
+    }
+}
+
+/// Succeeds if the agent has ammo left. It always does unless `GameParams::limited_ammo` is
+/// enabled.
+pub(super) struct HasAmmoNode;
+
+impl BehaviorNode for HasAmmoNode {
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        _ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        if arg(self)
+            .and_then(|res| res.downcast_ref().copied())
+            .unwrap_or(false)
+        {
+            BehaviorResult::Success
+        } else {
+            BehaviorResult::Fail
+        }
+    }
+}
+
+/// Refills ammo while the agent stays next to a friendly spawner. Runs while reloading, succeeds
+/// when the ammo is full and fails if there is no friendly spawner nearby.
+pub(super) struct ReloadNode;
+
+impl BehaviorNode for ReloadNode {
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        _ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        arg(self)
+            .and_then(|res| res.downcast_ref().copied())
+            .unwrap_or(BehaviorResult::Fail)
+    }
+}
+
+pub(super) struct SeekRepairCommand;
+
+/// Follows a path to the nearest friendly repair point, which is a spawner or an agent with the
+/// role `repair`, and stays there while the agent is repaired. Runs while the agent is damaged
+/// and fails if it has full health or there is no repair point.
+struct SeekRepairNode;
+
+impl BehaviorNode for SeekRepairNode {
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        _ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        arg(&SeekRepairCommand)
+            .and_then(|res| res.downcast_ref().copied())
+            .unwrap_or(BehaviorResult::Fail)
+    }
+}
+
+pub(super) struct EscortCommand {
+    pub ally: usize,
+    pub radius: f64,
+}
+
+/// Default distance from the escortee that an escort may drift before catching up
+const DEFAULT_ESCORT_RADIUS: f64 = 5.;
+
+/// Keeps the agent within `radius` of the allied entity whose id is given in `ally`, following a
+/// path to catch up when it falls behind, and targets visible enemies that come close to the
+/// escortee. Runs while the escortee is alive and fails otherwise.
+struct EscortNode;
+
+impl BehaviorNode for EscortNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_in("ally"), PortSpec::new_in("radius")]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let Some(ally) = ctx.get_parse::<usize>("ally") else {
+            return BehaviorResult::Fail;
+        };
+        let radius = ctx
+            .get_parse::<f64>("radius")
+            .unwrap_or(DEFAULT_ESCORT_RADIUS);
+        arg(&EscortCommand { ally, radius })
+            .and_then(|res| res.downcast_ref().copied())
+            .unwrap_or(BehaviorResult::Fail)
+    }
+}
+
 pub(super) struct ShootCommand;
 
 pub(super) struct ShootNode;
@@ -521,6 +1576,58 @@ impl BehaviorNode for ShootNode {
     }
 }
 
+pub(super) struct FightingRetreatCommand;
+
+/// Backs away from the current target while facing it, shooting whenever the cooldown allows.
+/// Keeps running as long as there is a target and fails otherwise.
+struct FightingRetreatNode;
+
+impl BehaviorNode for FightingRetreatNode {
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        _ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        if arg(&FightingRetreatCommand).is_some() {
+            BehaviorResult::Running
+        } else {
+            BehaviorResult::Fail
+        }
+    }
+}
+
+pub(super) struct BaitCommand {
+    pub distance: f64,
+}
+
+/// Default distance from the target that `Bait` approaches to
+const DEFAULT_BAIT_DISTANCE: f64 = 10.;
+
+/// Approaches the target entity up to `distance` to draw its attention, and once the target
+/// commits to chasing the agent, falls back to the nearest ally while shooting at it. Runs
+/// during the maneuver and succeeds when the agent is back with the ally. Fails without a
+/// target entity.
+struct BaitNode;
+
+impl BehaviorNode for BaitNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_in("distance")]
+    }
+
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let distance = ctx
+            .get_parse::<f64>("distance")
+            .unwrap_or(DEFAULT_BAIT_DISTANCE);
+        arg(&BaitCommand { distance })
+            .and_then(|res| res.downcast_ref().copied())
+            .unwrap_or(BehaviorResult::Fail)
+    }
+}
+
 struct TimeoutNode(Option<usize>);
 
 impl BehaviorNode for TimeoutNode {
@@ -550,6 +1657,52 @@ impl BehaviorNode for TimeoutNode {
     }
 }
 
+/// Succeeds once every `n` ticks, starting from the first tick, and fails otherwise. Put it
+/// before an expensive node in a `Sequence` to rate limit it, e.g.
+///
+/// ```txt
+/// Sequence {
+///     EveryNTicks (n <- "10")
+///     FindPath (target <- target)
+/// }
+/// ```
+///
+/// Unlike `Throttle`, it has no child, so the rest of the sequence can be arbitrary. The count is
+/// held by the node, which every agent has its own instance of, and advances only when the node
+/// is ticked.
+struct EveryNTicksNode(usize);
+
+impl BehaviorNode for EveryNTicksNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_in("n")]
+    }
+
+    fn tick(
+        &mut self,
+        _arg: BehaviorCallback,
+        ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let Some(n) = ctx.get_parse::<usize>("n").filter(|n| 0 < *n) else {
+            return BehaviorResult::Fail;
+        };
+        let pass = self.0.is_multiple_of(n);
+        self.0 = (self.0 + 1) % n;
+        if pass {
+            BehaviorResult::Success
+        } else {
+            BehaviorResult::Fail
+        }
+    }
+}
+
+/// Samples a value in `min..max` from the agent's own random number generator. The node outputs
+/// `min` without sampling if `max == min`, and fails with a message in the agent's log if
+/// `max < min`.
+pub(super) struct RandomizeCommand {
+    pub min: usize,
+    pub max: usize,
+}
+
 struct RandomizeNode;
 
 impl BehaviorNode for RandomizeNode {
@@ -563,13 +1716,26 @@ impl BehaviorNode for RandomizeNode {
 
     fn tick(
         &mut self,
-        _arg: BehaviorCallback,
+        arg: BehaviorCallback,
         ctx: &mut behavior_tree_lite::Context,
     ) -> BehaviorResult {
         if let Some(max) = ctx.get_parse::<usize>("max") {
             let min = ctx.get_parse::<usize>("min").unwrap_or(0);
-            let between = Uniform::from(min..max);
-            let value = between.sample(&mut rand::thread_rng());
+            if max < min {
+                arg(&PrintCommand(format!(
+                    "Randomize: max ({max}) is less than min ({min})"
+                )));
+                return BehaviorResult::Fail;
+            }
+            if max == min {
+                ctx.set::<usize>("value", min);
+                return BehaviorResult::Success;
+            }
+            let Some(value) = arg(&RandomizeCommand { min, max })
+                .and_then(|res| res.downcast_ref::<usize>().copied())
+            else {
+                return BehaviorResult::Fail;
+            };
             // println!("Randomizing! {}/{}", value, max);
             ctx.set::<usize>("value", value);
             return BehaviorResult::Success;
@@ -748,6 +1914,155 @@ impl BehaviorNode for NewPositionNode {
     }
 }
 
+/// Outputs the agent's current position to `pos`, so that it can be bound to a blackboard
+/// variable and returned to later with `GoToSavedPosition`.
+struct SavePositionNode;
+
+impl BehaviorNode for SavePositionNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_out("pos")]
+    }
+
+    fn tick(&mut self, arg: BehaviorCallback, ctx: &mut Context) -> BehaviorResult {
+        let Some(state) =
+            arg(&GetStateCommand).and_then(|val| val.downcast_ref::<AgentState>().cloned())
+        else {
+            return BehaviorResult::Fail;
+        };
+        let pos: [f64; 2] = [state.x, state.y];
+        ctx.set("pos", pos);
+        BehaviorResult::Success
+    }
+}
+
+pub(super) struct GoToPositionCommand(pub [f64; 2]);
+
+/// Follows a path back to `pos`, typically saved by `SavePosition`. Keeps running until the
+/// agent arrives and succeeds after that. Fails if `pos` is not set.
+struct GoToSavedPositionNode;
+
+impl BehaviorNode for GoToSavedPositionNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_in("pos")]
+    }
+
+    fn tick(&mut self, arg: BehaviorCallback, ctx: &mut Context) -> BehaviorResult {
+        let Some(pos) = ctx.get::<[f64; 2]>("pos").copied() else {
+            return BehaviorResult::Fail;
+        };
+        let Some(state) =
+            arg(&GetStateCommand).and_then(|val| val.downcast_ref::<AgentState>().cloned())
+        else {
+            return BehaviorResult::Fail;
+        };
+        if Vector2::from(pos).distance(Vector2::new(state.x, state.y)) < ARRIVE_DISTANCE {
+            return BehaviorResult::Success;
+        }
+        arg(&GoToPositionCommand(pos));
+        BehaviorResult::Running
+    }
+}
+
+/// Returns the reachable position nearest to the given one, or `None` if there is none.
+pub(super) struct NearestReachableCommand(pub [f64; 2]);
+
+/// Moves to the reachable position nearest to `pos`, which is `pos` itself unless it is walled
+/// off, e.g. an enemy spawner in an enclosed region. The position is looked up again only when
+/// `pos` changes. Succeeds once the agent arrives and fails if there is no reachable position.
+#[derive(Default)]
+struct MoveToNearestReachableNode {
+    /// The last `pos` and the reachable position found for it
+    goal: Option<([f64; 2], [f64; 2])>,
+}
+
+impl BehaviorNode for MoveToNearestReachableNode {
+    fn provided_ports(&self) -> Vec<PortSpec> {
+        vec![PortSpec::new_in("pos")]
+    }
+
+    fn tick(&mut self, arg: BehaviorCallback, ctx: &mut Context) -> BehaviorResult {
+        let Some(pos) = ctx.get::<[f64; 2]>("pos").copied() else {
+            return BehaviorResult::Fail;
+        };
+        let goal = match self.goal {
+            Some((target, goal)) if target == pos => goal,
+            _ => {
+                let Some(goal) = arg(&NearestReachableCommand(pos))
+                    .and_then(|res| res.downcast_ref::<Option<[f64; 2]>>().copied())
+                    .flatten()
+                else {
+                    return BehaviorResult::Fail;
+                };
+                self.goal = Some((pos, goal));
+                goal
+            }
+        };
+        let Some(state) =
+            arg(&GetStateCommand).and_then(|val| val.downcast_ref::<AgentState>().cloned())
+        else {
+            return BehaviorResult::Fail;
+        };
+        if Vector2::from(goal).distance(Vector2::new(state.x, state.y)) < ARRIVE_DISTANCE {
+            self.goal = None;
+            return BehaviorResult::Success;
+        }
+        arg(&GoToPositionCommand(goal));
+        BehaviorResult::Running
+    }
+}
+
+/// Returns the best position by terrain around the agent, or `None` if there is none.
+pub(super) struct SeekTerrainCommand {
+    /// Seek open ground with the most distance to walls instead of a chokepoint
+    pub open: bool,
+}
+
+/// Moves to a nearby chokepoint, i.e. the narrowest passage around the agent, or to open ground
+/// far from walls if `open` is set. The position is chosen on the first tick and kept until the
+/// agent arrives there, at which point the node succeeds. Fails if there is no such position.
+struct SeekTerrainNode {
+    open: bool,
+    goal: Option<[f64; 2]>,
+}
+
+impl SeekTerrainNode {
+    fn new(open: bool) -> Self {
+        Self { open, goal: None }
+    }
+}
+
+impl BehaviorNode for SeekTerrainNode {
+    fn tick(
+        &mut self,
+        arg: BehaviorCallback,
+        _ctx: &mut behavior_tree_lite::Context,
+    ) -> BehaviorResult {
+        let goal = match self.goal {
+            Some(goal) => goal,
+            None => {
+                let Some(goal) = arg(&SeekTerrainCommand { open: self.open })
+                    .and_then(|res| res.downcast_ref::<Option<[f64; 2]>>().copied())
+                    .flatten()
+                else {
+                    return BehaviorResult::Fail;
+                };
+                *self.goal.insert(goal)
+            }
+        };
+        let Some(state) =
+            arg(&GetStateCommand).and_then(|val| val.downcast_ref::<AgentState>().cloned())
+        else {
+            return BehaviorResult::Fail;
+        };
+        if Vector2::from(goal).distance(Vector2::new(state.x, state.y)) < ARRIVE_DISTANCE {
+            self.goal = None;
+            return BehaviorResult::Success;
+        }
+        arg(&GoToPositionCommand(goal));
+        BehaviorResult::Running
+    }
+}
+
 pub(super) struct IsTargetVisibleCommand(pub [f64; 2]);
 pub(crate) struct IsTargetVisibleNode;
 
@@ -800,3 +2115,144 @@ impl BehaviorNode for FaceToTargetNode {
         }
     }
 }
+
+pub(super) struct OrientAlongPathCommand;
+
+/// Face toward the next node of the path. Fails if there is no path, and keeps running until the
+/// agent is aligned with it. It can be ticked before `FollowPath` to turn before moving.
+struct OrientAlongPathNode;
+
+impl BehaviorNode for OrientAlongPathNode {
+    fn tick(&mut self, arg: BehaviorCallback, _ctx: &mut Context) -> BehaviorResult {
+        let Some(val) = arg(&OrientAlongPathCommand)
+            .and_then(|val| val.downcast_ref::<Option<OrientToResult>>().copied())
+        else {
+            return BehaviorResult::Fail;
+        };
+
+        match val {
+            Some(OrientToResult::Arrived) => BehaviorResult::Success,
+            Some(OrientToResult::Blocked) => BehaviorResult::Fail,
+            _ => BehaviorResult::Running,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use behavior_tree_lite::PortType;
+
+    #[test]
+    fn test_broken_tree() {
+        let source = r#"tree main = Sequence {
+    FindEnemy
+}
+
+tree broken = Sequence {
+    Print (input <- "missing paren"
+}
+"#;
+        match build_tree(source) {
+            Err(BuildTreeError::Parse { line, column, .. }) => {
+                assert_eq!(line, 5);
+                assert!(1 <= column);
+            }
+            Err(e) => panic!("Unexpected error: {e}"),
+            Ok(_) => panic!("Broken tree should not build"),
+        }
+        assert!(matches!(
+            build_tree("tree main = Sequence {\n    NoSuchNode\n}\n"),
+            Err(BuildTreeError::Load(_))
+        ));
+    }
+
+    #[test]
+    fn test_distance_bucket() {
+        let thresholds = [3., 10., 20.];
+        for (distance, expected) in [
+            (0., "melee"),
+            (2.99, "melee"),
+            (3., "close"),
+            (9.99, "close"),
+            (10., "medium"),
+            (19.99, "medium"),
+            (20., "far"),
+            (1000., "far"),
+        ] {
+            assert_eq!(
+                distance_bucket(distance, thresholds),
+                expected,
+                "{distance}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_describe_nodes() {
+        let docs = describe_nodes();
+        let ports = |name: &str| {
+            let doc = docs
+                .iter()
+                .find(|doc| doc.name == name)
+                .unwrap_or_else(|| panic!("{name} was not described"));
+            doc.ports
+                .iter()
+                .map(|port| (port.key.to_string(), port.ty))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ports("MoveTo"), [("pos".to_string(), PortType::Input)]);
+        let find_path = ports("FindPath");
+        assert!(find_path.contains(&("target".to_string(), PortType::Input)));
+        assert!(find_path.contains(&("path".to_string(), PortType::Output)));
+        assert!(ports("Shoot").is_empty());
+        assert!(docs.iter().any(|doc| doc.name == "Sequence"));
+        assert!(docs.iter().any(|doc| doc.name == "Print"));
+    }
+
+    #[test]
+    fn test_every_n_ticks() {
+        use crate::{
+            agent::Agent,
+            entity::Entity,
+            game::{Game, GameParams},
+            qtree::CellState,
+        };
+        use std::{cell::RefCell, rc::Rc};
+
+        let mut game = Game::new();
+        let mut params = GameParams::new();
+        params.fow = false;
+        game.set_params(&params);
+        let (xs, ys) = game.shape();
+        let pos = (0..ys)
+            .flat_map(|y| (0..xs).map(move |x| [x as f64 + 0.5, y as f64 + 0.5]))
+            .find(|pos| matches!(game.qtree.find(*pos), Some((_, CellState::Free))))
+            .unwrap();
+        let source = r#"tree main = Sequence {
+    EveryNTicks (n <- "3")
+    Print (input <- "pass")
+}"#;
+        let agent = Agent::new(
+            &mut game.id_gen,
+            pos,
+            0.,
+            0,
+            AgentClass::Worker,
+            Rc::new(source.to_string()),
+        )
+        .unwrap();
+        game.entities.push(RefCell::new(Entity::Agent(agent)));
+
+        for _ in 0..10 {
+            game.update();
+        }
+
+        let entity = game.entities[0].borrow();
+        let Entity::Agent(agent) = &*entity else {
+            unreachable!()
+        };
+        // Passes at the ticks 0, 3, 6 and 9
+        assert_eq!(agent.log_buffer().len(), 4);
+    }
+}
@@ -2,12 +2,16 @@ use std::cell::{Cell, RefCell};
 
 use cgmath::{MetricSpace, Vector2};
 
-use super::{behavior_nodes::FindPathCommand, Agent, AgentTarget, AGENT_HALFLENGTH};
+use super::{behavior_nodes::FindPathCommand, Agent, AgentTarget};
 use crate::{
     fog_of_war::FOG_MAX_AGE,
     game::{Game, Profiler},
-    measure_time,
-    qtree::{qtree::PathFindError, PathFindResponse, QTreePathNode, QTreeSearcher},
+    grid_astar::grid_path_find,
+    measure_time_if,
+    qtree::{
+        qtree::PathFindError, simplify_path, PathFindResponse, QTreePath, QTreePathNode,
+        QTreeSearcher, SearchTree,
+    },
     CellState,
 };
 
@@ -17,42 +21,108 @@ impl Agent {
         com: &FindPathCommand,
         game: &mut Game,
     ) -> Result<Vec<QTreePathNode>, PathFindError> {
-        let ((found_path, search_tree), time) = measure_time(|| {
-            let qtree = &game.qtree;
-            let target = com.target;
-            let fog = |pos| game.is_fog_older_than(self.team, pos, FOG_MAX_AGE);
-            if com.ignore_obstacles {
-                qtree.path_find(|_| true, self.pos, target, &fog, AGENT_HALFLENGTH * 1.5)
-            } else if let Some(AgentTarget::Entity(tgt_id)) = self.target {
-                qtree.path_find(
-                    ignore_id(&[self.id, tgt_id]),
-                    self.pos,
-                    target,
-                    &fog,
-                    AGENT_HALFLENGTH * 1.5,
-                )
-            } else {
-                qtree.path_find(
-                    ignore_id(&[self.id]),
-                    self.pos,
-                    target,
-                    &fog,
-                    AGENT_HALFLENGTH * 1.5,
-                )
-            }
-        });
-        game.path_find_profiler.get_mut().add(time);
+        let ((found_path, search_tree), time) =
+            measure_time_if(game.params.profiling_enabled, || {
+                let qtree = &game.qtree;
+                let clearance = self.class.shape().0 * 1.5;
+                let target = game.path_goal(com.target);
+                let fog = |pos| game.is_fog_older_than(self.team, pos, FOG_MAX_AGE);
+                let stealth_cost = |pos| game.enemy_fog_cost_at(self.team, pos);
+                let cell_cost = if com.stealth {
+                    Some(&stealth_cost as &dyn Fn([f64; 2]) -> f64)
+                } else {
+                    None
+                };
+                // Grid A* is the fallback when the qtree is not available
+                if com.grid || !qtree.is_initialized() {
+                    (self.grid_path(game, target, clearance), SearchTree::new())
+                } else if com.ignore_obstacles {
+                    qtree.path_find(|_| true, self.pos, target, &fog, clearance, cell_cost)
+                } else if let Some(AgentTarget::Entity(tgt_id)) = self.target {
+                    qtree.path_find(
+                        ignore_id(&[self.id, tgt_id]),
+                        self.pos,
+                        target,
+                        &fog,
+                        clearance,
+                        cell_cost,
+                    )
+                } else {
+                    qtree.path_find(
+                        ignore_id(&[self.id]),
+                        self.pos,
+                        target,
+                        &fog,
+                        clearance,
+                        cell_cost,
+                    )
+                }
+            });
+        if let Some(time) = time {
+            game.path_find_profiler.get_mut().add(time);
+        }
         self.search_tree = Some(search_tree);
         match found_path {
             Ok(mut path) => {
+                // String-pulling would cut through the cells that stealth path finding avoided
+                if !com.stealth {
+                    simplify_path(
+                        &mut path,
+                        game.path_board(),
+                        game.shape(),
+                        self.class.shape().1,
+                    );
+                }
                 self.shortcut_path(&mut path, &game.qtree);
                 self.path = path.clone();
+                self.path_target = Some(com.target);
+                self.path_complete = com.grid || !game.qtree.is_building();
                 Ok(path)
             }
             Err(err) => Err(err),
         }
     }
 
+    /// Give up the current target entity if the last `FindPath` failed while aiming at it, by
+    /// adding it to `unreachables` so that it is not selected again. Returns whether it gave up.
+    pub(super) fn abandon_unreachable_target(&mut self) -> bool {
+        let Some(AgentTarget::Entity(id)) = self.target else {
+            return false;
+        };
+        if self.path_failed_target != Some(id) {
+            return false;
+        }
+        self.unreachables.insert(id);
+        self.target = None;
+        self.path_failed_target = None;
+        true
+    }
+
+    /// Find a path with `grid_path_find`, in the same order as the qtree path, from the goal to
+    /// the start. Unlike the qtree, it does not avoid other entities.
+    fn grid_path(
+        &self,
+        game: &Game,
+        target: [f64; 2],
+        goal_radius: f64,
+    ) -> Result<QTreePath, PathFindError> {
+        let to_cell = |pos: [f64; 2]| [pos[0] as i32, pos[1] as i32];
+        let cells = grid_path_find(
+            game.path_board(),
+            game.shape(),
+            to_cell(self.pos),
+            to_cell(target),
+        )
+        .ok_or(PathFindError::SearchFailed)?;
+        Ok(std::iter::once(QTreePathNode::new(target, goal_radius))
+            .chain(
+                cells.iter().rev().map(|cell| {
+                    QTreePathNode::new([cell[0] as f64 + 0.5, cell[1] as f64 + 0.5], 0.5)
+                }),
+            )
+            .collect())
+    }
+
     /// Shortcut last few nodes if it's still visible. It won't attempt to shortcut the whole path
     /// since line-of-sight check can be expensive.
     fn shortcut_path(&mut self, path: &mut Vec<QTreePathNode>, qtree: &QTreeSearcher) {
@@ -101,20 +171,61 @@ impl Agent {
         }
     }
 
+    /// Returns true if the current path should be recomputed, i.e. there is no path, `target` has
+    /// moved more than `threshold` away from the goal of the path, or a node of the path is blocked.
+    pub(crate) fn is_path_stale(&self, target: [f64; 2], threshold: f64, game: &Game) -> bool {
+        if self.path.is_empty() {
+            return true;
+        }
+        let Some(path_target) = self.path_target else {
+            return true;
+        };
+        if threshold.powi(2) < Vector2::from(path_target).distance2(Vector2::from(target)) {
+            return true;
+        }
+        let target_id = match self.target {
+            Some(AgentTarget::Entity(id)) => Some(id),
+            _ => None,
+        };
+        self.path
+            .iter()
+            .any(|node| match game.qtree.find(node.pos) {
+                Some((_, CellState::Obstacle)) => true,
+                Some((_, CellState::Occupied(id))) => id != self.id && Some(id) != target_id,
+                _ => false,
+            })
+    }
+
+    /// Returns true if the current path is complete and its goal is within the goal radius of
+    /// `target`.
+    pub(crate) fn is_path_ready(&self, target: [f64; 2]) -> bool {
+        let (Some(goal), Some(path_target)) = (self.path.first(), self.path_target) else {
+            return false;
+        };
+        self.path_complete
+            && Vector2::from(path_target).distance(Vector2::from(target)) <= goal.radius
+    }
+
     pub(super) fn find_path_many(
         &mut self,
         qtree: &QTreeSearcher,
-        path_find_profiler: &RefCell<Profiler>,
+        path_find_profiler: Option<&RefCell<Profiler>>,
         cond: impl FnMut([f64; 2]) -> PathFindResponse,
     ) -> Result<Vec<QTreePathNode>, PathFindError> {
         let ((found_path, search_tree), time) =
-            measure_time(|| qtree.path_find_many(ignore_id(&[self.id]), self.pos, cond, 1.));
-        let _ = path_find_profiler.try_borrow_mut().map(|mut p| p.add(time));
+            measure_time_if(path_find_profiler.is_some(), || {
+                qtree.path_find_many(ignore_id(&[self.id]), self.pos, cond, 1.)
+            });
+        if let (Some(path_find_profiler), Some(time)) = (path_find_profiler, time) {
+            let _ = path_find_profiler.try_borrow_mut().map(|mut p| p.add(time));
+        }
         self.search_tree = Some(search_tree);
         match found_path {
             Ok(mut path) => {
                 self.shortcut_path(&mut path, qtree);
                 self.path = path.clone();
+                self.path_target = path.first().map(|node| node.pos);
+                self.path_complete = !qtree.is_building();
                 Ok(path)
             }
             Err(err) => Err(err),
@@ -2,7 +2,11 @@ use std::cell::RefCell;
 
 use cgmath::{InnerSpace, Vector2};
 
-use crate::{entity::Entity, game::is_passable_at, game::Game};
+use crate::{
+    entity::Entity,
+    game::is_passable_at,
+    game::{Game, Terrain, SLOW_TERRAIN_SPEED},
+};
 
 use super::{wrap_angle, Agent, AgentState, MotionResult};
 
@@ -136,7 +140,11 @@ impl Agent {
         others: &[RefCell<Entity>],
     ) -> bool {
         let forward = Vector2::new(self.orient.cos(), self.orient.sin());
-        let speed = self.class.speed();
+        let speed = if game.terrain_at(self.pos) == Terrain::Slow {
+            self.class.speed() * SLOW_TERRAIN_SPEED
+        } else {
+            self.class.speed()
+        };
         let target_pos = Vector2::from(self.pos) + drive.min(speed).max(-speed) * forward;
         let target_state = AgentState {
             x: target_pos.x,
@@ -144,7 +152,14 @@ impl Agent {
             heading: self.orient,
         };
 
-        if Self::collision_check(Some(self.id), target_state, self.class, others, false) {
+        if Self::collision_check(
+            Some(self.id),
+            target_state,
+            self.class,
+            self.collision_layers(),
+            others,
+            false,
+        ) {
             self.speed = 0.;
             return false;
         }
@@ -0,0 +1,181 @@
+//! Recording of (observation, action) pairs of agents for offline training of policies.
+
+use std::cell::RefCell;
+
+use cgmath::{InnerSpace, Vector2};
+
+use super::{Agent, AgentTarget};
+use crate::{entity::Entity, game::Game};
+
+/// Maximum number of nearby entities in an observation, nearest first
+const MAX_NEARBY: usize = 8;
+/// Range within which entities are observed
+const OBSERVATION_RANGE: f64 = 20.;
+/// Number of fog samples along each axis of the grid around the agent
+const FOG_GRID: i32 = 5;
+/// Distance between fog samples
+const FOG_SPACING: f64 = 4.;
+
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct NearbyEntity {
+    /// Position relative to the observing agent
+    pub delta: [f64; 2],
+    pub enemy: bool,
+    pub is_agent: bool,
+    pub health_rate: f64,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Observation {
+    pub pos: [f64; 2],
+    pub orient: f64,
+    pub health: u32,
+    pub resource: i32,
+    /// Position of the current target relative to the agent, if any
+    pub target: Option<[f64; 2]>,
+    pub nearby: Vec<NearbyEntity>,
+    /// Whether the fog is clear on a `FOG_GRID` x `FOG_GRID` grid centered at the agent, row major
+    pub fog: Vec<bool>,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrainingAction {
+    Idle,
+    Drive(f64),
+    MoveTo([f64; 2]),
+    MoveBackTo([f64; 2]),
+    FollowPath,
+    FaceToTarget([f64; 2]),
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrainingSample {
+    pub tick: i32,
+    pub observation: Observation,
+    pub action: TrainingAction,
+    pub shoot: bool,
+}
+
+impl Agent {
+    pub(super) fn observe(&self, game: &Game, entities: &[RefCell<Entity>]) -> Observation {
+        let self_pos = Vector2::from(self.pos);
+        let mut target = None;
+        let mut nearby: Vec<_> = entities
+            .iter()
+            .filter_map(|entity| {
+                let entity = entity.try_borrow().ok()?;
+                let delta = Vector2::from(entity.get_pos()) - self_pos;
+                if matches!(self.target, Some(AgentTarget::Entity(id)) if id == entity.get_id()) {
+                    target = Some(delta.into());
+                }
+                if OBSERVATION_RANGE.powi(2) < delta.magnitude2() {
+                    return None;
+                }
+                Some(NearbyEntity {
+                    delta: delta.into(),
+                    enemy: entity.get_team() != self.team,
+                    is_agent: entity.is_agent(),
+                    health_rate: entity.get_health_rate(),
+                })
+            })
+            .collect();
+        nearby.sort_by(|a, b| {
+            let (da, db) = (Vector2::from(a.delta), Vector2::from(b.delta));
+            da.magnitude2().total_cmp(&db.magnitude2())
+        });
+        nearby.truncate(MAX_NEARBY);
+
+        if let Some(AgentTarget::Resource(pos) | AgentTarget::Fog(pos)) = self.target {
+            target = Some((Vector2::from(pos) - self_pos).into());
+        }
+
+        let half = FOG_GRID / 2;
+        let fog = (-half..=half)
+            .flat_map(|y| (-half..=half).map(move |x| [x as f64, y as f64]))
+            .map(|[x, y]| {
+                let pos = [self.pos[0] + x * FOG_SPACING, self.pos[1] + y * FOG_SPACING];
+                game.is_clear_fog_at(self.team, pos)
+            })
+            .collect();
+
+        Observation {
+            pos: self.pos,
+            orient: self.orient,
+            health: self.health,
+            resource: self.resource,
+            target,
+            nearby,
+            fog,
+        }
+    }
+
+    /// Samples recorded while `Game::training` is enabled, oldest first.
+    pub fn training_samples(&self) -> &[TrainingSample] {
+        &self.training_samples
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{agent::AgentClass, game::GameParams, CellState};
+    use std::rc::Rc;
+
+    #[test]
+    fn test_training_samples() {
+        let mut game = Game::new();
+        let mut params = GameParams::new();
+        params.fow = false;
+        game.set_params(&params);
+        game.training = true;
+        let (xs, ys) = game.shape();
+        let pos = (0..ys)
+            .flat_map(|y| (0..xs).map(move |x| [x as f64 + 0.5, y as f64 + 0.5]))
+            .find(|pos| matches!(game.qtree.find(*pos), Some((_, CellState::Free))))
+            .unwrap();
+        let agent = Agent::new(
+            &mut game.id_gen,
+            pos,
+            0.,
+            0,
+            AgentClass::Worker,
+            Rc::new(
+                "tree main = Sequence {\n    Shoot\n    Drive (direction <- \"forward\")\n}"
+                    .to_string(),
+            ),
+        )
+        .unwrap();
+        game.entities.push(RefCell::new(Entity::Agent(agent)));
+
+        const TICKS: usize = 5;
+        for _ in 0..TICKS {
+            game.update();
+        }
+
+        let entity = game
+            .entities
+            .iter()
+            .find(|entity| entity.borrow().is_agent())
+            .expect("Agent should survive")
+            .borrow();
+        let Entity::Agent(agent) = &*entity else {
+            unreachable!()
+        };
+        let samples = agent.training_samples();
+        assert_eq!(samples.len(), TICKS);
+        for (prev, next) in samples.iter().zip(samples.iter().skip(1)) {
+            assert!(prev.tick < next.tick);
+        }
+        for sample in samples {
+            assert_eq!(sample.action, TrainingAction::Drive(1.));
+            assert!(sample.shoot);
+            assert_eq!(sample.observation.fog.len(), (FOG_GRID * FOG_GRID) as usize);
+            assert!(sample.observation.fog.iter().all(|clear| *clear));
+            assert!(sample.observation.nearby.len() <= MAX_NEARBY);
+        }
+    }
+}
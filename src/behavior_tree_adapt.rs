@@ -1,8 +1,8 @@
 //! An adapter functions and types for behavior_tree_lite
 
 use behavior_tree_lite::{
-    boxify, BehaviorCallback, BehaviorNode, BehaviorNodeContainer, BehaviorResult, NumChildren,
-    PortSpec, Registry,
+    boxify, error::LoadError, parse_file, parser::TreeSource, BehaviorCallback, BehaviorNode,
+    BehaviorNodeContainer, BehaviorResult, NumChildren, PortSpec, Registry,
 };
 
 use crate::qtree::QTreePathNode;
@@ -16,7 +16,122 @@ impl std::fmt::Debug for BehaviorTree {
     }
 }
 
-pub(super) fn common_tree_nodes(registry: &mut Registry) {
+/// An error while building a behavior tree from the source text.
+#[derive(Debug)]
+pub enum BuildTreeError {
+    /// The source has a syntax error. `line` and `column` are 1-based positions
+    /// where the parser gave up, and `rest` is the unparsed text on that line.
+    Parse {
+        line: usize,
+        column: usize,
+        rest: String,
+    },
+    Load(LoadError),
+}
+
+impl std::fmt::Display for BuildTreeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Parse { line, column, rest } => {
+                write!(f, "Parse error at line {line}, column {column}: {rest:?}")
+            }
+            Self::Load(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for BuildTreeError {}
+
+impl From<LoadError> for BuildTreeError {
+    fn from(e: LoadError) -> Self {
+        Self::Load(e)
+    }
+}
+
+/// Parse the behavior tree source, reporting the position of a syntax error if any.
+pub fn parse_tree_source(source: &str) -> Result<TreeSource<'_>, BuildTreeError> {
+    let to_error = |rest: &str| {
+        let parsed = &source[..rest.as_ptr() as usize - source.as_ptr() as usize];
+        let line = parsed.matches('\n').count() + 1;
+        let column = parsed.len() - parsed.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+        BuildTreeError::Parse {
+            line,
+            column,
+            rest: rest.lines().next().unwrap_or("").to_string(),
+        }
+    };
+    match parse_file(source) {
+        Ok((rest, tree_source)) => {
+            if rest.trim().is_empty() {
+                Ok(tree_source)
+            } else {
+                Err(to_error(rest))
+            }
+        }
+        Err(e) => Err(to_error(e.input)),
+    }
+}
+
+/// Description of a behavior node type for tree authors.
+#[derive(Debug, Clone)]
+pub struct NodeDoc {
+    pub name: String,
+    pub ports: Vec<PortSpec>,
+}
+
+/// A destination to register behavior node types to. It's implemented for `Registry` to build
+/// trees and for `Vec<NodeDoc>` to describe the same set of nodes.
+pub(crate) trait NodeRegistry {
+    fn register(&mut self, type_name: &str, constructor: Box<dyn Fn() -> Box<dyn BehaviorNode>>);
+}
+
+impl NodeRegistry for Registry {
+    fn register(&mut self, type_name: &str, constructor: Box<dyn Fn() -> Box<dyn BehaviorNode>>) {
+        Registry::register(self, type_name, constructor);
+    }
+}
+
+impl NodeRegistry for Vec<NodeDoc> {
+    fn register(&mut self, type_name: &str, constructor: Box<dyn Fn() -> Box<dyn BehaviorNode>>) {
+        self.push(NodeDoc {
+            name: type_name.to_string(),
+            ports: constructor().provided_ports(),
+        });
+    }
+}
+
+/// Node types that `Registry::default()` provides, except the ones used internally by the syntax.
+const BUILTIN_NODES: [&str; 11] = [
+    "Sequence",
+    "ReactiveSequence",
+    "Fallback",
+    "ReactiveFallback",
+    "ForceSuccess",
+    "ForceFailure",
+    "Inverter",
+    "Repeat",
+    "Retry",
+    "IsTrue",
+    "SetBool",
+];
+
+/// Describe the builtin nodes and the nodes common to agents and spawners.
+pub(super) fn describe_common_nodes() -> Vec<NodeDoc> {
+    let registry = Registry::default();
+    let mut docs: Vec<_> = BUILTIN_NODES
+        .iter()
+        .filter_map(|name| {
+            Some(NodeDoc {
+                name: name.to_string(),
+                ports: registry.build(name)?.provided_ports(),
+            })
+        })
+        .collect();
+    common_tree_nodes(&mut docs);
+    docs
+}
+
+pub(super) fn common_tree_nodes(registry: &mut impl NodeRegistry) {
     registry.register("StringEq", boxify(|| StringEqNode));
     registry.register("Gt", boxify(|| GtNode));
     registry.register("Ge", boxify(|| GeNode));
@@ -119,6 +234,7 @@ impl BehaviorNode for PrintNode {
                     .cloned()
                     .or_else(|| ctx.get::<bool>(key).map(|v| v.to_string()))
                     .or_else(|| ctx.get::<i32>(key).map(|v| v.to_string()))
+                    .or_else(|| ctx.get::<usize>(key).map(|v| v.to_string()))
                     .or_else(|| ctx.get::<f64>(key).map(|v| v.to_string()))
                     .or_else(|| ctx.get::<[i32; 2]>(key).map(|v| format!("{:?}", v)))
                     .or_else(|| ctx.get::<[f64; 2]>(key).map(|v| format!("{:?}", v)))
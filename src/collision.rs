@@ -75,6 +75,22 @@ impl CollisionShape {
     }
 }
 
+/// Bits of collision layers. Entities and bullets have a layer they belong to and a mask of the
+/// layers they collide with. Two objects collide only if each one's mask contains the other's
+/// layer, see [`layers_collide`].
+pub mod layers {
+    pub const WALL: u32 = 1;
+    pub const WORKER: u32 = 1 << 1;
+    pub const FIGHTER: u32 = 1 << 2;
+    pub const SPAWNER: u32 = 1 << 3;
+    pub const BULLET: u32 = 1 << 4;
+    pub const ALL: u32 = u32::MAX;
+}
+
+pub fn layers_collide(layer_a: u32, mask_a: u32, layer_b: u32, mask_b: u32) -> bool {
+    mask_a & layer_b != 0 && mask_b & layer_a != 0
+}
+
 pub(crate) fn aabb_intersects(a: &Aabb, b: &Aabb) -> bool {
     a[0].floor() <= b[2].ceil()
         && b[0].floor() <= a[2].ceil()
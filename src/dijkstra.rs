@@ -7,7 +7,7 @@ pub(crate) trait DijkstraField {
 
 impl DijkstraField for &mut [i32] {
     fn is_filled(&self, idx: usize) -> bool {
-        self[idx] != std::i32::MAX
+        self[idx] != i32::MAX
     }
 
     fn fill(&mut self, idx: usize, cost: i32) {
@@ -93,6 +93,64 @@ pub(crate) fn dijkstra_fill(
     }
 }
 
+/// The cost field of a Dijkstra search and the path traced back on it, for visualization.
+#[derive(Debug, Clone)]
+pub struct DijkstraResult {
+    pub shape: (usize, usize),
+    /// Number of steps from the start to each cell, or `i32::MAX` if unreachable.
+    pub costs: Vec<i32>,
+    /// Cells from the start to the goal. Empty if the goal is not reachable.
+    pub path: Vec<[i32; 2]>,
+}
+
+impl DijkstraResult {
+    /// The largest cost among the reachable cells.
+    pub fn max_cost(&self) -> i32 {
+        self.costs
+            .iter()
+            .copied()
+            .filter(|cost| *cost != i32::MAX)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+pub(crate) fn dijkstra_path(
+    board: &[bool],
+    shape: (usize, usize),
+    start: [i32; 2],
+    goal: [i32; 2],
+) -> DijkstraResult {
+    let mut costs = vec![i32::MAX; board.len()];
+    dijkstra_fill(board, shape, start, &mut &mut costs[..]);
+
+    const DIRECTIONS: [[i32; 2]; 4] = [[-1, 0], [0, -1], [1, 0], [0, 1]];
+    let cost_at = |pos: [i32; 2]| {
+        if pos[0] < 0 || shape.0 as i32 <= pos[0] || pos[1] < 0 || shape.1 as i32 <= pos[1] {
+            return i32::MAX;
+        }
+        costs[pos[0] as usize + pos[1] as usize * shape.0]
+    };
+
+    let mut path = vec![];
+    if cost_at(goal) != i32::MAX {
+        let mut cur = goal;
+        path.push(cur);
+        // Descend the cost field, which always has a neighbor one step closer to the start.
+        while let Some(next) = DIRECTIONS
+            .iter()
+            .map(|dir| [cur[0] + dir[0], cur[1] + dir[1]])
+            .find(|next| cost_at(*next) == cost_at(cur) - 1)
+        {
+            cur = next;
+            path.push(cur);
+        }
+        path.reverse();
+    }
+
+    DijkstraResult { shape, costs, path }
+}
+
 pub(crate) fn label(board: &[bool], shape: (usize, usize)) -> Vec<i32> {
     let mut labels = vec![0; board.len()];
     let mut label_counter = 1;
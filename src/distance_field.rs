@@ -0,0 +1,166 @@
+use crate::game::Terrain;
+
+/// Compute the distance to the nearest wall pixel for every pixel of the board with a two-pass
+/// chamfer distance transform.
+///
+/// Wall pixels have distance 0. The outside of the board is not regarded as a wall, so a board
+/// without any wall pixel yields `f32::INFINITY` everywhere.
+pub(crate) fn distance_transform(board: &[Terrain], shape: (usize, usize)) -> Vec<f32> {
+    const ORTHOGONAL: f32 = 1.;
+    const DIAGONAL: f32 = std::f32::consts::SQRT_2;
+    let (xs, ys) = (shape.0 as isize, shape.1 as isize);
+
+    let mut field: Vec<f32> = board
+        .iter()
+        .map(|t| if t.is_passable() { f32::INFINITY } else { 0. })
+        .collect();
+
+    let relax = |field: &mut [f32], x: isize, y: isize, neighbors: &[(isize, isize, f32)]| {
+        let idx = (x + y * xs) as usize;
+        for &(dx, dy, cost) in neighbors {
+            let (nx, ny) = (x + dx, y + dy);
+            if nx < 0 || xs <= nx || ny < 0 || ys <= ny {
+                continue;
+            }
+            let candidate = field[(nx + ny * xs) as usize] + cost;
+            if candidate < field[idx] {
+                field[idx] = candidate;
+            }
+        }
+    };
+
+    // Forward pass propagates distances from the upper left neighbors
+    let forward = [
+        (-1, 0, ORTHOGONAL),
+        (-1, -1, DIAGONAL),
+        (0, -1, ORTHOGONAL),
+        (1, -1, DIAGONAL),
+    ];
+    for y in 0..ys {
+        for x in 0..xs {
+            relax(&mut field, x, y, &forward);
+        }
+    }
+
+    // Backward pass propagates distances from the lower right neighbors
+    let backward = [
+        (1, 0, ORTHOGONAL),
+        (1, 1, DIAGONAL),
+        (0, 1, ORTHOGONAL),
+        (-1, 1, DIAGONAL),
+    ];
+    for y in (0..ys).rev() {
+        for x in (0..xs).rev() {
+            relax(&mut field, x, y, &backward);
+        }
+    }
+
+    field
+}
+
+/// Returns a copy of the board with the floor within `clearance` of a wall turned into walls, so
+/// that paths on it keep that distance from the original walls.
+///
+/// Cells on a ridge of the distance field, i.e. those not closer to the walls than both of their
+/// neighbors in some direction, are kept passable so that corridors narrower than twice the
+/// clearance are not closed off.
+pub(crate) fn inflate_obstacles(
+    board: &[Terrain],
+    shape: (usize, usize),
+    clearance: f64,
+) -> Vec<Terrain> {
+    const DIRECTIONS: [(isize, isize); 4] = [(1, 0), (0, 1), (1, 1), (1, -1)];
+    let field = distance_transform(board, shape);
+    let (xs, ys) = (shape.0 as isize, shape.1 as isize);
+    let get = |x: isize, y: isize| {
+        if x < 0 || xs <= x || y < 0 || ys <= y {
+            None
+        } else {
+            Some(field[(x + y * xs) as usize])
+        }
+    };
+    let is_ridge = |x: isize, y: isize, dist: f32| {
+        DIRECTIONS.iter().any(|&(dx, dy)| {
+            let (Some(a), Some(b)) = (get(x - dx, y - dy), get(x + dx, y + dy)) else {
+                return false;
+            };
+            a <= dist && b <= dist && (a < dist || b < dist)
+        })
+    };
+    board
+        .iter()
+        .zip(&field)
+        .enumerate()
+        .map(|(i, (terrain, &dist))| {
+            let (x, y) = (i as isize % xs, i as isize / xs);
+            if terrain.is_passable() && dist as f64 <= clearance && !is_ridge(x, y, dist) {
+                Terrain::Wall
+            } else {
+                *terrain
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_distance_transform() {
+        let shape = (9, 7);
+        let wall = (2, 3);
+        let mut board = vec![Terrain::Floor; shape.0 * shape.1];
+        board[wall.0 + wall.1 * shape.0] = Terrain::Wall;
+        let field = distance_transform(&board, shape);
+        let at = |x: usize, y: usize| field[x + y * shape.0];
+
+        assert_eq!(at(wall.0, wall.1), 0.);
+        // Along the row, the distance increases in both directions away from the wall
+        for x in wall.0..shape.0 - 1 {
+            assert!(at(x, wall.1) < at(x + 1, wall.1));
+        }
+        for x in 1..=wall.0 {
+            assert!(at(x, wall.1) < at(x - 1, wall.1));
+        }
+        // So does it along the column and the diagonal
+        for y in wall.1..shape.1 - 1 {
+            assert!(at(wall.0, y) < at(wall.0, y + 1));
+        }
+        for d in 0..3 {
+            assert!(at(wall.0 + d, wall.1 + d) < at(wall.0 + d + 1, wall.1 + d + 1));
+        }
+        assert_eq!(at(wall.0 + 4, wall.1), 4.);
+        assert_eq!(at(wall.0 + 1, wall.1 + 1), std::f32::consts::SQRT_2);
+    }
+
+    #[test]
+    fn test_inflate_obstacles() {
+        // A horizontal corridor of height 2 between two walls, opening into a room on the right
+        let shape = (12, 9);
+        let board: Vec<_> = (0..shape.0 * shape.1)
+            .map(|i| {
+                let (x, y) = (i % shape.0, i / shape.0);
+                if x < 6 && !(3..5).contains(&y) {
+                    Terrain::Wall
+                } else {
+                    Terrain::Floor
+                }
+            })
+            .collect();
+        let inflated = inflate_obstacles(&board, shape, 2.);
+        let at = |x: usize, y: usize| inflated[x + y * shape.0];
+
+        // The corridor stays open
+        for x in 0..6 {
+            assert!((3..5).any(|y| at(x, y).is_passable()));
+        }
+        // The room is shrunk away from the walls
+        assert!(!at(6, 1).is_passable());
+        assert!(at(9, 1).is_passable());
+        assert!(!inflated
+            .iter()
+            .zip(&board)
+            .any(|(a, b)| a.is_passable() && !b.is_passable()));
+    }
+}
@@ -2,11 +2,13 @@ use cgmath::{InnerSpace, Vector2};
 
 use crate::{
     agent::Agent,
-    agent::{AgentClass, Bullet, PathNode, AGENT_MAX_RESOURCE},
+    agent::{
+        avoidance::AvoidanceStats, AgentClass, Bullet, PathNode, SightLine, AGENT_MAX_RESOURCE,
+    },
     behavior_tree_adapt::BehaviorTree,
     collision::CollisionShape,
     game::Game,
-    measure_time,
+    measure_time_if,
     qtree::QTreePathNode,
     shape::Idx,
     spawner::{Spawner, SPAWNER_MAX_HEALTH, SPAWNER_MAX_RESOURCE},
@@ -21,12 +23,17 @@ pub enum Entity {
     Spawner(Spawner),
 }
 
+#[derive(Debug)]
 pub(crate) enum GameEvent {
     SpawnAgent {
         pos: [f64; 2],
         team: usize,
         class: AgentClass,
         spawner: usize,
+        /// Index into `TeamConfig::agent_sources`
+        source: Option<usize>,
+        /// Resource to consume from the spawner
+        cost: i32,
     },
 }
 
@@ -52,6 +59,20 @@ impl Entity {
         }
     }
 
+    pub fn get_layer(&self) -> u32 {
+        match self {
+            Entity::Agent(agent) => agent.layer,
+            Entity::Spawner(spawner) => spawner.layer,
+        }
+    }
+
+    pub fn get_mask(&self) -> u32 {
+        match self {
+            Entity::Agent(agent) => agent.mask,
+            Entity::Spawner(spawner) => spawner.mask,
+        }
+    }
+
     pub fn get_pos(&self) -> [f64; 2] {
         match self {
             Entity::Agent(agent) => agent.pos,
@@ -141,6 +162,13 @@ impl Entity {
         }
     }
 
+    pub fn get_velocity(&self) -> Option<[f64; 2]> {
+        match self {
+            Entity::Agent(agent) => Some(agent.velocity()),
+            _ => None,
+        }
+    }
+
     pub fn get_aabb(&self) -> [f64; 4] {
         match self {
             Entity::Agent(agent) => agent.get_shape().to_aabb(),
@@ -169,6 +197,22 @@ impl Entity {
         }
     }
 
+    /// Lines of sight to the target and nearby enemies, for debugging.
+    pub fn sight_lines(&self, game: &Game) -> Vec<SightLine> {
+        match self {
+            Entity::Agent(agent) => agent.sight_lines(game),
+            _ => vec![],
+        }
+    }
+
+    /// The role given to the agent by its behavior tree, if any.
+    pub fn get_role(&self) -> Option<&str> {
+        match self {
+            Entity::Agent(agent) => agent.role.as_deref(),
+            _ => None,
+        }
+    }
+
     pub fn get_trace(&self) -> Option<&VecDeque<[f64; 2]>> {
         match self {
             Entity::Agent(agent) => Some(&agent.trace),
@@ -239,6 +283,13 @@ impl Entity {
         }
     }
 
+    pub fn avoidance_stats(&self) -> Option<&AvoidanceStats> {
+        match self {
+            Entity::Agent(agent) => agent.avoidance_stats(),
+            _ => None,
+        }
+    }
+
     pub fn behavior_tree(&self) -> Option<&BehaviorTree> {
         match self {
             Entity::Agent(agent) => agent.behavior_tree(),
@@ -276,19 +327,23 @@ impl Entity {
         }
 
         if game.params.fow {
-            let (_, time) = measure_time(|| {
+            let (_, time) = measure_time_if(game.params.profiling_enabled, || {
                 if game.params.fow_raycasting {
                     self.fow_raycast(game);
                 } else {
                     self.defog(game);
                 }
             });
-            game.fow_raycast_profiler.borrow_mut().add(time);
+            if let Some(time) = time {
+                game.fow_raycast_profiler.borrow_mut().add(time);
+            }
         }
         ret
     }
 
-    fn fow_raycast(&mut self, game: &mut Game) {
+    /// Clear the fog within the vision range where the view is not obstructed by walls.
+    /// Returns the number of rays cast, which is 0 if the visibility was cached.
+    pub(crate) fn fow_raycast(&mut self, game: &mut Game) -> usize {
         let pos = Vector2::from(self.get_pos());
         let pos_i = pos.cast::<i32>().unwrap();
 
@@ -298,6 +353,8 @@ impl Entity {
 
         let graph_shape = (VISION_RANGE_U, VISION_RANGE_U);
         let pos_a: [i32; 2] = pos_i.into();
+        let step = game.params.fog_raycast_step.max(1) as i32;
+        let mut rays = 0;
 
         let visibility_map = if let Some(cache) = game
             .fog_raycast_map_cache
@@ -323,7 +380,19 @@ impl Entity {
                         continue;
                     }
                     let pos = pos_i + Vector2::new(x, y);
-                    let res = game.is_passable_at(pos.cast::<f64>().unwrap().into());
+                    let passable =
+                        |pos: Vector2<i32>| game.is_passable_at(pos.cast::<f64>().unwrap().into());
+                    // A wall cell can be skipped only if the wall continues behind it, since a
+                    // wall a single cell thick has nothing else to block the view.
+                    if (x + y).rem_euclid(step) != 0
+                        && (passable(pos)
+                            || !passable(pos + Vector2::new(x.signum(), 0))
+                                && !passable(pos + Vector2::new(0, y.signum())))
+                    {
+                        continue;
+                    }
+                    rays += 1;
+                    let res = passable(pos);
                     if !res && visibility_map[xf + yf * VISION_RANGE_FULL] {
                         visibility_map[xf + yf * VISION_RANGE_FULL] = false;
 
@@ -350,7 +419,7 @@ impl Entity {
             game.fog_raycast_map_cache
                 .insert(self.get_id(), (pos_i.into(), visibility_map));
             let Some((_, cache)) = game.fog_raycast_map_cache.get(&self.get_id()) else {
-                return;
+                return rays;
             };
             cache
         };
@@ -410,6 +479,7 @@ impl Entity {
         }
 
         game.fog_raycast_map_real.push(real_graph);
+        rays
     }
 
     /// Erase fog unconditionally within the radius
@@ -3,7 +3,7 @@ use std::cell::RefCell;
 use crate::{
     agent::interpolation::interpolate_raycast,
     entity::Entity,
-    game::{Board, Game, Resource},
+    game::{Game, Resource, Terrain},
 };
 
 pub(crate) const FOG_MAX_AGE: i32 = 10000;
@@ -17,7 +17,7 @@ pub struct FogOfWar {
 }
 
 impl FogOfWar {
-    pub(crate) fn new(board: &Board) -> Self {
+    pub(crate) fn new(board: &[Terrain]) -> Self {
         Self {
             fow: vec![i32::MIN; board.len()],
             resources: vec![],
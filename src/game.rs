@@ -1,24 +1,32 @@
 mod maze;
 mod rooms;
 
-use cgmath::{InnerSpace, Vector2};
+use cgmath::{InnerSpace, MetricSpace, Vector2};
 
 use std::{
-    cell::RefCell,
-    collections::HashMap,
+    cell::{Ref, RefCell},
+    collections::{HashMap, HashSet},
+    error::Error,
     rc::Rc,
     sync::atomic::{AtomicUsize, Ordering},
 };
 
 use crate::{
-    agent::{Agent, AgentClass, AgentState, Bullet},
-    collision::CollisionShape,
+    agent::{
+        avoidance::{sampler::REWIRE_DISTANCE, AvoidanceLoad, AvoidanceQuota},
+        Agent, AgentClass, AgentState, Bullet,
+    },
+    behavior_tree_adapt::BuildTreeError,
+    collision::{layers, layers_collide, CollisionShape},
+    dijkstra::{dijkstra_fill, dijkstra_path, DijkstraResult},
+    distance_field::{distance_transform, inflate_obstacles},
     entity::{Entity, GameEvent, VISION_RANGE},
     fog_of_war::{precompute_raycast_map, FogOfWar, FogRaycastMap, FOG_MAX_AGE},
-    measure_time,
+    measure_time, measure_time_if,
     mesh::{create_mesh, Mesh, MeshResult},
     perlin_noise::{gen_terms, perlin_noise_pixel, Xor128},
     qtree::{CellState, QTreeSearcher, Rect},
+    replay::Replay,
     spawner::Spawner,
     temp_ents::TempEnt,
     triangle_utils::check_shape_in_mesh,
@@ -33,7 +41,40 @@ pub struct Resource {
     pub amount: i32,
 }
 
-pub(crate) type Board = Vec<bool>;
+/// Colors of the teams in RGB, used unless the frontend configures its own.
+pub const DEFAULT_TEAM_COLORS: [[u8; 3]; 2] = [[0, 255, 127], [255, 0, 63]];
+
+/// The kind of ground a pixel on the board has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Terrain {
+    Floor,
+    /// Passable, but agents move at `SLOW_TERRAIN_SPEED` of their speed on it.
+    Slow,
+    Wall,
+}
+
+impl Terrain {
+    pub fn is_passable(self) -> bool {
+        !matches!(self, Self::Wall)
+    }
+}
+
+impl From<bool> for Terrain {
+    fn from(passable: bool) -> Self {
+        if passable {
+            Self::Floor
+        } else {
+            Self::Wall
+        }
+    }
+}
+
+/// Speed multiplier for agents on `Terrain::Slow`
+pub const SLOW_TERRAIN_SPEED: f64 = 0.5;
+
+pub(crate) type Board = Vec<Terrain>;
+/// A qtree built on a board, or the reason it could not be built
+type QTreeResult = Result<QTreeSearcher, Box<dyn Error>>;
 
 #[cfg_attr(feature = "druid", derive(Data))]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
@@ -44,6 +85,8 @@ pub enum BoardType {
     Perlin,
     Rooms,
     Maze,
+    /// Rooms connected by maze corridors
+    RoomsMaze,
 }
 
 #[derive(Debug, Clone)]
@@ -52,9 +95,53 @@ pub struct Profiler {
     count: usize,
 }
 
+/// Ticks until a position seen by the enemy is considered safe for stealthy path finding
+pub const STEALTH_MEMORY: i32 = 100;
+/// Additional path cost of a position the enemy sees right now
+pub const STEALTH_COST: f64 = 10.;
+/// Minimum distance from enemy agents to a new agent spawned with `GameParams::safe_spawn`
+pub const SAFE_SPAWN_RADIUS: f64 = 5.;
+
 pub enum UpdateResult {
     Running,
-    TeamWon(usize),
+    TeamWon(MatchResult),
+    /// The time limit was reached with the teams tied in every tiebreak
+    Draw {
+        ticks: i32,
+    },
+}
+
+/// A notable event in the simulation, recorded by `Game::advance`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GameEventRecord {
+    Spawn {
+        tick: i32,
+        team: usize,
+        id: usize,
+        class: AgentClass,
+    },
+    /// An entity was destroyed by a bullet of `team`
+    Kill {
+        tick: i32,
+        team: usize,
+        victim: usize,
+    },
+    TeamWon(MatchResult),
+    Draw {
+        tick: i32,
+    },
+}
+
+/// A summary of a finished match, sampled at the tick a team won.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MatchResult {
+    pub won_team: usize,
+    /// The value of `Game::global_time` at which the match was decided
+    pub ticks: i32,
+    /// The number of agents alive for each team at the end of the match
+    pub surviving_agents: [usize; 2],
+    /// Total resources banked in the spawners of each team
+    pub resources: [i32; 2],
 }
 
 impl Profiler {
@@ -84,6 +171,7 @@ impl Profiler {
 }
 
 #[cfg_attr(feature = "druid", derive(Data))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AvoidanceMode {
     Kinematic,
@@ -91,18 +179,36 @@ pub enum AvoidanceMode {
     RrtStar,
 }
 
+#[derive(Clone, Debug)]
 pub struct BoardParams {
     pub shape: (usize, usize),
     pub seed: u32,
     pub simplify: f64,
     pub maze_expansions: usize,
+    /// Turn the low lying ground along the shores of a perlin board into `Terrain::Slow`
+    pub slow_shores: bool,
 }
 
 #[cfg_attr(feature = "druid", derive(Data))]
-#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct TeamConfig {
     pub agent_source: Rc<String>,
     pub spawner_source: Rc<String>,
+    /// Alternative agent behavior tree sources that the spawner can choose from by index.
+    pub agent_sources: Rc<Vec<Rc<String>>>,
+}
+
+impl TeamConfig {
+    /// Select the agent behavior tree source by the index into `agent_sources`.
+    /// Falls back to `agent_source` if the index is not given or out of range.
+    pub fn select_agent_source(&self, index: Option<usize>) -> Rc<String> {
+        index
+            .and_then(|index| self.agent_sources.get(index))
+            .unwrap_or(&self.agent_source)
+            .clone()
+    }
 }
 
 #[derive(Clone, Copy, Debug, Default)]
@@ -112,19 +218,122 @@ pub struct TeamStats {
     pub wins: usize,
 }
 
+/// Number of ticks a readiness signal of `WaitForSquad` lasts, so that agents that stopped waiting
+/// or died are no longer counted as ready.
+const SQUAD_READY_TIMEOUT: i32 = 5;
+
+/// Agents of a team that signaled readiness for a coordinated attack, with the time of their last
+/// signal. Waiting agents signal every tick, so the signals of the others go stale.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SquadReadiness(HashMap<usize, i32>);
+
+impl SquadReadiness {
+    pub(crate) fn signal(&mut self, id: usize, time: i32) {
+        self.0
+            .retain(|_, signaled| time - *signaled <= SQUAD_READY_TIMEOUT);
+        self.0.insert(id, time);
+    }
+
+    pub(crate) fn is_ready(&self, id: usize, time: i32) -> bool {
+        matches!(self.0.get(&id), Some(signaled) if time - signaled <= SQUAD_READY_TIMEOUT)
+    }
+}
+
+/// A scripted reinforcement that spawns agents at a specific tick, regardless of spawners.
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpawnWave {
+    /// The value of `Game::global_time` at which the wave spawns
+    pub tick: i32,
+    pub team: usize,
+    pub class: AgentClass,
+    pub count: usize,
+    pub pos: [f64; 2],
+}
+
+pub type SpawnSchedule = Rc<Vec<SpawnWave>>;
+
 #[cfg_attr(feature = "druid", derive(Data))]
-#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+// Scenario files written before a parameter was added still load with its default value
+#[cfg_attr(feature = "serde", serde(default))]
+#[derive(Clone, Debug, PartialEq)]
 pub struct GameParams {
     pub avoidance_mode: AvoidanceMode,
     pub paused: bool,
     pub avoidance_expands: f64,
+    /// Average seconds an avoidance search may take before the number of expansions is scaled
+    /// down from `avoidance_expands`. 0 disables the adaptation, which is the default because
+    /// it depends on the wall-clock time and makes the simulation nondeterministic.
+    pub avoidance_budget: f64,
+    /// Maximum number of agents that run an avoidance search in a tick. The rest keep their
+    /// current search state and take turns in the following ticks. 0 is unlimited.
+    pub max_avoidance_searches: usize,
     pub agent_count: usize,
     /// Fog of War, some area of the map is covered by lack of knowledge, adding some depth to the strategy.
     pub fow: bool,
     /// Use raycasting to check visibility to clear fog of war. It can be expensive.
     pub fow_raycasting: bool,
     pub fow_raycast_visible: bool,
+    /// Cast a ray to only every `fog_raycast_step`-th cell in the vision range, counted along
+    /// the diagonals, to check for obstacles. Walls a single cell thick are always checked, but
+    /// larger values can let the view leak around the corners of thicker walls. 1 casts rays to
+    /// all cells.
+    pub fog_raycast_step: usize,
     pub teams: [TeamConfig; 2],
+    pub spawn_schedule: SpawnSchedule,
+    /// Minimum ticks between two productions of a spawner
+    pub spawner_cooldown: usize,
+    /// Additional cost ratio for each agent a spawner has produced. 0 means constant cost.
+    pub spawn_cost_scaling: f64,
+    /// Radius to look for nodes to rewire in RRT* search. Larger values give shorter paths at more cost.
+    pub rrt_star_radius: f64,
+    /// Bullets bounce off walls instead of vanishing, up to `Bullet::bounces_left` times.
+    pub ricochet: bool,
+    /// Health points per tick that agents regenerate when they have not been damaged recently.
+    /// 0 disables regeneration.
+    pub health_regen: f64,
+    /// Agents regain health within the reach of a friendly repair point, a spawner or a repair
+    /// unit, when they have not been damaged recently.
+    pub repair: bool,
+    /// Agents detect enemies only within the forward cone given by `AgentClass::sensor_fov`.
+    pub sensor_cone: bool,
+    /// Number of steps the simple avoidance planner looks ahead. 1 checks only the immediate steer.
+    pub lookahead_depth: usize,
+    /// Bullets are stopped by `Terrain::Slow` as well as walls.
+    pub slow_blocks_bullets: bool,
+    /// Distance from walls that paths keep, by regarding the floor closer than this to a wall as
+    /// an obstacle in path finding. 0 disables the inflation.
+    pub path_clearance: f64,
+    /// Maximum number of qtree cells to build in a single update. The tree of a new board is
+    /// built across several updates with this budget, and paths are not available until it
+    /// is complete. 0 builds the whole tree at once.
+    pub qtree_build_budget: usize,
+    /// Agents spend a round of ammo on each shot and need to reload at a friendly spawner once
+    /// they run out.
+    pub limited_ammo: bool,
+    /// Minimum distance between spawners placed at the start of a game, so that the teams do not
+    /// start in contact. 0 disables the constraint.
+    pub min_spawner_distance: f64,
+    /// Ticks after which the match ends even if both teams still have spawners. The team with
+    /// more living agents wins, then the one with more resources, then the one with more kills.
+    /// 0 disables the limit.
+    pub time_limit_ticks: i32,
+    /// Bullets deal less damage the farther they have traveled, down to a fraction at the
+    /// `AgentClass::max_range` of the shooter.
+    pub damage_falloff: bool,
+    /// Agents are not spawned within `SAFE_SPAWN_RADIUS` of an enemy agent. A spawn that finds
+    /// no safe position is retried in the next tick.
+    pub safe_spawn: bool,
+    /// Measure the time of the qtree update, path finding, fog of war and collision checks into
+    /// the profilers of `Game`. Turning it off saves the timer overhead when they are not shown.
+    pub profiling_enabled: bool,
+}
+
+impl Default for GameParams {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl GameParams {
@@ -133,13 +342,76 @@ impl GameParams {
             avoidance_mode: AvoidanceMode::RrtStar,
             paused: false,
             avoidance_expands: 1.,
+            avoidance_budget: 0.,
+            max_avoidance_searches: 0,
             agent_count: 3,
             fow: true,
             fow_raycasting: true,
             fow_raycast_visible: false,
+            fog_raycast_step: 1,
             teams: Default::default(),
+            spawn_schedule: Rc::new(vec![]),
+            spawner_cooldown: 0,
+            spawn_cost_scaling: 0.,
+            rrt_star_radius: REWIRE_DISTANCE,
+            ricochet: false,
+            health_regen: 0.,
+            repair: false,
+            sensor_cone: false,
+            lookahead_depth: 1,
+            slow_blocks_bullets: false,
+            path_clearance: 0.,
+            qtree_build_budget: 0,
+            limited_ammo: false,
+            min_spawner_distance: 0.,
+            time_limit_ticks: 0,
+            damage_falloff: false,
+            safe_spawn: false,
+            profiling_enabled: true,
         }
     }
+
+    /// Load the parameters of a match, including the behavior trees of the teams, from a RON
+    /// file written by `to_scenario_file`. Missing parameters take their default values.
+    #[cfg(feature = "serde")]
+    pub fn from_scenario_file(path: impl AsRef<std::path::Path>) -> Result<Self, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(ron::from_str(&text)?)
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn to_scenario_file(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<(), Box<dyn Error>> {
+        let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+}
+
+/// Why `Game::try_new_agent` could not place an agent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SpawnError {
+    /// None of the candidate positions was free
+    NoRoom,
+    /// Some candidate positions were rejected for being near an enemy by
+    /// `GameParams::safe_spawn`
+    EnemyNear,
+}
+
+/// What `Game::try_new_agent` spawns and where
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct AgentSpawn {
+    pub pos: [f64; 2],
+    pub team: usize,
+    pub class: AgentClass,
+    /// Use the behavior tree of a static obstacle instead of the team's
+    pub static_: bool,
+    /// Index into `TeamConfig::agent_sources`
+    pub source: Option<usize>,
+    /// Width of the range of random offsets from `pos` to try
+    pub randomness: f64,
 }
 
 #[derive(Debug)]
@@ -165,8 +437,13 @@ pub struct Game {
     pub fow_raycast_profiler: RefCell<Profiler>,
     pub params: GameParams,
     pub stats: [TeamStats; 2],
+    /// Kills of each team in the current match, unlike `stats` which accumulates over matches
+    match_kills: [usize; 2],
     pub global_time: i32,
     pub qtree: QTreeSearcher,
+    /// The error of the last qtree initialization, if it failed. Path finding falls back to grid
+    /// A* while the qtree is not available.
+    qtree_error: Option<String>,
 
     pub enable_raycast_board: bool,
     /// A visualization of visited pixels by raycasting visibility checking
@@ -178,33 +455,129 @@ pub struct Game {
     pub fog_raycast_map_real: Vec<Vec<[[i32; 2]; 2]>>,
     /// Cached raycast maps for each Entity
     pub(crate) fog_raycast_map_cache: HashMap<usize, ([i32; 2], Vec<bool>)>,
+    /// Events are recorded only while this is `Some`, so that a long running game won't
+    /// accumulate them.
+    event_records: Option<Vec<GameEventRecord>>,
+    /// Start recording a `Replay` at the next `new_board`
+    replay_recording: bool,
+    replay: Option<Replay>,
+    /// Cache of `distance_field`, cleared by `invalidate_distance_field`.
+    distance_field_cache: RefCell<Option<Vec<f32>>>,
+    /// The board with walls inflated by `GameParams::path_clearance`, if enabled.
+    path_board: Option<Board>,
+    /// Number of kills that happened in each cell of the board, in the same layout as `board`.
+    pub kill_heatmap: Vec<u32>,
+    /// Ids of agents that are not updated, for inspecting them while the rest of the game runs.
+    /// They can still be hit and killed by others.
+    pub frozen_agents: HashSet<usize>,
+    /// Spawn events postponed by `GameParams::safe_spawn` until the spawn point is clear. A
+    /// spawner does not produce another agent while it has one here.
+    pending_spawns: Vec<GameEvent>,
+    /// Record observations and actions of agents into `Agent::training_samples` each tick.
+    #[cfg(feature = "training")]
+    pub training: bool,
+    pub(crate) avoidance_load: AvoidanceLoad,
+    pub(crate) avoidance_quota: AvoidanceQuota,
+    pub(crate) squad_ready: [SquadReadiness; 2],
 }
 
 impl Game {
     pub fn new() -> Self {
         let seed = 123513;
         let simplify = 1.;
+        let shape = (128, 128);
 
-        let xs = 128;
-        let ys = 128;
-
-        let MeshResult { board, mesh } = Self::create_rooms_board(&BoardParams {
-            shape: (xs, ys),
+        let mesh_result = Self::create_rooms_board(&BoardParams {
+            shape,
             seed,
             simplify,
             maze_expansions: 0,
+            slow_shores: false,
         });
+        Self::with_mesh(shape, simplify, mesh_result)
+    }
 
-        let id_gen = 0;
+    /// Create a game on a board given by the caller instead of a generated one, e.g. a
+    /// hand-crafted map for tests. `board` is in row major order with `shape.0` columns.
+    ///
+    /// Like generated boards, passable regions disconnected from the largest one are filled.
+    pub fn from_board<T: Into<Terrain>>(board: Vec<T>, shape: (usize, usize)) -> Self {
+        assert_eq!(board.len(), shape.0 * shape.1, "Board size mismatch");
+        let simplify = 1.;
+        let terrain: Board = board.into_iter().map(Into::into).collect();
+        let mesh_result = create_mesh(shape, simplify, |x, y| terrain[x + y * shape.0]);
+        Self::with_mesh(shape, simplify, mesh_result)
+    }
+
+    /// Render the board as text for debugging, a line per row. Floor is `.`, slow terrain is
+    /// `~` and walls are `#`. Spawners are marked with `A` and `B` for each team, and agents with
+    /// `a` and `b`, drawn over the terrain of the cell they are in.
+    pub fn board_to_ascii(&self) -> String {
+        let mut chars: Vec<char> = self
+            .board
+            .iter()
+            .map(|terrain| match terrain {
+                Terrain::Floor => '.',
+                Terrain::Slow => '~',
+                Terrain::Wall => '#',
+            })
+            .collect();
+        for entity in self.entities.iter().filter_map(|e| e.try_borrow().ok()) {
+            let pos = entity.get_pos();
+            if pos[0] < 0. || self.xs as f64 <= pos[0] || pos[1] < 0. || self.ys as f64 <= pos[1] {
+                continue;
+            }
+            let marker = match (entity.is_agent(), entity.get_team()) {
+                (false, 0) => 'A',
+                (false, _) => 'B',
+                (true, 0) => 'a',
+                (true, _) => 'b',
+            };
+            chars[pos[0] as usize + pos[1] as usize * self.xs] = marker;
+        }
+        chars
+            .chunks(self.xs)
+            .flat_map(|row| row.iter().copied().chain(std::iter::once('\n')))
+            .collect()
+    }
+
+    /// Parse a board written like the output of `board_to_ascii` into the passability of each
+    /// cell and the shape, which can be given to `from_board`. `#` is a wall and any other
+    /// character is passable, so entity markers are read as floor. Rows shorter than the
+    /// longest one are padded with walls.
+    pub fn board_from_ascii(s: &str) -> (Vec<bool>, (usize, usize)) {
+        let rows: Vec<_> = s.lines().filter(|line| !line.is_empty()).collect();
+        let xs = rows
+            .iter()
+            .map(|row| row.chars().count())
+            .max()
+            .unwrap_or(0);
+        let board = rows
+            .iter()
+            .flat_map(|row| {
+                row.chars()
+                    .map(|c| c != '#')
+                    .chain(std::iter::repeat(false))
+                    .take(xs)
+            })
+            .collect();
+        (board, (xs, rows.len()))
+    }
 
-        let shape = (xs, ys);
-        let (qtree, timer) = measure_time(|| Self::new_qtree(shape, &board, &[]));
+    fn with_mesh(shape: (usize, usize), simplify: f64, mesh_result: MeshResult) -> Self {
+        let MeshResult { board, mesh } = mesh_result;
+        let (xs, ys) = shape;
+        let params = GameParams::new();
 
-        let fog = FogOfWar::new(&board);
-        let fog = [fog.clone(), fog];
+        let id_gen = 0;
+
+        let ((path_board, qtree, fog), timer) =
+            measure_time(|| Self::board_state(&board, shape, &params));
 
         println!("qtree time: {timer:?}");
 
+        let (qtree, qtree_error) = Self::qtree_or_empty(qtree);
+
         let (fog_raycast_map, fog_raycast_map_forward) =
             precompute_raycast_map(VISION_RANGE as usize);
 
@@ -227,10 +600,12 @@ impl Game {
             qtree_profiler: RefCell::new(Profiler::new()),
             path_find_profiler: RefCell::new(Profiler::new()),
             fow_raycast_profiler: RefCell::new(Profiler::new()),
-            params: GameParams::new(),
+            params,
             stats: Default::default(),
+            match_kills: [0; 2],
             global_time: 0,
             qtree,
+            qtree_error,
             enable_raycast_board: false,
             raycast_board: RefCell::new(vec![]),
             fog_rays: vec![],
@@ -238,6 +613,19 @@ impl Game {
             fog_raycast_map_forward,
             fog_raycast_map_real: vec![],
             fog_raycast_map_cache: HashMap::new(),
+            distance_field_cache: RefCell::new(None),
+            path_board,
+            kill_heatmap: vec![0; xs * ys],
+            frozen_agents: HashSet::new(),
+            pending_spawns: vec![],
+            #[cfg(feature = "training")]
+            training: false,
+            avoidance_load: AvoidanceLoad::new(),
+            avoidance_quota: AvoidanceQuota::default(),
+            squad_ready: Default::default(),
+            event_records: None,
+            replay_recording: false,
+            replay: None,
         }
     }
 
@@ -255,6 +643,8 @@ impl Game {
                 let spawner = self.try_new_spawner(team);
                 if let Some(spawner) = spawner {
                     self.entities.push(RefCell::new(spawner));
+                } else {
+                    println!("Failed to place a spawner for team {team}");
                 }
             }
         }
@@ -272,7 +662,14 @@ impl Game {
             let dy = (yi as isize - shape.1 as isize / 2) as f64;
             let noise_val =
                 perlin_noise_pixel(xi as f64, yi as f64, min_octave, max_octave, &terms, 0.5);
-            0. + (noise_val - 0.5 * (dx * dx + dy * dy).sqrt() / shape.0 as f64) > -0.125
+            let height = noise_val - 0.5 * (dx * dx + dy * dy).sqrt() / shape.0 as f64;
+            if height <= -0.125 {
+                Terrain::Wall
+            } else if params.slow_shores && height <= -0.1 {
+                Terrain::Slow
+            } else {
+                Terrain::Floor
+            }
         })
     }
 
@@ -297,36 +694,47 @@ impl Game {
         })
     }
     pub fn new_board(&mut self, board_type: BoardType, params: &BoardParams) {
+        self.replay = self
+            .replay_recording
+            .then(|| Replay::new(self, board_type, params));
         self.xs = params.shape.0;
         self.ys = params.shape.1;
 
         let MeshResult { board, mesh } = match board_type {
-            BoardType::Rect => Self::create_rect_board(&params),
-            BoardType::Crank => Self::create_crank_board(&params),
-            BoardType::Perlin => Self::create_perlin_board(&params),
-            BoardType::Rooms => Self::create_rooms_board(&params),
-            BoardType::Maze => Self::create_maze_board(&params),
+            BoardType::Rect => Self::create_rect_board(params),
+            BoardType::Crank => Self::create_crank_board(params),
+            BoardType::Perlin => Self::create_perlin_board(params),
+            BoardType::Rooms => Self::create_rooms_board(params),
+            BoardType::Maze => Self::create_maze_board(params),
+            BoardType::RoomsMaze => Self::create_rooms_maze_board(params),
         };
 
-        let fog = FogOfWar::new(&board);
-
-        self.qtree = Self::new_qtree(params.shape, &board, &[]);
+        let (path_board, qtree, fog) = Self::board_state(&board, params.shape, &self.params);
+        self.path_board = path_board;
+        (self.qtree, self.qtree_error) = Self::qtree_or_empty(qtree);
         self.raycast_board = RefCell::new(vec![]);
         self.board = board;
-        self.fog = [fog.clone(), fog];
+        self.fog = fog;
         self.mesh = mesh;
         self.entities = vec![];
         self.bullets = vec![];
         self.resources.clear();
         self.global_time = 0;
+        self.match_kills = [0; 2];
         self.fog_raycast_map_cache.clear();
+        self.invalidate_distance_field();
+        self.kill_heatmap = vec![0; self.xs * self.ys];
+        self.frozen_agents.clear();
+        self.pending_spawns.clear();
+        self.squad_ready = Default::default();
     }
 
     fn new_qtree(
         shape: (usize, usize),
-        board: &Board,
+        board: &[Terrain],
         entities: &[RefCell<Entity>],
-    ) -> QTreeSearcher {
+        build_budget: usize,
+    ) -> QTreeResult {
         let mut qtree = QTreeSearcher::new();
         let calls: AtomicUsize = AtomicUsize::new(0);
         let unpassables: AtomicUsize = AtomicUsize::new(0);
@@ -337,7 +745,7 @@ impl Game {
                 (entity.get_id(), entity.get_shape().to_aabb())
             })
             .collect();
-        let init_result = qtree.initialize(shape, &|rect: Rect| {
+        let cell_state = |rect: Rect| {
             let mut has_passable = false;
             let mut has_unpassable = None;
             for x in rect[0]..rect[2] {
@@ -383,28 +791,53 @@ impl Game {
             } else {
                 CellState::Obstacle
             }
-        });
-        match init_result {
-            Ok(_) => println!("calls: {:?} unpassables: {unpassables:?}", calls),
-            Err(e) => println!("Failed to initialize QTree: {e}"),
+        };
+        if build_budget == 0 {
+            qtree.initialize(shape, &cell_state)?;
+        } else {
+            qtree.start_initialize(shape, &cell_state)?;
+        }
+        println!("calls: {:?} unpassables: {unpassables:?}", calls);
+        Ok(qtree)
+    }
+
+    /// Take the qtree out of the result of `new_qtree`, or an empty one with the error message if
+    /// it failed to initialize.
+    fn qtree_or_empty(result: QTreeResult) -> (QTreeSearcher, Option<String>) {
+        match result {
+            Ok(qtree) => (qtree, None),
+            Err(e) => {
+                println!("Failed to initialize QTree: {e}");
+                (QTreeSearcher::new(), Some(e.to_string()))
+            }
         }
-        qtree
+    }
+
+    /// The error message if the qtree failed to initialize on the current board. Path finding
+    /// still works with grid A* in that case, only slower.
+    pub fn qtree_error(&self) -> Option<&str> {
+        self.qtree_error.as_deref()
     }
 
     pub(crate) fn try_new_agent(
         &mut self,
-        pos: [f64; 2],
-        team: usize,
-        class: AgentClass,
+        spawn: &AgentSpawn,
         entities: &[RefCell<Entity>],
-        static_: bool,
-        randomness: f64,
-    ) -> Option<Entity> {
+    ) -> Result<Entity, SpawnError> {
         const STATIC_SOURCE_FILE: &str = include_str!("../behavior_tree_config/test_obstacle.btc");
+        let AgentSpawn {
+            pos,
+            team,
+            class,
+            static_,
+            source,
+            randomness,
+        } = *spawn;
         let rng = &mut self.rng;
         let id_gen = &mut self.id_gen;
         // let triangle_labels = &self.mesh.triangle_labels;
         // let largest_label = self.mesh.largest_label;
+        let mut enemy_near = false;
 
         for _ in 0..10 {
             let state_candidate = AgentState {
@@ -413,11 +846,20 @@ impl Game {
                 heading: rng.next() * std::f64::consts::PI * 2.,
             };
 
+            // Checked first to tell the failures that may resolve once the enemy leaves
+            if self.params.safe_spawn
+                && Self::enemy_near([state_candidate.x, state_candidate.y], team, entities)
+            {
+                enemy_near = true;
+                continue;
+            }
+
             if Agent::qtree_collision(None, state_candidate, class, entities) {
                 continue;
             }
 
-            if Agent::collision_check(None, state_candidate, class, entities, false) {
+            let layers = (class.collision_layer(), layers::ALL);
+            if Agent::collision_check(None, state_candidate, class, layers, entities, false) {
                 continue;
             }
 
@@ -438,15 +880,45 @@ impl Game {
                 if static_ {
                     Rc::new(STATIC_SOURCE_FILE.to_string())
                 } else {
-                    self.params.teams[team].agent_source.clone()
+                    self.params.teams[team].select_agent_source(source)
                 },
             );
             match agent {
-                Ok(agent) => return Some(Entity::Agent(agent)),
+                Ok(mut agent) => {
+                    agent.rng = Xor128::new(rng.nexti().wrapping_add(agent.id as u32));
+                    return Ok(Entity::Agent(agent));
+                }
                 Err(e) => println!("Failed to create an Agent! {e}"),
             }
         }
-        None
+        Err(if enemy_near {
+            SpawnError::EnemyNear
+        } else {
+            SpawnError::NoRoom
+        })
+    }
+
+    /// Whether a spawn of `spawner` was postponed by `GameParams::safe_spawn` and is waiting
+    /// for the spawn point to clear.
+    pub(crate) fn has_pending_spawn(&self, spawner: usize) -> bool {
+        self.pending_spawns.iter().any(|event| match event {
+            GameEvent::SpawnAgent { spawner: id, .. } => *id == spawner,
+        })
+    }
+
+    /// Whether an agent of another team than `team` is within `SAFE_SPAWN_RADIUS` of `pos`.
+    fn enemy_near(pos: [f64; 2], team: usize, entities: &[RefCell<Entity>]) -> bool {
+        let pos = Vector2::from(pos);
+        entities.iter().any(|entity| {
+            entity
+                .try_borrow()
+                .map(|entity| {
+                    entity.is_agent()
+                        && entity.get_team() != team
+                        && (Vector2::from(entity.get_pos()) - pos).magnitude() < SAFE_SPAWN_RADIUS
+                })
+                .unwrap_or(false)
+        })
     }
 
     /// Check collision with the environment
@@ -460,11 +932,17 @@ impl Game {
         //         return true;
         //     }
         // }
-        check_shape_in_mesh(&self.mesh, &state, &mut self.triangle_profiler.borrow_mut())
+        let mut profiler = self
+            .params
+            .profiling_enabled
+            .then(|| self.triangle_profiler.borrow_mut());
+        check_shape_in_mesh(&self.mesh, state, profiler.as_deref_mut())
     }
 
     fn try_new_spawner(&mut self, team: usize) -> Option<Entity> {
-        for _ in 0..10 {
+        const MAX_ATTEMPTS: usize = 100;
+        let min_distance2 = self.params.min_spawner_distance.powi(2);
+        for _ in 0..MAX_ATTEMPTS {
             let rng = &mut self.rng;
             let pos_candidate = [rng.next() * self.xs as f64, rng.next() * self.ys as f64];
             if self
@@ -476,7 +954,17 @@ impl Game {
             if Spawner::qtree_collision(None, pos_candidate, &self.entities) {
                 continue;
             }
-            if self.board[pos_candidate[0] as usize + self.xs * pos_candidate[1] as usize] {
+            if self.entities.iter().any(|entity| {
+                let entity = entity.borrow();
+                !entity.is_agent()
+                    && Vector2::from(entity.get_pos()).distance2(Vector2::from(pos_candidate))
+                        < min_distance2
+            }) {
+                continue;
+            }
+            if self.board[pos_candidate[0] as usize + self.xs * pos_candidate[1] as usize]
+                .is_passable()
+            {
                 let spawner = Spawner::new(
                     &mut self.id_gen,
                     pos_candidate,
@@ -500,6 +988,15 @@ impl Game {
         if 10 < self.resources.len() {
             return;
         }
+        // Resources in a pocket that no spawner can reach would never be collected
+        let spawner_labels: Vec<_> = self
+            .entities
+            .iter()
+            .filter_map(|entity| entity.try_borrow().ok())
+            .filter(|entity| !entity.is_agent())
+            .map(|entity| self.region_label_at(entity.get_pos()))
+            .filter(|label| *label != 0)
+            .collect();
         for _ in 0..10 {
             let rng = &mut self.rng;
             let pos_candidate = [rng.next() * self.xs as f64, rng.next() * self.ys as f64];
@@ -511,24 +1008,137 @@ impl Game {
                 continue;
             }
 
-            if self.board[pos_candidate[0] as usize + self.xs * pos_candidate[1] as usize] {
+            if !spawner_labels.is_empty()
+                && !spawner_labels.contains(&self.region_label_at(pos_candidate))
+            {
+                continue;
+            }
+
+            if self.board[pos_candidate[0] as usize + self.xs * pos_candidate[1] as usize]
+                .is_passable()
+            {
                 self.resources.push(Resource {
                     pos: pos_candidate,
-                    amount: (rng.nexti() % 128 + 80) as i32,
+                    amount: (self.rng.nexti() % 128 + 80) as i32,
                 });
             }
         }
     }
 
+    /// Label of the connected passable region at `pos` in `Mesh::labeled_image`, 0 if it is a
+    /// wall or outside the board.
+    fn region_label_at(&self, pos: [f64; 2]) -> i32 {
+        if pos[0] < 0. || pos[1] < 0. {
+            return 0;
+        }
+        let (x, y) = (pos[0] as usize, pos[1] as usize);
+        if self.xs <= x || self.ys <= y {
+            return 0;
+        }
+        self.mesh
+            .labeled_image
+            .get(x + y * self.xs)
+            .copied()
+            .unwrap_or(0)
+    }
+
     pub fn set_params(&mut self, params: &GameParams) {
+        let rebuild_path_board = self.params.path_clearance != params.path_clearance;
+        if self.params.fog_raycast_step != params.fog_raycast_step {
+            self.fog_raycast_map_cache.clear();
+        }
         self.params = params.clone();
+        if let Some(replay) = &mut self.replay {
+            replay.record_params(self.global_time, params);
+        }
+        if rebuild_path_board {
+            self.rebuild_path_board();
+        }
+    }
+
+    /// Build the states derived from a new board: the board for path finding, the qtree and the
+    /// fog of war of each team.
+    fn board_state(
+        board: &[Terrain],
+        shape: (usize, usize),
+        params: &GameParams,
+    ) -> (Option<Board>, QTreeResult, [FogOfWar; 2]) {
+        let path_board = Self::inflate_board(board, shape, params.path_clearance);
+        let qtree = Self::new_qtree(
+            shape,
+            path_board.as_deref().unwrap_or(board),
+            &[],
+            params.qtree_build_budget,
+        );
+        let fog = FogOfWar::new(board);
+        (path_board, qtree, [fog.clone(), fog])
+    }
+
+    fn inflate_board(board: &[Terrain], shape: (usize, usize), clearance: f64) -> Option<Board> {
+        (0. < clearance).then(|| inflate_obstacles(board, shape, clearance))
+    }
+
+    /// Recompute the inflated board for path finding and the qtree on it.
+    fn rebuild_path_board(&mut self) {
+        let shape = (self.xs, self.ys);
+        self.path_board = Self::inflate_board(&self.board, shape, self.params.path_clearance);
+        (self.qtree, self.qtree_error) = Self::qtree_or_empty(Self::new_qtree(
+            shape,
+            self.path_board(),
+            &self.entities,
+            self.params.qtree_build_budget,
+        ));
+    }
+
+    /// The board that path finding sees, which has walls inflated by
+    /// `GameParams::path_clearance`.
+    pub(crate) fn path_board(&self) -> &[Terrain] {
+        self.path_board.as_ref().unwrap_or(&self.board)
+    }
+
+    /// The goal that path finding should aim at for `pos`. A passable position that is closed
+    /// off by `GameParams::path_clearance` is snapped to the center of the nearest cell that
+    /// keeps the clearance, since no path could reach it otherwise.
+    pub(crate) fn path_goal(&self, pos: [f64; 2]) -> [f64; 2] {
+        let Some(path_board) = &self.path_board else {
+            return pos;
+        };
+        let shape = (self.xs, self.ys);
+        if !self.is_passable_at(pos) || is_passable_at(path_board, shape, pos) {
+            return pos;
+        }
+        // The inflation closes off cells only up to the clearance away from the open ones
+        let radius = self.params.path_clearance.ceil() as i32 + 1;
+        let [x, y] = [pos[0] as i32, pos[1] as i32];
+        (-radius..=radius)
+            .flat_map(|dy| (-radius..=radius).map(move |dx| [x + dx, y + dy]))
+            .filter(|cell| is_passable_at_i(path_board, shape, *cell))
+            .map(|cell| [cell[0] as f64 + 0.5, cell[1] as f64 + 0.5])
+            .min_by(|a, b| {
+                let distance2 =
+                    |cell: &[f64; 2]| (cell[0] - pos[0]).powi(2) + (cell[1] - pos[1]).powi(2);
+                distance2(a).total_cmp(&distance2(b))
+            })
+            .unwrap_or(pos)
     }
 
     pub fn update(&mut self) -> UpdateResult {
         self.global_time += 1;
+        if let Some(replay) = &mut self.replay {
+            replay.record_tick(self.global_time);
+        }
 
         self.fog_rays.clear();
         self.fog_raycast_map_real.clear();
+        self.avoidance_quota.begin_tick();
+
+        if self.qtree.is_building() {
+            let budget = match self.params.qtree_build_budget {
+                0 => usize::MAX,
+                budget => budget,
+            };
+            self.qtree.step_initialize(budget);
+        }
 
         if self.enable_raycast_board {
             let mut raycast_board = self.raycast_board.borrow_mut();
@@ -539,37 +1149,75 @@ impl Game {
         }
 
         let mut entities = std::mem::take(&mut self.entities);
+        // Update entities in the order of ids, so that the outcome does not depend on how the
+        // Vec was reordered. It is sorted by construction in most cases, so this is cheap.
+        entities.sort_by_key(|entity| entity.borrow().get_id());
         let mut bullets = std::mem::take(&mut self.bullets);
         let mut events = vec![];
         for entity in entities.iter() {
             let mut entity = entity.borrow_mut();
+            if self.frozen_agents.contains(&entity.get_id()) {
+                continue;
+            }
             events.extend(entity.update(self, &entities, &mut bullets));
         }
 
-        for event in events {
+        let pending_spawns = std::mem::take(&mut self.pending_spawns);
+        for event in pending_spawns.into_iter().chain(events) {
             match event {
                 GameEvent::SpawnAgent {
                     pos,
                     team,
                     class,
                     spawner,
+                    source,
+                    cost,
                 } => {
-                    if let Some(agent) = self.try_new_agent(pos, team, class, &entities, false, 10.)
-                    {
+                    let res = self.try_new_agent(
+                        &AgentSpawn {
+                            pos,
+                            team,
+                            class,
+                            static_: false,
+                            source,
+                            randomness: 10.,
+                        },
+                        &entities,
+                    );
+                    if let Ok(agent) = res {
                         println!("Spawning agent {class:?}");
+                        self.record_event(GameEventRecord::Spawn {
+                            tick: self.global_time,
+                            team,
+                            id: agent.get_id(),
+                            class,
+                        });
                         entities.push(RefCell::new(agent));
                         self.stats[team].spawned += 1;
                         if let Some(spawner) = entities
                             .iter_mut()
                             .find(|ent| ent.borrow().get_id() == spawner)
                         {
-                            spawner.borrow_mut().remove_resource(class.cost());
+                            spawner.borrow_mut().remove_resource(cost);
                         }
+                    } else if matches!(res, Err(SpawnError::EnemyNear))
+                        && entities.iter().any(|ent| ent.borrow().get_id() == spawner)
+                    {
+                        self.pending_spawns.push(GameEvent::SpawnAgent {
+                            pos,
+                            team,
+                            class,
+                            spawner,
+                            source,
+                            cost,
+                        });
                     }
                 }
             }
         }
 
+        self.process_spawn_schedule(&mut entities);
+
         // let (qtree, timer) =
         //     measure_time(|| Rc::new(Self::new_qtree((self.xs, self.ys), &self.board, &entities)));
         // self.qtree = qtree;
@@ -581,14 +1229,43 @@ impl Game {
             let agents = &self.entities;
             let mut temp_ents = std::mem::take(&mut self.temp_ents);
             let mut kills = [0usize; 2];
+            let mut kill_records = vec![];
+            let mut kill_positions = vec![];
+            let global_time = self.global_time;
             bullets.retain_mut(|bullet| {
-                if !self.is_passable_at(bullet.pos) {
+                // A bullet off the board never comes back, even if it is not stopped by walls
+                let [x, y] = bullet.pos;
+                if x < 0. || y < 0. || self.xs as f64 <= x || self.ys as f64 <= y {
+                    return false;
+                }
+                if self.params.slow_blocks_bullets && self.terrain_at(bullet.pos) == Terrain::Slow {
                     return false;
                 }
+                if bullet.mask & layers::WALL != 0 && !self.is_passable_at(bullet.pos) {
+                    if !self.params.ricochet || bullet.bounces_left == 0 {
+                        return false;
+                    }
+                    let Some(normal) = self.wall_normal_at(bullet.pos) else {
+                        return false;
+                    };
+                    let velo = Vector2::from(bullet.velo);
+                    // Step back out of the wall and reflect the velocity about the normal
+                    bullet.pos = (Vector2::from(bullet.pos) - velo).into();
+                    bullet.velo = (velo - 2. * velo.dot(normal) * normal).into();
+                    bullet.bounces_left -= 1;
+                    return true;
+                }
                 let newpos = (Vector2::from(bullet.pos) + Vector2::from(bullet.velo)).into();
                 for agent in agents.iter() {
                     let mut agent = agent.borrow_mut();
-                    if agent.get_team() == bullet.team {
+                    if agent.get_team() == bullet.team
+                        || !layers_collide(
+                            bullet.layer,
+                            bullet.mask,
+                            agent.get_layer(),
+                            agent.get_mask(),
+                        )
+                    {
                         continue;
                     }
                     if let Some(agent_vertices) = agent.get_shape().to_vertices() {
@@ -606,9 +1283,18 @@ impl Game {
                                 }
                             };
                             temp_ents.push(temp_ent);
-                            if agent.damage(bullet.damage) {
+                            if let Entity::Agent(agent) = &mut *agent {
+                                agent.last_damaged_tick = global_time;
+                            }
+                            if agent.damage(bullet.hit_damage(self.params.damage_falloff)) {
                                 agent.set_active(false);
                                 kills[bullet.team] += 1;
+                                kill_positions.push(agent.get_pos());
+                                kill_records.push(GameEventRecord::Kill {
+                                    tick: global_time,
+                                    team: bullet.team,
+                                    victim: agent.get_id(),
+                                });
                                 println!("Entity {} is being killed", agent.get_id());
                             }
                             return false;
@@ -625,40 +1311,33 @@ impl Game {
 
             for team in 0..self.stats.len() {
                 self.stats[team].kills += kills[team];
+                self.match_kills[team] += kills[team];
+            }
+            for record in kill_records {
+                self.record_event(record);
+            }
+            for pos in kill_positions {
+                self.add_kill_heat(pos);
+            }
+
+            for entity in &self.entities {
+                if let Entity::Agent(agent) = &mut *entity.borrow_mut() {
+                    agent.regenerate(global_time, self.params.health_regen);
+                    agent.repair(global_time, self.params.repair, &self.entities);
+                }
             }
         }
 
-        let (_, timer) = measure_time(|| {
+        let (_, timer) = measure_time_if(self.params.profiling_enabled, || {
             let qtree = &mut self.qtree;
             let entities = &self.entities;
 
             qtree.start_update();
 
-            fn update_aabb(
-                qtree: &mut QTreeSearcher,
-                aabb: [f64; 4],
-                cell_state: impl Fn([i32; 2]) -> CellState,
-            ) {
-                for y in aabb[1].floor() as i32..aabb[3].ceil() as i32 {
-                    for x in aabb[0].floor() as i32..aabb[2].ceil() as i32 {
-                        if let Err(e) = qtree.update([x, y], cell_state([x, y])) {
-                            println!("qtree.update error: {e}");
-                        }
-                    }
-                }
-            }
-
-            let get_background = |pos: [i32; 2]| {
-                if is_passable_at(
-                    &self.board,
-                    (self.xs, self.ys),
-                    [pos[0] as f64 + 0.5, pos[1] as f64 + 0.5],
-                ) {
-                    CellState::Free
-                } else {
-                    CellState::Obstacle
-                }
-            };
+            let board = &self.board;
+            let shape = (self.xs, self.ys);
+            let path_board = self.path_board.as_ref().unwrap_or(board);
+            let get_background = |pos| get_background(path_board, shape, pos);
 
             // Clear the previous cells
             for shape in entities
@@ -673,8 +1352,9 @@ impl Game {
                 let entity = entity.borrow();
                 let id = entity.get_id();
                 let aabb = entity.get_shape().to_aabb();
-                update_aabb(qtree, aabb, |pos| {
-                    if let CellState::Obstacle = get_background(pos) {
+                update_aabb(qtree, aabb, |pos: [i32; 2]| {
+                    // Entities may stand in the cells inflated for clearance, but not in walls
+                    if !is_passable_at_i(board, shape, pos) {
                         CellState::Obstacle
                     } else if entity.get_active() {
                         CellState::Occupied(id)
@@ -687,7 +1367,9 @@ impl Game {
             qtree.finish_update();
         });
 
-        self.qtree_profiler.borrow_mut().add(timer);
+        if let Some(timer) = timer {
+            self.qtree_profiler.borrow_mut().add(timer);
+        }
 
         self.entities.retain(|agent| agent.borrow().get_active());
         let entities = std::mem::take(&mut self.entities);
@@ -712,11 +1394,22 @@ impl Game {
                 .iter()
                 .any(|agent| !agent.borrow().is_agent() && agent.borrow().get_team() == team)
             {
-                self.entities = entities;
-                let won_team = (team + 1) % 2;
-                self.stats[won_team].wins += 1;
-                return UpdateResult::TeamWon(won_team);
+                return self.finish_match((team + 1) % 2, entities);
+            }
+        }
+
+        let time_limit = self.params.time_limit_ticks;
+        if 0 < time_limit && time_limit <= self.global_time {
+            if let Some(won_team) = self.tiebreak_winner(&entities) {
+                return self.finish_match(won_team, entities);
             }
+            self.entities = entities;
+            self.record_event(GameEventRecord::Draw {
+                tick: self.global_time,
+            });
+            return UpdateResult::Draw {
+                ticks: self.global_time,
+            };
         }
         self.entities = entities;
 
@@ -725,38 +1418,368 @@ impl Game {
         UpdateResult::Running
     }
 
-    pub(crate) fn is_passable_at(&self, pos: [f64; 2]) -> bool {
-        if pos[0] < 0. || self.xs <= pos[0] as usize || pos[1] < 0. || self.ys <= pos[1] as usize {
-            false
+    /// Remove all entities (agents and spawners) of a team at once, along with the other team's
+    /// memory of them and the qtree cells they occupied. Useful for resetting a scenario.
+    pub fn clear_team(&mut self, team: usize) {
+        let (removed, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.entities)
+            .into_iter()
+            .partition(|entity| entity.borrow().get_team() == team);
+        self.entities = kept;
+
+        let board = self.path_board.as_ref().unwrap_or(&self.board);
+        let shape = (self.xs, self.ys);
+        let qtree = &mut self.qtree;
+        qtree.start_update();
+        for entity in &removed {
+            let entity = entity.borrow();
+            if let Some(last_state) = entity.get_last_state() {
+                update_aabb(qtree, last_state.to_aabb(), |pos| {
+                    get_background(board, shape, pos)
+                });
+            }
+            update_aabb(qtree, entity.get_shape().to_aabb(), |pos| {
+                get_background(board, shape, pos)
+            });
+        }
+        // Restore the cells that the remaining entities share with the removed ones
+        for entity in &self.entities {
+            let entity = entity.borrow();
+            if !entity.get_active() {
+                continue;
+            }
+            let id = entity.get_id();
+            update_aabb(qtree, entity.get_shape().to_aabb(), |pos| {
+                if !is_passable_at_i(&self.board, shape, pos) {
+                    CellState::Obstacle
+                } else {
+                    CellState::Occupied(id)
+                }
+            });
+        }
+        qtree.finish_update();
+
+        let removed_ids: HashSet<_> = removed
+            .iter()
+            .map(|entity| entity.borrow().get_id())
+            .collect();
+        for fog in &mut self.fog {
+            fog.entities
+                .retain(|shadow| !removed_ids.contains(&shadow.id));
+        }
+    }
+
+    fn finish_match(&mut self, won_team: usize, entities: Vec<RefCell<Entity>>) -> UpdateResult {
+        let result = self.match_result(won_team, &entities);
+        self.entities = entities;
+        self.stats[won_team].wins += 1;
+        self.record_event(GameEventRecord::TeamWon(result));
+        UpdateResult::TeamWon(result)
+    }
+
+    /// The team that wins a match ended by the time limit, comparing the living agents, then the
+    /// resources, then the kills in this match. `None` if the teams are tied in all of them.
+    fn tiebreak_winner(&self, entities: &[RefCell<Entity>]) -> Option<usize> {
+        let result = self.match_result(0, entities);
+        let score = |team: usize| {
+            (
+                result.surviving_agents[team],
+                result.resources[team],
+                self.match_kills[team],
+            )
+        };
+        let (score0, score1) = (score(0), score(1));
+        if score1 < score0 {
+            Some(0)
+        } else if score0 < score1 {
+            Some(1)
         } else {
-            let (ret, time) =
-                measure_time(|| self.board[pos[0] as usize + pos[1] as usize * self.xs]);
-            self.pixel_profiler.borrow_mut().add(time);
-            ret
+            None
         }
     }
 
-    pub fn is_clear_fog_at(&self, team: usize, pos: [f64; 2]) -> bool {
-        if !self.params.fow {
-            return true;
+    /// Kills of `team` in the current match
+    pub fn match_kills(&self, team: usize) -> usize {
+        self.match_kills[team]
+    }
+
+    fn match_result(&self, won_team: usize, entities: &[RefCell<Entity>]) -> MatchResult {
+        let mut surviving_agents = [0; 2];
+        let mut resources = [0; 2];
+        for entity in entities {
+            let entity = entity.borrow();
+            let team = entity.get_team();
+            if entity.is_agent() {
+                surviving_agents[team] += 1;
+            } else {
+                resources[team] += entity.resource();
+            }
         }
-        if pos[0] < 0. || self.xs <= pos[0] as usize || pos[1] < 0. || self.ys <= pos[1] as usize {
-            false
-        } else {
-            self.global_time <= self.fog[team].fow[pos[0] as usize + pos[1] as usize * self.xs]
+        MatchResult {
+            won_team,
+            ticks: self.global_time,
+            surviving_agents,
+            resources,
         }
     }
 
-    pub(crate) fn is_fog_older_than(&self, team: usize, pos: [f64; 2], age: i32) -> bool {
-        if !self.params.fow {
-            return true;
+    fn process_spawn_schedule(&mut self, entities: &mut Vec<RefCell<Entity>>) {
+        let schedule = self.params.spawn_schedule.clone();
+        let global_time = self.global_time;
+        for wave in schedule.iter().filter(|wave| wave.tick == global_time) {
+            for _ in 0..wave.count {
+                if let Ok(agent) = self.try_new_agent(
+                    &AgentSpawn {
+                        pos: wave.pos,
+                        team: wave.team,
+                        class: wave.class,
+                        static_: false,
+                        source: None,
+                        randomness: 10.,
+                    },
+                    entities,
+                ) {
+                    self.record_event(GameEventRecord::Spawn {
+                        tick: global_time,
+                        team: wave.team,
+                        id: agent.get_id(),
+                        class: wave.class,
+                    });
+                    entities.push(RefCell::new(agent));
+                    self.stats[wave.team].spawned += 1;
+                }
+            }
+        }
+    }
+
+    /// Run `update` `n` times, or until the match ends, and return the events that happened.
+    pub fn advance(&mut self, n: usize) -> Vec<GameEventRecord> {
+        let prev_records = self.event_records.replace(vec![]);
+        for _ in 0..n {
+            if !matches!(self.update(), UpdateResult::Running) {
+                break;
+            }
+        }
+        let records = std::mem::replace(&mut self.event_records, prev_records).unwrap_or_default();
+        if let Some(prev_records) = &mut self.event_records {
+            prev_records.extend(records.iter().cloned());
+        }
+        records
+    }
+
+    /// Start or stop recording events to be taken by `take_events`, e.g. for a UI to present a
+    /// team winning the game.
+    pub fn set_event_recording(&mut self, enabled: bool) {
+        if !enabled {
+            self.event_records = None;
+        } else if self.event_records.is_none() {
+            self.event_records = Some(vec![]);
+        }
+    }
+
+    /// Take the events recorded since the last call.
+    pub fn take_events(&mut self) -> Vec<GameEventRecord> {
+        self.event_records
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Record a `Replay` of each match from the next `new_board` on, or stop recording.
+    pub fn set_replay_recording(&mut self, enabled: bool) {
+        self.replay_recording = enabled;
+        if !enabled {
+            self.replay = None;
+        }
+    }
+
+    /// The replay of the current match up to the current tick, if it is being recorded.
+    pub fn replay(&self) -> Option<&Replay> {
+        self.replay.as_ref()
+    }
+
+    fn record_event(&mut self, record: GameEventRecord) {
+        if let Some(replay) = &mut self.replay {
+            replay.record_event(&record);
         }
+        if let Some(records) = &mut self.event_records {
+            records.push(record);
+        }
+    }
+
+    pub(crate) fn is_passable_at(&self, pos: [f64; 2]) -> bool {
         if pos[0] < 0. || self.xs <= pos[0] as usize || pos[1] < 0. || self.ys <= pos[1] as usize {
             false
         } else {
-            age <= self
-                .global_time
-                .saturating_sub(self.fog[team].fow[pos[0] as usize + pos[1] as usize * self.xs])
+            let (ret, time) = measure_time_if(self.params.profiling_enabled, || {
+                self.board[pos[0] as usize + pos[1] as usize * self.xs].is_passable()
+            });
+            if let Some(time) = time {
+                self.pixel_profiler.borrow_mut().add(time);
+            }
+            ret
+        }
+    }
+
+    /// Returns the distance to the nearest wall for each pixel of the board, in the same layout as
+    /// `board`. The result is cached until `invalidate_distance_field` is called.
+    pub fn distance_field(&self) -> Ref<'_, [f32]> {
+        if self.distance_field_cache.borrow().is_none() {
+            *self.distance_field_cache.borrow_mut() =
+                Some(distance_transform(&self.board, (self.xs, self.ys)));
+        }
+        Ref::map(self.distance_field_cache.borrow(), |field| {
+            field.as_deref().unwrap()
+        })
+    }
+
+    /// Call this after editing `board` to recompute `distance_field` on the next request.
+    pub fn invalidate_distance_field(&mut self) {
+        self.distance_field_cache = RefCell::new(None);
+    }
+
+    /// Returns the terrain at `pos`, which is `Terrain::Wall` outside the board.
+    pub fn terrain_at(&self, pos: [f64; 2]) -> Terrain {
+        terrain_at(&self.board, (self.xs, self.ys), pos)
+    }
+
+    /// Estimate the normal vector of the wall at `pos` by summing directions to the passable
+    /// neighbor pixels. Returns `None` if there is no passable pixel around.
+    pub(crate) fn wall_normal_at(&self, pos: [f64; 2]) -> Option<Vector2<f64>> {
+        let center = [pos[0].floor() as i32, pos[1].floor() as i32];
+        let sum = (-1..=1)
+            .flat_map(|dy| (-1..=1).map(move |dx| Vector2::new(dx, dy)))
+            .filter(|delta| {
+                is_passable_at_i(
+                    &self.board,
+                    (self.xs, self.ys),
+                    [center[0] + delta.x, center[1] + delta.y],
+                )
+            })
+            .fold(Vector2::new(0., 0.), |acc, delta| {
+                acc + delta.cast::<f64>().unwrap()
+            });
+        if sum.magnitude2() == 0. {
+            None
+        } else {
+            Some(sum.normalize())
+        }
+    }
+
+    /// The team whose nearest agent is closer to `resource` than any agent of the other team, or
+    /// `None` if there are no agents or the nearest agents of both teams are equally close.
+    /// The agents are looked up in the cells of the qtree around the resource, so only those
+    /// within `VISION_RANGE` take part.
+    pub fn resource_contest(&self, resource: &Resource) -> Option<usize> {
+        let center = [resource.pos[0] as i32, resource.pos[1] as i32];
+        let mut nearest = [f64::INFINITY; 2];
+        let mut visited = HashSet::new();
+        for radius in 0..=VISION_RANGE as i32 {
+            // An agent has cells within a cell of its center, so no agent on this ring or
+            // beyond can be nearer than the ones found so far
+            if (radius - 2) as f64 > nearest[0].min(nearest[1]).sqrt() {
+                break;
+            }
+            let ring = (-radius..=radius)
+                .flat_map(|d| [[d, -radius], [d, radius]])
+                .chain((1 - radius..radius).flat_map(|d| [[-radius, d], [radius, d]]));
+            for [dx, dy] in ring {
+                let cell = [center[0] + dx, center[1] + dy];
+                let pos = [cell[0] as f64 + 0.5, cell[1] as f64 + 0.5];
+                let Some((_, CellState::Occupied(id))) = self.qtree.find(pos) else {
+                    continue;
+                };
+                if !visited.insert(id) {
+                    continue;
+                }
+                let Some(entity) = self.get_entity(id) else {
+                    continue;
+                };
+                if !entity.is_agent() {
+                    continue;
+                }
+                let dist2 = Vector2::from(entity.get_pos()).distance2(Vector2::from(resource.pos));
+                let team = entity.get_team();
+                nearest[team] = nearest[team].min(dist2);
+            }
+        }
+        match nearest[0].partial_cmp(&nearest[1])? {
+            std::cmp::Ordering::Less => Some(0),
+            std::cmp::Ordering::Greater => Some(1),
+            std::cmp::Ordering::Equal => None,
+        }
+    }
+
+    pub fn is_clear_fog_at(&self, team: usize, pos: [f64; 2]) -> bool {
+        if !self.params.fow {
+            return true;
+        }
+        if pos[0] < 0. || self.xs <= pos[0] as usize || pos[1] < 0. || self.ys <= pos[1] as usize {
+            false
+        } else {
+            self.global_time <= self.fog[team].fow[pos[0] as usize + pos[1] as usize * self.xs]
+        }
+    }
+
+    /// Fraction of the passable cells that `team` has ever seen, or 1 if the fog of war is
+    /// disabled.
+    pub fn fog_coverage(&self, team: usize) -> f64 {
+        if !self.params.fow {
+            return 1.;
+        }
+        let (seen, passable) = self
+            .board
+            .iter()
+            .zip(self.fog[team].fow.iter())
+            .filter(|(terrain, _)| terrain.is_passable())
+            .fold((0, 0), |(seen, passable), (_, fow)| {
+                (seen + (*fow != i32::MIN) as usize, passable + 1)
+            });
+        if passable == 0 {
+            1.
+        } else {
+            seen as f64 / passable as f64
+        }
+    }
+
+    /// Returns whether `pos` is enemy territory for `team`, i.e. the enemy team has seen the cell at
+    /// least as recently as `team` did. A cell seen by both teams in the same tick counts as
+    /// contested ground, since an agent always sees its own position.
+    pub fn is_enemy_territory(&self, team: usize, pos: [f64; 2]) -> bool {
+        if pos[0] < 0. || self.xs <= pos[0] as usize || pos[1] < 0. || self.ys <= pos[1] as usize {
+            return false;
+        }
+        let idx = pos[0] as usize + pos[1] as usize * self.xs;
+        let enemy_seen = self.fog[(team + 1) % 2].fow[idx];
+        enemy_seen != i32::MIN && self.fog[team].fow[idx] <= enemy_seen
+    }
+
+    /// Returns a path cost multiplier for `my_team` at `pos` that is higher if the enemy team has
+    /// seen the position recently. It decays to 1 after `STEALTH_MEMORY` ticks.
+    pub fn enemy_fog_cost_at(&self, my_team: usize, pos: [f64; 2]) -> f64 {
+        if pos[0] < 0. || self.xs <= pos[0] as usize || pos[1] < 0. || self.ys <= pos[1] as usize {
+            return 1.;
+        }
+        let enemy_team = (my_team + 1) % 2;
+        let age = self
+            .global_time
+            .saturating_sub(self.fog[enemy_team].fow[pos[0] as usize + pos[1] as usize * self.xs]);
+        if STEALTH_MEMORY <= age {
+            1.
+        } else {
+            1. + STEALTH_COST * (1. - age as f64 / STEALTH_MEMORY as f64)
+        }
+    }
+
+    pub(crate) fn is_fog_older_than(&self, team: usize, pos: [f64; 2], age: i32) -> bool {
+        if !self.params.fow {
+            return true;
+        }
+        if pos[0] < 0. || self.xs <= pos[0] as usize || pos[1] < 0. || self.ys <= pos[1] as usize {
+            false
+        } else {
+            age <= self
+                .global_time
+                .saturating_sub(self.fog[team].fow[pos[0] as usize + pos[1] as usize * self.xs])
         }
     }
 
@@ -768,14 +1791,22 @@ impl Game {
     //     }
     // }
 
-    /// Returns an RGB image and its dimensions
+    /// Returns an RGB image of the board darkened by the fog of war of the teams in `fog_active`.
+    /// If `colored_fog` is true, the area each team sees is tinted with its color in `team_colors`.
     pub fn occupancy_image(
         &self,
         fog_active: &[bool; 2],
         colored_fog: bool,
+        team_colors: &[[u8; 3]; 2],
     ) -> Option<([usize; 2], Vec<u8>)> {
         const OBSTACLE_COLOR: u8 = 80u8;
         const BACKGROUND_COLOR: u8 = 191u8;
+        const SLOW_COLOR: u8 = 140u8;
+        let terrain_color = |p: &Terrain| match p {
+            Terrain::Floor => BACKGROUND_COLOR,
+            Terrain::Slow => SLOW_COLOR,
+            Terrain::Wall => OBSTACLE_COLOR,
+        };
 
         if self.params.fow {
             let (fa0, fa1) = (fog_active[0], fog_active[1]);
@@ -786,7 +1817,11 @@ impl Game {
                     .iter()
                     .zip(self.fog[0].fow.iter().zip(self.fog[1].fow.iter()))
                     .map(|(p, (f0, f1))| {
-                        let c = if *p { BACKGROUND_COLOR } else { OBSTACLE_COLOR };
+                        let c = terrain_color(p);
+
+                        let tint = |c: u8, team: usize| {
+                            team_colors[team].map(|ch| (c as u32 * ch as u32 / 255) as u8)
+                        };
 
                         let age_map = |time| {
                             let age = self.global_time.saturating_sub(time);
@@ -802,16 +1837,18 @@ impl Game {
                         if !fa0 && !fa1 {
                             [c, c, c]
                         } else if colored_fog {
-                            if fa0 && fa1 {
-                                let (c0, c1) = (age_map(*f0), age_map(*f1));
-                                [c1 / 2, c0 / 2, c1.max(c0)]
-                            } else if fa0 {
-                                let c0 = age_map(*f0);
-                                [c / 4, c0 / 2, c0]
-                            } else {
-                                let c1 = age_map(*f1);
-                                [c1 / 2, c / 4, c1]
+                            let mut rgb = [c / 4; 3];
+                            for (team, (active, time)) in
+                                [(fa0, f0), (fa1, f1)].into_iter().enumerate()
+                            {
+                                if active {
+                                    let tinted = tint(age_map(*time), team);
+                                    for (ch, t) in rgb.iter_mut().zip(tinted) {
+                                        *ch = (*ch).max(t);
+                                    }
+                                }
                             }
+                            rgb
                         } else {
                             let age = if fa0 && fa1 {
                                 *f0.max(f1)
@@ -832,13 +1869,129 @@ impl Game {
                 [self.xs, self.ys],
                 self.board
                     .iter()
-                    .map(|p| [if *p { BACKGROUND_COLOR } else { OBSTACLE_COLOR }; 3])
-                    .flatten()
+                    .flat_map(|p| [terrain_color(p); 3])
                     .collect::<Vec<_>>(),
             ))
         }
     }
 
+    fn add_kill_heat(&mut self, pos: [f64; 2]) {
+        let [x, y] = [pos[0].floor() as isize, pos[1].floor() as isize];
+        if x < 0 || self.xs as isize <= x || y < 0 || self.ys as isize <= y {
+            return;
+        }
+        if let Some(cell) = self.kill_heatmap.get_mut(x as usize + y as usize * self.xs) {
+            *cell += 1;
+        }
+    }
+
+    /// Returns an RGB image of `kill_heatmap` over the board and its dimensions. Cells with kills
+    /// are colored red, brighter in proportion to the number of kills relative to the hottest cell.
+    pub fn kill_heatmap_image(&self) -> Option<([usize; 2], Vec<u8>)> {
+        const OBSTACLE_COLOR: u8 = 40u8;
+        const BACKGROUND_COLOR: u8 = 95u8;
+        /// Red intensity of a cell with a single kill, so that it stands out from the board
+        const MIN_HEAT: u32 = 64;
+        let max = (*self.kill_heatmap.iter().max()?).max(1);
+
+        Some((
+            [self.xs, self.ys],
+            self.board
+                .iter()
+                .zip(self.kill_heatmap.iter())
+                .flat_map(|(p, &kills)| {
+                    let c = if p.is_passable() {
+                        BACKGROUND_COLOR
+                    } else {
+                        OBSTACLE_COLOR
+                    };
+                    if kills == 0 {
+                        return [c, c, c];
+                    }
+                    let heat = (MIN_HEAT + kills * (255 - MIN_HEAT) / max) as u8;
+                    [heat, c / 2, c / 2]
+                })
+                .collect::<Vec<_>>(),
+        ))
+    }
+
+    /// Run a grid Dijkstra search from `start` over the board that path finding sees, and
+    /// trace the path to `goal` on it. Returns `None` if `start` is not on a passable cell.
+    pub fn dijkstra(&self, start: [f64; 2], goal: [f64; 2]) -> Option<DijkstraResult> {
+        let shape = (self.xs, self.ys);
+        let to_cell = |pos: [f64; 2]| [pos[0] as i32, pos[1] as i32];
+        let board = self.path_board();
+        if !is_passable_at_i(board, shape, to_cell(start)) {
+            return None;
+        }
+        let passable: Vec<_> = board.iter().map(|p| p.is_passable()).collect();
+        Some(dijkstra_path(
+            &passable,
+            shape,
+            to_cell(start),
+            to_cell(goal),
+        ))
+    }
+
+    /// Returns the center of the passable cell nearest to `pos` among those reachable from
+    /// `start` over the board that path finding sees, so that an agent can get as close as
+    /// possible to a walled-off position. Cells closer than `clearance` to a wall are skipped.
+    /// Returns `None` if `start` is not on a passable cell or no cell has the clearance.
+    pub fn nearest_free_cell(
+        &self,
+        pos: [f64; 2],
+        start: [f64; 2],
+        clearance: f64,
+    ) -> Option<[f64; 2]> {
+        let shape = (self.xs, self.ys);
+        let start = [start[0] as i32, start[1] as i32];
+        let board = self.path_board();
+        if !is_passable_at_i(board, shape, start) {
+            return None;
+        }
+        let passable: Vec<_> = board.iter().map(|p| p.is_passable()).collect();
+        let mut costs = vec![i32::MAX; board.len()];
+        dijkstra_fill(&passable, shape, start, &mut &mut costs[..]);
+        let distance_field = self.distance_field();
+        let distance2 = |idx: usize| {
+            let cell = [(idx % self.xs) as f64 + 0.5, (idx / self.xs) as f64 + 0.5];
+            (cell[0] - pos[0]).powi(2) + (cell[1] - pos[1]).powi(2)
+        };
+        let best = (0..costs.len())
+            .filter(|idx| {
+                // The field is measured from the center of the cell to the center of the wall
+                costs[*idx] != i32::MAX && clearance + 0.5 <= distance_field[*idx] as f64
+            })
+            .min_by(|a, b| distance2(*a).total_cmp(&distance2(*b)))?;
+        Some([(best % self.xs) as f64 + 0.5, (best / self.xs) as f64 + 0.5])
+    }
+
+    /// Returns an RGB image of the cost field of `result` over the board and its dimensions.
+    /// Reachable cells are shaded from blue near the start to yellow at the farthest cell.
+    pub fn dijkstra_image(&self, result: &DijkstraResult) -> ([usize; 2], Vec<u8>) {
+        const OBSTACLE_COLOR: u8 = 40u8;
+        const BACKGROUND_COLOR: u8 = 95u8;
+        let max = result.max_cost().max(1);
+        let image = self
+            .board
+            .iter()
+            .zip(result.costs.iter())
+            .flat_map(|(p, &cost)| {
+                if cost == i32::MAX {
+                    let c = if p.is_passable() {
+                        BACKGROUND_COLOR
+                    } else {
+                        OBSTACLE_COLOR
+                    };
+                    return [c, c, c];
+                }
+                let f = (cost as f64 / max as f64 * 255.) as u8;
+                [f, f, 255 - f]
+            })
+            .collect();
+        ([self.xs, self.ys], image)
+    }
+
     pub fn labeled_image(&self) -> Option<([usize; 2], Vec<u8>)> {
         let mut rng = Xor128::new(616516);
         let max_label = *self.mesh.labeled_image.iter().max()? + 1;
@@ -869,6 +2022,60 @@ impl Game {
         ))
     }
 
+    /// Rebuild the behavior trees of all the living agents of `team` from `source`, and use it
+    /// for the agents spawned later too. Nothing is changed if the source fails to build.
+    pub fn reapply_team_bt(
+        &mut self,
+        team: usize,
+        source: Rc<String>,
+    ) -> Result<(), BuildTreeError> {
+        let agents: Vec<_> = self
+            .entities
+            .iter()
+            .filter(|entity| {
+                let entity = entity.borrow();
+                entity.is_agent() && entity.get_team() == team
+            })
+            .collect();
+        // Build all the trees up front, so that an error leaves every agent intact
+        let trees = Agent::build_behavior_trees(&source, agents.len())?;
+        for (entity, tree) in agents.into_iter().zip(trees) {
+            if let Entity::Agent(agent) = &mut *entity.borrow_mut() {
+                agent.set_behavior_tree(source.clone(), tree);
+            }
+        }
+        self.params.teams[team].agent_source = source;
+        Ok(())
+    }
+
+    /// The behavior tree sources that each team applies to new agents and spawners, as pairs of
+    /// the agent source and the spawner source.
+    pub fn team_bt_sources(&self) -> [(Rc<String>, Rc<String>); 2] {
+        self.params
+            .teams
+            .each_ref()
+            .map(|team| (team.agent_source.clone(), team.spawner_source.clone()))
+    }
+
+    /// The behavior tree sources that the living agents of `team` run, with the ids of the agents
+    /// running each of them, in the order of first appearance.
+    pub fn agent_bt_sources(&self, team: usize) -> Vec<(Rc<String>, Vec<usize>)> {
+        let mut groups: Vec<(Rc<String>, Vec<usize>)> = vec![];
+        for entity in &self.entities {
+            let entity = entity.borrow();
+            if !entity.is_agent() || entity.get_team() != team {
+                continue;
+            }
+            let source = entity.behavior_source();
+            if let Some((_, ids)) = groups.iter_mut().find(|(s, _)| *s == source) {
+                ids.push(entity.get_id());
+            } else {
+                groups.push((source, vec![entity.get_id()]));
+            }
+        }
+        groups
+    }
+
     pub fn get_entity(&self, id: usize) -> Option<std::cell::Ref<Entity>> {
         self.entities.iter().find_map(|entity| {
             let entity = entity.borrow();
@@ -906,10 +2113,37 @@ pub(crate) fn separating_axis(
     }
 }
 
-pub fn is_passable_at(board: &[bool], shape: (usize, usize), pos: [f64; 2]) -> bool {
+fn update_aabb(
+    qtree: &mut QTreeSearcher,
+    aabb: [f64; 4],
+    cell_state: impl Fn([i32; 2]) -> CellState,
+) {
+    for y in aabb[1].floor() as i32..aabb[3].ceil() as i32 {
+        for x in aabb[0].floor() as i32..aabb[2].ceil() as i32 {
+            if let Err(e) = qtree.update([x, y], cell_state([x, y])) {
+                println!("qtree.update error: {e}");
+            }
+        }
+    }
+}
+
+/// The cell state of a pixel when no entity occupies it
+fn get_background(board: &[Terrain], shape: (usize, usize), pos: [i32; 2]) -> CellState {
+    if is_passable_at(board, shape, [pos[0] as f64 + 0.5, pos[1] as f64 + 0.5]) {
+        CellState::Free
+    } else {
+        CellState::Obstacle
+    }
+}
+
+pub fn is_passable_at(board: &[Terrain], shape: (usize, usize), pos: [f64; 2]) -> bool {
+    terrain_at(board, shape, pos).is_passable()
+}
+
+pub fn terrain_at(board: &[Terrain], shape: (usize, usize), pos: [f64; 2]) -> Terrain {
     let pos = [pos[0] as isize, pos[1] as isize];
     if pos[0] < 0 || shape.0 as isize <= pos[0] || pos[1] < 0 || shape.1 as isize <= pos[1] {
-        false
+        Terrain::Wall
     } else {
         let pos = [pos[0] as usize, pos[1] as usize];
         board[pos[0] + shape.0 * pos[1]]
@@ -917,13 +2151,1563 @@ pub fn is_passable_at(board: &[bool], shape: (usize, usize), pos: [f64; 2]) -> b
 }
 
 /// An integer variant of `is_passable_at`.
-pub fn is_passable_at_i(board: &[bool], shape: (usize, usize), pos: impl Into<[i32; 2]>) -> bool {
+pub fn is_passable_at_i(
+    board: &[Terrain],
+    shape: (usize, usize),
+    pos: impl Into<[i32; 2]>,
+) -> bool {
     let pos = pos.into();
     let pos = [pos[0] as isize, pos[1] as isize];
     if pos[0] < 0 || shape.0 as isize <= pos[0] || pos[1] < 0 || shape.1 as isize <= pos[1] {
         false
     } else {
         let pos = [pos[0] as usize, pos[1] as usize];
-        board[pos[0] + shape.0 * pos[1]]
+        board[pos[0] + shape.0 * pos[1]].is_passable()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::agent::{BULLET_DAMAGE, BULLET_SPEED, HEALTH_REGEN_DELAY};
+    use std::ops::RangeInclusive;
+
+    const IDLE_SOURCE: &str = "tree main = Print (input <- \"idle\")";
+
+    /// Teams whose agents and spawners do nothing, so that only the test changes the game
+    fn idle_teams() -> [TeamConfig; 2] {
+        [0, 1].map(|_| TeamConfig {
+            agent_source: Rc::new(IDLE_SOURCE.to_string()),
+            spawner_source: Rc::new(IDLE_SOURCE.to_string()),
+            ..TeamConfig::default()
+        })
+    }
+
+    /// Teams that both play with the behavior trees of the green team
+    fn green_teams() -> [TeamConfig; 2] {
+        [0, 1].map(|_| TeamConfig {
+            agent_source: Rc::new(
+                include_str!("../behavior_tree_config/green/agent.btc").to_string(),
+            ),
+            spawner_source: Rc::new(
+                include_str!("../behavior_tree_config/green/spawner.btc").to_string(),
+            ),
+            ..TeamConfig::default()
+        })
+    }
+
+    /// A game on the default board, initialized with `params`
+    fn init_game(params: &GameParams) -> Game {
+        let mut game = Game::new();
+        game.set_params(params);
+        game.init();
+        game
+    }
+
+    /// The centers of the cells that are free in the qtree, row by row
+    fn free_cells(game: &Game) -> Vec<[f64; 2]> {
+        let (xs, ys) = game.shape();
+        (0..ys)
+            .flat_map(|y| (0..xs).map(move |x| [x as f64 + 0.5, y as f64 + 0.5]))
+            .filter(|pos| matches!(game.qtree.find(*pos), Some((_, CellState::Free))))
+            .collect()
+    }
+
+    /// Whether the cells within the offsets `dxs` and `dys` from `pos` are all free and on the
+    /// board
+    fn is_free_around(
+        game: &Game,
+        pos: [f64; 2],
+        dxs: RangeInclusive<i32>,
+        dys: RangeInclusive<i32>,
+    ) -> bool {
+        dxs.into_iter().all(|dx| {
+            dys.clone().all(|dy| {
+                let pos = [pos[0] + dx as f64, pos[1] + dy as f64];
+                0. <= pos[0]
+                    && 0. <= pos[1]
+                    && matches!(game.qtree.find(pos), Some((_, CellState::Free)))
+            })
+        })
+    }
+
+    #[test]
+    fn test_spawn_wave() {
+        let mut params = GameParams::new();
+        params.agent_count = 0;
+        params.fow = false;
+        params.teams = green_teams();
+        let mut game = init_game(&params);
+        let pos = [game.xs as f64 / 2., game.ys as f64 / 2.];
+        let pos = (0..game.xs * game.ys)
+            .map(|i| [(i % game.xs) as f64 + 0.5, (i / game.xs) as f64 + 0.5])
+            .min_by_key(|p| {
+                if game.is_passable_at(*p) {
+                    ((p[0] - pos[0]).powi(2) + (p[1] - pos[1]).powi(2)) as i64
+                } else {
+                    i64::MAX
+                }
+            })
+            .unwrap();
+        params.spawn_schedule = Rc::new(vec![SpawnWave {
+            tick: 50,
+            team: 0,
+            class: AgentClass::Worker,
+            count: 3,
+            pos,
+        }]);
+        game.set_params(&params);
+
+        let count_agents = |game: &Game| {
+            game.entities
+                .iter()
+                .filter(|e| {
+                    let e = e.borrow();
+                    e.is_agent() && e.get_team() == 0
+                })
+                .count()
+        };
+
+        while game.global_time < 50 - 1 {
+            game.update();
+        }
+        let before = count_agents(&game);
+        while game.global_time < 50 + 1 {
+            game.update();
+        }
+        assert!(before < count_agents(&game));
+    }
+
+    #[test]
+    fn test_entity_order_independent() {
+        let run = |shuffle: bool| {
+            let mut params = GameParams::new();
+            params.teams = green_teams();
+            let mut game = init_game(&params);
+            for tick in 0..1000 {
+                // Force a different order of the entities Vec than the one they were created in
+                if shuffle && tick % 50 == 0 {
+                    game.entities.reverse();
+                }
+                game.update();
+            }
+            let mut states: Vec<_> = game
+                .entities
+                .iter()
+                .map(|entity| {
+                    let entity = entity.borrow();
+                    (entity.get_id(), entity.get_pos(), entity.get_health())
+                })
+                .collect();
+            states.sort_by_key(|(id, _, _)| *id);
+            let bullets: Vec<_> = game.bullets.iter().map(|bullet| bullet.pos).collect();
+            (states, bullets)
+        };
+        let (states, bullets) = run(false);
+        assert!(4 < states.len(), "{}", states.len());
+        assert_eq!((states, bullets), run(true));
+    }
+
+    #[test]
+    fn test_profiling_disabled() {
+        let run = |profiling_enabled: bool| {
+            let mut params = GameParams::new();
+            params.teams = green_teams();
+            params.profiling_enabled = profiling_enabled;
+            let mut game = init_game(&params);
+            for _ in 0..500 {
+                game.update();
+            }
+            let states: Vec<_> = game
+                .entities
+                .iter()
+                .map(|entity| {
+                    let entity = entity.borrow();
+                    (entity.get_id(), entity.get_pos(), entity.get_health())
+                })
+                .collect();
+            let bullets: Vec<_> = game.bullets.iter().map(|bullet| bullet.pos).collect();
+            let profiled = [
+                &game.qtree_profiler,
+                &game.path_find_profiler,
+                &game.fow_raycast_profiler,
+            ]
+            .map(|profiler| profiler.borrow().get_count());
+            (states, bullets, profiled)
+        };
+        let (states, bullets, profiled) = run(true);
+        assert!(profiled.iter().all(|count| 0 < *count), "{profiled:?}");
+        let (states_off, bullets_off, profiled_off) = run(false);
+        assert_eq!(profiled_off, [0; 3]);
+        assert_eq!((states, bullets), (states_off, bullets_off));
+    }
+
+    #[test]
+    fn test_spawner_agent_sources() {
+        const SPAWNER_SOURCE: &str = r#"
+tree main = Sequence {
+    var alt
+    if (LastSpawnResult) {
+        if (alt) {
+            alt = false
+        } else {
+            alt = true
+        }
+    }
+    if (alt) {
+        SpawnWorker (source <- "1")
+    } else {
+        SpawnWorker (source <- "0")
+    }
+}
+"#;
+        let sources = [
+            Rc::new("tree main = Print (input <- \"A\")".to_string()),
+            Rc::new("tree main = Print (input <- \"B\")".to_string()),
+        ];
+        let mut params = GameParams::new();
+        params.fow = false;
+        params.agent_count = 10;
+        params.teams = [0, 1].map(|_| TeamConfig {
+            agent_source: Rc::new(
+                include_str!("../behavior_tree_config/green/agent.btc").to_string(),
+            ),
+            spawner_source: Rc::new(SPAWNER_SOURCE.to_string()),
+            agent_sources: Rc::new(sources.to_vec()),
+        });
+        let mut game = init_game(&params);
+
+        for _ in 0..1000 {
+            game.update();
+        }
+
+        let agent_sources: Vec<_> = game
+            .entities
+            .iter()
+            .filter_map(|e| {
+                let e = e.borrow();
+                if e.is_agent() && e.get_team() == 0 {
+                    Some(e.behavior_source())
+                } else {
+                    None
+                }
+            })
+            .collect();
+        assert!(sources.iter().all(|source| agent_sources.contains(source)));
+    }
+
+    #[test]
+    fn test_spawner_cooldown() {
+        const COOLDOWN: usize = 400;
+        const TICKS: usize = 1000;
+        let mut params = GameParams::new();
+        params.fow = false;
+        params.agent_count = 10;
+        params.spawner_cooldown = COOLDOWN;
+        params.teams = [0, 1].map(|_| TeamConfig {
+            agent_source: Rc::new(
+                include_str!("../behavior_tree_config/green/agent.btc").to_string(),
+            ),
+            spawner_source: Rc::new("tree main = SpawnWorker".to_string()),
+            ..TeamConfig::default()
+        });
+        let mut game = init_game(&params);
+
+        for _ in 0..TICKS {
+            for entity in &game.entities {
+                if let Entity::Spawner(spawner) = &mut *entity.borrow_mut() {
+                    spawner.resource = crate::spawner::SPAWNER_MAX_RESOURCE;
+                }
+            }
+            game.update();
+        }
+
+        assert!(0 < game.stats[0].spawned);
+        assert!(game.stats[0].spawned <= TICKS / COOLDOWN + 1);
+    }
+
+    #[test]
+    fn test_spawn_cost_capped() {
+        let mut params = GameParams::new();
+        params.fow = false;
+        params.agent_count = 10;
+        params.spawn_cost_scaling = 100.;
+        params.teams = [0, 1].map(|_| TeamConfig {
+            agent_source: Rc::new("tree main = Sequence { }".to_string()),
+            spawner_source: Rc::new("tree main = SpawnWorker".to_string()),
+            ..TeamConfig::default()
+        });
+        let mut game = init_game(&params);
+
+        // The scaled cost would exceed any resource a spawner can hold after the first spawn
+        for _ in 0..1000 {
+            for entity in &game.entities {
+                if let Entity::Spawner(spawner) = &mut *entity.borrow_mut() {
+                    spawner.resource = crate::spawner::SPAWNER_MAX_RESOURCE;
+                }
+            }
+            game.update();
+        }
+
+        assert!(1 < game.stats[0].spawned);
+    }
+
+    #[test]
+    fn test_safe_spawn() {
+        let mut params = GameParams::new();
+        params.fow = false;
+        params.agent_count = 10;
+        params.safe_spawn = true;
+        params.teams = [0, 1].map(|team| TeamConfig {
+            agent_source: Rc::new("tree main = Sequence { }".to_string()),
+            spawner_source: Rc::new(
+                if team == 0 {
+                    "tree main = SpawnWorker"
+                } else {
+                    "tree main = Sequence { }"
+                }
+                .to_string(),
+            ),
+            ..TeamConfig::default()
+        });
+        let mut game = init_game(&params);
+
+        let spawner_pos = game
+            .entities
+            .iter()
+            .map(|e| e.borrow())
+            .find(|e| !e.is_agent() && e.get_team() == 0)
+            .unwrap()
+            .get_pos();
+        let enemy_pos = [spawner_pos[0] + 2., spawner_pos[1]];
+        let enemy = Agent::new(
+            &mut game.id_gen,
+            enemy_pos,
+            0.,
+            1,
+            AgentClass::Worker,
+            params.teams[1].agent_source.clone(),
+        )
+        .unwrap();
+        game.frozen_agents.insert(enemy.id);
+        game.entities.push(RefCell::new(Entity::Agent(enemy)));
+
+        for _ in 0..2000 {
+            for entity in &game.entities {
+                if let Entity::Spawner(spawner) = &mut *entity.borrow_mut() {
+                    spawner.resource = crate::spawner::SPAWNER_MAX_RESOURCE;
+                }
+            }
+            game.update();
+        }
+
+        assert!(0 < game.stats[0].spawned);
+        for entity in &game.entities {
+            let entity = entity.borrow();
+            if entity.is_agent() && entity.get_team() == 0 {
+                let pos = entity.get_pos();
+                let dist = Vector2::from(pos) - Vector2::from(enemy_pos);
+                assert!(SAFE_SPAWN_RADIUS <= dist.magnitude(), "{pos:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_safe_spawn_camping() {
+        const COOLDOWN: usize = 10;
+        let mut params = GameParams::new();
+        params.fow = false;
+        params.agent_count = 100;
+        params.safe_spawn = true;
+        params.spawner_cooldown = COOLDOWN;
+        params.teams = [0, 1].map(|team| TeamConfig {
+            agent_source: Rc::new("tree main = Sequence { }".to_string()),
+            spawner_source: Rc::new(
+                if team == 0 {
+                    "tree main = SpawnWorker"
+                } else {
+                    "tree main = Sequence { }"
+                }
+                .to_string(),
+            ),
+            ..TeamConfig::default()
+        });
+        let mut game = init_game(&params);
+
+        let spawner_pos = game
+            .entities
+            .iter()
+            .map(|e| e.borrow())
+            .find(|e| !e.is_agent() && e.get_team() == 0)
+            .unwrap()
+            .get_pos();
+        // Enemies camping around the spawner block every spawn position
+        let mut enemy_ids = vec![];
+        for offset in [[0., 0.], [-3.5, -3.5], [3.5, -3.5], [-3.5, 3.5], [3.5, 3.5]] {
+            let enemy = Agent::new(
+                &mut game.id_gen,
+                [spawner_pos[0] + offset[0], spawner_pos[1] + offset[1]],
+                0.,
+                1,
+                AgentClass::Worker,
+                params.teams[1].agent_source.clone(),
+            )
+            .unwrap();
+            enemy_ids.push(enemy.id);
+            game.frozen_agents.insert(enemy.id);
+            game.entities.push(RefCell::new(Entity::Agent(enemy)));
+        }
+
+        let fill_resource = |game: &Game| {
+            for entity in &game.entities {
+                if let Entity::Spawner(spawner) = &mut *entity.borrow_mut() {
+                    spawner.resource = crate::spawner::SPAWNER_MAX_RESOURCE;
+                }
+            }
+        };
+        for _ in 0..1000 {
+            fill_resource(&game);
+            game.update();
+            assert!(game.pending_spawns.len() <= 1);
+        }
+        assert_eq!(game.stats[0].spawned, 0);
+        assert_eq!(game.pending_spawns.len(), 1);
+
+        // Only the single pending spawn goes through once the enemies leave
+        game.entities
+            .retain(|entity| !enemy_ids.contains(&entity.borrow().get_id()));
+        fill_resource(&game);
+        game.update();
+        assert_eq!(game.stats[0].spawned, 1);
+        assert!(game.pending_spawns.is_empty());
+        for _ in 0..COOLDOWN {
+            fill_resource(&game);
+            game.update();
+        }
+        assert!(game.stats[0].spawned <= 2);
+    }
+
+    #[test]
+    fn test_min_spawner_distance() {
+        const SHAPE: (usize, usize) = (32, 32);
+        let init = |min_spawner_distance: f64| {
+            let mut game = Game::from_board(vec![true; SHAPE.0 * SHAPE.1], SHAPE);
+            let mut params = GameParams::new();
+            params.teams = idle_teams();
+            params.min_spawner_distance = min_spawner_distance;
+            game.set_params(&params);
+            game.init();
+            game.entities
+                .iter()
+                .map(|entity| Vector2::from(entity.borrow().get_pos()))
+                .collect::<Vec<_>>()
+        };
+
+        let spawners = init(30.);
+        assert_eq!(spawners.len(), 2);
+        assert!(30. <= spawners[0].distance(spawners[1]));
+
+        // Too far apart to fit in the board, so the second spawner is not placed
+        assert_eq!(init(100.).len(), 1);
+    }
+
+    #[test]
+    fn test_clear_team() {
+        let mut params = GameParams::new();
+        params.fow = false;
+        params.teams = green_teams();
+        let mut game = init_game(&params);
+
+        for _ in 0..100 {
+            game.update();
+        }
+
+        let occupied: Vec<_> = game
+            .entities
+            .iter()
+            .map(|e| e.borrow())
+            .filter(|e| e.get_team() == 0)
+            .map(|e| (e.get_id(), e.get_pos()))
+            .collect();
+        assert!(!occupied.is_empty());
+        for (id, pos) in &occupied {
+            assert!(
+                matches!(game.qtree.find(*pos), Some((_, CellState::Occupied(occ))) if occ == *id)
+            );
+        }
+
+        // An enemy overlapping one of the agents keeps its cells
+        let (_, overlap_pos) = occupied[0];
+        let enemy = Agent::new(
+            &mut game.id_gen,
+            overlap_pos,
+            0.,
+            1,
+            AgentClass::Worker,
+            params.teams[1].agent_source.clone(),
+        )
+        .unwrap();
+        let enemy_id = enemy.id;
+        game.qtree.start_update();
+        update_aabb(&mut game.qtree, enemy.get_shape().to_aabb(), |_| {
+            CellState::Occupied(enemy_id)
+        });
+        game.qtree.finish_update();
+        game.entities.push(RefCell::new(Entity::Agent(enemy)));
+
+        game.clear_team(0);
+
+        assert!(game.entities.iter().all(|e| e.borrow().get_team() != 0));
+        assert!(matches!(
+            game.qtree.find(overlap_pos),
+            Some((_, CellState::Occupied(occ))) if occ == enemy_id
+        ));
+        for (_, pos) in &occupied {
+            if 2. < Vector2::from(*pos).distance(Vector2::from(overlap_pos)) {
+                assert!(matches!(game.qtree.find(*pos), Some((_, CellState::Free))));
+            }
+        }
+    }
+
+    #[test]
+    fn test_match_result() {
+        const WIN_TICK: i32 = 20;
+        let mut params = GameParams::new();
+        params.fow = false;
+        params.teams = idle_teams();
+        let mut game = init_game(&params);
+
+        let spawner_pos = game
+            .entities
+            .iter()
+            .map(|e| e.borrow())
+            .find(|e| e.get_team() == 0)
+            .unwrap()
+            .get_pos();
+        params.spawn_schedule = Rc::new(vec![SpawnWave {
+            tick: 1,
+            team: 0,
+            class: AgentClass::Worker,
+            count: 2,
+            pos: spawner_pos,
+        }]);
+        game.set_params(&params);
+
+        while game.global_time < WIN_TICK - 1 {
+            assert!(matches!(game.update(), UpdateResult::Running));
+        }
+        let surviving = game
+            .entities
+            .iter()
+            .filter(|e| e.borrow().is_agent() && e.borrow().get_team() == 0)
+            .count();
+        assert!(0 < surviving);
+
+        game.clear_team(1);
+        let UpdateResult::TeamWon(result) = game.update() else {
+            panic!("Team 0 should have won");
+        };
+        assert_eq!(result.won_team, 0);
+        assert_eq!(result.ticks, WIN_TICK);
+        assert_eq!(result.surviving_agents, [surviving, 0]);
+        assert_eq!(game.stats[0].wins, 1);
+    }
+
+    #[test]
+    fn test_time_limit() {
+        const TIME_LIMIT: i32 = 10;
+        let run = |counts: [usize; 2]| {
+            let mut params = GameParams::new();
+            params.fow = false;
+            params.agent_count = 0;
+            params.time_limit_ticks = TIME_LIMIT;
+            params.teams = idle_teams();
+            let mut game = init_game(&params);
+
+            let waves = game
+                .entities
+                .iter()
+                .map(|e| e.borrow())
+                .map(|spawner| SpawnWave {
+                    tick: 1,
+                    team: spawner.get_team(),
+                    class: AgentClass::Worker,
+                    count: counts[spawner.get_team()],
+                    pos: spawner.get_pos(),
+                })
+                .collect();
+            params.spawn_schedule = Rc::new(waves);
+            game.set_params(&params);
+
+            loop {
+                match game.update() {
+                    UpdateResult::Running => assert!(game.global_time < TIME_LIMIT),
+                    result => return (result, game),
+                }
+            }
+        };
+
+        let (result, game) = run([2, 1]);
+        let UpdateResult::TeamWon(result) = result else {
+            panic!("Team 0 should have won by the agent count");
+        };
+        assert_eq!(result.won_team, 0);
+        assert_eq!(result.ticks, TIME_LIMIT);
+        assert_eq!(result.surviving_agents, [2, 1]);
+        assert_eq!(game.stats[0].wins, 1);
+
+        let (result, _) = run([1, 1]);
+        assert!(matches!(result, UpdateResult::Draw { ticks: TIME_LIMIT }));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_scenario_file() {
+        let mut params = GameParams::new();
+        params.agent_count = 7;
+        params.time_limit_ticks = 1000;
+        params.teams[1] = TeamConfig {
+            agent_source: Rc::new("tree main = Print (input <- \"agent\")".to_string()),
+            spawner_source: Rc::new("tree main = Print (input <- \"spawner\")".to_string()),
+            agent_sources: Rc::new(vec![Rc::new("tree main = Sequence {\n}".to_string())]),
+        };
+        params.spawn_schedule = Rc::new(vec![SpawnWave {
+            tick: 10,
+            team: 1,
+            class: AgentClass::Fighter,
+            count: 2,
+            pos: [1.5, 2.5],
+        }]);
+
+        let path =
+            std::env::temp_dir().join(format!("swarm_rs_test_scenario_{}.ron", std::process::id()));
+        params.to_scenario_file(&path).unwrap();
+        let loaded = GameParams::from_scenario_file(&path).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(format!("{loaded:?}"), format!("{params:?}"));
+
+        // A scenario without a parameter, e.g. written by an older version, takes its default
+        let old_text: String = text
+            .lines()
+            .filter(|line| !line.trim_start().starts_with("time_limit_ticks:"))
+            .map(|line| format!("{line}\n"))
+            .collect();
+        assert_ne!(old_text, text);
+        let loaded: GameParams = ron::from_str(&old_text).unwrap();
+        assert_eq!(loaded.time_limit_ticks, GameParams::new().time_limit_ticks);
+        assert_eq!(loaded.agent_count, params.agent_count);
+    }
+
+    #[test]
+    fn test_ricochet() {
+        let mut game = Game::new();
+        let mut params = GameParams::new();
+        params.fow = false;
+        params.ricochet = true;
+        game.set_params(&params);
+
+        // A flat wall filling x >= 64
+        let (xs, ys) = game.shape();
+        game.board = (0..ys)
+            .flat_map(|_| (0..xs).map(|x| (x < 64).into()))
+            .collect();
+
+        let bullet = Bullet::new([64.5, 50.5], [1., 0.5], 0, 10, AgentClass::Fighter);
+        let bounces = bullet.bounces_left;
+        game.bullets.push(bullet);
+        game.update();
+
+        let bullet = game
+            .bullets
+            .first()
+            .expect("Bullet should survive a ricochet");
+        assert_eq!(bullet.velo, [-1., 0.5]);
+        assert_eq!(bullet.bounces_left, bounces - 1);
+    }
+
+    #[test]
+    fn test_damage_falloff() {
+        let damage_taken = |falloff: bool, traveled: f64| {
+            let mut game = Game::new();
+            let mut params = GameParams::new();
+            params.fow = false;
+            params.damage_falloff = falloff;
+            game.set_params(&params);
+            let (xs, ys) = game.shape();
+            game.board = vec![Terrain::Floor; xs * ys];
+
+            let agent = Agent::new(
+                &mut game.id_gen,
+                [60.5, 50.5],
+                0.,
+                1,
+                AgentClass::Worker,
+                Rc::new(IDLE_SOURCE.to_string()),
+            )
+            .unwrap();
+            let health = agent.health;
+            game.entities.push(RefCell::new(Entity::Agent(agent)));
+
+            let mut bullet = Bullet::new([59.5, 50.5], [1., 0.], 0, 10, AgentClass::Worker);
+            bullet.traveled = traveled;
+            game.bullets.push(bullet);
+            game.update();
+            assert!(game.bullets.is_empty(), "The bullet should have hit");
+            let Entity::Agent(agent) = &*game.entities[0].borrow() else {
+                panic!("The agent should survive");
+            };
+            health - agent.health
+        };
+
+        let max_range = AgentClass::Worker.max_range();
+        assert_eq!(damage_taken(false, 0.), 10);
+        assert_eq!(damage_taken(false, max_range), 10);
+        assert_eq!(damage_taken(true, 0.), 10);
+        let long_range = damage_taken(true, max_range);
+        assert!(0 < long_range && long_range < 10, "{long_range}");
+        assert!(damage_taken(true, max_range / 2.) < 10);
+    }
+
+    #[test]
+    fn test_slow_terrain() {
+        let mut game = Game::new();
+        let mut params = GameParams::new();
+        params.fow = false;
+        game.set_params(&params);
+
+        // A band of slow terrain in 40 <= x < 80
+        let (xs, ys) = game.shape();
+        game.board = (0..ys)
+            .flat_map(|_| {
+                (0..xs).map(|x| {
+                    if (40..80).contains(&x) {
+                        Terrain::Slow
+                    } else {
+                        Terrain::Floor
+                    }
+                })
+            })
+            .collect();
+
+        let mut agent = Agent::new(
+            &mut game.id_gen,
+            [20.5, 50.5],
+            0.,
+            0,
+            AgentClass::Worker,
+            Rc::new(IDLE_SOURCE.to_string()),
+        )
+        .unwrap();
+        let mut step = |agent: &mut Agent| {
+            let prev = agent.pos[0];
+            assert!(agent.drive(1., &mut game, &[]));
+            agent.pos[0] - prev
+        };
+        let floor_speed = step(&mut agent);
+        while agent.pos[0] < 40. {
+            step(&mut agent);
+        }
+        let slow_speed = step(&mut agent);
+        assert_eq!(slow_speed, floor_speed * SLOW_TERRAIN_SPEED);
+        while agent.pos[0] < 80. {
+            step(&mut agent);
+        }
+        assert_eq!(step(&mut agent), floor_speed);
+
+        // Bullets fly over slow terrain unless configured otherwise
+        for blocks in [false, true] {
+            params.slow_blocks_bullets = blocks;
+            game.set_params(&params);
+            game.bullets.clear();
+            game.bullets.push(Bullet::new(
+                [50.5, 50.5],
+                [1., 0.],
+                0,
+                10,
+                AgentClass::Fighter,
+            ));
+            game.update();
+            assert_eq!(game.bullets.is_empty(), blocks);
+        }
+    }
+
+    #[test]
+    fn test_perlin_slow_shores() {
+        let count_slow = |slow_shores| {
+            let MeshResult { board, .. } = Game::create_perlin_board(&BoardParams {
+                shape: (128, 128),
+                seed: 123513,
+                simplify: 1.,
+                maze_expansions: 0,
+                slow_shores,
+            });
+            board.iter().filter(|t| matches!(t, Terrain::Slow)).count()
+        };
+        assert_eq!(count_slow(false), 0);
+        assert!(0 < count_slow(true));
+    }
+
+    #[test]
+    fn test_health_regen() {
+        let mut game = Game::new();
+        let mut params = GameParams::new();
+        params.fow = false;
+        params.health_regen = 0.5;
+        game.set_params(&params);
+
+        let mut free_positions = free_cells(&game).into_iter().step_by(100);
+        for damaged_tick in [i32::MIN, game.global_time] {
+            let mut agent = Agent::new(
+                &mut game.id_gen,
+                free_positions.next().unwrap(),
+                0.,
+                0,
+                AgentClass::Worker,
+                Rc::new(IDLE_SOURCE.to_string()),
+            )
+            .unwrap();
+            agent.health = agent.get_max_health() / 2;
+            agent.last_damaged_tick = damaged_tick;
+            game.entities.push(RefCell::new(Entity::Agent(agent)));
+        }
+
+        for _ in 0..HEALTH_REGEN_DELAY / 2 {
+            game.update();
+        }
+
+        let health: Vec<_> = game
+            .entities
+            .iter()
+            .map(|e| (e.borrow().get_health(), e.borrow().get_max_health()))
+            .collect();
+        assert!(health[0].1 / 2 < health[0].0);
+        assert!(health[0].0 <= health[0].1);
+        assert_eq!(health[1].0, health[1].1 / 2);
+    }
+
+    #[test]
+    fn test_advance() {
+        let mut params = GameParams::new();
+        params.fow = false;
+        params.teams = idle_teams();
+        let mut game = init_game(&params);
+
+        // A fighter shooting at a weak enemy fighter on a clear line
+        let pos = free_cells(&game)
+            .into_iter()
+            .find(|pos| is_free_around(&game, *pos, -2..=8, -1..=1))
+            .unwrap();
+        let shooter = Agent::new(
+            &mut game.id_gen,
+            pos,
+            0.,
+            0,
+            AgentClass::Fighter,
+            Rc::new("tree main = Shoot".to_string()),
+        )
+        .unwrap();
+        let mut victim = Agent::new(
+            &mut game.id_gen,
+            [pos[0] + 5., pos[1]],
+            0.,
+            1,
+            AgentClass::Fighter,
+            Rc::new(IDLE_SOURCE.to_string()),
+        )
+        .unwrap();
+        victim.health = 1;
+        let victim_id = victim.id;
+        game.entities.push(RefCell::new(Entity::Agent(shooter)));
+        game.entities.push(RefCell::new(Entity::Agent(victim)));
+
+        let events = game.advance(100);
+        assert!(events.iter().any(|event| matches!(
+            event,
+            GameEventRecord::Kill { team: 0, victim, .. } if *victim == victim_id
+        )));
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, GameEventRecord::TeamWon(_))));
+        assert_eq!(game.global_time, 100);
+
+        // The events are also kept for take_events while the recording is on
+        game.set_event_recording(true);
+        game.clear_team(1);
+        let events = game.advance(100);
+        assert!(matches!(
+            events.last(),
+            Some(GameEventRecord::TeamWon(MatchResult { won_team: 0, .. }))
+        ));
+        assert_eq!(game.global_time, 101);
+        assert_eq!(game.take_events().len(), events.len());
+        assert!(game.take_events().is_empty());
+    }
+
+    #[test]
+    fn test_path_clearance() {
+        use crate::qtree::simplify_path;
+
+        // An open map with a single block of wall in the middle, which paths have to go around
+        let mut game = Game::new();
+        let shape = (game.xs, game.ys);
+        let (cx, cy) = (shape.0 / 2, shape.1 / 2);
+        game.board = (0..shape.0 * shape.1)
+            .map(|i| {
+                let (x, y) = (i % shape.0, i / shape.0);
+                if x.abs_diff(cx) < 16 && y.abs_diff(cy) < 4 {
+                    Terrain::Wall
+                } else {
+                    Terrain::Floor
+                }
+            })
+            .collect();
+        game.invalidate_distance_field();
+        let field = game.distance_field().to_vec();
+
+        let start = [cx as f64 - 24.5, cy as f64 + 0.5];
+        let goal = [cx as f64 + 24.5, cy as f64 + 0.5];
+        let min_clearance = |game: &Game| {
+            let (path, _) = game
+                .qtree
+                .path_find(|_| false, start, goal, &|_| false, 1., None);
+            let mut path = path.unwrap();
+            simplify_path(&mut path, game.path_board(), shape, 0.);
+            path.iter()
+                .zip(path.iter().skip(1))
+                .flat_map(|(a, b)| {
+                    (0..=100).map(move |t| {
+                        let t = t as f64 / 100.;
+                        [
+                            a.pos[0] * (1. - t) + b.pos[0] * t,
+                            a.pos[1] * (1. - t) + b.pos[1] * t,
+                        ]
+                    })
+                })
+                .map(|pos| field[pos[0] as usize + pos[1] as usize * shape.0])
+                .fold(f32::INFINITY, f32::min)
+        };
+
+        let mut params = GameParams::new();
+        params.fow = false;
+        game.set_params(&params);
+        game.rebuild_path_board();
+        let without_clearance = min_clearance(&game);
+
+        params.path_clearance = 4.;
+        game.set_params(&params);
+        let with_clearance = min_clearance(&game);
+
+        assert!(
+            without_clearance + 2. < with_clearance,
+            "{without_clearance} vs {with_clearance}"
+        );
+        assert!(3. <= with_clearance, "{with_clearance}");
+
+        // A goal right next to the wall is snapped out of the clearance band to stay reachable
+        let goal = [cx as f64 + 0.5, cy as f64 + 4.5];
+        assert!(!is_passable_at(game.path_board(), shape, goal));
+        let snapped = game.path_goal(goal);
+        assert!(is_passable_at(game.path_board(), shape, snapped));
+        assert!(Vector2::from(snapped).distance(Vector2::from(goal)) <= 5.);
+        let (path, _) = game
+            .qtree
+            .path_find(|_| false, start, snapped, &|_| false, 1., None);
+        assert!(path.is_ok());
+    }
+
+    #[test]
+    fn test_team_colored_fog() {
+        const TEAM_COLORS: [[u8; 3]; 2] = [[255, 0, 0], [0, 0, 255]];
+        let mut game = Game::new();
+        let (xs, ys) = game.shape();
+        let cell = (0..xs * ys)
+            .find(|i| game.board[*i] == Terrain::Floor)
+            .unwrap();
+        for team in 0..2 {
+            game.fog[team].fow.fill(i32::MIN);
+            game.fog[team].fow[cell] = game.global_time;
+        }
+
+        for (team, expected) in [[191, 47, 47], [47, 47, 191]].into_iter().enumerate() {
+            let mut fog_active = [false; 2];
+            fog_active[team] = true;
+            let (_, image) = game
+                .occupancy_image(&fog_active, true, &TEAM_COLORS)
+                .unwrap();
+            assert_eq!(image[cell * 3..cell * 3 + 3], expected);
+        }
+
+        // Both teams see the cell
+        let (_, image) = game
+            .occupancy_image(&[true; 2], true, &TEAM_COLORS)
+            .unwrap();
+        assert_eq!(image[cell * 3..cell * 3 + 3], [191, 47, 191]);
+    }
+
+    #[test]
+    fn test_fog_raycast_step() {
+        const SHAPE: (usize, usize) = (64, 64);
+        // A wall a single cell thick at x = 40, with a gap out of sight so that the other side
+        // is not filled as a disconnected region
+        let board = (0..SHAPE.0 * SHAPE.1)
+            .map(|i| i % SHAPE.0 != 40 || i / SHAPE.0 < 2)
+            .collect();
+        let mut game = Game::from_board(board, SHAPE);
+        let mut params = GameParams::new();
+        let pos = [32.5, 32.5];
+        let agent = Agent::new(
+            &mut game.id_gen,
+            pos,
+            0.,
+            0,
+            AgentClass::Worker,
+            Rc::new(IDLE_SOURCE.to_string()),
+        )
+        .unwrap();
+        let mut entity = Entity::Agent(agent);
+
+        let mut cast = |game: &mut Game, step| {
+            params.fog_raycast_step = step;
+            game.set_params(&params);
+            game.fog[0].fow.fill(i32::MIN);
+            let rays = entity.fow_raycast(game);
+            let vicinity_clear = (-2..=2).all(|dy| {
+                (-2..=2).all(|dx| game.is_clear_fog_at(0, [pos[0] + dx as f64, pos[1] + dy as f64]))
+            });
+            let behind_wall_clear = (41..SHAPE.0)
+                .flat_map(|x| (0..SHAPE.1).map(move |y| [x as f64 + 0.5, y as f64 + 0.5]))
+                .filter(|pos| game.is_clear_fog_at(0, *pos))
+                .count();
+            (rays, vicinity_clear, behind_wall_clear)
+        };
+        let (full_rays, full_clear, full_behind) = cast(&mut game, 1);
+        let (coarse_rays, coarse_clear, coarse_behind) = cast(&mut game, 2);
+        assert!(full_clear);
+        assert!(coarse_clear);
+        // Skipping cells does not let the view through the thin wall
+        assert!(
+            coarse_behind <= full_behind,
+            "{coarse_behind} vs {full_behind}"
+        );
+        assert!(0 < coarse_rays);
+        assert!(coarse_rays < full_rays);
+    }
+
+    #[test]
+    fn test_resource_outside_pocket() {
+        const SHAPE: (usize, usize) = (64, 64);
+        let is_ring = |x: usize, y: usize| {
+            (38..52).contains(&x)
+                && (38..52).contains(&y)
+                && !((40..50).contains(&x) && (40..50).contains(&y))
+        };
+        let board = (0..SHAPE.0 * SHAPE.1)
+            .map(|i| !is_ring(i % SHAPE.0, i / SHAPE.0))
+            .collect();
+        let mut game = Game::from_board(board, SHAPE);
+
+        // Pockets are filled when the mesh is created, so open it again as if edited at runtime
+        let in_pocket =
+            |pos: [f64; 2]| (40. ..50.).contains(&pos[0]) && (40. ..50.).contains(&pos[1]);
+        for y in 40..50 {
+            for x in 40..50 {
+                game.board[x + y * SHAPE.0] = Terrain::Floor;
+            }
+        }
+        game.mesh.relabel(&game.board, SHAPE);
+        game.rebuild_path_board();
+        assert!(game.is_passable_at([45., 45.]));
+        let spawner = Spawner::new(
+            &mut game.id_gen,
+            [10., 10.],
+            0,
+            Rc::new(IDLE_SOURCE.to_string()),
+        )
+        .unwrap();
+        game.entities.push(RefCell::new(Entity::Spawner(spawner)));
+
+        let mut placed = 0;
+        for _ in 0..50 {
+            game.try_new_resource();
+            assert!(game.resources.iter().all(|res| !in_pocket(res.pos)));
+            placed += game.resources.len();
+            game.resources.clear();
+        }
+        assert!(0 < placed);
+    }
+
+    #[test]
+    fn test_resource_contest() {
+        let mut game = Game::new();
+        let resource = Resource {
+            pos: [20.5, 20.5],
+            amount: 100,
+        };
+        assert_eq!(game.resource_contest(&resource), None);
+
+        // The entities are looked up in the qtree, which is usually updated by `Game::update`
+        let add_entity = |game: &mut Game, entity: Entity| {
+            let id = entity.get_id();
+            game.qtree.start_update();
+            update_aabb(&mut game.qtree, entity.get_shape().to_aabb(), |_| {
+                CellState::Occupied(id)
+            });
+            game.qtree.finish_update();
+            game.entities.push(RefCell::new(entity));
+        };
+        let add_agent = |game: &mut Game, pos: [f64; 2], team| {
+            let agent = Agent::new(
+                &mut game.id_gen,
+                pos,
+                0.,
+                team,
+                AgentClass::Worker,
+                Rc::new(IDLE_SOURCE.to_string()),
+            )
+            .unwrap();
+            add_entity(game, Entity::Agent(agent));
+        };
+        add_agent(&mut game, [22.5, 20.5], 0);
+        add_agent(&mut game, [20.5, 17.5], 0);
+        assert_eq!(game.resource_contest(&resource), Some(0));
+
+        // A spawner does not contest a resource, but a nearer enemy agent does
+        let spawner = Spawner::new(
+            &mut game.id_gen,
+            [20.5, 21.5],
+            1,
+            Rc::new(IDLE_SOURCE.to_string()),
+        )
+        .unwrap();
+        add_entity(&mut game, Entity::Spawner(spawner));
+        assert_eq!(game.resource_contest(&resource), Some(0));
+        add_agent(&mut game, [21.5, 20.5], 1);
+        assert_eq!(game.resource_contest(&resource), Some(1));
+    }
+
+    #[test]
+    fn test_frozen_agents() {
+        let mut game = Game::new();
+        let mut params = GameParams::new();
+        params.fow = false;
+        game.set_params(&params);
+        let free: Vec<_> = free_cells(&game)
+            .into_iter()
+            .filter(|pos| (-3..=3).all(|dx| game.is_passable_at([pos[0] + dx as f64, pos[1]])))
+            .collect();
+        let source =
+            Rc::new("tree main = Sequence {\n    Drive (direction <- \"forward\")\n}".to_string());
+        let ids = [free[0], free[free.len() / 2]].map(|pos| {
+            let agent = Agent::new(
+                &mut game.id_gen,
+                pos,
+                0.,
+                0,
+                AgentClass::Worker,
+                source.clone(),
+            )
+            .unwrap();
+            let id = agent.id;
+            game.entities.push(RefCell::new(Entity::Agent(agent)));
+            id
+        });
+        game.frozen_agents.insert(ids[0]);
+
+        let positions = |game: &Game| ids.map(|id| game.get_entity(id).unwrap().get_pos());
+        let before = positions(&game);
+        for _ in 0..5 {
+            game.update();
+        }
+        let after = positions(&game);
+        assert_eq!(before[0], after[0]);
+        assert_ne!(before[1], after[1]);
+    }
+
+    #[test]
+    fn test_bt_sources() {
+        let print_source = |text: &str| {
+            Rc::new(format!(
+                "tree main = Sequence {{\n    Print (input <- \"{text}\")\n}}"
+            ))
+        };
+        let mut game = Game::new();
+        let free = free_cells(&game);
+        let ids = [free[0], free[free.len() / 2]].map(|pos| {
+            let agent = Agent::new(
+                &mut game.id_gen,
+                pos,
+                0.,
+                0,
+                AgentClass::Worker,
+                print_source("old"),
+            )
+            .unwrap();
+            let id = agent.id;
+            game.entities.push(RefCell::new(Entity::Agent(agent)));
+            id
+        });
+        assert_eq!(
+            game.agent_bt_sources(0),
+            [(print_source("old"), ids.to_vec())]
+        );
+        assert!(game.agent_bt_sources(1).is_empty());
+
+        let new_source = print_source("new");
+        game.reapply_team_bt(0, new_source.clone()).unwrap();
+        assert_eq!(game.team_bt_sources()[0].0, new_source);
+        assert_eq!(game.agent_bt_sources(0), [(new_source, ids.to_vec())]);
+    }
+
+    #[test]
+    fn test_reapply_team_bt() {
+        let print_source = |text: &str| {
+            Rc::new(format!(
+                "tree main = Sequence {{\n    Print (input <- \"{text}\")\n}}"
+            ))
+        };
+        let mut game = Game::new();
+        let mut params = GameParams::new();
+        params.fow = false;
+        game.set_params(&params);
+        let free = free_cells(&game);
+        let ids = [(free[0], 0), (free[free.len() / 2], 1)].map(|(pos, team)| {
+            let agent = Agent::new(
+                &mut game.id_gen,
+                pos,
+                0.,
+                team,
+                AgentClass::Worker,
+                print_source("old"),
+            )
+            .unwrap();
+            let id = agent.id;
+            game.entities.push(RefCell::new(Entity::Agent(agent)));
+            id
+        });
+        let last_logs = |game: &Game| {
+            ids.map(|id| {
+                let entity = game.get_entity(id).unwrap();
+                let Entity::Agent(agent) = &*entity else {
+                    unreachable!()
+                };
+                agent.log_buffer().back().cloned()
+            })
+        };
+
+        game.update();
+        assert_eq!(
+            last_logs(&game),
+            [Some("old".to_string()), Some("old".to_string())]
+        );
+
+        let broken = Rc::new("tree main = Sequence {".to_string());
+        assert!(game.reapply_team_bt(0, broken).is_err());
+        game.update();
+        assert_eq!(
+            last_logs(&game),
+            [Some("old".to_string()), Some("old".to_string())]
+        );
+
+        let new_source = print_source("new");
+        game.reapply_team_bt(0, new_source.clone()).unwrap();
+        assert_eq!(game.params.teams[0].agent_source, new_source);
+        game.update();
+        assert_eq!(
+            last_logs(&game),
+            [Some("new".to_string()), Some("old".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_kill_heatmap() {
+        const KILLS: u32 = 3;
+        let mut params = GameParams::new();
+        params.fow = false;
+        params.teams = idle_teams();
+        let mut game = init_game(&params);
+
+        let (xs, ys) = game.shape();
+        let pos = free_cells(&game)
+            .into_iter()
+            .find(|pos| is_free_around(&game, *pos, -4..=1, -1..=1))
+            .unwrap();
+        let cell = pos[0] as usize + pos[1] as usize * xs;
+
+        // Stage kills by shooting a bullet at a fresh victim at the same position each time
+        for _ in 0..KILLS {
+            let mut victim = Agent::new(
+                &mut game.id_gen,
+                pos,
+                0.,
+                1,
+                AgentClass::Worker,
+                Rc::new(IDLE_SOURCE.to_string()),
+            )
+            .unwrap();
+            victim.health = 1;
+            game.entities.push(RefCell::new(Entity::Agent(victim)));
+            game.bullets.push(Bullet::new(
+                [pos[0] - 3., pos[1]],
+                [BULLET_SPEED, 0.],
+                0,
+                BULLET_DAMAGE,
+                AgentClass::Fighter,
+            ));
+            for _ in 0..3 {
+                game.update();
+            }
+        }
+
+        assert_eq!(game.kill_heatmap[cell], KILLS);
+        assert_eq!(game.kill_heatmap.iter().sum::<u32>(), KILLS);
+
+        let (size, image) = game.kill_heatmap_image().unwrap();
+        assert_eq!(size, [xs, ys]);
+        let rgb = &image[cell * 3..cell * 3 + 3];
+        assert_eq!(rgb[0], 255);
+        assert!(rgb[1] < rgb[0] && rgb[2] < rgb[0]);
+        let other = (cell + 1) * 3;
+        assert_eq!(image[other], image[other + 1]);
+    }
+
+    #[test]
+    fn test_from_board() {
+        const SHAPE: (usize, usize) = (16, 16);
+        let is_wall = |x: usize, y: usize| (5..9).contains(&x) && (6..10).contains(&y);
+        let board = (0..SHAPE.0 * SHAPE.1)
+            .map(|i| !is_wall(i % SHAPE.0, i / SHAPE.0))
+            .collect();
+        let game = Game::from_board(board, SHAPE);
+        assert_eq!(game.shape(), SHAPE);
+
+        for y in 0..SHAPE.1 {
+            for x in 0..SHAPE.0 {
+                let pos = [x as f64 + 0.5, y as f64 + 0.5];
+                assert_eq!(game.is_passable_at(pos), !is_wall(x, y), "{pos:?}");
+                let expected = if is_wall(x, y) {
+                    CellState::Obstacle
+                } else {
+                    CellState::Free
+                };
+                assert_eq!(game.qtree.find(pos).map(|(_, state)| state), Some(expected));
+            }
+        }
+    }
+
+    #[test]
+    fn test_board_ascii() {
+        let game = Game::new();
+        let (board, shape) = Game::board_from_ascii(&game.board_to_ascii());
+        assert_eq!(shape, game.shape());
+        let expected: Vec<_> = game.board.iter().map(|t| t.is_passable()).collect();
+        assert_eq!(board, expected);
+
+        const MAP: &str = "\
+##########
+#........#
+#..####..#
+#........#
+##########
+";
+        let (board, shape) = Game::board_from_ascii(MAP);
+        let mut game = Game::from_board(board.clone(), shape);
+        assert_eq!(game.board_to_ascii(), MAP);
+
+        // Entity markers are drawn over the terrain and read back as floor
+        let source = Rc::new("tree main = Sequence { }".to_string());
+        let spawner = Spawner::new(&mut game.id_gen, [1.5, 1.5], 0, source.clone()).unwrap();
+        game.entities.push(RefCell::new(Entity::Spawner(spawner)));
+        let agent = Agent::new(
+            &mut game.id_gen,
+            [8.5, 3.5],
+            0.,
+            1,
+            AgentClass::Worker,
+            source,
+        )
+        .unwrap();
+        game.entities.push(RefCell::new(Entity::Agent(agent)));
+        let ascii = game.board_to_ascii();
+        assert_eq!(ascii.lines().nth(1), Some("#A.......#"));
+        assert_eq!(ascii.lines().nth(3), Some("#.......b#"));
+        assert_eq!(Game::board_from_ascii(&ascii), (board, shape));
+
+        // Hand-written maps may have ragged rows
+        let (board, shape) = Game::board_from_ascii("..#\n.\n");
+        assert_eq!(shape, (3, 2));
+        assert_eq!(board, [true, true, false, true, false, false]);
+    }
+
+    #[test]
+    fn test_rooms_maze_board() {
+        let params = BoardParams {
+            shape: (128, 128),
+            seed: 7,
+            simplify: 0.,
+            maze_expansions: 512,
+            slow_shores: false,
+        };
+        let mut game = Game::new();
+        game.new_board(BoardType::RoomsMaze, &params);
+        let (xs, ys) = game.shape();
+        let passable: Vec<bool> = game.board.iter().map(|t| t.is_passable()).collect();
+        let free = |x: i32, y: i32| {
+            0 <= x
+                && x < xs as i32
+                && 0 <= y
+                && y < ys as i32
+                && passable[x as usize + y as usize * xs]
+        };
+
+        let labels = crate::dijkstra::label(&passable, (xs, ys));
+        assert_eq!(
+            labels.iter().max(),
+            Some(&1),
+            "The board should be connected"
+        );
+
+        // An open arena has a free square of 9x9 pixels
+        let has_arena = (0..ys as i32).any(|y| {
+            (0..xs as i32).any(|x| (-4..=4).all(|dy| (-4..=4).all(|dx| free(x + dx, y + dy))))
+        });
+        assert!(has_arena);
+
+        // A tight passage is at most 2 pixels wide between walls on both sides
+        let has_passage = (0..ys as i32).any(|y| {
+            (0..xs as i32).any(|x| {
+                free(x, y)
+                    && ((!free(x - 1, y) && (!free(x + 1, y) || !free(x + 2, y)))
+                        || (!free(x, y - 1) && (!free(x, y + 1) || !free(x, y + 2))))
+            })
+        });
+        assert!(has_passage);
+    }
+
+    #[test]
+    fn test_incremental_qtree() {
+        const SHAPE: (usize, usize) = (32, 24);
+        let board: Vec<Terrain> = (0..SHAPE.0 * SHAPE.1)
+            .map(|i| {
+                let (x, y) = (i % SHAPE.0, i / SHAPE.0);
+                if (x * 7 + y * 3) % 11 == 0 || (10..14).contains(&x) && y < 18 {
+                    Terrain::Wall
+                } else {
+                    Terrain::Floor
+                }
+            })
+            .collect();
+        let one_shot = Game::new_qtree(SHAPE, &board, &[], 0).unwrap();
+        assert!(!one_shot.is_building());
+
+        let mut incremental = Game::new_qtree(SHAPE, &board, &[], 7).unwrap();
+        let mut steps = 0;
+        while incremental.is_building() {
+            incremental.step_initialize(7);
+            steps += 1;
+        }
+        assert!(1 < steps);
+
+        assert_eq!(
+            incremental.get_qtree().toplevel,
+            one_shot.get_qtree().toplevel
+        );
+        assert_eq!(incremental.get_qtree().levels, one_shot.get_qtree().levels);
+    }
+
+    #[test]
+    fn test_dijkstra() {
+        const SHAPE: (usize, usize) = (16, 16);
+        // A wall across the board except for a gap at the bottom
+        let board = (0..SHAPE.0 * SHAPE.1)
+            .map(|i| !(i % SHAPE.0 == 8 && i / SHAPE.0 < 12))
+            .collect();
+        let game = Game::from_board(board, SHAPE);
+
+        assert!(game.dijkstra([8.5, 2.5], [12.5, 2.5]).is_none());
+
+        let result = game.dijkstra([2.5, 2.5], [12.5, 2.5]).unwrap();
+        assert_eq!(result.path.first(), Some(&[2, 2]));
+        assert_eq!(result.path.last(), Some(&[12, 2]));
+        assert_eq!(result.path.len() as i32, result.costs[12 + 2 * SHAPE.0] + 1);
+        for pair in result.path.windows(2) {
+            let d = (pair[0][0] - pair[1][0]).abs() + (pair[0][1] - pair[1][1]).abs();
+            assert_eq!(d, 1);
+        }
+        assert!(result.path.iter().any(|cell| cell[0] == 8 && 12 <= cell[1]));
+        assert_eq!(result.costs[8 + 2 * SHAPE.0], i32::MAX);
+    }
+
+    #[test]
+    fn test_collision_layers() {
+        let mut params = GameParams::new();
+        params.fow = false;
+        params.teams = idle_teams();
+        let mut game = init_game(&params);
+
+        // A worker in front of a fighter on a clear line of fire
+        let pos = free_cells(&game)
+            .into_iter()
+            .find(|pos| is_free_around(&game, *pos, -4..=6, -1..=1))
+            .unwrap();
+        let mut ids = vec![];
+        for (class, x) in [
+            (AgentClass::Worker, pos[0]),
+            (AgentClass::Fighter, pos[0] + 4.),
+        ] {
+            let mut agent = Agent::new(
+                &mut game.id_gen,
+                [x, pos[1]],
+                0.,
+                1,
+                class,
+                Rc::new(IDLE_SOURCE.to_string()),
+            )
+            .unwrap();
+            agent.health = 1;
+            ids.push(agent.id);
+            game.entities.push(RefCell::new(Entity::Agent(agent)));
+        }
+        let mut bullet = Bullet::new(
+            [pos[0] - 3., pos[1]],
+            [BULLET_SPEED, 0.],
+            0,
+            BULLET_DAMAGE,
+            AgentClass::Fighter,
+        );
+        bullet.mask = layers::ALL & !layers::WORKER;
+        game.bullets.push(bullet);
+
+        for _ in 0..6 {
+            game.update();
+        }
+
+        let alive = |id: usize| game.entities.iter().any(|e| e.borrow().get_id() == id);
+        assert!(alive(ids[0]), "The bullet should pass through the worker");
+        assert!(!alive(ids[1]), "The bullet should hit the fighter");
+    }
+
+    #[test]
+    fn test_bullet_leaves_board() {
+        let mut game = Game::new();
+        let mut params = GameParams::new();
+        params.fow = false;
+        game.set_params(&params);
+        let (xs, ys) = game.shape();
+        let mut bullet = Bullet::new(
+            [xs as f64 - 5., ys as f64 / 2.],
+            [BULLET_SPEED, 0.],
+            0,
+            BULLET_DAMAGE,
+            AgentClass::Fighter,
+        );
+        // Not stopped by walls, so only the board edge can remove it
+        bullet.mask = layers::ALL & !layers::WALL;
+        game.bullets.push(bullet);
+
+        for _ in 0..10 {
+            game.update();
+        }
+        assert!(game.bullets.is_empty());
     }
 }
@@ -13,7 +13,33 @@ impl Game {
     /// The resolutions are power of 2 to make it better fit to QTree.
     pub(crate) fn create_maze_board(params: &BoardParams) -> MeshResult {
         let shape = params.shape;
-        let mut board = vec![false; shape.0 * shape.1];
+        let board = Self::expand_maze(params, &vec![false; shape.0 * shape.1]);
+
+        create_mesh(shape, params.simplify, |xi, yi| {
+            *board.get(xi + yi * shape.0).unwrap_or(&false)
+        })
+    }
+
+    /// A hybrid of the rooms and the maze, in which the rooms are connected and perturbed by
+    /// maze corridors grown from them, giving both open arenas and tight passages.
+    /// The amount of corridors is controlled by `maze_expansions`.
+    pub(crate) fn create_rooms_maze_board(params: &BoardParams) -> MeshResult {
+        let Some(rooms) = Self::rooms_bitmap(params) else {
+            return Self::create_maze_board(params);
+        };
+        let shape = params.shape;
+        let board = Self::expand_maze(params, &rooms);
+
+        create_mesh(shape, params.simplify, |xi, yi| {
+            *board.get(xi + yi * shape.0).unwrap_or(&false)
+        })
+    }
+
+    /// Dig maze corridors into the walls of `base` at decreasing resolutions. Corridors grow
+    /// from the center and from the free pixels of `base`, which are kept free.
+    fn expand_maze(params: &BoardParams, base: &[bool]) -> Vec<bool> {
+        let shape = params.shape;
+        let mut board = base.to_vec();
         for resolution in [8, 4, 2, 1] {
             let maze_shape = (shape.0 / resolution, shape.1 / resolution);
             let mut maze_board = vec![false; maze_shape.0 * maze_shape.1];
@@ -64,7 +90,7 @@ impl Game {
             // and we don't care too much about prioritizing unexplored area in lower resolutions,
             // where the general structure of the map is already built in larger resolutions.
             let mut costmap = if 4 < resolution {
-                let mut costmap = vec![std::i32::MAX; maze_board.len()];
+                let mut costmap = vec![i32::MAX; maze_board.len()];
                 let start = [(maze_shape.0 / 2) as i32, (maze_shape.1 / 2) as i32];
                 dijkstra::dijkstra_fill(&maze_board, maze_shape, start, &mut &mut costmap[..]);
                 Some(costmap)
@@ -161,14 +187,13 @@ impl Game {
             // Upsample
             for ix in 0..shape.0 {
                 for iy in 0..shape.1 {
-                    board[ix + iy * shape.0] =
-                        maze_board[ix / resolution + iy / resolution * maze_shape.0];
+                    board[ix + iy * shape.0] = maze_board
+                        [ix / resolution + iy / resolution * maze_shape.0]
+                        || base[ix + iy * shape.0];
                 }
             }
         }
 
-        create_mesh(shape, params.simplify, |xi, yi| {
-            *board.get(xi + yi * shape.0).unwrap_or(&false)
-        })
+        board
     }
 }
@@ -15,13 +15,23 @@ const DROPOFF_FACTOR: f64 = 0.15;
 
 impl Game {
     pub(crate) fn create_rooms_board(params: &BoardParams) -> MeshResult {
+        let Some(board) = Self::rooms_bitmap(params) else {
+            return Self::create_perlin_board(params);
+        };
+        let shape = params.shape;
+        create_mesh(shape, params.simplify, |xi, yi| board[xi + shape.0 * yi])
+    }
+
+    /// Generate free pixels of rooms connected by passages, or `None` if the board is too small
+    /// to have rooms.
+    pub(super) fn rooms_bitmap(params: &BoardParams) -> Option<Vec<bool>> {
         let shape = params.shape;
 
         let mut xor128 = Xor128::new(params.seed);
 
         let room_rows = shape.0.min(shape.1) * 4 / 128;
         if room_rows <= 1 {
-            return Self::create_perlin_board(params);
+            return None;
         }
         let room_size = shape.0.min(shape.1) / 2 / room_rows;
         let room_margin = room_size * 2;
@@ -194,9 +204,7 @@ impl Game {
 
         board_bin = opening(board_bin, shape, 3, 3);
 
-        create_mesh(shape, params.simplify, |xi, yi| {
-            board_bin[xi + shape.0 * yi]
-        })
+        Some(board_bin)
     }
 }
 
@@ -0,0 +1,156 @@
+//! A* path finding on the board cells, as an alternative to the qtree.
+//!
+//! It explores every cell, so it is slower than the qtree on large boards, but it gives
+//! fine-grained paths and is useful for validating the qtree results.
+
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use crate::game::{is_passable_at_i, Terrain};
+
+/// Cost of a straight step. A diagonal step costs `DIAGONAL_COST`, approximating the square root of
+/// 2 in integers.
+const STRAIGHT_COST: i32 = 10;
+const DIAGONAL_COST: i32 = 14;
+
+/// Find a path from the cell `start` to the cell `goal` with 8-connected A* on the board cells.
+/// A diagonal step is allowed only if both cells adjacent to it are passable, so that the path
+/// does not cut the corners of walls.
+///
+/// The returned path starts with `start` and ends with `goal`. Returns `None` if either end is
+/// not passable or there is no path between them.
+pub fn grid_path_find(
+    board: &[Terrain],
+    shape: (usize, usize),
+    start: [i32; 2],
+    goal: [i32; 2],
+) -> Option<Vec<[i32; 2]>> {
+    if !is_passable_at_i(board, shape, start) || !is_passable_at_i(board, shape, goal) {
+        return None;
+    }
+    let to_idx = |pos: [i32; 2]| pos[0] as usize + pos[1] as usize * shape.0;
+    let heuristic = |pos: [i32; 2]| {
+        let dx = (pos[0] - goal[0]).abs();
+        let dy = (pos[1] - goal[1]).abs();
+        STRAIGHT_COST * dx.max(dy) + (DIAGONAL_COST - STRAIGHT_COST) * dx.min(dy)
+    };
+
+    let mut costs = vec![i32::MAX; shape.0 * shape.1];
+    let mut came_from: Vec<Option<[i32; 2]>> = vec![None; shape.0 * shape.1];
+    let mut open_set = BinaryHeap::new();
+    costs[to_idx(start)] = 0;
+    open_set.push(Reverse((heuristic(start), start)));
+
+    while let Some(Reverse((_, pos))) = open_set.pop() {
+        if pos == goal {
+            let mut path = vec![goal];
+            let mut node = goal;
+            while let Some(prev) = came_from[to_idx(node)] {
+                path.push(prev);
+                node = prev;
+            }
+            path.reverse();
+            return Some(path);
+        }
+        let cost = costs[to_idx(pos)];
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                if dx == 0 && dy == 0 {
+                    continue;
+                }
+                let next = [pos[0] + dx, pos[1] + dy];
+                if !is_passable_at_i(board, shape, next) {
+                    continue;
+                }
+                let step_cost = if dx != 0 && dy != 0 {
+                    if !is_passable_at_i(board, shape, [pos[0] + dx, pos[1]])
+                        || !is_passable_at_i(board, shape, [pos[0], pos[1] + dy])
+                    {
+                        continue;
+                    }
+                    DIAGONAL_COST
+                } else {
+                    STRAIGHT_COST
+                };
+                let next_cost = cost + step_cost;
+                if next_cost < costs[to_idx(next)] {
+                    costs[to_idx(next)] = next_cost;
+                    came_from[to_idx(next)] = Some(pos);
+                    open_set.push(Reverse((next_cost + heuristic(next), next)));
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{game::Game, qtree::path_length};
+    use cgmath::{MetricSpace, Vector2};
+
+    #[test]
+    fn test_maze() {
+        const SHAPE: (usize, usize) = (32, 32);
+        // Vertical walls at every 4 columns with openings alternating at the top and the bottom
+        let is_wall = |x: usize, y: usize| {
+            x % 4 == 3
+                && x < 28
+                && if (x / 4).is_multiple_of(2) {
+                    3 <= y
+                } else {
+                    y < 29
+                }
+        };
+        let board: Vec<_> = (0..SHAPE.0 * SHAPE.1)
+            .map(|i| {
+                if is_wall(i % SHAPE.0, i / SHAPE.0) {
+                    Terrain::Wall
+                } else {
+                    Terrain::Floor
+                }
+            })
+            .collect();
+        let start = [1, 30];
+        let goal = [30, 30];
+
+        let path = grid_path_find(&board, SHAPE, start, goal).unwrap();
+        assert_eq!(path.first(), Some(&start));
+        assert_eq!(path.last(), Some(&goal));
+        assert!(path.iter().all(|pos| is_passable_at_i(&board, SHAPE, *pos)));
+        assert!(path
+            .iter()
+            .zip(path.iter().skip(1))
+            .all(|(a, b)| { (a[0] - b[0]).abs() <= 1 && (a[1] - b[1]).abs() <= 1 && a != b }));
+
+        let to_center = |pos: &[i32; 2]| Vector2::new(pos[0] as f64 + 0.5, pos[1] as f64 + 0.5);
+        let length: f64 = path
+            .iter()
+            .zip(path.iter().skip(1))
+            .map(|(a, b)| to_center(a).distance(to_center(b)))
+            .sum();
+
+        // The qtree path through the same maze should be about as long as the grid path, which is
+        // the shortest in 8-connected steps
+        let game = Game::from_board(board.clone(), SHAPE);
+        let (qtree_path, _) = game.qtree.path_find(
+            |_| false,
+            to_center(&start).into(),
+            to_center(&goal).into(),
+            &|_| false,
+            0.5,
+            None,
+        );
+        let qtree_length = path_length(&qtree_path.unwrap());
+        // Every wall has to be passed around through its opening
+        assert!(7. * 26. < length, "{length}");
+        assert!(length < qtree_length * 1.1, "{length} vs {qtree_length}");
+
+        // Close the opening of the first wall
+        let mut blocked = board;
+        for y in 0..3 {
+            blocked[3 + y * SHAPE.0] = Terrain::Wall;
+        }
+        assert_eq!(grid_path_find(&blocked, SHAPE, start, goal), None);
+    }
+}
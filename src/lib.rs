@@ -1,6 +1,7 @@
 mod behavior_tree_adapt;
 // mod board_widget;
 mod dijkstra;
+mod distance_field;
 pub mod marching_squares;
 // mod paint_board;
 pub mod perlin_noise;
@@ -14,15 +15,25 @@ mod collision;
 pub mod entity;
 pub mod fog_of_war;
 pub mod game;
+pub mod grid_astar;
 mod mesh;
 pub mod qtree;
+pub mod replay;
 mod spawner;
 mod temp_ents;
 pub mod triangle_utils;
 pub mod vfs;
 
 pub use crate::agent::Bullet;
-pub use crate::{behavior_tree_adapt::BehaviorTree, qtree::CellState};
+pub use crate::{
+    agent::describe_nodes as describe_agent_nodes,
+    behavior_tree_adapt::{parse_tree_source, BehaviorTree, BuildTreeError, NodeDoc},
+    collision::{layers, layers_collide},
+    dijkstra::DijkstraResult,
+    mesh::MeshTriangle,
+    qtree::CellState,
+    spawner::describe_nodes as describe_spawner_nodes,
+};
 pub use behavior_tree_lite;
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -36,3 +47,14 @@ fn measure_time<T>(f: impl FnOnce() -> T) -> (T, f64) {
 fn measure_time<T>(f: impl FnOnce() -> T) -> (T, f64) {
     (f(), 0.)
 }
+
+/// Measure the time like `measure_time` only if `enabled`, so that the timer can be skipped in
+/// hot paths when `GameParams::profiling_enabled` is off.
+fn measure_time_if<T>(enabled: bool, f: impl FnOnce() -> T) -> (T, Option<f64>) {
+    if enabled {
+        let (ret, time) = measure_time(f);
+        (ret, Some(time))
+    } else {
+        (f(), None)
+    }
+}
@@ -6,10 +6,10 @@ use druid::{kurbo::BezPath, Point};
 
 use crate::{
     dijkstra::label,
-    game::is_passable_at,
+    game::{is_passable_at, Board, Terrain},
     marching_squares::{trace_lines, BoolField},
     measure_time,
-    triangle_utils::{center_of_triangle_obj, label_triangles},
+    triangle_utils::{center_of_triangle_obj, find_triangle_at, label_triangles},
 };
 
 /// The Mesh is a data structure to allow AI controlled agents to navigate or help detection
@@ -27,22 +27,36 @@ pub struct Mesh {
     pub labeled_image: Vec<i32>,
 }
 
+/// A triangle of the mesh found by [`Mesh::triangle_at`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MeshTriangle {
+    /// Triangle id, multiply with 3 to get index into `triangulation.triangles`
+    pub index: usize,
+    pub passable: bool,
+    /// The connected component of passable triangles it belongs to, `None` if impassable
+    pub label: Option<i32>,
+}
+
 pub(crate) struct MeshResult {
-    pub board: Vec<bool>,
+    pub board: Board,
     pub mesh: Mesh,
 }
 
-pub(crate) fn create_mesh(
+pub(crate) fn create_mesh<T>(
     (xs, ys): (usize, usize),
     simplify_epsilon: f64,
-    mut pixel_proc: impl FnMut(usize, usize) -> bool,
-) -> MeshResult {
-    let mut board = vec![false; xs * ys];
-    for (i, cell) in board.iter_mut().enumerate() {
+    mut pixel_proc: impl FnMut(usize, usize) -> T,
+) -> MeshResult
+where
+    T: Into<Terrain>,
+{
+    let mut terrain = vec![Terrain::Wall; xs * ys];
+    for (i, cell) in terrain.iter_mut().enumerate() {
         let xi = i % xs;
         let yi = i / xs;
-        *cell = pixel_proc(xi, yi);
+        *cell = pixel_proc(xi, yi).into();
     }
+    let mut board: Vec<bool> = terrain.iter().map(|t| t.is_passable()).collect();
 
     println!(
         "true: {}, false: {}",
@@ -64,9 +78,14 @@ pub(crate) fn create_mesh(
             })
             .max_by_key(|(_, count)| *count)
     }) {
-        for (pix, pix_label) in board.iter_mut().zip(labeled_image.iter()) {
+        for ((pix, cell), pix_label) in board
+            .iter_mut()
+            .zip(terrain.iter_mut())
+            .zip(labeled_image.iter())
+        {
             if *pix_label != 0 && *pix_label != largest_label {
                 *pix = false;
+                *cell = Terrain::Wall;
             }
         }
     }
@@ -149,23 +168,14 @@ pub(crate) fn create_mesh(
 
     let triangulation = triangulate(&points);
 
-    let triangle_passable = calc_passable_triangles(&board, (xs, ys), &points, &triangulation);
+    let triangle_passable = calc_passable_triangles(&terrain, (xs, ys), &points, &triangulation);
 
     let triangle_labels = label_triangles(&triangulation, &triangle_passable);
 
-    let mut label_stats = HashMap::new();
-    for label in &triangle_labels {
-        if *label != -1 {
-            *label_stats.entry(*label).or_insert(0) += 1;
-        }
-    }
-    let largest_label = label_stats
-        .iter()
-        .max_by_key(|(_, count)| **count)
-        .map(|(key, _)| *key);
+    let largest_label = find_largest_label(&triangle_labels);
 
     MeshResult {
-        board,
+        board: terrain,
         mesh: Mesh {
             #[cfg(feature = "druid")]
             simplified_border,
@@ -180,8 +190,47 @@ pub(crate) fn create_mesh(
     }
 }
 
+impl Mesh {
+    /// Recompute passability and connected components of the triangles from the current board.
+    ///
+    /// The triangulation itself is kept intact, so call this after editing the board at runtime
+    /// to keep `triangle_labels`, `largest_label` and `labeled_image` in sync with it.
+    pub fn relabel(&mut self, board: &[Terrain], shape: (usize, usize)) {
+        self.triangle_passable =
+            calc_passable_triangles(board, shape, &self.points, &self.triangulation);
+        self.triangle_labels = label_triangles(&self.triangulation, &self.triangle_passable);
+        self.largest_label = find_largest_label(&self.triangle_labels);
+        let passable: Vec<bool> = board.iter().map(|t| t.is_passable()).collect();
+        self.labeled_image = label(&passable, shape);
+    }
+
+    /// Find the triangle containing `pos` along with its passability and label.
+    pub fn triangle_at(&self, pos: [f64; 2]) -> Option<MeshTriangle> {
+        let index = find_triangle_at(&self.triangulation, &self.points, pos)?;
+        let label = self.triangle_labels[index];
+        Some(MeshTriangle {
+            index,
+            passable: self.triangle_passable[index],
+            label: (label != -1).then_some(label),
+        })
+    }
+}
+
+fn find_largest_label(triangle_labels: &[i32]) -> Option<i32> {
+    let mut label_stats = HashMap::new();
+    for label in triangle_labels {
+        if *label != -1 {
+            *label_stats.entry(*label).or_insert(0) += 1;
+        }
+    }
+    label_stats
+        .iter()
+        .max_by_key(|(_, count)| **count)
+        .map(|(key, _)| *key)
+}
+
 pub(crate) fn calc_passable_triangles(
-    board: &[bool],
+    board: &[Terrain],
     shape: (usize, usize),
     points: &[delaunator::Point],
     triangulation: &Triangulation,
@@ -196,3 +245,86 @@ pub(crate) fn calc_passable_triangles(
         })
         .collect()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A mesh triangulating a grid of points at every 2 pixels of the board
+    fn grid_mesh(board: &[Terrain], shape: (usize, usize)) -> Mesh {
+        let points: Vec<_> = (0..shape.1.div_ceil(2))
+            .flat_map(|y| {
+                (0..shape.0.div_ceil(2)).map(move |x| delaunator::Point {
+                    x: (x * 2) as f64,
+                    y: (y * 2) as f64,
+                })
+            })
+            .collect();
+        let triangulation = triangulate(&points);
+        let triangle_passable = calc_passable_triangles(board, shape, &points, &triangulation);
+        let triangle_labels = label_triangles(&triangulation, &triangle_passable);
+        let passable: Vec<bool> = board.iter().map(|t| t.is_passable()).collect();
+        Mesh {
+            #[cfg(feature = "druid")]
+            simplified_border: vec![],
+            polygons: geo::geometry::MultiPolygon::new(vec![]),
+            points,
+            triangulation,
+            largest_label: find_largest_label(&triangle_labels),
+            triangle_passable,
+            triangle_labels,
+            labeled_image: label(&passable, shape),
+        }
+    }
+
+    #[test]
+    fn test_relabel_split() {
+        let shape = (9, 9);
+        let mut board = vec![Terrain::Floor; shape.0 * shape.1];
+        let mut mesh = grid_mesh(&board, shape);
+        let count_labels = |mesh: &Mesh| {
+            let mut labels: Vec<_> = mesh
+                .triangle_labels
+                .iter()
+                .copied()
+                .filter(|label| *label != -1)
+                .collect();
+            labels.sort();
+            labels.dedup();
+            labels.len()
+        };
+        assert_eq!(count_labels(&mesh), 1);
+
+        // Build a vertical wall in the middle
+        for y in 0..shape.1 {
+            board[4 + y * shape.0] = Terrain::Wall;
+            board[5 + y * shape.0] = Terrain::Wall;
+        }
+        mesh.relabel(&board, shape);
+        assert_eq!(count_labels(&mesh), 2);
+    }
+
+    #[test]
+    fn test_triangle_at() {
+        let shape = (13, 9);
+        let mut board = vec![Terrain::Floor; shape.0 * shape.1];
+        // A wall splitting the board into a large left part and a small right part
+        for y in 0..shape.1 {
+            board[8 + y * shape.0] = Terrain::Wall;
+            board[9 + y * shape.0] = Terrain::Wall;
+        }
+        let mesh = grid_mesh(&board, shape);
+
+        let found = mesh.triangle_at([2.7, 3.1]).unwrap();
+        assert!(found.passable);
+        assert_eq!(found.label, mesh.largest_label);
+        let triangle = &mesh.triangulation.triangles[found.index * 3..found.index * 3 + 3];
+        assert!(triangle.iter().all(|v| mesh.points[*v].x <= 4.));
+
+        let right = mesh.triangle_at([11.5, 3.1]).unwrap();
+        assert!(right.passable);
+        assert_ne!(right.label, mesh.largest_label);
+
+        assert_eq!(mesh.triangle_at([30., 3.]), None);
+    }
+}
@@ -19,9 +19,15 @@ pub mod qtree;
 
 pub use self::cache_map::FRESH_TICKS;
 
+use cgmath::{InnerSpace, MetricSpace, Vector2, Zero};
+
 use std::{error::Error, fmt::Display};
 
-use crate::collision::Aabb;
+use crate::{
+    agent::interpolation::interpolate,
+    collision::Aabb,
+    game::{is_passable_at, Terrain},
+};
 
 use self::{
     cache_map::CacheMap,
@@ -30,7 +36,8 @@ use self::{
 
 const DEBUG: bool = false;
 
-pub(crate) type Rect = [i32; 4];
+/// A rectangle in `[left, top, right, bottom]` order
+pub type Rect = [i32; 4];
 
 pub(crate) enum PathFindResponse {
     Continue,
@@ -46,6 +53,9 @@ pub(crate) enum PathFindResponse {
 pub struct QTreeSearcher {
     qtree: QTree,
     cache_map: CacheMap,
+    /// Cells yet to be visited by an incremental initialization, as `(level, position)`.
+    /// It replaces the call stack of `QTree::recurse_update` so that the build can be resumed.
+    build_stack: Vec<(usize, [i32; 2])>,
 }
 
 impl QTreeSearcher {
@@ -53,6 +63,7 @@ impl QTreeSearcher {
         Self {
             qtree: QTree::new(),
             cache_map: CacheMap::new(),
+            build_stack: vec![],
         }
     }
 
@@ -68,24 +79,74 @@ impl QTreeSearcher {
         &mut self,
         shape: (usize, usize),
         f: &impl Fn(Rect) -> CellState,
+    ) -> Result<(), Box<dyn Error>> {
+        self.start_initialize(shape, f)?;
+        self.step_initialize(usize::MAX);
+        Ok(())
+    }
+
+    /// Begin building the tree incrementally. The cache map is filled immediately, but
+    /// the tree itself is built by subsequent calls to `step_initialize`.
+    pub(crate) fn start_initialize(
+        &mut self,
+        shape: (usize, usize),
+        f: &impl Fn(Rect) -> CellState,
     ) -> Result<(), Box<dyn Error>> {
         let max_size = shape.0.max(shape.1);
         let topbit = log2ceil(max_size)?;
 
         self.qtree.toplevel = topbit;
+        self.qtree.shape = shape;
+        self.qtree.levels.clear();
 
         self.cache_map.cache(topbit, shape, f);
 
         dbg_println!("maxlevel: {topbit}");
 
-        self.qtree
-            .recurse_update(0, [0, 0], &|rect| self.cache_map.query(rect));
+        self.build_stack = vec![(0, [0, 0])];
 
-        for (i, cell) in self.qtree.levels.iter().enumerate() {
-            dbg_println!("level {i}: {}", cell.len());
+        Ok(())
+    }
+
+    /// Visit at most `budget` cells of a pending incremental initialization.
+    /// Returns true if the tree is complete.
+    pub(crate) fn step_initialize(&mut self, budget: usize) -> bool {
+        let mut stack = std::mem::take(&mut self.build_stack);
+        for _ in 0..budget {
+            let (level, pos) = if let Some(node) = stack.pop() {
+                node
+            } else {
+                break;
+            };
+            if self
+                .qtree
+                .update_node(level, pos, &|rect| self.cache_map.query(rect))
+            {
+                // Push in reverse so that children are visited in the same order as recursion
+                let children: Vec<_> = QTree::children(pos).collect();
+                stack.extend(children.into_iter().rev().map(|child| (level + 1, child)));
+            }
         }
+        self.build_stack = stack;
 
-        Ok(())
+        if self.build_stack.is_empty() {
+            for (i, cell) in self.qtree.levels.iter().enumerate() {
+                dbg_println!("level {i}: {}", cell.len());
+            }
+        }
+
+        !self.is_building()
+    }
+
+    /// Whether an incremental initialization is in progress, in which case path finding
+    /// cannot be relied on.
+    pub fn is_building(&self) -> bool {
+        !self.build_stack.is_empty()
+    }
+
+    /// Whether the tree has been initialized on a board. It is not if the initialization failed.
+    pub fn is_initialized(&self) -> bool {
+        !self.qtree.levels.is_empty() || self.is_building()
     }
 
     pub(crate) fn find(&self, pos: [f64; 2]) -> Option<(usize, CellState)> {
@@ -124,6 +185,10 @@ impl QTreeSearcher {
                     }
                 }
                 if level == 0 {
+                    if self.is_building() {
+                        // The cell is not built yet and it will see the updated cache map.
+                        return Ok(());
+                    }
                     return Err("Could not find a cell to update".to_string());
                 }
                 level -= 1;
@@ -144,8 +209,13 @@ impl QTreeSearcher {
         end: [f64; 2],
         fog: &impl Fn([f64; 2]) -> bool,
         goal_radius: f64,
+        cell_cost: Option<&dyn Fn([f64; 2]) -> f64>,
     ) -> (Result<QTreePath, PathFindError>, SearchTree) {
-        self.qtree.path_find(ignore, start, end, fog, goal_radius)
+        if !self.is_initialized() {
+            return (Err(PathFindError::Uninitialized), SearchTree::new());
+        }
+        self.qtree
+            .path_find(ignore, start, end, fog, goal_radius, cell_cost)
     }
 
     pub(crate) fn path_find_many(
@@ -155,6 +225,9 @@ impl QTreeSearcher {
         mut end: impl FnMut([f64; 2]) -> PathFindResponse,
         goal_radius: f64,
     ) -> (Result<QTreePath, PathFindError>, SearchTree) {
+        if !self.is_initialized() {
+            return (Err(PathFindError::Uninitialized), SearchTree::new());
+        }
         self.qtree.path_find_many(
             ignore,
             start,
@@ -166,6 +239,15 @@ impl QTreeSearcher {
         )
     }
 
+    pub(crate) fn find_contour(
+        &self,
+        ignore: impl Fn(usize) -> bool,
+        start: [f64; 2],
+        distance: f64,
+    ) -> Vec<[f64; 2]> {
+        self.qtree.find_contour(ignore, start, distance)
+    }
+
     pub fn check_collision(&self, aabb: &Aabb) -> bool {
         for x in aabb[0].floor() as i32..aabb[2].ceil() as i32 {
             for y in aabb[1].floor() as i32..aabb[3].ceil() as i32 {
@@ -209,6 +291,70 @@ impl QTreePathNode {
 
 pub(crate) type QTreePath = Vec<QTreePathNode>;
 
+/// The total length of the straight segments connecting the waypoints of `path`.
+pub fn path_length(path: &[QTreePathNode]) -> f64 {
+    path.iter()
+        .zip(path.iter().skip(1))
+        .map(|(prev, next)| Vector2::from(prev.pos).distance(Vector2::from(next.pos)))
+        .sum()
+}
+
+/// The number of waypoints in `path`, including both ends.
+pub fn path_node_count(path: &[QTreePathNode]) -> usize {
+    path.len()
+}
+
+/// Remove intermediate waypoints of a path by string-pulling, as long as the straight segment
+/// between the remaining waypoints, swept sideways by `clearance` on each side, stays in passable
+/// pixels of the board. The sweep keeps shortcuts from grazing corners that the agent's body
+/// would collide with.
+///
+/// A waypoint that replaces culled ones gets the smallest radius among them, so an agent
+/// following the simplified path does not cut corners more than the original path allowed.
+pub(crate) fn simplify_path(
+    path: &mut QTreePath,
+    board: &[Terrain],
+    shape: (usize, usize),
+    clearance: f64,
+) {
+    const INTERPOLATE_INTERVAL: f64 = 0.5;
+    if path.len() < 3 {
+        return;
+    }
+    let visible = |from: [f64; 2], to: [f64; 2]| {
+        let (from, to) = (Vector2::from(from), Vector2::from(to));
+        let delta = to - from;
+        let normal = if delta.is_zero() {
+            Vector2::zero()
+        } else {
+            Vector2::new(-delta.y, delta.x).normalize() * clearance
+        };
+        let lanes = (clearance / INTERPOLATE_INTERVAL).ceil().max(1.) as i32;
+        (-lanes..=lanes).all(|lane| {
+            let offset = normal * (lane as f64 / lanes as f64);
+            !interpolate(
+                (from + offset).into(),
+                (to + offset).into(),
+                INTERPOLATE_INTERVAL,
+                |point| !is_passable_at(board, shape, point),
+            )
+        })
+    };
+    let mut simplified = vec![path[0]];
+    let mut anchor = 0;
+    while anchor < path.len() - 1 {
+        let mut next = anchor + 1;
+        let mut radius = path[next].radius;
+        while next + 1 < path.len() && visible(path[anchor].pos, path[next + 1].pos) {
+            next += 1;
+            radius = radius.min(path[next].radius);
+        }
+        simplified.push(QTreePathNode::new(path[next].pos, radius));
+        anchor = next;
+    }
+    *path = simplified;
+}
+
 #[derive(Debug)]
 pub struct SearchTree {
     pub nodes: Vec<[f64; 2]>,
@@ -216,7 +362,7 @@ pub struct SearchTree {
 }
 
 impl SearchTree {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
             nodes: vec![],
             edges: vec![],
@@ -260,3 +406,162 @@ fn log2test() {
     assert_eq!(log2ceil(4usize), Ok(2));
     assert_eq!(log2ceil(5usize), Ok(3));
 }
+
+#[test]
+fn test_path_length() {
+    let path = [[0., 0.], [3., 4.], [3., 10.], [4., 10.]].map(|pos| QTreePathNode::new(pos, 1.));
+    assert_eq!(path_length(&path), 5. + 6. + 1.);
+    assert_eq!(path_node_count(&path), 4);
+    assert_eq!(path_length(&path[..1]), 0.);
+    assert_eq!(path_length(&[]), 0.);
+}
+
+#[test]
+fn test_leaves() {
+    let shape = (16, 16);
+    let mut searcher = QTreeSearcher::new();
+    searcher
+        .initialize(shape, &|rect: Rect| {
+            let (dx, dy) = (rect[0] - 8, rect[1] - 6);
+            if dx * dx + dy * dy < 20 {
+                CellState::Obstacle
+            } else {
+                CellState::Free
+            }
+        })
+        .unwrap();
+    let mut coverage = vec![0; shape.0 * shape.1];
+    for ([x0, y0, x1, y1], state) in searcher.get_qtree().leaves() {
+        assert!(!matches!(state, CellState::Mixed));
+        for y in y0..y1 {
+            for x in x0..x1 {
+                coverage[x as usize + y as usize * shape.0] += 1;
+            }
+        }
+    }
+    assert!(coverage.iter().all(|c| *c == 1));
+}
+
+#[test]
+fn test_simplify_path() {
+    let shape = (32, 16);
+    // A horizontal corridor between y = 6 and y = 10
+    let board: Vec<Terrain> = (0..shape.1)
+        .flat_map(|y| (0..shape.0).map(move |_| (6..10).contains(&y).into()))
+        .collect();
+    let mut path: QTreePath = (1..16)
+        .map(|i| {
+            let y = if i % 2 == 0 { 7.5 } else { 8.5 };
+            QTreePathNode::new([i as f64 * 2., y], 1.)
+        })
+        .collect();
+    let (first, last) = (path[0].pos, path[path.len() - 1].pos);
+    simplify_path(&mut path, &board, shape, 1.);
+    assert_eq!(path.len(), 2);
+    assert_eq!(path[0].pos, first);
+    assert_eq!(path[1].pos, last);
+}
+
+#[test]
+fn test_simplify_path_clearance() {
+    let shape = (32, 32);
+    // An L-shaped corridor, going right along 4 <= y < 12 and then down along 20 <= x < 28
+    let board: Vec<Terrain> = (0..shape.1)
+        .flat_map(|y| {
+            (0..shape.0).map(move |x| {
+                let horizontal = (4..28).contains(&x) && (4..12).contains(&y);
+                let vertical = (20..28).contains(&x) && (4..28).contains(&y);
+                (horizontal || vertical).into()
+            })
+        })
+        .collect();
+    // The segment from the first to the last waypoint passes less than a pixel off the inner
+    // corner at (20, 12), which is in line of sight but too close for a body of half width 2.
+    let path = || -> QTreePath {
+        [[15., 6.], [23., 8.], [25., 16.]]
+            .map(|pos| QTreePathNode::new(pos, 1.))
+            .to_vec()
+    };
+    let mut thin = path();
+    simplify_path(&mut thin, &board, shape, 0.);
+    assert_eq!(thin.len(), 2);
+    let mut wide = path();
+    simplify_path(&mut wide, &board, shape, 2.);
+    assert_eq!(wide.len(), 3);
+}
+
+#[test]
+fn test_path_find_cell_cost() {
+    let shape = (32, 32);
+    let mut searcher = QTreeSearcher::new();
+    // A wall in the middle, leaving gaps at the top and the bottom
+    searcher
+        .initialize(shape, &|rect: Rect| {
+            if rect[0] < 18 && 14 < rect[2] && rect[1] < 28 && 4 < rect[3] {
+                CellState::Obstacle
+            } else {
+                CellState::Free
+            }
+        })
+        .unwrap();
+    let (start, goal) = ([4.5, 8.5], [27.5, 8.5]);
+    let find = |cell_cost: Option<&dyn Fn([f64; 2]) -> f64>| {
+        searcher
+            .path_find(|_| false, start, goal, &|_| false, 1., cell_cost)
+            .0
+            .unwrap()
+    };
+
+    let path = find(None);
+    assert!(path.iter().all(|node| node.pos[1] < 16.));
+
+    // Pretend the enemy watches the top half
+    let watched = |pos: [f64; 2]| if pos[1] < 16. { 20. } else { 1. };
+    let path = find(Some(&watched));
+    assert!(path.iter().any(|node| 28. <= node.pos[1]));
+}
+
+#[test]
+fn test_rectangular_board() {
+    let shape = (512, 128);
+    let mut searcher = QTreeSearcher::new();
+    // Walls across the board at every 64 pixels, with a gap alternating between the top and
+    // the bottom
+    searcher
+        .initialize(shape, &|rect: Rect| {
+            let wall = (1..8).find(|i| rect[0] < i * 64 + 2 && i * 64 < rect[2]);
+            match wall {
+                Some(i) if i % 2 == 0 && 16 < rect[3] => CellState::Obstacle,
+                Some(i) if i % 2 == 1 && rect[1] < 112 => CellState::Obstacle,
+                _ => CellState::Free,
+            }
+        })
+        .unwrap();
+
+    let qtree = searcher.get_qtree();
+    let mut coverage = vec![0; shape.0 * shape.1];
+    for ([x0, y0, x1, y1], _) in qtree.leaves() {
+        assert!(0 <= x0 && x1 <= shape.0 as i32 && 0 <= y0 && y1 <= shape.1 as i32);
+        for y in y0..y1 {
+            for x in x0..x1 {
+                coverage[x as usize + y as usize * shape.0] += 1;
+            }
+        }
+    }
+    assert!(coverage.iter().all(|c| *c == 1));
+    assert!(searcher.find([300., 200.]).is_none());
+    assert!(searcher.find([-1., 10.]).is_none());
+
+    let (start, goal) = ([5.5, 64.5], [505.5, 64.5]);
+    let path = searcher
+        .path_find(|_| false, start, goal, &|_| false, 1., None)
+        .0
+        .unwrap();
+    assert_eq!(path[0].pos, goal);
+    assert!(path
+        .iter()
+        .all(|node| node.pos[0] < shape.0 as f64 && node.pos[1] < shape.1 as f64));
+    // The path has to weave through the gaps at the top and the bottom
+    assert!(path.iter().any(|node| node.pos[1] < 16.));
+    assert!(path.iter().any(|node| 112. < node.pos[1]));
+}
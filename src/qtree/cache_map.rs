@@ -14,12 +14,13 @@ use std::{
 /// much larger than u32.
 #[derive(Debug)]
 pub struct CacheMap {
-    /// An internal map having the size (2 ^ toplevel) ^ 2, indicating index into [`cache_buf`]
+    /// An internal map having the size of the board, indicating index into [`cache_buf`].
+    /// It does not cover the padding up to the power of two size of the quad tree.
     map: Vec<u32>,
     /// An array of actual values in [`cache_map`], extracted to reduce the pixel size.
     buf: Vec<CellState>,
     topbit: usize,
-    size: usize,
+    shape: (usize, usize),
     /// A history of recently updated cells for visualization
     pub fresh_cells: HashMap<[i32; 2], usize>,
     prev_map: Option<Vec<u32>>,
@@ -36,14 +37,21 @@ impl CacheMap {
             map: vec![],
             buf: vec![],
             topbit: 0,
-            size: 0,
+            shape: (0, 0),
             fresh_cells: HashMap::new(),
             prev_map: None,
         }
     }
 
     pub fn get(&self, pos: [i32; 2]) -> CellState {
-        self.buf[self.map[pos[0] as usize + pos[1] as usize * self.size] as usize]
+        self.buf[self.map[pos[0] as usize + pos[1] as usize * self.shape.0] as usize]
+    }
+
+    fn in_bounds(&self, pos: [i32; 2]) -> bool {
+        0 <= pos[0]
+            && (pos[0] as usize) < self.shape.0
+            && 0 <= pos[1]
+            && (pos[1] as usize) < self.shape.1
     }
 
     pub(super) fn cache(
@@ -53,15 +61,15 @@ impl CacheMap {
         f: &impl Fn(Rect) -> CellState,
     ) {
         self.topbit = topbit;
-        self.size = 1 << topbit;
-        self.map = vec![0; self.size * self.size];
+        self.shape = shape;
+        self.map = vec![0; shape.0 * shape.1];
         for y in 0..shape.1 {
             for x in 0..shape.0 {
                 let pix = f([x as i32, y as i32, x as i32 + 1, y as i32 + 1]);
                 if let Some((idx, _)) = self.buf.iter().enumerate().find(|(_, b)| **b == pix) {
-                    self.map[x + y * self.size] = idx as u32;
+                    self.map[x + y * shape.0] = idx as u32;
                 } else {
-                    self.map[x + y * self.size] = self.buf.len() as u32;
+                    self.map[x + y * shape.0] = self.buf.len() as u32;
                     self.buf.push(pix);
                 }
             }
@@ -85,11 +93,11 @@ impl CacheMap {
     }
 
     pub(super) fn update(&mut self, pos: [i32; 2], pix: CellState) -> Result<bool, String> {
-        if pos[0] < 0 || self.size as i32 <= pos[0] || pos[1] < 0 || self.size as i32 <= pos[1] {
+        if !self.in_bounds(pos) {
             return Err("Out of bounds!".to_string());
         }
 
-        let existing = &mut self.map[pos[0] as usize + pos[1] as usize * self.size];
+        let existing = &mut self.map[pos[0] as usize + pos[1] as usize * self.shape.0];
 
         if self.buf[*existing as usize] != pix {
             // dbg_println!("Updating cache_map {pos:?}: {:?} -> {:?}", *existing, pix);
@@ -128,7 +136,7 @@ impl CacheMap {
             })
         {
             self.fresh_cells.insert(
-                [(i % self.size) as i32, (i / self.size) as i32],
+                [(i % self.shape.0) as i32, (i / self.shape.0) as i32],
                 FRESH_TICKS,
             );
         }
@@ -147,14 +155,15 @@ impl CacheMap {
         }
     }
 
+    /// Query the state of a rectangle. The part of the rectangle outside of the board is ignored.
     pub(super) fn query(&self, rect: Rect) -> CellState {
         let mut has_passable = false;
         let mut has_unpassable = None;
-        for x in rect[0]..rect[2] {
-            for y in rect[1]..rect[3] {
+        for x in rect[0].max(0)..rect[2].min(self.shape.0 as i32) {
+            for y in rect[1].max(0)..rect[3].min(self.shape.1 as i32) {
                 QUERY_CALLS.fetch_add(1, Ordering::Relaxed);
                 let mut has_unpassable_local = None;
-                let pix = &self.buf[self.map[x as usize + y as usize * self.size] as usize];
+                let pix = &self.buf[self.map[x as usize + y as usize * self.shape.0] as usize];
                 if !matches!(pix, CellState::Free) {
                     UNPASSABLES.fetch_add(1, Ordering::Relaxed);
                     has_unpassable_local = Some(*pix);
@@ -192,9 +201,9 @@ impl CacheMap {
         }
         !interpolation::interpolate(source, target, INTERPOLATE_INTERVAL, |point| {
             if point[0] < 0.
-                || self.size <= point[0] as usize
+                || self.shape.0 <= point[0] as usize
                 || point[1] < 0.
-                || self.size <= point[1] as usize
+                || self.shape.1 <= point[1] as usize
             {
                 true
             } else {
@@ -8,10 +8,16 @@ use super::{CellState, PathFindResponse, QTreePath, QTreePathNode, Rect, SearchT
 /// A quad tree to divide space for navigation.
 ///
 /// It is not actually a quad tree data structure. The algorithm is.
+///
+/// The top level cell is a square of power of two size covering the board, but cells outside
+/// of the board `shape` are never stored and cells crossing its edge are always subdivided,
+/// so a non-square board does not pay for the padding.
 #[derive(Debug)]
 pub struct QTree {
     pub toplevel: usize,
     pub levels: Vec<HashMap<[i32; 2], CellState>>,
+    /// The size of the board in pixels
+    pub shape: (usize, usize),
 }
 
 impl QTree {
@@ -19,6 +25,7 @@ impl QTree {
         Self {
             toplevel: 0,
             levels: vec![],
+            shape: (0, 0),
         }
     }
 
@@ -26,33 +33,95 @@ impl QTree {
         1 << (self.toplevel - level)
     }
 
+    fn idx_to_rect(&self, level: usize, idx: [i32; 2]) -> Rect {
+        let width = self.width(level) as i32;
+        [
+            idx[0] * width,
+            idx[1] * width,
+            (idx[0] + 1) * width,
+            (idx[1] + 1) * width,
+        ]
+    }
+
+    /// Whether the rectangle overlaps with the board
+    fn overlaps_board(&self, rect: Rect) -> bool {
+        0 < rect[2] && rect[0] < self.shape.0 as i32 && 0 < rect[3] && rect[1] < self.shape.1 as i32
+    }
+
+    /// Whether the rectangle is contained in the board
+    fn inside_board(&self, rect: Rect) -> bool {
+        0 <= rect[0]
+            && rect[2] <= self.shape.0 as i32
+            && 0 <= rect[1]
+            && rect[3] <= self.shape.1 as i32
+    }
+
+    /// Iterate all leaf cells, i.e. cells that are not `Mixed`, with their rectangles
+    /// in world coordinates.
+    pub fn leaves(&self) -> impl Iterator<Item = (Rect, CellState)> + '_ {
+        self.levels
+            .iter()
+            .enumerate()
+            .flat_map(move |(level, cells)| {
+                let width = self.width(level) as i32;
+                cells
+                    .iter()
+                    .filter(|(_, state)| !matches!(state, CellState::Mixed))
+                    .map(move |(cell, state)| {
+                        let (x, y) = (
+                            cell[0] << (self.toplevel - level),
+                            cell[1] << (self.toplevel - level),
+                        );
+                        ([x, y, x + width, y + width], *state)
+                    })
+            })
+    }
+
     pub(super) fn recurse_update(
         &mut self,
         level: usize,
         parent: [i32; 2],
         f: &impl Fn(Rect) -> CellState,
     ) {
-        let width = self.width(level) as i32;
-        let rect = [
-            parent[0] * width,
-            parent[1] * width,
-            (parent[0] + 1) * width,
-            (parent[1] + 1) * width,
-        ];
         if level <= 2 {
-            dbg_println!("level: {level}, rect: {rect:?}");
+            dbg_println!("level: {level}, parent: {parent:?}");
+        }
+        if self.update_node(level, parent, f) {
+            for child in Self::children(parent) {
+                self.recurse_update(level + 1, child, f);
+            }
+        }
+    }
+
+    /// Insert a single cell without visiting its children, returning whether the children
+    /// need to be updated, i.e. the cell turned out to be `Mixed`.
+    pub(super) fn update_node(
+        &mut self,
+        level: usize,
+        parent: [i32; 2],
+        f: &impl Fn(Rect) -> CellState,
+    ) -> bool {
+        let rect = self.idx_to_rect(level, parent);
+        if !self.overlaps_board(rect) {
+            return false;
+        }
+        if !self.inside_board(rect) {
+            // A cell sticking out of the board is subdivided regardless of its content, so that
+            // only the cells inside the board are stored.
+            self.insert(level, parent, CellState::Mixed);
+            return true;
         }
         let cell_state = f(rect);
         if self.toplevel <= level || !matches!(cell_state, CellState::Mixed) {
             self.insert(level, parent, cell_state);
-            return;
+            return false;
         }
         self.insert(level, parent, CellState::Mixed);
-        for x in 0..2i32 {
-            for y in 0..2i32 {
-                self.recurse_update(level + 1, [parent[0] * 2 + x, parent[1] * 2 + y], f);
-            }
-        }
+        true
+    }
+
+    pub(super) fn children(parent: [i32; 2]) -> impl Iterator<Item = [i32; 2]> {
+        (0..2i32).flat_map(move |x| (0..2i32).map(move |y| [parent[0] * 2 + x, parent[1] * 2 + y]))
     }
 
     pub(super) fn try_merge(&mut self, level: usize, cell_pos: [i32; 2]) {
@@ -139,7 +208,9 @@ impl QTree {
 
     /// Find by index at the bottom level
     pub fn find_by_idx(&self, pos: [i32; 2]) -> Option<(usize, CellState)> {
-        // let width = 1 << self.toplevel;
+        if !self.inside_board([pos[0], pos[1], pos[0] + 1, pos[1] + 1]) {
+            return None;
+        }
         for (level, cells) in self.levels.iter().enumerate().rev() {
             let cell_pos = self.to_idx(pos, level);
             let cell = cells.get(&cell_pos);
@@ -182,7 +253,7 @@ impl QTree {
 
     /// Find a neighbor cell in given level or its sublevels.
     fn sub_recurse_find(&self, level: usize, idx: [i32; 2], side: Side) -> Vec<(usize, [i32; 2])> {
-        if self.levels.len() <= level {
+        if self.levels.len() <= level || !self.overlaps_board(self.idx_to_rect(level, idx)) {
             return vec![];
         }
         let same_level = self.levels[level].get(&idx);
@@ -267,6 +338,8 @@ pub(crate) enum PathFindError {
     StartBlocked,
     GoalBlocked,
     SearchFailed,
+    /// The tree has not been initialized, e.g. because the board was too small to build one.
+    Uninitialized,
 }
 
 impl Display for PathFindError {
@@ -278,6 +351,7 @@ impl Display for PathFindError {
                 Self::StartBlocked => "StartBlocked",
                 Self::GoalBlocked => "GoalBlocked",
                 Self::SearchFailed => "SearchFailed",
+                Self::Uninitialized => "Uninitialized",
             }
         )
     }
@@ -335,6 +409,7 @@ impl QTree {
         end: [f64; 2],
         fog: &impl Fn([f64; 2]) -> bool,
         goal_radius: f64,
+        cell_cost: Option<&dyn Fn([f64; 2]) -> f64>,
     ) -> (Result<QTreePath, PathFindError>, SearchTree) {
         let mut result = Err(PathFindError::SearchFailed);
         let Some(start_found) = self.find(start) else {
@@ -373,7 +448,7 @@ impl QTree {
 
         dbg_println!("Start Searching from {start:?}");
 
-        let search_tree = self.explore(ignore, start_idx, |idx, state, closed_set| {
+        let search_tree = self.explore(ignore, start_idx, cell_cost, |idx, state, closed_set| {
             if idx == end_idx {
                 let mut path = vec![];
                 // The last node should directly connect to the goal
@@ -416,7 +491,7 @@ impl QTree {
 
         dbg_println!("Start Searching from {start:?}");
 
-        let search_tree = self.explore(ignore, start_idx, |idx, state, closed_set| {
+        let search_tree = self.explore(ignore, start_idx, None, |idx, state, closed_set| {
             let res = end(idx);
             match res {
                 PathFindResponse::Goal => {
@@ -438,12 +513,67 @@ impl QTree {
         (result, search_tree)
     }
 
+    /// Find points at straight line `distance` from `start` in the cells that can be reached
+    /// from `start` without going farther than that, one point per cell, ordered by the angle
+    /// around `start` so that they form a loop.
+    pub(crate) fn find_contour(
+        &self,
+        ignore: impl Fn(usize) -> bool,
+        start: [f64; 2],
+        distance: f64,
+    ) -> Vec<[f64; 2]> {
+        let Some((start_level, _)) = self.find(start) else {
+            return vec![];
+        };
+        let start_idx = (start_level, self.pos_to_idx(start, start_level));
+
+        let mut found = HashMap::new();
+        let mut visit = |idx: QTreeIdx| {
+            let width = self.width(idx.0) as f64;
+            let (x0, y0) = (idx.1[0] as f64 * width, idx.1[1] as f64 * width);
+            let (x1, y1) = (x0 + width, y0 + width);
+            let nearest = [start[0].clamp(x0, x1), start[1].clamp(y0, y1)];
+            if distance < (nearest[0] - start[0]).hypot(nearest[1] - start[1]) {
+                return PathFindResponse::Abandon;
+            }
+            match self.levels.get(idx.0).and_then(|level| level.get(&idx.1)) {
+                Some(CellState::Mixed) | None => return PathFindResponse::Continue,
+                Some(cell) if blocked(*cell, &ignore) => return PathFindResponse::Continue,
+                _ => (),
+            }
+            let center = self.idx_to_center(idx);
+            let delta = [center[0] - start[0], center[1] - start[1]];
+            let length = delta[0].hypot(delta[1]);
+            if length == 0. {
+                return PathFindResponse::Continue;
+            }
+            let point = [
+                start[0] + delta[0] / length * distance,
+                start[1] + delta[1] / length * distance,
+            ];
+            if x0 <= point[0] && point[0] < x1 && y0 <= point[1] && point[1] < y1 {
+                found.insert(idx, point);
+            }
+            PathFindResponse::Continue
+        };
+        visit(start_idx);
+        self.explore(&ignore, start_idx, None, |idx, _, _| visit(idx));
+
+        let angle = |point: &[f64; 2]| (point[1] - start[1]).atan2(point[0] - start[0]);
+        let mut points: Vec<_> = found.into_values().collect();
+        points.sort_by(|a, b| angle(a).total_cmp(&angle(b)));
+        points
+    }
+
     /// Explore the quad tree structure from given start index. `terminate` will give a condition to terminate the search.
     /// Typically, it also constructs the path by tracking the tree in reverse.
+    ///
+    /// `cell_cost` gives the cost of entering a cell by its center position. Each cell costs 1 if it's not given.
     fn explore(
         &self,
         ignore: impl Fn(usize) -> bool,
         start_idx: QTreeIdx,
+        cell_cost: Option<&dyn Fn([f64; 2]) -> f64>,
         mut terminate: impl FnMut(
             QTreeIdx,
             QTreeIdx,
@@ -482,7 +612,8 @@ impl QTree {
                     PathFindResponse::Abandon => continue,
                     _ => (),
                 }
-                let new_cost = state.cost + 1.;
+                let new_cost = state.cost
+                    + cell_cost.map_or(1., |f| f(self.idx_to_center((nei_level, nei_idx))));
                 let cell = self.levels[nei_level].get(&nei_idx);
                 if state.cost < 10. {
                     // dbg_println!(
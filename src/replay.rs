@@ -0,0 +1,255 @@
+//! Recording of a match to seek to any of its ticks afterwards.
+//!
+//! A `Game` cannot be copied as a snapshot, because agents own behavior trees whose nodes keep
+//! private state. Instead, a `Replay` keeps the setup of the match at its start as the keyframe
+//! and the parameter changes as the deltas, and a tick is reconstructed by simulating the match
+//! from the keyframe, which relies on the simulation being deterministic. The events recorded
+//! during the match are compared to the reconstructed ones to detect that the replay went out of
+//! sync, e.g. after an intervention that is not recorded, like freezing agents.
+//!
+//! Without intermediate keyframes, seeking backward costs as much as playing the match up to the
+//! tick again. `ReplayPlayer::seek_by` bounds the updates per call, so that a user interface can
+//! spread a long seek over frames.
+
+use crate::{
+    game::{BoardParams, BoardType, Game, GameEventRecord, GameParams, TeamStats, UpdateResult},
+    perlin_noise::Xor128,
+};
+
+/// The setup and the inputs of a match, recorded by a `Game` from `Game::new_board` while
+/// `Game::set_replay_recording` is on.
+#[derive(Clone, Debug)]
+pub struct Replay {
+    board_type: BoardType,
+    board_params: BoardParams,
+    rng: Xor128,
+    id_gen: usize,
+    stats: [TeamStats; 2],
+    /// The parameters that the board was created with
+    initial_params: GameParams,
+    /// Parameters set by `Game::set_params` with the tick from which they are in effect
+    params: Vec<(i32, GameParams)>,
+    events: Vec<GameEventRecord>,
+    ticks: i32,
+}
+
+impl Replay {
+    /// Start a recording with the state of `game` right before creating a new board.
+    pub(crate) fn new(game: &Game, board_type: BoardType, board_params: &BoardParams) -> Self {
+        Self {
+            board_type,
+            board_params: board_params.clone(),
+            rng: game.rng,
+            id_gen: game.id_gen,
+            stats: game.stats,
+            initial_params: game.params.clone(),
+            params: vec![],
+            events: vec![],
+            ticks: 0,
+        }
+    }
+
+    pub(crate) fn record_params(&mut self, tick: i32, params: &GameParams) {
+        match self.params.last_mut() {
+            Some((_, last)) if last == params => {}
+            Some((last_tick, last)) if *last_tick == tick => *last = params.clone(),
+            _ => self.params.push((tick, params.clone())),
+        }
+    }
+
+    pub(crate) fn record_event(&mut self, event: &GameEventRecord) {
+        self.events.push(event.clone());
+    }
+
+    pub(crate) fn record_tick(&mut self, tick: i32) {
+        self.ticks = tick;
+    }
+
+    /// The number of ticks recorded
+    pub fn ticks(&self) -> i32 {
+        self.ticks
+    }
+
+    pub fn events(&self) -> &[GameEventRecord] {
+        &self.events
+    }
+
+    /// Build the game at the start of the match.
+    fn start(&self) -> Game {
+        let mut game = Game::new();
+        game.rng = self.rng;
+        game.id_gen = self.id_gen;
+        game.stats = self.stats;
+        game.set_params(&self.initial_params);
+        game.new_board(self.board_type, &self.board_params);
+        game.init();
+        game.set_event_recording(true);
+        game
+    }
+
+    /// The parameters in effect for the update from `tick` to the next one
+    fn params_at(&self, tick: i32) -> &GameParams {
+        let idx = self.params.partition_point(|(from, _)| *from <= tick);
+        idx.checked_sub(1)
+            .map_or(&self.initial_params, |idx| &self.params[idx].1)
+    }
+}
+
+/// Reconstructs the ticks of a `Replay` on a game to seek back and forth in the match.
+pub struct ReplayPlayer {
+    replay: Replay,
+    /// The number of recorded events that were reproduced so far
+    matched_events: usize,
+    in_sync: bool,
+}
+
+impl ReplayPlayer {
+    pub fn new(replay: Replay) -> Self {
+        Self {
+            replay,
+            matched_events: 0,
+            in_sync: true,
+        }
+    }
+
+    pub fn replay(&self) -> &Replay {
+        &self.replay
+    }
+
+    /// Build the game at the start of the match, to be given to `seek`.
+    pub fn start(&mut self) -> Game {
+        self.matched_events = 0;
+        self.in_sync = true;
+        self.replay.start()
+    }
+
+    /// Bring `game`, which was built by `start`, to `tick` in the recorded range. Seeking forward
+    /// continues from the current tick of `game`, while seeking backward restarts the match.
+    pub fn seek(&mut self, game: &mut Game, tick: i32) {
+        self.seek_by(game, tick, usize::MAX);
+    }
+
+    /// Like `seek`, but update `game` at most `max_updates` times. Returns whether `game` is done
+    /// seeking, otherwise call it again with the same `tick` to continue.
+    pub fn seek_by(&mut self, game: &mut Game, tick: i32, max_updates: usize) -> bool {
+        let tick = tick.clamp(0, self.replay.ticks);
+        if tick < game.global_time {
+            *game = self.start();
+        }
+        for _ in 0..max_updates {
+            if tick <= game.global_time {
+                return true;
+            }
+            game.set_params(self.replay.params_at(game.global_time));
+            let res = game.update();
+            for event in game.take_events() {
+                if self.replay.events.get(self.matched_events) == Some(&event) {
+                    self.matched_events += 1;
+                } else {
+                    self.in_sync = false;
+                }
+            }
+            if !matches!(res, UpdateResult::Running) {
+                return true;
+            }
+        }
+        tick <= game.global_time
+    }
+
+    /// Whether the reconstruction has reproduced the recorded events so far. It goes out of sync
+    /// if the match was altered by something other than the parameters.
+    pub fn is_in_sync(&self) -> bool {
+        self.in_sync
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::game::TeamConfig;
+    use std::rc::Rc;
+
+    fn entity_states(game: &Game) -> Vec<(usize, [f64; 2], u32)> {
+        game.entities
+            .iter()
+            .map(|entity| {
+                let entity = entity.borrow();
+                (entity.get_id(), entity.get_pos(), entity.get_health())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_seek() {
+        const MID_TICK: i32 = 300;
+        const END_TICK: i32 = 600;
+        let mut game = Game::new();
+        let mut params = GameParams::new();
+        params.teams = ["green", "red"].map(|_| TeamConfig {
+            agent_source: Rc::new(
+                include_str!("../behavior_tree_config/green/agent.btc").to_string(),
+            ),
+            spawner_source: Rc::new(
+                include_str!("../behavior_tree_config/green/spawner.btc").to_string(),
+            ),
+            ..TeamConfig::default()
+        });
+        game.set_params(&params);
+        game.set_replay_recording(true);
+        game.new_board(
+            BoardType::Rooms,
+            &BoardParams {
+                shape: (128, 128),
+                seed: 4321,
+                simplify: 1.,
+                maze_expansions: 0,
+                slow_shores: false,
+            },
+        );
+        game.init();
+
+        let mut mid_states = vec![];
+        for tick in 0..END_TICK {
+            // A change of the parameters in the middle of the match is replayed as well
+            if tick == MID_TICK / 2 {
+                params.ricochet = true;
+            }
+            game.set_params(&params);
+            game.update();
+            if game.global_time == MID_TICK {
+                mid_states = entity_states(&game);
+            }
+        }
+
+        let mut player = ReplayPlayer::new(game.replay().unwrap().clone());
+        assert_eq!(player.replay().ticks(), END_TICK);
+        assert!(!player.replay().events().is_empty());
+        let mut replayed = player.start();
+        player.seek(&mut replayed, END_TICK);
+        assert_eq!(replayed.global_time, END_TICK);
+        assert!(4 < replayed.entities.len());
+        assert_eq!(entity_states(&replayed), entity_states(&game));
+        assert!(player.is_in_sync());
+
+        // Seeking backward reconstructs the same state as playing forward
+        player.seek(&mut replayed, MID_TICK);
+        assert_eq!(replayed.global_time, MID_TICK);
+        assert_eq!(entity_states(&replayed), mid_states);
+
+        // A seek spread over calls ends up in the same state
+        let mut replayed = player.start();
+        let mut calls = 0;
+        while !player.seek_by(&mut replayed, MID_TICK, 100) {
+            calls += 1;
+        }
+        assert_eq!(calls, 2);
+        assert_eq!(entity_states(&replayed), mid_states);
+
+        // An intervention that is not recorded makes the replay go out of sync
+        let mut player = ReplayPlayer::new(game.replay().unwrap().clone());
+        let mut replayed = player.start();
+        replayed.clear_team(1);
+        player.seek(&mut replayed, END_TICK);
+        assert!(!player.is_in_sync());
+    }
+}
@@ -1,16 +1,19 @@
 mod behavior_nodes;
 
-use behavior_tree_lite::{error::LoadError, Blackboard, Context};
+pub use self::behavior_nodes::describe_nodes;
+
+use behavior_tree_lite::{Blackboard, Context};
 
 use self::behavior_nodes::{
-    build_tree, CancelSpawnTask, CurrentSpawnTask, LastSpawnResult, SpawnFighter, SpawnWorker,
+    build_tree, CancelSpawnTask, CurrentSpawnTask, LastSpawnResult, SpawnFighterCommand,
+    SpawnWorkerCommand,
 };
 use crate::{
     agent::AgentClass,
-    behavior_tree_adapt::{BehaviorTree, GetIdCommand, GetResource, PrintCommand},
-    collision::{aabb_intersects, CollisionShape, Obb},
+    behavior_tree_adapt::{BehaviorTree, BuildTreeError, GetIdCommand, GetResource, PrintCommand},
+    collision::{aabb_intersects, layers, CollisionShape, Obb},
     entity::{Entity, GameEvent, MAX_LOG_ENTRIES},
-    game::Game,
+    game::{Game, GameParams},
 };
 use std::{cell::RefCell, collections::VecDeque, rc::Rc};
 
@@ -28,12 +31,22 @@ pub struct Spawner {
     pub active: bool,
     pub health: u32,
     pub resource: i32,
+    /// Collision layer of this spawner, `layers::SPAWNER` by default
+    pub layer: u32,
+    /// Collision layers this spawner collides with
+    pub mask: u32,
     behavior_source: Rc<String>,
     behavior_tree: Option<BehaviorTree>,
     blackboard: Blackboard,
     log_buffer: VecDeque<String>,
     spawn_progress: Option<(usize, AgentClass)>,
+    /// The agent behavior tree source index for the current spawn task
+    spawn_source: Option<usize>,
     spawn_result: Option<AgentClass>,
+    /// Remaining ticks until the next production is allowed
+    production_cooldown: usize,
+    /// Number of agents produced, used for cost scaling
+    spawn_count: usize,
 }
 
 impl Spawner {
@@ -42,7 +55,7 @@ impl Spawner {
         pos: [f64; 2],
         team: usize,
         behavior_source: Rc<String>,
-    ) -> Result<Self, LoadError> {
+    ) -> Result<Self, BuildTreeError> {
         let id = *id_gen;
         *id_gen += 1;
 
@@ -55,12 +68,17 @@ impl Spawner {
             active: true,
             health: SPAWNER_MAX_HEALTH,
             resource: 0,
+            layer: layers::SPAWNER,
+            mask: layers::ALL,
             behavior_source,
             behavior_tree: Some(tree),
             blackboard: Blackboard::new(),
             log_buffer: VecDeque::new(),
             spawn_progress: None,
+            spawn_source: None,
             spawn_result: None,
+            production_cooldown: 0,
+            spawn_count: 0,
         })
     }
 
@@ -126,6 +144,7 @@ impl Spawner {
         if self.resource < AgentClass::Worker.cost() {
             self.resource += 1;
         }
+        self.production_cooldown = self.production_cooldown.saturating_sub(1);
 
         let mut ret = vec![];
 
@@ -141,10 +160,10 @@ impl Spawner {
                     }
                 } else if f.downcast_ref::<GetResource>().is_some() {
                     return Some(Box::new(self.resource));
-                } else if f.downcast_ref::<SpawnFighter>().is_some() {
-                    return self.start_spawn(AgentClass::Fighter);
-                } else if f.downcast_ref::<SpawnWorker>().is_some() {
-                    return self.start_spawn(AgentClass::Worker);
+                } else if let Some(com) = f.downcast_ref::<SpawnFighterCommand>() {
+                    return self.start_spawn(AgentClass::Fighter, com.0);
+                } else if let Some(com) = f.downcast_ref::<SpawnWorkerCommand>() {
+                    return self.start_spawn(AgentClass::Worker, com.0);
                 } else if f.downcast_ref::<LastSpawnResult>().is_some() {
                     return self.last_spawn_result();
                 } else if f.downcast_ref::<CurrentSpawnTask>().is_some() {
@@ -168,7 +187,11 @@ impl Spawner {
         ret
     }
 
-    fn start_spawn(&mut self, class: AgentClass) -> Option<Box<dyn std::any::Any>> {
+    fn start_spawn(
+        &mut self,
+        class: AgentClass,
+        source: Option<usize>,
+    ) -> Option<Box<dyn std::any::Any>> {
         let mut ret = false;
         if self.spawn_progress.is_none() {
             self.spawn_progress = Some((class.time(), class));
@@ -179,6 +202,9 @@ impl Spawner {
                 ret = true;
             }
         }
+        if ret {
+            self.spawn_source = source;
+        }
         Some(Box::new(ret))
     }
 
@@ -186,13 +212,24 @@ impl Spawner {
         Some(Box::new(self.spawn_result.take()) as Box<dyn std::any::Any>)
     }
 
+    /// The cost to produce an agent of the class, which may grow as the spawner produces more.
+    /// It is capped at the resource a spawner can hold, so that production never stops for good.
+    pub(crate) fn spawn_cost(&self, class: AgentClass, params: &GameParams) -> i32 {
+        let cost = class.cost() as f64 * (1. + params.spawn_cost_scaling * self.spawn_count as f64);
+        (cost as i32).min(SPAWNER_MAX_RESOURCE)
+    }
+
     fn try_spawn(&mut self, game: &Game, entities: &[RefCell<Entity>]) -> Option<GameEvent> {
         if let Some((ref mut remaining, class)) = self.spawn_progress {
             if 1 <= *remaining {
                 *remaining -= 1;
                 return None;
             }
-            if self.resource < class.cost() {
+            if 0 < self.production_cooldown || game.has_pending_spawn(self.id) {
+                return None;
+            }
+            let cost = self.spawn_cost(class, &game.params);
+            if self.resource < cost {
                 return None;
             }
             let agent_count = entities
@@ -214,9 +251,13 @@ impl Spawner {
                     team: self.team,
                     spawner: self.id,
                     class,
+                    source: self.spawn_source,
+                    cost,
                 });
                 self.spawn_progress = None;
                 self.spawn_result = Some(class);
+                self.production_cooldown = game.params.spawner_cooldown;
+                self.spawn_count += 1;
                 return ret;
             }
         }
@@ -1,38 +1,58 @@
 use behavior_tree_lite::{
-    boxify, error::LoadError, load, parse_file, BehaviorCallback, BehaviorNode, BehaviorResult,
-    PortSpec, Registry,
+    boxify, load, BehaviorCallback, BehaviorNode, BehaviorResult, PortSpec, Registry,
 };
 
 use crate::{
     agent::AgentClass,
-    behavior_tree_adapt::{common_tree_nodes, BehaviorTree},
+    behavior_tree_adapt::{
+        common_tree_nodes, describe_common_nodes, parse_tree_source, BehaviorTree, BuildTreeError,
+        NodeDoc, NodeRegistry,
+    },
 };
 
-pub(super) fn build_tree(source: &str) -> Result<BehaviorTree, LoadError> {
+pub(super) fn build_tree(source: &str) -> Result<BehaviorTree, BuildTreeError> {
     let mut registry = Registry::default();
     common_tree_nodes(&mut registry);
+    register_nodes(&mut registry);
+
+    let tree_source = parse_tree_source(source)?;
+    Ok(BehaviorTree(load(&tree_source, &registry, true)?))
+}
+
+/// Returns the names and ports of all the nodes available in spawner trees.
+pub fn describe_nodes() -> Vec<NodeDoc> {
+    let mut docs = describe_common_nodes();
+    register_nodes(&mut docs);
+    docs
+}
+
+fn register_nodes(registry: &mut impl NodeRegistry) {
     registry.register("SpawnFighter", boxify(|| SpawnFighter));
     registry.register("SpawnWorker", boxify(|| SpawnWorker));
     registry.register("LastSpawnResult", boxify(|| LastSpawnResult));
     registry.register("CurrentSpawnTask", boxify(|| CurrentSpawnTask));
     registry.register("CancelSpawnTask", boxify(|| CancelSpawnTask));
-
-    let (_i, tree_source) = parse_file(source).unwrap();
-    // println!("parse_file rest: {i:?}");
-    Ok(BehaviorTree(load(&tree_source, &registry, true)?))
 }
 
 macro_rules! spawn_impl {
-    {$name:ident} => {
+    {$name:ident, $command:ident} => {
+        /// Carries an optional index into `TeamConfig::agent_sources`
+        pub(super) struct $command(pub Option<usize>);
+
         pub(super) struct $name;
 
         impl BehaviorNode for $name {
+            fn provided_ports(&self) -> Vec<PortSpec> {
+                vec![PortSpec::new_in("source")]
+            }
+
             fn tick(
                 &mut self,
                 arg: BehaviorCallback,
-                _ctx: &mut behavior_tree_lite::Context,
+                ctx: &mut behavior_tree_lite::Context,
             ) -> BehaviorResult {
-                let result = arg(&Self).and_then(|a| a.downcast_ref::<bool>().copied());
+                let source = ctx.get_parse::<usize>("source");
+                let result = arg(&$command(source)).and_then(|a| a.downcast_ref::<bool>().copied());
                 if result.is_some() {
                     BehaviorResult::Success
                 } else {
@@ -43,8 +63,8 @@ macro_rules! spawn_impl {
     }
 }
 
-spawn_impl!(SpawnFighter);
-spawn_impl!(SpawnWorker);
+spawn_impl!(SpawnFighter, SpawnFighterCommand);
+spawn_impl!(SpawnWorker, SpawnWorkerCommand);
 
 pub(super) struct LastSpawnResult;
 
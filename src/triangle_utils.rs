@@ -2,7 +2,7 @@ use ::delaunator::{Point, Triangulation};
 use geo::Contains;
 use std::collections::HashSet;
 
-use crate::{collision::CollisionShape, game::Profiler, measure_time, mesh::Mesh};
+use crate::{collision::CollisionShape, game::Profiler, measure_time, measure_time_if, mesh::Mesh};
 
 pub(crate) fn center_of_triangle(v1: Point, v2: Point, v3: Point) -> Point {
     Point {
@@ -23,14 +23,14 @@ pub fn center_of_triangle_obj(
     )
 }
 
-pub(crate) fn _sign(p1: Point, p2: Point, p3: Point) -> f64 {
+pub(crate) fn sign(p1: Point, p2: Point, p3: Point) -> f64 {
     (p1.x - p3.x) * (p2.y - p3.y) - (p2.x - p3.x) * (p1.y - p3.y)
 }
 
-pub(crate) fn _point_in_triangle(pt: Point, v1: Point, v2: Point, v3: Point) -> bool {
-    let d1 = _sign(pt.clone(), v1.clone(), v2.clone());
-    let d2 = _sign(pt.clone(), v2.clone(), v3.clone());
-    let d3 = _sign(pt.clone(), v3.clone(), v1.clone());
+pub(crate) fn point_in_triangle(pt: Point, v1: Point, v2: Point, v3: Point) -> bool {
+    let d1 = sign(pt.clone(), v1.clone(), v2.clone());
+    let d2 = sign(pt.clone(), v2.clone(), v3.clone());
+    let d3 = sign(pt.clone(), v3.clone(), v1.clone());
 
     let has_neg = (d1 < 0.) || (d2 < 0.) || (d3 < 0.);
     let has_pos = (d1 > 0.) || (d2 > 0.) || (d3 > 0.);
@@ -38,32 +38,43 @@ pub(crate) fn _point_in_triangle(pt: Point, v1: Point, v2: Point, v3: Point) ->
     return !(has_neg && has_pos);
 }
 
-fn _to_point(p: [f64; 2]) -> Point {
+fn to_point(p: [f64; 2]) -> Point {
     Point { x: p[0], y: p[1] }
 }
 
 /// Returns triangle id (multiply with 3 to get index into `triangulation.triangles`)
+/// of the first triangle that contains `point`, including its edges.
+pub(crate) fn find_triangle_at(
+    triangulation: &Triangulation,
+    points: &[Point],
+    point: [f64; 2],
+) -> Option<usize> {
+    let point = to_point(point);
+    triangulation.triangles.chunks(3).position(|triangle| {
+        let [v1, v2, v3] = [
+            &points[triangle[0]],
+            &points[triangle[1]],
+            &points[triangle[2]],
+        ];
+        // Reject by the bounding box before the more expensive sign tests
+        if point.x < v1.x.min(v2.x).min(v3.x)
+            || v1.x.max(v2.x).max(v3.x) < point.x
+            || point.y < v1.y.min(v2.y).min(v3.y)
+            || v1.y.max(v2.y).max(v3.y) < point.y
+        {
+            return false;
+        }
+        point_in_triangle(point.clone(), v1.clone(), v2.clone(), v3.clone())
+    })
+}
+
 pub(crate) fn _find_triangle_at(
     mesh: &Mesh,
     point: [f64; 2],
     profiler: &mut Profiler,
 ) -> Option<usize> {
-    let points = &mesh.points;
-    let (ret, time) = measure_time(move || {
-        let triangles = &mesh.triangulation.triangles;
-        let point = _to_point(point);
-        for (i, triangle) in triangles.chunks(3).enumerate() {
-            let [v1, v2, v3] = [
-                points[triangle[0]].clone(),
-                points[triangle[1]].clone(),
-                points[triangle[2]].clone(),
-            ];
-            if _point_in_triangle(point.clone(), v1, v2, v3) {
-                return Some(i);
-            }
-        }
-        None
-    });
+    let (ret, time) =
+        measure_time(move || find_triangle_at(&mesh.triangulation, &mesh.points, point));
     profiler.add(time);
     ret
 }
@@ -113,9 +124,9 @@ pub(crate) fn label_triangles(
 pub(crate) fn check_shape_in_mesh(
     mesh: &Mesh,
     shape: &CollisionShape,
-    profiler: &mut Profiler,
+    profiler: Option<&mut Profiler>,
 ) -> bool {
-    let (ret, time) = measure_time(move || {
+    let (ret, time) = measure_time_if(profiler.is_some(), move || {
         let Some(vertices) = shape.to_vertices() else {
             return false;
         };
@@ -144,6 +155,8 @@ pub(crate) fn check_shape_in_mesh(
         // }
         // None
     });
-    profiler.add(time);
+    if let (Some(profiler), Some(time)) = (profiler, time) {
+        profiler.add(time);
+    }
     ret
 }